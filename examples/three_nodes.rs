@@ -0,0 +1,82 @@
+//! Three nodes in one process, connected in a line over `MemoryTransport`:
+//! `alice <- bob <- carol`. Alice and Carol never dial each other, so what
+//! Alice says reaches Carol by way of Bob.
+//!
+//! ```sh
+//! cargo run --example three_nodes
+//! ```
+
+use futures::future::select_all;
+use libp2p::{identity, multiaddr::Protocol, Multiaddr};
+use peardchat::{config::Config, memory_transport, ChatEvent, ChatMessage, ChatNode};
+use std::error::Error;
+
+const TOPIC: &str = "chat";
+
+/// Starts a node with an identity, config and memory address of its own.
+async fn start(name: &str) -> Result<(ChatNode, Multiaddr), Box<dyn Error>> {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let (transport, relay, bandwidth) = memory_transport(&id_keys);
+    let addr = Multiaddr::from(Protocol::Memory(rand::random::<u64>() | 1));
+    // Nothing from the user's own setup: no bootstrap peers, no mDNS, and
+    // files kept apart from other nodes'.
+    let dir = std::env::temp_dir().join(format!("peardchat-example-{}", name));
+    let config = Config {
+        bootstrap: Vec::new(),
+        mdns: false,
+        remember_peers: false,
+        nick: Some(name.to_string()),
+        blocklist: dir.join("blocklist"),
+        history_dir: dir.join("history"),
+        profiles_dir: dir.join("profiles"),
+        downloads: dir.join("downloads"),
+        ..Config::default()
+    };
+    let mut node = ChatNode::with_transport(id_keys, &config, transport, relay, bandwidth, addr.clone()).await?;
+    node.join(TOPIC).expect("Joining a topic for the first time");
+    println!("{} is {}", name, node.local_peer_id());
+    Ok((node, addr))
+}
+
+/// Polls every node until `f` says to stop, handing it each event with the
+/// index of the node it came from.
+async fn run_until(nodes: &mut [ChatNode], mut f: impl FnMut(&mut [ChatNode], usize, ChatEvent) -> bool) {
+    loop {
+        let (event, i, _) = select_all(nodes.iter_mut().map(|n| Box::pin(n.poll_event()))).await;
+        if f(nodes, i, event) {
+            return;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let (alice, alice_addr) = start("alice").await?;
+    let (mut bob, bob_addr) = start("bob").await?;
+    let (mut carol, _) = start("carol").await?;
+    bob.dial(alice_addr)?;
+    carol.dial(bob_addr)?;
+    let mut nodes = vec![alice, bob, carol];
+    let names = ["alice", "bob", "carol"];
+
+    // Alice can only be heard once Bob is in the room with her, and Carol
+    // once she is in it with Bob.
+    run_until(&mut nodes, |nodes, _, _| {
+        let in_room = |node: &mut ChatNode| node.peers().iter().filter(|p| p.topics.iter().any(|t| t == TOPIC)).count();
+        in_room(&mut nodes[0]) >= 1 && in_room(&mut nodes[2]) >= 1
+    })
+    .await;
+
+    nodes[0].send(TOPIC, ChatMessage::new(Some("alice".into()), "hello, carol")).await
+        .map_err(|e| format!("Publish error: {:?}", e))?;
+    let mut heard = [false; 3];
+    run_until(&mut nodes, |_, i, event| {
+        if let ChatEvent::MessageReceived { message, .. } = event {
+            println!("{} got \"{}\" from {}", names[i], message.body, message.nick.as_deref().unwrap_or("?"));
+            heard[i] = true;
+        }
+        heard[1] && heard[2]
+    })
+    .await;
+    Ok(())
+}
@@ -0,0 +1,165 @@
+//! Recent chat replayed to peers as they join a topic.
+//!
+//! A node configured as a history provider keeps the last few chat messages
+//! of each topic, exactly as they were published. A node that joins a topic
+//! asks each peer it finds there, once, for that topic's backlog, and a
+//! provider answers with the lot in a single response; other peers answer
+//! with none. Backlogs nobody asked for are never taken in. Each message
+//! still carries its author's signature, so the joiner checks it as it
+//! would a live one and a provider cannot put words in anyone's mouth.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    core::upgrade::{read_one, write_one, ProtocolName},
+    request_response::RequestResponseCodec,
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+};
+
+/// Upper bound on an encoded backlog. Older messages are left out of a
+/// backlog that would be larger.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Upper bound on an encoded request, which only names a topic.
+const MAX_REQUEST_SIZE: usize = 4 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct BacklogProtocol;
+
+impl ProtocolName for BacklogProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/peardchat/backlog/2.0.0"
+    }
+}
+
+/// Asks for the [`Backlog`] of `topic`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BacklogRequest {
+    pub topic: String,
+}
+
+/// Recent messages on `topic`, oldest first, each the payload it was
+/// published with and the bytes of its author's `PeerId`, if known.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backlog {
+    pub topic: String,
+    pub messages: Vec<(Option<Vec<u8>>, Vec<u8>)>,
+}
+
+/// Encodes [`BacklogRequest`]s and the [`Backlog`]s answering them with
+/// bincode behind a length prefix.
+#[derive(Clone)]
+pub struct BacklogCodec;
+
+/// Reads one bincode encoded message of up to `max_size` bytes.
+pub(crate) async fn read_message<T, M>(io: &mut T, max_size: usize) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: for<'de> Deserialize<'de>,
+{
+    let bytes = read_one(io, max_size)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Writes `message` as [`read_message`] reads it.
+pub(crate) async fn write_message<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let bytes = bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_one(io, bytes).await
+}
+
+#[async_trait]
+impl RequestResponseCodec for BacklogCodec {
+    type Protocol = BacklogProtocol;
+    type Request = BacklogRequest;
+    type Response = Backlog;
+
+    async fn read_request<T>(&mut self, _: &BacklogProtocol, io: &mut T) -> io::Result<BacklogRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, MAX_REQUEST_SIZE).await
+    }
+
+    async fn read_response<T>(&mut self, _: &BacklogProtocol, io: &mut T) -> io::Result<Backlog>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, MAX_MESSAGE_SIZE).await
+    }
+
+    async fn write_request<T>(&mut self, _: &BacklogProtocol, io: &mut T, req: BacklogRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &req).await
+    }
+
+    async fn write_response<T>(&mut self, _: &BacklogProtocol, io: &mut T, res: Backlog) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &res).await
+    }
+}
+
+/// A published message: its author, if known, and its payload.
+type Published = (Option<PeerId>, Vec<u8>);
+
+/// The last `capacity` chat messages published on each topic.
+pub struct Recent {
+    capacity: usize,
+    topics: HashMap<String, VecDeque<Published>>,
+}
+
+impl Recent {
+    pub fn new(capacity: usize) -> Self {
+        Recent { capacity, topics: HashMap::new() }
+    }
+
+    /// Keeps `data`, as published on `topic` by `source`, dropping the
+    /// oldest message once there are too many.
+    pub fn push(&mut self, topic: &str, source: Option<PeerId>, data: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let messages = self.topics.entry(topic.to_string()).or_default();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back((source, data));
+    }
+
+    /// What to answer a peer joining `topic` with, if we have anything. As
+    /// many of the newest messages as fit are included.
+    pub fn backlog(&self, topic: &str) -> Option<Backlog> {
+        let mut size = 0;
+        let mut messages: Vec<_> = self
+            .topics
+            .get(topic)?
+            .iter()
+            .rev()
+            .map(|(source, data)| (source.map(|s| s.to_bytes()), data.clone()))
+            .take_while(|(source, data)| {
+                // Room for the lengths bincode puts in front of each part.
+                size += data.len() + source.as_ref().map_or(0, Vec::len) + 32;
+                size < MAX_MESSAGE_SIZE
+            })
+            .collect();
+        if messages.is_empty() {
+            return None;
+        }
+        messages.reverse();
+        Some(Backlog { topic: topic.to_string(), messages })
+    }
+}
@@ -0,0 +1,1144 @@
+//! The libp2p behaviour underneath a [`ChatNode`](crate::ChatNode).
+
+use crate::{
+    backlog::{Backlog, BacklogCodec, BacklogProtocol, BacklogRequest, Recent},
+    blocklist::{load_allowlist, Blocker, Blocklist},
+    config::Config,
+    dm::{DmKeys, DM_TOPIC},
+    fragment::{self, Reassembler},
+    message::{self, check_nick, check_reaction, ChatMessage, MessageKind, FRAGMENT, SEALED},
+    motd::{Motd, MotdCodec, MotdProtocol, MotdRequest},
+    pubsub::{Flood, PubSub},
+    room_key::RoomKey,
+    roster::Roster,
+    transfer::{FileCodec, FileProtocol, FileRequest, FileResponse, Transfers},
+    ChatEvent,
+};
+use chrono::{DateTime, Local};
+use libp2p::{
+    floodsub::FloodsubEvent,
+    gossipsub::{
+        error::{PublishError, SubscriptionError},
+        Gossipsub, GossipsubEvent,
+    },
+    identify::{Identify, IdentifyEvent},
+    identity,
+    kad::{record::store::MemoryStore, Kademlia, KademliaEvent, QueryResult},
+    mdns::{Mdns, MdnsEvent},
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    relay::Relay,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage,
+    },
+    swarm::toggle::Toggle,
+    NetworkBehaviour, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    io,
+    time::{Duration, Instant},
+};
+
+/// Furthest ahead of ours a clock on a message may be for us to catch up
+/// with it. Far more than honest peers ever get ahead, as every one of
+/// them catches up with the rest on each message.
+const MAX_CLOCK_LEAD: u64 = 1_000_000;
+
+// We create a custom network behaviour that combines gossipsub or floodsub, mDNS,
+// Kademlia, identify, circuit relay and request-response protocols for
+// file transfers, for replaying recent chat to peers that join and for
+// handing out a message of the day.
+// The derive generates a delegating `NetworkBehaviour` impl that hands
+// what each behaviour reports up to the swarm as a `BehaviourEvent`. The
+// node passes those back to `handle`, which queues up the `ChatEvent`s
+// they amount to for `next_event`.
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "BehaviourEvent", event_process = false)]
+pub(crate) struct MyBehaviour {
+    // Exactly one of the two is enabled, as the config picks.
+    pub gossipsub: Toggle<Gossipsub>,
+    pub floodsub: Toggle<Flood>,
+    // Disabled when the config turns mDNS off.
+    pub mdns: Toggle<Mdns>,
+    pub kademlia: Kademlia<MemoryStore>,
+    pub identify: Identify,
+    pub ping: Ping,
+    pub relay: Relay,
+    pub blocker: Blocker,
+    pub request_response: RequestResponse<FileCodec>,
+    pub backlog: RequestResponse<BacklogCodec>,
+    pub motd: RequestResponse<MotdCodec>,
+    #[behaviour(ignore)]
+    pub transfers: Transfers,
+    // What we replay to peers joining a topic, if we are a history provider.
+    #[behaviour(ignore)]
+    recent_chat: Option<Recent>,
+    // The backlogs we have asked for and not had yet, with their topics.
+    #[behaviour(ignore)]
+    backlog_requests: HashMap<RequestId, String>,
+    // Whom we have asked for each topic's backlog since joining it.
+    #[behaviour(ignore)]
+    backlogs_asked: HashSet<(PeerId, String)>,
+    // What we answer peers asking for our message of the day.
+    #[behaviour(ignore)]
+    motd_text: Option<String>,
+    // Opens direct messages sent to us; `None` if our identity cannot.
+    #[behaviour(ignore)]
+    pub dm_keys: Option<DmKeys>,
+    // Signs what we publish.
+    #[behaviour(ignore)]
+    id_keys: identity::Keypair,
+    // Encrypts what we publish on chat topics, if we have a room key.
+    #[behaviour(ignore)]
+    room_key: Option<RoomKey>,
+    // Nicknames learned from the messages each peer has sent us.
+    #[behaviour(ignore)]
+    pub nicks: HashMap<PeerId, String>,
+    // Peers and the nicknames we have warned are taken by others as well.
+    #[behaviour(ignore)]
+    collisions: HashSet<(PeerId, String)>,
+    // Peers mDNS currently sees, however many addresses each has.
+    #[behaviour(ignore)]
+    mdns_peers: HashSet<PeerId>,
+    // Whether to dial the peers mDNS and Kademlia find.
+    #[behaviour(ignore)]
+    auto_dial: bool,
+    // Messages published in fragments, until all of each has arrived.
+    #[behaviour(ignore)]
+    fragments: Reassembler,
+    // Longest message body we pass on, in bytes.
+    #[behaviour(ignore)]
+    max_message_size: usize,
+    // Chat messages shown lately, so repeats of them are not.
+    #[behaviour(ignore)]
+    recent: RecentIds,
+    // Whether to show our own messages should they come back to us.
+    #[behaviour(ignore)]
+    echo: bool,
+    // Oldest message format we accept, and the peers we have told the
+    // user send an older one.
+    #[behaviour(ignore)]
+    min_version: u32,
+    #[behaviour(ignore)]
+    outdated: HashSet<PeerId>,
+    // How fast each peer has been sending chat messages.
+    #[behaviour(ignore)]
+    rates: RateLimiter,
+    // Slow mode in each topic, and when each peer last sent chat under it.
+    #[behaviour(ignore)]
+    pub slowmode: SlowMode,
+    // How busy each topic has been.
+    #[behaviour(ignore)]
+    pub activity: Activity,
+    // The client each peer said it runs when identifying itself.
+    #[behaviour(ignore)]
+    pub agents: HashMap<PeerId, String>,
+    // Who is around, as the node and our handlers see it.
+    #[behaviour(ignore)]
+    pub roster: Roster,
+    // When each peer's latest heartbeat arrived.
+    #[behaviour(ignore)]
+    pub heartbeats: HashMap<PeerId, Instant>,
+    // Round-trip time of the latest ping each connected peer answered.
+    #[behaviour(ignore)]
+    pub rtts: HashMap<PeerId, Duration>,
+    // Our Lamport clock: the highest of the clocks on the chat messages we
+    // have sent and taken up from those we received.
+    #[behaviour(ignore)]
+    pub clock: u64,
+    // Chat messages and actions handed on as `MessageReceived`.
+    #[behaviour(ignore)]
+    pub messages_received: u64,
+    #[behaviour(ignore)]
+    events: VecDeque<ChatEvent>,
+}
+
+/// What one of the behaviours in [`MyBehaviour`] reported.
+#[derive(Debug)]
+pub(crate) enum BehaviourEvent {
+    Gossipsub(GossipsubEvent),
+    Floodsub(FloodsubEvent),
+    Mdns(MdnsEvent),
+    Kademlia(KademliaEvent),
+    Identify(IdentifyEvent),
+    Ping(PingEvent),
+    File(RequestResponseEvent<FileRequest, FileResponse>),
+    Backlog(RequestResponseEvent<BacklogRequest, Backlog>),
+    Motd(RequestResponseEvent<MotdRequest, Motd>),
+}
+
+impl From<GossipsubEvent> for BehaviourEvent {
+    fn from(event: GossipsubEvent) -> Self {
+        BehaviourEvent::Gossipsub(event)
+    }
+}
+
+impl From<FloodsubEvent> for BehaviourEvent {
+    fn from(event: FloodsubEvent) -> Self {
+        BehaviourEvent::Floodsub(event)
+    }
+}
+
+impl From<MdnsEvent> for BehaviourEvent {
+    fn from(event: MdnsEvent) -> Self {
+        BehaviourEvent::Mdns(event)
+    }
+}
+
+impl From<KademliaEvent> for BehaviourEvent {
+    fn from(event: KademliaEvent) -> Self {
+        BehaviourEvent::Kademlia(event)
+    }
+}
+
+impl From<IdentifyEvent> for BehaviourEvent {
+    fn from(event: IdentifyEvent) -> Self {
+        BehaviourEvent::Identify(event)
+    }
+}
+
+impl From<PingEvent> for BehaviourEvent {
+    fn from(event: PingEvent) -> Self {
+        BehaviourEvent::Ping(event)
+    }
+}
+
+impl From<RequestResponseEvent<FileRequest, FileResponse>> for BehaviourEvent {
+    fn from(event: RequestResponseEvent<FileRequest, FileResponse>) -> Self {
+        BehaviourEvent::File(event)
+    }
+}
+
+impl From<RequestResponseEvent<BacklogRequest, Backlog>> for BehaviourEvent {
+    fn from(event: RequestResponseEvent<BacklogRequest, Backlog>) -> Self {
+        BehaviourEvent::Backlog(event)
+    }
+}
+
+impl From<RequestResponseEvent<MotdRequest, Motd>> for BehaviourEvent {
+    fn from(event: RequestResponseEvent<MotdRequest, Motd>) -> Self {
+        BehaviourEvent::Motd(event)
+    }
+}
+
+impl From<Infallible> for BehaviourEvent {
+    fn from(event: Infallible) -> Self {
+        match event {}
+    }
+}
+
+impl From<()> for BehaviourEvent {
+    // `relay` does its work out of sight and never reports anything.
+    fn from(_: ()) -> Self {
+        unreachable!("The relay behaviour reports no events")
+    }
+}
+
+impl MyBehaviour {
+    /// Combines the protocols, adding pings, file transfers, backlogs and
+    /// the message of the day, and takes everything else from `config`.
+    /// Without `gossipsub`, chat spreads over floodsub. Fails if the
+    /// blocklist or allowlist cannot be read.
+    pub fn new(
+        gossipsub: Option<Gossipsub>,
+        mdns: Toggle<Mdns>,
+        kademlia: Kademlia<MemoryStore>,
+        identify: Identify,
+        relay: Relay,
+        id_keys: identity::Keypair,
+        config: &Config,
+    ) -> io::Result<Self> {
+        let floodsub = gossipsub.is_none().then(|| Flood::new(id_keys.public().into_peer_id()));
+        Ok(MyBehaviour {
+            gossipsub: gossipsub.into(),
+            floodsub: floodsub.into(),
+            mdns,
+            kademlia,
+            identify,
+            ping: Ping::new(
+                PingConfig::new()
+                    .with_interval(config.ping_interval())
+                    .with_timeout(config.ping_timeout())
+                    .with_keep_alive(config.ping_keep_alive),
+            ),
+            relay,
+            blocker: Blocker::new(
+                Blocklist::load(config.blocklist.clone())?,
+                config.allowlist.as_deref().map(load_allowlist).transpose()?,
+            ),
+            request_response: RequestResponse::new(
+                FileCodec,
+                std::iter::once((FileProtocol, ProtocolSupport::Full)),
+                // Leave the receiver time to confirm large files.
+                RequestResponseConfig::default()
+                    .set_request_timeout(Duration::from_secs(120))
+                    .set_connection_keep_alive(config.idle_timeout())
+                    .clone(),
+            ),
+            backlog: RequestResponse::new(
+                BacklogCodec,
+                std::iter::once((BacklogProtocol, ProtocolSupport::Full)),
+                RequestResponseConfig::default().set_connection_keep_alive(config.idle_timeout()).clone(),
+            ),
+            motd: RequestResponse::new(
+                MotdCodec,
+                std::iter::once((MotdProtocol, ProtocolSupport::Full)),
+                RequestResponseConfig::default().set_connection_keep_alive(config.idle_timeout()).clone(),
+            ),
+            transfers: Transfers::new(config.downloads.clone()),
+            motd_text: config.motd.clone(),
+            recent_chat: config.history_provider.then(|| Recent::new(config.history_provider_messages)),
+            backlog_requests: HashMap::new(),
+            backlogs_asked: HashSet::new(),
+            dm_keys: DmKeys::new(&id_keys, config.forward_secrecy),
+            id_keys,
+            room_key: config.room_key.as_deref().map(RoomKey::new),
+            nicks: HashMap::new(),
+            collisions: HashSet::new(),
+            agents: HashMap::new(),
+            roster: Roster::default(),
+            heartbeats: HashMap::new(),
+            rtts: HashMap::new(),
+            clock: 0,
+            messages_received: 0,
+            mdns_peers: HashSet::new(),
+            auto_dial: config.auto_dial,
+            fragments: Reassembler::default(),
+            max_message_size: config.max_message_size,
+            recent: RecentIds::new(config.dedup_window()),
+            echo: config.echo,
+            min_version: config.min_version,
+            outdated: HashSet::new(),
+            rates: RateLimiter::new(config.rate_limit_messages, config.rate_limit_window(), config.soft_block()),
+            slowmode: SlowMode::default(),
+            activity: Activity::default(),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Whichever of gossipsub and floodsub spreads chat.
+    pub fn pubsub(&self) -> &dyn PubSub {
+        match (self.gossipsub.as_ref(), self.floodsub.as_ref()) {
+            (Some(gossipsub), _) => gossipsub,
+            (None, Some(floodsub)) => floodsub,
+            (None, None) => unreachable!("Gossipsub or floodsub is always enabled"),
+        }
+    }
+
+    pub fn pubsub_mut(&mut self) -> &mut dyn PubSub {
+        match (self.gossipsub.as_mut(), self.floodsub.as_mut()) {
+            (Some(gossipsub), _) => gossipsub,
+            (None, Some(floodsub)) => floodsub,
+            (None, None) => unreachable!("Gossipsub or floodsub is always enabled"),
+        }
+    }
+
+    /// Subscribes to `topic`, returning false if we already were, and asks
+    /// the peers already there for its recent chat.
+    pub fn join(&mut self, topic: &str) -> Result<bool, SubscriptionError> {
+        let joined = self.pubsub_mut().subscribe(topic)?;
+        let there: Vec<_> = self.pubsub().peers().into_iter().filter(|(_, t)| t.iter().any(|t| t == topic)).collect();
+        for (peer, _) in there {
+            self.request_backlog(peer, topic);
+        }
+        Ok(joined)
+    }
+
+    /// Unsubscribes from `topic`, returning false if we were not subscribed.
+    pub fn leave(&mut self, topic: &str) -> bool {
+        self.backlogs_asked.retain(|(_, t)| t != topic);
+        self.pubsub_mut().unsubscribe(topic)
+    }
+
+    /// Forgets having asked `peer` for backlogs, once we are no longer
+    /// connected to it.
+    pub fn forget_backlogs(&mut self, peer: &PeerId) {
+        self.backlogs_asked.retain(|(p, _)| p != peer);
+    }
+
+    /// Asks `peer` for the recent chat on `topic`, unless we have since
+    /// joining it.
+    fn request_backlog(&mut self, peer: PeerId, topic: &str) {
+        if self.blocker.refuses(&peer) || !self.backlogs_asked.insert((peer, topic.to_string())) {
+            return;
+        }
+        let request = self.backlog.send_request(&peer, BacklogRequest { topic: topic.to_string() });
+        self.backlog_requests.insert(request, topic.to_string());
+    }
+
+    /// Records that `peer` goes by `nick`. A nickname another peer goes by
+    /// too is reported once per peer and nickname.
+    fn set_nick(&mut self, peer: PeerId, nick: String) {
+        let others: Vec<_> = self.nicks.iter().filter(|&(p, n)| *p != peer && *n == nick).map(|(p, _)| *p).collect();
+        if !others.is_empty() && self.collisions.insert((peer, nick.clone())) {
+            self.events.push_back(ChatEvent::NickCollision { nick: nick.clone(), peer, others });
+        }
+        self.roster.set_nick(&peer, &nick);
+        self.nicks.insert(peer, nick);
+    }
+
+    /// Publishes `message` on `topic`, signed, and sealed if we have a
+    /// room key.
+    pub fn publish(&mut self, topic: &str, message: &ChatMessage) -> Result<(), PublishError> {
+        let mut data = message.encode_signed(topic, &self.id_keys);
+        if let Some(key) = &self.room_key {
+            data = key.seal(topic, &data);
+        }
+        let me = self.id_keys.public().into_peer_id();
+        if let (Some(recent), MessageKind::Chat | MessageKind::Action) = (&mut self.recent_chat, message.kind) {
+            recent.push(topic, Some(me), data.clone());
+        }
+        // Shown when sent, so a copy coming back is dropped as a repeat.
+        // Another instance running as us sends other ids, which still show.
+        if let (false, MessageKind::Chat | MessageKind::Action, Some(id)) = (self.echo, message.kind, message.id) {
+            self.recent.insert((Some(me), id));
+        }
+        if let MessageKind::Chat | MessageKind::Action = message.kind {
+            let _ = self.slowmode.allow(topic, me, sent_at(message));
+            self.activity.record(topic, Some(me));
+        }
+        self.publish_data(topic, data)
+    }
+
+    /// Publishes a sealed direct message, signed so that whoever opens it
+    /// knows who sent it, whichever protocol carries it.
+    pub fn publish_dm(&mut self, payload: &[u8]) -> Result<(), PublishError> {
+        let data = message::sign(DM_TOPIC, payload, &self.id_keys);
+        self.publish_data(DM_TOPIC, data)
+    }
+
+    /// Publishes `data` on `topic`, in fragments if it is too long to go in
+    /// one piece. Fails with [`PublishError::MessageTooLarge`] if that would
+    /// take too many.
+    pub fn publish_data(&mut self, topic: &str, data: Vec<u8>) -> Result<(), PublishError> {
+        let max_len = self.pubsub().max_data_len();
+        if data.len() <= max_len {
+            return self.pubsub_mut().publish(topic, data);
+        }
+        let fragments = fragment::split(rand::random(), &data, max_len).ok_or(PublishError::MessageTooLarge)?;
+        tracing::debug!(%topic, size = data.len(), fragments = fragments.len(), "Publishing in fragments");
+        for fragment in fragments {
+            self.pubsub_mut().publish(topic, fragment)?;
+        }
+        Ok(())
+    }
+
+    /// Hands on the chat messages in a backlog for one of our topics, as
+    /// [`ChatEvent::History`]. They are checked like live messages, and
+    /// those we have shown already are left out.
+    fn replay(&mut self, Backlog { topic, messages }: Backlog) {
+        if !self.pubsub().topics().contains(&topic) {
+            return;
+        }
+        for (source, data) in messages {
+            let source = match source.map(|s| PeerId::from_bytes(&s)).transpose() {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+            let data = match self.unseal(&topic, data) {
+                Some(data) => data,
+                None => continue,
+            };
+            if source.is_some_and(|s| self.blocker.refuses(&s)) {
+                continue;
+            }
+            let (message, authenticated) = match ChatMessage::decode_signed(&data, &topic, source.as_ref()) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            if message.body.len() > self.max_message_size || !matches!(message.kind, MessageKind::Chat | MessageKind::Action) {
+                continue;
+            }
+            if self.outdated(source, &message) {
+                continue;
+            }
+            // A provider could claim any id for what it made up, and so
+            // hide what its author really said.
+            if let Some(id) = message.id.filter(|_| authenticated) {
+                if !self.recent.insert((source, id)) {
+                    continue;
+                }
+            }
+            if self.too_fast(&topic, source, &message) {
+                continue;
+            }
+            let message = self.observe_clock(message, authenticated);
+            self.events.push_back(ChatEvent::History { topic: topic.clone(), source, authenticated, message });
+        }
+    }
+
+    /// Catches our clock up with the one on `message`, if it is authenticated
+    /// and no more than [`MAX_CLOCK_LEAD`] ahead. Clocks we do not take are
+    /// stripped, so they do not reorder what is shown either.
+    fn observe_clock(&mut self, message: ChatMessage, authenticated: bool) -> ChatMessage {
+        match message.clock {
+            Some(clock) if authenticated && clock <= self.clock.saturating_add(MAX_CLOCK_LEAD) => {
+                self.clock = self.clock.max(clock);
+                message
+            }
+            Some(clock) => {
+                tracing::debug!(clock, ours = self.clock, authenticated, "Ignored a clock we cannot trust");
+                ChatMessage { clock: None, ..message }
+            }
+            None => message,
+        }
+    }
+
+    /// Whether `source` has sent more chat than the rate limit allows.
+    fn over_rate(&mut self, topic: &str, source: Option<PeerId>) -> bool {
+        match source {
+            Some(source) if !self.rates.allow(source) => {
+                tracing::debug!(%topic, %source, "Dropped a message over the rate limit");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `message` from `source` was sent too soon after its last one
+    /// on `topic` for slow mode there, in which case we say so.
+    fn too_fast(&mut self, topic: &str, source: Option<PeerId>, message: &ChatMessage) -> bool {
+        let peer = match source {
+            Some(peer) => peer,
+            None => return false,
+        };
+        match self.slowmode.allow(topic, peer, sent_at(message)) {
+            Ok(()) => false,
+            Err(interval) => {
+                tracing::debug!(%topic, %peer, ?interval, "Dropped a message sent too soon for slow mode");
+                let nick = self.nicks.get(&peer).cloned();
+                self.events.push_back(ChatEvent::SlowModeDropped {
+                    topic: topic.to_string(),
+                    peer,
+                    nick,
+                    interval_secs: interval.as_secs(),
+                });
+                true
+            }
+        }
+    }
+
+    /// Whether `message` is in a format older than we accept. The first
+    /// time that is so for a peer, we say which.
+    fn outdated(&mut self, source: Option<PeerId>, message: &ChatMessage) -> bool {
+        if message.version >= self.min_version {
+            return false;
+        }
+        if let Some(peer) = source.filter(|peer| self.outdated.insert(*peer)) {
+            self.events.push_back(ChatEvent::OutdatedPeer {
+                peer,
+                version: message.version,
+                min_version: self.min_version,
+                agent_version: self.agents.get(&peer).cloned(),
+            });
+        }
+        true
+    }
+
+    /// What was published on `topic` as `data`, decrypted with our room key
+    /// if we have one. `None` if it is sealed and we cannot open it, or if
+    /// we have a key and it is not sealed.
+    fn unseal(&self, topic: &str, data: Vec<u8>) -> Option<Vec<u8>> {
+        match &self.room_key {
+            Some(key) => key.open(topic, &data),
+            None if data.first() == Some(&SEALED) => None,
+            None => Some(data),
+        }
+    }
+
+    /// What has come of the events handled so far, and of file transfers,
+    /// one at a time.
+    pub fn next_event(&mut self) -> Option<ChatEvent> {
+        self.events.pop_front().or_else(|| self.transfers.next_event().map(ChatEvent::Transfer))
+    }
+
+    /// Acts on what one of the behaviours reported, queueing up whatever
+    /// should be reported in turn for [`MyBehaviour::next_event`].
+    pub fn handle(&mut self, event: BehaviourEvent) {
+        match event {
+            BehaviourEvent::Gossipsub(event) => self.on_gossipsub(event),
+            BehaviourEvent::Floodsub(event) => self.on_floodsub(event),
+            BehaviourEvent::Mdns(event) => self.on_mdns(event),
+            BehaviourEvent::Kademlia(event) => self.on_kademlia(event),
+            BehaviourEvent::Identify(event) => self.on_identify(event),
+            BehaviourEvent::Ping(event) => self.on_ping(event),
+            BehaviourEvent::File(event) => self.transfers.inject_event(&mut self.request_response, event),
+            BehaviourEvent::Backlog(event) => self.on_backlog(event),
+            BehaviourEvent::Motd(event) => self.on_motd(event),
+        }
+    }
+}
+
+/// Ids of recent chat messages, with their authors, each kept for a while.
+struct RecentIds {
+    window: Duration,
+    order: VecDeque<(Instant, (Option<PeerId>, u64))>,
+    ids: HashSet<(Option<PeerId>, u64)>,
+}
+
+impl RecentIds {
+    fn new(window: Duration) -> Self {
+        RecentIds { window, order: VecDeque::new(), ids: HashSet::new() }
+    }
+
+    /// Records `key`, returning whether it was not already there.
+    fn insert(&mut self, key: (Option<PeerId>, u64)) -> bool {
+        let now = Instant::now();
+        while let Some((seen, _)) = self.order.front() {
+            if now.duration_since(*seen) < self.window {
+                break;
+            }
+            let (_, old) = self.order.pop_front().expect("front exists");
+            self.ids.remove(&old);
+        }
+        if !self.ids.insert(key) {
+            return false;
+        }
+        self.order.push_back((now, key));
+        true
+    }
+}
+
+/// How many times a peer may go over the rate limit, within a soft block's
+/// length of each other, before it is soft blocked.
+const STRIKES: usize = 3;
+
+/// Counts each peer's chat messages over a sliding window.
+struct RateLimiter {
+    limit: usize,
+    window: Duration,
+    soft_block: Duration,
+    peers: HashMap<PeerId, PeerRate>,
+}
+
+#[derive(Default)]
+struct PeerRate {
+    // When the messages still within the window arrived.
+    recent: VecDeque<Instant>,
+    // When the peer went over the limit, at most once per window.
+    strikes: VecDeque<Instant>,
+    soft_blocked_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(limit: usize, window: Duration, soft_block: Duration) -> Self {
+        RateLimiter { limit, window, soft_block, peers: HashMap::new() }
+    }
+
+    /// Counts a message from `peer`, returning whether it is within the
+    /// limit and the peer is not soft blocked.
+    fn allow(&mut self, peer: PeerId) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+        let (now, window, soft_block) = (Instant::now(), self.window, self.soft_block);
+        let rate = self.peers.entry(peer).or_default();
+        if rate.soft_blocked_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        while rate.recent.front().is_some_and(|t| now.duration_since(*t) >= window) {
+            rate.recent.pop_front();
+        }
+        if rate.recent.len() < self.limit {
+            rate.recent.push_back(now);
+            return true;
+        }
+        if rate.strikes.back().is_some_and(|t| now.duration_since(*t) < window) {
+            return false;
+        }
+        tracing::warn!(%peer, limit = self.limit, ?window, "Peer is over the rate limit, dropping its messages");
+        rate.strikes.push_back(now);
+        while rate.strikes.front().is_some_and(|t| now.duration_since(*t) >= soft_block) {
+            rate.strikes.pop_front();
+        }
+        if rate.strikes.len() >= STRIKES && !soft_block.is_zero() {
+            tracing::warn!(%peer, duration = ?soft_block, "Ignoring peer for flooding");
+            rate.soft_blocked_until = Some(now + soft_block);
+            rate.strikes.clear();
+        }
+        false
+    }
+}
+
+/// When `message` says it was sent, in milliseconds since the Unix epoch,
+/// or now if it does not say.
+fn sent_at(message: &ChatMessage) -> i64 {
+    message.sent_at.unwrap_or_else(|| chrono::Local::now().timestamp_millis())
+}
+
+/// Slow mode in each topic, as last announced by whoever set it first, and
+/// when each peer's latest chat message under it was sent.
+///
+/// Messages are judged by when they say they were sent rather than when
+/// they arrive, so every peer drops the same ones, however they are
+/// delayed on the way.
+#[derive(Default)]
+pub(crate) struct SlowMode {
+    rooms: HashMap<String, SlowModeSetting>,
+    last_sent: HashMap<(String, PeerId), i64>,
+}
+
+/// Slow mode as someone set it for a topic.
+#[derive(Debug, Clone)]
+pub(crate) struct SlowModeSetting {
+    pub interval: Duration,
+    pub set_by: PeerId,
+    /// The message that set it, re-sent as it is to peers that join later.
+    pub announcement: ChatMessage,
+}
+
+impl SlowMode {
+    /// The setting in `topic`, if slow mode is on there.
+    pub fn get(&self, topic: &str) -> Option<&SlowModeSetting> {
+        self.rooms.get(topic).filter(|setting| !setting.interval.is_zero())
+    }
+
+    /// Who set slow mode in `topic` first, and so may change it, even if
+    /// they have since turned it off.
+    pub fn host(&self, topic: &str) -> Option<PeerId> {
+        self.rooms.get(topic).map(|setting| setting.set_by)
+    }
+
+    /// Takes up what `set_by` announced for `topic`, unless it is no later
+    /// than the current setting or someone else set that. Returns the new
+    /// interval if it was taken up, or `None` for an older setting, a repeat
+    /// of the current one or one from anyone but the host.
+    pub fn set(&mut self, topic: &str, set_by: PeerId, announcement: ChatMessage) -> Option<Duration> {
+        let secs = announcement.body.trim().parse().ok()?;
+        if let Some(current) = self.rooms.get(topic) {
+            if current.set_by != set_by || sent_at(&current.announcement) >= sent_at(&announcement) {
+                return None;
+            }
+        }
+        let interval = Duration::from_secs(secs);
+        self.rooms.insert(topic.to_string(), SlowModeSetting { interval, set_by, announcement });
+        Some(interval)
+    }
+
+    /// Counts a chat message `peer` sent on `topic` at `sent_at`, or fails
+    /// with the interval if it came too soon after the last one.
+    pub fn allow(&mut self, topic: &str, peer: PeerId, sent_at: i64) -> Result<(), Duration> {
+        let interval = match self.get(topic) {
+            Some(setting) => setting.interval,
+            None => return Ok(()),
+        };
+        let last = self.last_sent.entry((topic.to_string(), peer)).or_insert(i64::MIN);
+        if sent_at.saturating_sub(*last) < interval.as_millis() as i64 {
+            return Err(interval);
+        }
+        *last = sent_at;
+        Ok(())
+    }
+
+    /// How much longer `peer` has to wait before sending on `topic`.
+    pub fn wait(&self, topic: &str, peer: PeerId) -> Option<Duration> {
+        let interval = self.get(topic)?.interval.as_millis() as i64;
+        let last = *self.last_sent.get(&(topic.to_string(), peer))?;
+        let left = last.saturating_add(interval) - chrono::Local::now().timestamp_millis();
+        (left > 0).then(|| Duration::from_millis(left as u64))
+    }
+}
+
+/// How long the recent chat in a topic's [`TopicActivity`] goes back.
+pub(crate) const ACTIVITY_WINDOW: Duration = Duration::from_secs(60);
+
+/// How busy each topic has been, going by the chat messages shown and sent
+/// there since starting or the last reset.
+#[derive(Default)]
+pub(crate) struct Activity {
+    topics: HashMap<String, TopicActivity>,
+}
+
+#[derive(Default)]
+pub(crate) struct TopicActivity {
+    pub total: u64,
+    /// When each message of the last [`ACTIVITY_WINDOW`] was seen.
+    pub recent: VecDeque<Instant>,
+    /// Everyone who sent any of them, as far as we know, us included.
+    pub senders: HashSet<PeerId>,
+    pub last: Option<DateTime<Local>>,
+}
+
+impl Activity {
+    /// Counts a chat message `source` sent on `topic`, seen just now.
+    pub fn record(&mut self, topic: &str, source: Option<PeerId>) {
+        let now = Instant::now();
+        let activity = self.topics.entry(topic.to_string()).or_default();
+        activity.total += 1;
+        while activity.recent.front().is_some_and(|seen| now.duration_since(*seen) >= ACTIVITY_WINDOW) {
+            activity.recent.pop_front();
+        }
+        activity.recent.push_back(now);
+        activity.senders.extend(source);
+        activity.last = Some(Local::now());
+    }
+
+    pub fn get(&self, topic: &str) -> Option<&TopicActivity> {
+        self.topics.get(topic)
+    }
+
+    /// Starts counting over in every topic.
+    pub fn reset(&mut self) {
+        self.topics.clear();
+    }
+}
+
+impl MyBehaviour {
+    /// Acts on what `gossipsub` reported.
+    fn on_gossipsub(&mut self, event: GossipsubEvent) {
+        match event {
+            GossipsubEvent::Subscribed { peer_id, topic } => self.on_subscribed(peer_id, topic.as_str()),
+            GossipsubEvent::Message { message, .. } => {
+                self.on_message(message.source, message.topic.into_string(), message.data)
+            }
+            GossipsubEvent::Unsubscribed { .. } => {}
+        }
+    }
+
+    /// Acts on what `floodsub` reported. A message can be on several topics
+    /// at once, though we only ever publish on one.
+    fn on_floodsub(&mut self, event: FloodsubEvent) {
+        match event {
+            FloodsubEvent::Subscribed { peer_id, topic } => self.on_subscribed(peer_id, topic.id()),
+            FloodsubEvent::Message(message) =>
+                for topic in message.topics {
+                    self.on_message(Some(message.source), topic.id().to_string(), message.data.clone());
+                }
+            FloodsubEvent::Unsubscribed { .. } => {}
+        }
+    }
+
+    /// Acts on `peer` joining `topic`.
+    fn on_subscribed(&mut self, peer: PeerId, topic: &str) {
+        if self.pubsub().topics().iter().any(|t| t == topic) {
+            self.request_backlog(peer, topic);
+        }
+        // Whoever set slow mode tells those who join after.
+        let me = self.id_keys.public().into_peer_id();
+        let announcement = self.slowmode.rooms.get(topic).filter(|s| s.set_by == me).map(|s| s.announcement.clone());
+        if let Some(announcement) = announcement {
+            if let Err(e) = self.publish(topic, &announcement) {
+                tracing::debug!(%topic, "Could not announce slow mode: {:?}", e);
+            }
+        }
+    }
+
+    /// Acts on `payload` arriving on `topic`, published by `source` if it
+    /// says so.
+    fn on_message(&mut self, source: Option<PeerId>, topic: String, payload: Vec<u8>) {
+        // Others may pass on what blocked peers publish.
+        if let Some(source) = source.filter(|s| self.blocker.refuses(s)) {
+            tracing::debug!(%source, "Dropped a message from a blocked peer");
+            return;
+        }
+        let payload = match payload.first() {
+            Some(&FRAGMENT) => match self.fragments.push(source, &topic, &payload, Instant::now()) {
+                Some(whole) => whole,
+                None => return,
+            },
+            _ => payload,
+        };
+        if topic == DM_TOPIC {
+            // Floodsub takes the `source` on trust, so direct messages must
+            // be signed by it. Most are for someone else; those, and any
+            // that fail authentication, are dropped silently.
+            let sealed = match message::open_signed(&payload, DM_TOPIC, source.as_ref()) {
+                Some((sealed, true)) => sealed,
+                _ => {
+                    tracing::debug!(source = ?source, "Dropped a direct message not signed by its source");
+                    return;
+                }
+            };
+            let opened = match (self.dm_keys.as_mut(), source) {
+                (Some(keys), Some(source)) => keys.open(&source, sealed).map(|(m, fs)| (source, m, fs)),
+                _ => None,
+            };
+            match opened {
+                Some((source, message, _)) if message.body.len() > self.max_message_size => {
+                    tracing::warn!(%source, size = message.body.len(), "Dropped oversized direct message");
+                }
+                Some((source, message, _)) if self.outdated(Some(source), &message) => {
+                    tracing::debug!(%source, version = message.version, "Dropped an outdated direct message");
+                }
+                Some((source, message, forward_secret)) => {
+                    self.events.push_back(ChatEvent::DirectMessage { source, message, forward_secret })
+                }
+                None => tracing::debug!("Dropped a direct message we could not open"),
+            }
+            return;
+        }
+        let data = match self.unseal(&topic, payload.clone()) {
+            Some(data) => data,
+            None => {
+                tracing::debug!(%topic, source = ?source, "Dropped a message not sealed with our room key");
+                return;
+            }
+        };
+        let (message, authenticated) = match ChatMessage::decode_signed(&data, &topic, source.as_ref()) {
+            Some(decoded) => decoded,
+            None => {
+                tracing::warn!(%topic, source = ?source, "Dropped a message that did not decode");
+                return;
+            }
+        };
+        if message.body.len() > self.max_message_size {
+            tracing::warn!(%topic, source = ?source, size = message.body.len(), "Dropped oversized message");
+            return;
+        }
+        if self.outdated(source, &message) {
+            tracing::debug!(%topic, source = ?source, version = message.version, "Dropped an outdated message");
+            return;
+        }
+        match (message.kind, message.id, source) {
+            (MessageKind::Ack, _, _) if !authenticated => return,
+            (MessageKind::Ack, Some(id), Some(peer)) => {
+                let nick = self.nicks.get(&peer).cloned();
+                self.events.push_back(ChatEvent::Delivered { topic, id, peer, nick });
+                return;
+            }
+            (MessageKind::Ack, ..) => return,
+            // Anyone could claim someone else is still around.
+            (MessageKind::Heartbeat, _, Some(peer)) if authenticated => {
+                let now = Instant::now();
+                self.heartbeats.insert(peer, now);
+                self.roster.heartbeat(&peer, now);
+                if let Some(nick) = message.nick {
+                    self.set_nick(peer, nick);
+                }
+                return;
+            }
+            (MessageKind::Heartbeat, ..) => return,
+            // Sent on every topic we share, and again and again while away,
+            // so only a change is news.
+            (MessageKind::Presence, _, Some(peer)) if authenticated => {
+                let ChatMessage { nick, body, .. } = message;
+                let now = Instant::now();
+                self.heartbeats.insert(peer, now);
+                self.roster.heartbeat(&peer, now);
+                if let Some(nick) = nick {
+                    self.set_nick(peer, nick);
+                }
+                let away = body == "away";
+                if self.roster.set_away(&peer, away) {
+                    let nick = self.nicks.get(&peer).cloned();
+                    self.events.push_back(ChatEvent::PresenceChanged { peer, nick, away });
+                }
+                return;
+            }
+            (MessageKind::Presence, ..) => return,
+            (MessageKind::Typing | MessageKind::StoppedTyping, _, Some(peer)) if authenticated => {
+                let nick = message.nick.or_else(|| self.nicks.get(&peer).cloned());
+                let typing = message.kind == MessageKind::Typing;
+                self.events.push_back(ChatEvent::Typing { topic, peer, nick, typing });
+                return;
+            }
+            (MessageKind::Typing | MessageKind::StoppedTyping, ..) => return,
+            (MessageKind::SlowMode, _, Some(peer)) if authenticated => {
+                let nick = message.nick.clone().or_else(|| self.nicks.get(&peer).cloned());
+                if let Some(interval) = self.slowmode.set(&topic, peer, message) {
+                    self.events.push_back(ChatEvent::SlowMode { topic, peer, nick, interval_secs: interval.as_secs() });
+                }
+                return;
+            }
+            (MessageKind::SlowMode, ..) => return,
+            // Sent on every topic we share, so only the first copy is news.
+            (MessageKind::Nick, _, Some(peer)) if authenticated => {
+                let ChatMessage { nick, body, .. } = message;
+                if let Some(new) = nick.filter(|n| check_nick(n).is_ok()) {
+                    let old = self.nicks.get(&peer).cloned().or_else(|| Some(body).filter(|b| !b.is_empty()));
+                    if old.as_ref() != Some(&new) {
+                        self.set_nick(peer, new.clone());
+                        self.events.push_back(ChatEvent::NickChanged { peer, old, new });
+                    }
+                }
+                return;
+            }
+            (MessageKind::Nick, ..) => return,
+            // Anyone could pile on in someone else's name.
+            (MessageKind::Reaction, _, Some(peer)) if authenticated => {
+                let ChatMessage { nick, body: emoji, reply_to, .. } = message;
+                if let (Some(to), Ok(())) = (reply_to, check_reaction(&emoji)) {
+                    let nick = nick.or_else(|| self.nicks.get(&peer).cloned());
+                    self.events.push_back(ChatEvent::Reaction { topic, peer, nick, to, emoji });
+                }
+                return;
+            }
+            (MessageKind::Reaction, ..) => return,
+            (MessageKind::Chat | MessageKind::Action, Some(id), source) => {
+                // Gossipsub drops copies of a message it has seen, but
+                // not the same message republished, which gets a new
+                // sequence number. Repeats typed on purpose get new ids.
+                if !self.recent.insert((source, id)) {
+                    tracing::debug!(%topic, ?source, id, "Dropped repeated message");
+                    return;
+                }
+                // Only after that, so repeats reaching us over several
+                // paths do not count against the author.
+                if self.over_rate(&topic, source) || self.too_fast(&topic, source, &message) {
+                    return;
+                }
+                // Let the author know we got it. This fails harmlessly
+                // if the author has already left the topic.
+                if let Err(e) = self.publish(&topic, &ChatMessage::ack(id)) {
+                    tracing::debug!("Could not acknowledge message {}: {:?}", id, e);
+                }
+            }
+            (MessageKind::Chat | MessageKind::Action, None, source)
+                if self.over_rate(&topic, source) || self.too_fast(&topic, source, &message) =>
+            {
+                return
+            }
+            (MessageKind::Chat | MessageKind::Action, None, _) => {}
+        }
+        tracing::info!(%topic, source = ?source, authenticated, "Chat message received");
+        if let (Some(nick), Some(source), true) = (&message.nick, source, authenticated) {
+            self.set_nick(source, nick.clone());
+        }
+        if let Some(recent) = &mut self.recent_chat {
+            recent.push(&topic, source, payload);
+        }
+        let message = self.observe_clock(message, authenticated);
+        self.messages_received += 1;
+        self.activity.record(&topic, source);
+        self.events.push_back(ChatEvent::MessageReceived {
+            topic,
+            source,
+            authenticated,
+            message,
+        });
+    }
+
+    /// Acts on what `mdns` reported.
+    fn on_mdns(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(list) =>
+                for (peer, addr) in list {
+                    if self.blocker.refuses(&peer) {
+                        continue;
+                    }
+                    if self.auto_dial {
+                        self.pubsub_mut().add_peer(&peer);
+                    }
+                    self.kademlia.add_address(&peer, addr);
+                    // A peer announces one record per address; report it once.
+                    if self.mdns_peers.insert(peer) {
+                        self.events.push_back(ChatEvent::PeerDiscovered(peer));
+                    }
+                }
+            MdnsEvent::Expired(list) =>
+                for (peer, _) in list {
+                    if !self.mdns.as_ref().is_some_and(|m| m.has_node(&peer)) && self.mdns_peers.remove(&peer) {
+                        self.pubsub_mut().remove_peer(&peer);
+                        self.events.push_back(ChatEvent::PeerExpired(peer));
+                    }
+                }
+        }
+    }
+
+    /// Acts on what `backlog` reported.
+    fn on_backlog(&mut self, event: RequestResponseEvent<BacklogRequest, Backlog>) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                let backlog = self.recent_chat.as_ref().and_then(|r| r.backlog(&request.topic));
+                let backlog = match backlog.filter(|_| !self.blocker.refuses(&peer)) {
+                    Some(backlog) => {
+                        tracing::debug!(%peer, topic = %request.topic, messages = backlog.messages.len(), "Replaying recent chat");
+                        backlog
+                    }
+                    None => Backlog { topic: request.topic, messages: Vec::new() },
+                };
+                let _ = self.backlog.send_response(channel, backlog);
+            }
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Response { request_id, response } } => {
+                // Only the one answer to each request of ours, and only
+                // for the topic asked about.
+                match self.backlog_requests.remove(&request_id) {
+                    Some(topic) if topic == response.topic => self.replay(response),
+                    _ => tracing::debug!(%peer, "Dropped recent chat we did not ask for"),
+                }
+            }
+            RequestResponseEvent::OutboundFailure { peer, request_id, error } => {
+                self.backlog_requests.remove(&request_id);
+                tracing::debug!("Recent chat from {} did not arrive: {:?}", peer, error);
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                tracing::debug!("Could not replay recent chat to {}: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Acts on what `motd` reported.
+    fn on_motd(&mut self, event: RequestResponseEvent<MotdRequest, Motd>) {
+        match event {
+            RequestResponseEvent::Message { message: RequestResponseMessage::Request { channel, .. }, .. } => {
+                let _ = self.motd.send_response(channel, Motd(self.motd_text.clone()));
+            }
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Response { response, .. } } => {
+                if let Some(text) = response.0.filter(|t| !t.trim().is_empty()) {
+                    self.events.push_back(ChatEvent::Motd { peer, text });
+                }
+            }
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                tracing::debug!("Could not get the message of the day from {}: {:?}", peer, error);
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                tracing::debug!("Could not send the message of the day to {}: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+
+    /// Acts on what `kademlia` reported.
+    fn on_kademlia(&mut self, event: KademliaEvent) {
+        match event {
+            // Peers found through the DHT join the pubsub mesh just like mDNS ones.
+            KademliaEvent::RoutingUpdated { peer, .. } if self.auto_dial => {
+                self.pubsub_mut().add_peer(&peer);
+            }
+            KademliaEvent::QueryResult { result: QueryResult::Bootstrap(result), .. } => {
+                match result {
+                    Ok(ok) if ok.num_remaining == 0 => tracing::info!("Kademlia bootstrap complete"),
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("Kademlia bootstrap failed: {:?}", e),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Acts on what `identify` reported.
+    fn on_identify(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            // Where the peer listens, unlike the ephemeral port it dialed us
+            // from, is where we can reach it again later.
+            for addr in &info.listen_addrs {
+                self.kademlia.add_address(&peer_id, addr.clone());
+            }
+            // Identify repeats itself periodically; only report news.
+            if self.agents.get(&peer_id) != Some(&info.agent_version) {
+                self.agents.insert(peer_id, info.agent_version.clone());
+                self.events.push_back(ChatEvent::PeerIdentified {
+                    peer: peer_id,
+                    agent_version: info.agent_version,
+                    protocol_version: info.protocol_version,
+                    listen_addrs: info.listen_addrs,
+                });
+            }
+        }
+    }
+
+    /// Acts on what `ping` reported. A connection whose pings keep failing
+    /// is closed by `ping` itself.
+    fn on_ping(&mut self, event: PingEvent) {
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                self.rtts.insert(event.peer, rtt);
+            }
+            Ok(PingSuccess::Pong) => {}
+            Err(error) => tracing::debug!(peer = %event.peer, %error, "Ping failed"),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! Peers we refuse to hear from, kept in a file with one PeerId per line.
+//! An allowlist, in the same format, turns it around: only the peers it
+//! lists are heard from.
+//!
+//! [`Blocker`] enforces both lists on connections: the handler it gives each
+//! connection to a blocked peer closes that connection straight away. That
+//! way the swarm reports the close to every other behaviour as usual, which
+//! banning the peer from the swarm would not. It closes connections we
+//! prune to stay under the connection limits, or hang up on, the same way.
+
+use libp2p::{
+    core::{
+        connection::ConnectionId,
+        upgrade::{DeniedUpgrade, InboundUpgrade, OutboundUpgrade},
+        ConnectedPoint,
+    },
+    swarm::{
+        IntoProtocolsHandler, KeepAlive, NegotiatedSubstream, NetworkBehaviour, NetworkBehaviourAction,
+        NotifyHandler, PollParameters, ProtocolsHandler, ProtocolsHandlerEvent, ProtocolsHandlerUpgrErr,
+        SubstreamProtocol,
+    },
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    fs, io,
+    path::{Path, PathBuf},
+    task::{Context, Poll},
+    time::Instant,
+};
+
+#[derive(Debug)]
+pub struct Blocklist {
+    path: PathBuf,
+    peers: HashSet<PeerId>,
+}
+
+impl Blocklist {
+    /// Reads the blocklist at `path`, which starts out empty if there is no
+    /// such file. Lines that are not PeerIds are skipped.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let peers = match fs::read_to_string(&path) {
+            Ok(contents) => parse_peers(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Blocklist { path, peers })
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers.contains(peer)
+    }
+
+    /// Blocks `peer` and saves the list. Returns whether it was not
+    /// blocked already.
+    pub fn insert(&mut self, peer: PeerId) -> io::Result<bool> {
+        if !self.peers.insert(peer) {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Unblocks `peer` and saves the list. Returns whether it was blocked.
+    pub fn remove(&mut self, peer: &PeerId) -> io::Result<bool> {
+        if !self.peers.remove(peer) {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = String::new();
+        for peer in &self.peers {
+            contents.push_str(&peer.to_string());
+            contents.push('\n');
+        }
+        fs::write(&self.path, contents)
+    }
+}
+
+/// Reads the allowlist at `path`. Unlike the blocklist, it must exist: an
+/// allowlist that is not there would let nobody in.
+pub fn load_allowlist(path: &Path) -> io::Result<HashSet<PeerId>> {
+    fs::read_to_string(path)
+        .map(|contents| parse_peers(&contents))
+        .map_err(|e| io::Error::new(e.kind(), format!("Could not read the allowlist {}: {}", path.display(), e)))
+}
+
+/// The PeerIds in a list, one per line; lines that are not PeerIds are
+/// skipped.
+fn parse_peers(contents: &str) -> HashSet<PeerId> {
+    contents.lines().filter_map(|l| l.trim().parse().ok()).collect()
+}
+
+/// A behaviour that closes every connection to a peer on its [`Blocklist`]
+/// or missing from its allowlist, and any other connection it is asked to.
+pub(crate) struct Blocker {
+    pub list: Blocklist,
+    /// The only peers we accept connections with, if there is an allowlist.
+    allowed: Option<HashSet<PeerId>>,
+    /// When each connection was established, and whether we dialed it.
+    connections: HashMap<PeerId, HashMap<ConnectionId, (Instant, bool)>>,
+    // Connections to close, because their peer was blocked after they
+    // opened, or they were pruned or hung up on.
+    closing: VecDeque<(PeerId, ConnectionId)>,
+}
+
+impl Blocker {
+    pub fn new(list: Blocklist, allowed: Option<HashSet<PeerId>>) -> Self {
+        Blocker { list, allowed, connections: HashMap::new(), closing: VecDeque::new() }
+    }
+
+    /// Whether we keep `peer` out, being blocked or not on the allowlist.
+    pub fn refuses(&self, peer: &PeerId) -> bool {
+        self.list.contains(peer) || self.allowed.as_ref().is_some_and(|a| !a.contains(peer))
+    }
+
+    /// Blocks `peer`, closing our connections to it. Returns whether it was
+    /// not blocked already.
+    pub fn block(&mut self, peer: PeerId) -> io::Result<bool> {
+        if !self.list.insert(peer)? {
+            return Ok(false);
+        }
+        for connection in self.connections.get(&peer).into_iter().flat_map(HashMap::keys) {
+            self.closing.push_back((peer, *connection));
+        }
+        Ok(true)
+    }
+
+    /// Closes our connections to `peer`, returning whether there were any.
+    pub fn disconnect(&mut self, peer: &PeerId) -> bool {
+        let connections: Vec<_> = self.connections.get(peer).into_iter().flat_map(HashMap::keys).copied().collect();
+        for &connection in &connections {
+            if !self.closing.contains(&(*peer, connection)) {
+                self.closing.push_back((*peer, connection));
+            }
+        }
+        !connections.is_empty()
+    }
+
+    /// Closes the oldest connection in the given direction whose peer is
+    /// not to be kept, returning that peer.
+    pub fn prune_oldest(&mut self, dialer: bool, keep: impl Fn(&PeerId) -> bool) -> Option<PeerId> {
+        let closing = &self.closing;
+        let (peer, connection, _) = self
+            .connections
+            .iter()
+            .filter(|(peer, _)| !keep(peer))
+            .flat_map(|(peer, connections)| connections.iter().map(move |(c, info)| (*peer, *c, *info)))
+            .filter(|&(peer, c, (_, dialed))| dialed == dialer && !closing.contains(&(peer, c)))
+            .min_by_key(|&(_, _, (since, _))| since)?;
+        self.closing.push_back((peer, connection));
+        Some(peer)
+    }
+}
+
+impl NetworkBehaviour for Blocker {
+    type ProtocolsHandler = IntoCloser;
+    type OutEvent = Infallible;
+
+    fn new_handler(&mut self) -> IntoCloser {
+        // The handler learns which peer it is for only once connected.
+        IntoCloser { blocked: self.list.peers.clone(), allowed: self.allowed.clone() }
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId) {}
+
+    fn inject_connection_established(&mut self, peer: &PeerId, connection: &ConnectionId, endpoint: &ConnectedPoint) {
+        // Its handler is closing it already.
+        if self.allowed.as_ref().is_some_and(|a| !a.contains(peer)) {
+            let address = endpoint.get_remote_address();
+            tracing::warn!(%peer, %address, "Closing a connection with a peer not on the allowlist");
+        }
+        let info = (Instant::now(), endpoint.is_dialer());
+        self.connections.entry(*peer).or_default().insert(*connection, info);
+    }
+
+    fn inject_connection_closed(&mut self, peer: &PeerId, connection: &ConnectionId, _: &ConnectedPoint) {
+        if let Some(connections) = self.connections.get_mut(peer) {
+            connections.remove(connection);
+            if connections.is_empty() {
+                self.connections.remove(peer);
+            }
+        }
+    }
+
+    fn inject_event(&mut self, _: PeerId, _: ConnectionId, event: Infallible) {
+        match event {}
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>, _: &mut impl PollParameters)
+        -> Poll<NetworkBehaviourAction<(), Infallible>>
+    {
+        match self.closing.pop_front() {
+            Some((peer_id, connection)) => Poll::Ready(NetworkBehaviourAction::NotifyHandler {
+                peer_id,
+                handler: NotifyHandler::One(connection),
+                event: (),
+            }),
+            None => Poll::Pending,
+        }
+    }
+}
+
+pub(crate) struct IntoCloser {
+    blocked: HashSet<PeerId>,
+    allowed: Option<HashSet<PeerId>>,
+}
+
+impl IntoProtocolsHandler for IntoCloser {
+    type Handler = Closer;
+
+    fn into_handler(self, peer: &PeerId, _: &ConnectedPoint) -> Closer {
+        let stranger = self.allowed.as_ref().is_some_and(|a| !a.contains(peer));
+        Closer { close: self.blocked.contains(peer) || stranger }
+    }
+
+    fn inbound_protocol(&self) -> DeniedUpgrade {
+        DeniedUpgrade
+    }
+}
+
+/// Speaks no protocol of its own, and closes its connection once told to,
+/// by any event, or straight away if the peer was blocked to begin with.
+pub(crate) struct Closer {
+    close: bool,
+}
+
+impl ProtocolsHandler for Closer {
+    type InEvent = ();
+    type OutEvent = Infallible;
+    type Error = io::Error;
+    type InboundProtocol = DeniedUpgrade;
+    type OutboundProtocol = DeniedUpgrade;
+    type InboundOpenInfo = ();
+    type OutboundOpenInfo = Infallible;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<DeniedUpgrade, ()> {
+        SubstreamProtocol::new(DeniedUpgrade, ())
+    }
+
+    fn inject_fully_negotiated_inbound(
+        &mut self,
+        output: <DeniedUpgrade as InboundUpgrade<NegotiatedSubstream>>::Output,
+        _: (),
+    ) {
+        match output {}
+    }
+
+    fn inject_fully_negotiated_outbound(
+        &mut self,
+        output: <DeniedUpgrade as OutboundUpgrade<NegotiatedSubstream>>::Output,
+        _: Infallible,
+    ) {
+        match output {}
+    }
+
+    fn inject_event(&mut self, _: ()) {
+        self.close = true;
+    }
+
+    fn inject_dial_upgrade_error(
+        &mut self,
+        info: Infallible,
+        _: ProtocolsHandlerUpgrErr<<DeniedUpgrade as OutboundUpgrade<NegotiatedSubstream>>::Error>,
+    ) {
+        match info {}
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        KeepAlive::No
+    }
+
+    fn poll(&mut self, _: &mut Context<'_>)
+        -> Poll<ProtocolsHandlerEvent<DeniedUpgrade, Infallible, Infallible, io::Error>>
+    {
+        if self.close {
+            Poll::Ready(ProtocolsHandlerEvent::Close(io::Error::other("peer is blocked")))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_survive_a_reload() {
+        let path = std::env::temp_dir().join(format!("peardchat-test-blocklist-{}", rand::random::<u64>()));
+        let [mallory, trudy] = [PeerId::random(), PeerId::random()];
+        let mut list = Blocklist::load(path.clone()).unwrap();
+        assert!(!list.contains(&mallory));
+        assert!(list.insert(mallory).unwrap());
+        assert!(!list.insert(mallory).unwrap());
+        assert!(list.insert(trudy).unwrap());
+        assert!(list.remove(&trudy).unwrap());
+
+        // Lines added by hand that are not PeerIds do no harm.
+        fs::write(&path, format!("{}\nnot a peer\n", fs::read_to_string(&path).unwrap())).unwrap();
+        let list = Blocklist::load(path.clone()).unwrap();
+        assert!(list.contains(&mallory) && !list.contains(&trudy));
+        let _ = fs::remove_file(path);
+    }
+}
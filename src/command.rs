@@ -0,0 +1,546 @@
+//! The slash-commands typed at the chat prompt.
+
+use libp2p::PeerId;
+use peardchat::message::{check_nick, check_reaction};
+use std::path::PathBuf;
+
+/// How many lines `/history` shows when not told.
+pub const DEFAULT_HISTORY_LINES: usize = 20;
+
+/// What `/help` says about a command.
+pub struct Help {
+    pub name: &'static str,
+    /// The command with its arguments, `[optional]` or `<required>`.
+    pub usage: &'static str,
+    pub summary: &'static str,
+    /// More on how it behaves, for `/help <command>`.
+    pub details: &'static str,
+}
+
+/// Every command there is, in the order `/help` lists them. Only these are
+/// recognized, so a new command has to come with its help.
+pub const COMMANDS: &[Help] = &[
+    Help {
+        name: "/help",
+        usage: "/help [command]",
+        summary: "List the commands, or explain one",
+        details: "The command may be given with or without its slash.",
+    },
+    Help {
+        name: "/join",
+        usage: "/join [--exclusive] <room>",
+        summary: "Join a room and make it the current one",
+        details: "What you type goes to the current room. You stay in the rooms you joined before, \
+                  and what arrives in them is shown dimmed, unless `--exclusive` leaves them.",
+    },
+    Help {
+        name: "/leave",
+        usage: "/leave [room]",
+        summary: "Leave a room, the current one if none is given",
+        details: "Peers in the room see you leave.",
+    },
+    Help {
+        name: "/mute",
+        usage: "/mute [room]",
+        summary: "Stop showing a room's chat, the current one's if none is given",
+        details: "You stay in the room, so messages still pass through you and are logged.",
+    },
+    Help {
+        name: "/unmute",
+        usage: "/unmute [room]",
+        summary: "Show a muted room's chat again",
+        details: "What arrived while it was muted can be read with /history.",
+    },
+    Help {
+        name: "/slowmode",
+        usage: "/slowmode [seconds | off]",
+        summary: "Let everyone in the current room send one message per so many seconds",
+        details: "Every peer drops messages sent faster than that, so it holds for the whole room. \
+                  Only whoever set it first can change it or turn it off. Without seconds, the current \
+                  setting is shown.",
+    },
+    Help {
+        name: "/topic-stats",
+        usage: "/topic-stats [--reset]",
+        summary: "Show how busy each of your rooms is",
+        details: "Messages in the last minute and in all, how many peers sent them and when the latest \
+                  came, counting yours too. --reset starts the counts over.",
+    },
+    Help {
+        name: "/list-topics",
+        usage: "/list-topics",
+        summary: "List the rooms you are in",
+        details: "The current room is marked with a `*`, and each comes with how many peers are in it \
+                  and whether it is muted.",
+    },
+    Help {
+        name: "/nick",
+        usage: "/nick <name>",
+        summary: "Change your nickname",
+        details: "Peers in your rooms are told straight away. At most 32 characters, without control \
+                  characters.",
+    },
+    Help {
+        name: "/me",
+        usage: "/me <action>",
+        summary: "Say what you are doing, shown as `* you waves`",
+        details: "Sent to the current room like any other message.",
+    },
+    Help {
+        name: "/reply",
+        usage: "/reply <message id> <text>",
+        summary: "Answer an earlier message, which is quoted alongside",
+        details: "The id is the one shown after the message, such as #3fa2c1. The reply goes to the \
+                  room the message was in.",
+    },
+    Help {
+        name: "/react",
+        usage: "/react <message id> <emoji>",
+        summary: "React to an earlier message",
+        details: "Everyone in the room sees the reactions tallied after the message, such as [👍 x3].",
+    },
+    Help {
+        name: "/dm",
+        usage: "/dm [peer id or nickname] [text]",
+        summary: "Send a private message to one peer, or talk with them until /back",
+        details: "It is encrypted end to end, so peers passing it on cannot read it. Messages marked \
+                  forward-secret were ratcheted, as `--forward-secrecy` does, each with a key of its own. Without text, the \
+                  conversation so far is shown and what you type goes to that peer. On its own, \
+                  `/dm` lists the conversations with messages you have not read.",
+    },
+    Help {
+        name: "/back",
+        usage: "/back",
+        summary: "Stop talking privately and go back to the current room",
+        details: "The conversation is kept, `/dm` with the same peer picks it up again.",
+    },
+    Help {
+        name: "/send",
+        usage: "/send <peer id> <path>",
+        summary: "Offer a file to a peer",
+        details: "Small files are taken straight away, the peer has to /accept larger ones.",
+    },
+    Help {
+        name: "/accept",
+        usage: "/accept <offer number>",
+        summary: "Take a file someone offered",
+        details: "The number is the one shown with the offer. The file goes to the downloads directory.",
+    },
+    Help {
+        name: "/reject",
+        usage: "/reject <offer number>",
+        summary: "Turn down a file someone offered",
+        details: "The sender is told the file was declined.",
+    },
+    Help {
+        name: "/profile",
+        usage: "/profile list | save <name> | load <name>",
+        summary: "Save the peers you are connected to under a name, or dial them again",
+        details: "Saving keeps the bootstrap peers and where every connected peer listens. \
+                  Loading dials them all. Names may only have letters, digits, `-` and `_`.",
+    },
+    Help {
+        name: "/peers",
+        usage: "/peers",
+        summary: "List the peers you are connected to",
+        details: "With their addresses, whether mDNS sees them and the rooms they are in.",
+    },
+    Help {
+        name: "/roster",
+        usage: "/roster",
+        summary: "Show who is online, away or gone",
+        details: "Everyone you have been connected to since starting, by nickname. Away peers are still \
+                  connected but have not sent a heartbeat for a while.",
+    },
+    Help {
+        name: "/discovered",
+        usage: "/discovered",
+        summary: "List the peers found but not connected to",
+        details: "Peers mDNS or Kademlia found, numbered for /dial. With auto_dial off in the config, or \
+                  --no-auto-dial, this is how you pick which of them to connect to.",
+    },
+    Help {
+        name: "/dial",
+        usage: "/dial <number, peer id or nickname>",
+        summary: "Connect to a discovered peer",
+        details: "A number is one from the last /discovered. The peer is dialed at every address found \
+                  for it.",
+    },
+    Help {
+        name: "/invite",
+        usage: "/invite",
+        summary: "Make a token a friend can connect to you with",
+        details: "It holds your PeerId and the addresses you are reachable on, and expires after a day. \
+                  They pass it to /connect or --connect.",
+    },
+    Help {
+        name: "/connect",
+        usage: "/connect <invite>",
+        summary: "Connect to whoever made an invite",
+        details: "Every address in the invite is dialed.",
+    },
+    Help {
+        name: "/whois",
+        usage: "/whois <peer id or nickname>",
+        summary: "Show what is known about a peer",
+        details: "A nickname that several peers go by shows each of them.",
+    },
+    Help {
+        name: "/myaddr",
+        usage: "/myaddr",
+        summary: "Show the addresses other peers can reach you on",
+        details: "Give one of them to a peer to dial you directly.",
+    },
+    Help {
+        name: "/disconnect",
+        usage: "/disconnect <peer id or nickname>",
+        summary: "Close every connection to a peer, for now",
+        details: "Unlike /block, the peer may connect again later, and nothing is saved. We do not \
+                  redial it ourselves after this.",
+    },
+    Help {
+        name: "/block",
+        usage: "/block <peer id>",
+        summary: "Stop hearing from a peer",
+        details: "Connections to it are closed, and it stays blocked across restarts.",
+    },
+    Help {
+        name: "/unblock",
+        usage: "/unblock <peer id>",
+        summary: "Hear from a blocked peer again",
+        details: "We try to connect to it again straight away.",
+    },
+    Help {
+        name: "/history",
+        usage: "/history [number of lines]",
+        summary: "Show the latest lines logged for the current room",
+        details: "Shows 20 lines unless told otherwise. The log is kept across restarts.",
+    },
+    Help {
+        name: "/search",
+        usage: "/search <term>",
+        summary: "Search the chat of every room kept in the --db database",
+        details: "Shows the latest 20 lines whose text or nickname contains the term, in any case.",
+    },
+    Help {
+        name: "/export",
+        usage: "/export <path>",
+        summary: "Write the chat of this session to a file",
+        details: "A path ending in .json gets JSON, anything else one readable line per message.",
+    },
+    Help {
+        name: "/stats",
+        usage: "/stats",
+        summary: "Show traffic and message counts since startup",
+        details: "Bytes are counted for everything that went over the network.",
+    },
+    Help {
+        name: "/clear",
+        usage: "/clear",
+        summary: "Clear the screen",
+        details: "Followed by a line saying who you are and where.",
+    },
+    Help {
+        name: "/quit",
+        usage: "/quit",
+        summary: "Say goodbye in every room and exit",
+        details: "Ctrl-C does the same.",
+    },
+];
+
+/// The help for `name`, which may leave out the slash.
+pub fn help(name: &str) -> Option<&'static Help> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    COMMANDS.iter().find(|c| &c.name[1..] == name)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `/help [command]`
+    Help(Option<String>),
+    /// `/peers`
+    Peers,
+    /// `/roster`
+    Roster,
+    /// `/discovered`
+    Discovered,
+    /// `/dial <number, peer id or nickname>`
+    Dial(String),
+    /// `/myaddr`
+    MyAddr,
+    /// `/clear`
+    Clear,
+    /// `/list-topics`
+    ListTopics,
+    /// `/stats`
+    Stats,
+    /// `/invite`
+    Invite,
+    /// `/connect <invite>`
+    Connect(String),
+    /// `/whois <peer id or nickname>`
+    Whois(String),
+    /// `/send <peer id> <path>`
+    Send { peer: PeerId, path: PathBuf },
+    /// `/dm`, listing conversations with unread messages.
+    Inbox,
+    /// `/dm <peer id or nickname> [text]`, where no text opens the
+    /// conversation with that peer.
+    Dm { peer: String, text: Option<String> },
+    /// `/back`
+    Back,
+    /// `/disconnect <peer id or nickname>`
+    Disconnect(String),
+    /// `/block <peer id>`
+    Block(PeerId),
+    /// `/unblock <peer id>`
+    Unblock(PeerId),
+    /// `/accept <offer>`
+    Accept(u64),
+    /// `/reject <offer>`
+    Reject(u64),
+    /// `/history [lines]`
+    History(usize),
+    /// `/search <term>`
+    Search(String),
+    /// `/export <path>`
+    Export(PathBuf),
+    /// `/profile list`
+    ProfileList,
+    /// `/profile save <name>`
+    ProfileSave(String),
+    /// `/profile load <name>`
+    ProfileLoad(String),
+    /// `/me <action>`
+    Me(String),
+    /// `/reply <message id> <text>`, with the id as the user typed it.
+    Reply { id: String, text: String },
+    /// `/react <message id> <emoji>`, with the id as the user typed it.
+    React { id: String, emoji: String },
+    /// `/nick <name>`
+    Nick(String),
+    /// `/join [--exclusive] <room>`, where `exclusive` leaves every other room.
+    Join { room: String, exclusive: bool },
+    /// `/leave [room]`, where no room means the current one.
+    Leave(Option<String>),
+    /// `/mute [room]`, where no room means the current one.
+    Mute(Option<String>),
+    /// `/unmute [room]`, likewise.
+    Unmute(Option<String>),
+    /// `/slowmode [seconds | off]`, where off is 0 and no seconds asks for
+    /// the current setting.
+    SlowMode(Option<u64>),
+    /// `/topic-stats [--reset]`
+    TopicStats { reset: bool },
+    /// `/quit`
+    Quit,
+    /// Anything else starting with a `/`, which is a mistyped command far
+    /// more often than chat, so it is never sent.
+    Unknown(String),
+}
+
+/// Recognizes a slash-command. Returns `None` for anything else, which is
+/// chat text, and the usage line if a command's arguments don't fit. Chat
+/// that starts with a `/` is typed with two, see [`chat_text`].
+pub fn parse_command(line: &str) -> Option<Result<Command, String>> {
+    let line = line.trim();
+    if !line.starts_with('/') || line.starts_with("//") {
+        return None;
+    }
+    let (name, args) = match line.find(' ') {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+    };
+
+    if !COMMANDS.iter().any(|c| c.name == name) {
+        return Some(Ok(Command::Unknown(name.to_string())));
+    }
+    let command = match name {
+        "/help" if args.is_empty() => Ok(Command::Help(None)),
+        "/help" => Ok(Command::Help(Some(args.to_string()))),
+        "/peers" => Ok(Command::Peers),
+        "/roster" => Ok(Command::Roster),
+        "/discovered" => Ok(Command::Discovered),
+        "/dial" if args.is_empty() => Err("Usage: /dial <number, peer id or nickname>".to_string()),
+        "/dial" => Ok(Command::Dial(args.to_string())),
+        "/myaddr" => Ok(Command::MyAddr),
+        "/clear" => Ok(Command::Clear),
+        "/list-topics" => Ok(Command::ListTopics),
+        "/stats" => Ok(Command::Stats),
+        "/invite" => Ok(Command::Invite),
+        "/connect" if args.is_empty() => Err("Usage: /connect <invite>".to_string()),
+        "/connect" => Ok(Command::Connect(args.to_string())),
+        "/whois" if args.is_empty() => Err("Usage: /whois <peer id or nickname>".to_string()),
+        "/whois" => Ok(Command::Whois(args.to_string())),
+        "/send" => peer_and_rest(args)
+            .map(|(peer, path)| Command::Send { peer, path: path.into() })
+            .ok_or_else(|| "Usage: /send <peer id> <path>".to_string()),
+        "/dm" if args.is_empty() => Ok(Command::Inbox),
+        "/dm" => Ok(match args.split_once(' ') {
+            Some((peer, text)) => Command::Dm { peer: peer.to_string(), text: Some(text.trim().to_string()) },
+            None => Command::Dm { peer: args.to_string(), text: None },
+        }),
+        "/back" => Ok(Command::Back),
+        "/disconnect" if args.is_empty() => Err("Usage: /disconnect <peer id or nickname>".to_string()),
+        "/disconnect" => Ok(Command::Disconnect(args.to_string())),
+        "/block" => args.parse().map(Command::Block).map_err(|_| "Usage: /block <peer id>".to_string()),
+        "/unblock" => args.parse().map(Command::Unblock).map_err(|_| "Usage: /unblock <peer id>".to_string()),
+        "/accept" => args.parse().map(Command::Accept).map_err(|_| "Usage: /accept <offer number>".to_string()),
+        "/reject" => args.parse().map(Command::Reject).map_err(|_| "Usage: /reject <offer number>".to_string()),
+        "/history" if args.is_empty() => Ok(Command::History(DEFAULT_HISTORY_LINES)),
+        "/history" => args.parse().map(Command::History).map_err(|_| "Usage: /history [number of lines]".to_string()),
+        "/search" if args.is_empty() => Err("Usage: /search <term>".to_string()),
+        "/search" => Ok(Command::Search(args.to_string())),
+        "/export" if args.is_empty() => Err("Usage: /export <path>".to_string()),
+        "/export" => Ok(Command::Export(args.into())),
+        "/profile" => match args.split_once(' ').map(|(verb, name)| (verb, name.trim())) {
+            _ if args == "list" => Ok(Command::ProfileList),
+            Some(("save", name)) if !name.is_empty() => Ok(Command::ProfileSave(name.to_string())),
+            Some(("load", name)) if !name.is_empty() => Ok(Command::ProfileLoad(name.to_string())),
+            _ => Err("Usage: /profile list | save <name> | load <name>".to_string()),
+        },
+        "/reply" => match args.split_once(' ') {
+            Some((id, text)) if !text.trim().is_empty() => {
+                Ok(Command::Reply { id: id.to_string(), text: text.trim().to_string() })
+            }
+            _ => Err("Usage: /reply <message id> <text>".to_string()),
+        },
+        "/react" => match args.split_once(' ').map(|(id, emoji)| (id, emoji.trim())) {
+            Some((id, emoji)) => {
+                check_reaction(emoji).map(|_| Command::React { id: id.to_string(), emoji: emoji.to_string() })
+            }
+            None => Err("Usage: /react <message id> <emoji>".to_string()),
+        },
+        "/me" if args.is_empty() => Err("Usage: /me <action>".to_string()),
+        "/me" => Ok(Command::Me(args.to_string())),
+        "/nick" if args.is_empty() => Err("Usage: /nick <name>".to_string()),
+        "/nick" => check_nick(args).map(|_| Command::Nick(args.to_string())),
+        "/join" => {
+            let (exclusive, room) = match args.split_once(' ') {
+                Some(("--exclusive", room)) => (true, room.trim()),
+                _ if args == "--exclusive" => (true, ""),
+                _ => (false, args),
+            };
+            if room.is_empty() {
+                Err("Usage: /join [--exclusive] <room>".to_string())
+            } else {
+                Ok(Command::Join { room: room.to_string(), exclusive })
+            }
+        }
+        "/leave" if args.is_empty() => Ok(Command::Leave(None)),
+        "/leave" => Ok(Command::Leave(Some(args.to_string()))),
+        "/mute" => Ok(Command::Mute(Some(args.to_string()).filter(|a| !a.is_empty()))),
+        "/unmute" => Ok(Command::Unmute(Some(args.to_string()).filter(|a| !a.is_empty()))),
+        "/slowmode" if args.is_empty() => Ok(Command::SlowMode(None)),
+        "/slowmode" if args == "off" => Ok(Command::SlowMode(Some(0))),
+        "/slowmode" => args.parse().map(|secs| Command::SlowMode(Some(secs))).map_err(|_| "Usage: /slowmode [seconds | off]".to_string()),
+        "/topic-stats" if args.is_empty() => Ok(Command::TopicStats { reset: false }),
+        "/topic-stats" if args == "--reset" => Ok(Command::TopicStats { reset: true }),
+        "/topic-stats" => Err("Usage: /topic-stats [--reset]".to_string()),
+        "/quit" => Ok(Command::Quit),
+        _ => unreachable!("{} is in COMMANDS but not parsed", name),
+    };
+    Some(command)
+}
+
+/// What to send for a line of chat: a leading `//` stands for a single `/`.
+pub fn chat_text(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with("//") {
+        &trimmed[1..]
+    } else {
+        line
+    }
+}
+
+/// Splits `<peer id> <rest>`, where the rest must not be empty.
+fn peer_and_rest(args: &str) -> Option<(PeerId, &str)> {
+    let mut args = args.splitn(2, ' ');
+    let peer = args.next()?.parse().ok()?;
+    match args.next().map(str::trim) {
+        Some(rest) if !rest.is_empty() => Some((peer, rest)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peardchat::message::MAX_NICK_LEN;
+
+    #[test]
+    fn parses_commands_and_leaves_chat_alone() {
+        assert_eq!(parse_command("/quit"), Some(Ok(Command::Quit)));
+        assert_eq!(parse_command("/clear"), Some(Ok(Command::Clear)));
+        assert_eq!(parse_command("/roster"), Some(Ok(Command::Roster)));
+        assert_eq!(parse_command("/dial 2"), Some(Ok(Command::Dial("2".into()))));
+        assert!(matches!(parse_command("/dial"), Some(Err(_))));
+        assert_eq!(parse_command("/list-topics"), Some(Ok(Command::ListTopics)));
+        assert_eq!(parse_command(" /leave "), Some(Ok(Command::Leave(None))));
+        assert_eq!(parse_command("/mute"), Some(Ok(Command::Mute(None))));
+        assert_eq!(parse_command("/unmute rust"), Some(Ok(Command::Unmute(Some("rust".into())))));
+        assert_eq!(parse_command("/slowmode 5"), Some(Ok(Command::SlowMode(Some(5)))));
+        assert_eq!(parse_command("/slowmode off"), Some(Ok(Command::SlowMode(Some(0)))));
+        assert_eq!(parse_command("/topic-stats --reset"), Some(Ok(Command::TopicStats { reset: true })));
+        assert!(matches!(parse_command("/topic-stats now"), Some(Err(_))));
+        assert_eq!(
+            parse_command("/join  rust"),
+            Some(Ok(Command::Join { room: "rust".into(), exclusive: false }))
+        );
+        assert_eq!(
+            parse_command("/join --exclusive  rust"),
+            Some(Ok(Command::Join { room: "rust".into(), exclusive: true }))
+        );
+        assert_eq!(parse_command("/history"), Some(Ok(Command::History(DEFAULT_HISTORY_LINES))));
+        assert_eq!(parse_command("/search  rust 2018"), Some(Ok(Command::Search("rust 2018".into()))));
+        assert_eq!(parse_command("/export"), Some(Err("Usage: /export <path>".to_string())));
+        assert_eq!(parse_command("/export  chat.json"), Some(Ok(Command::Export("chat.json".into()))));
+        assert_eq!(parse_command("/profile list"), Some(Ok(Command::ProfileList)));
+        assert_eq!(parse_command("/profile load  home"), Some(Ok(Command::ProfileLoad("home".into()))));
+        assert!(parse_command("/profile save").unwrap().is_err());
+        assert_eq!(parse_command("/accept x"), Some(Err("Usage: /accept <offer number>".to_string())));
+        assert_eq!(parse_command("/whois  bob"), Some(Ok(Command::Whois("bob".into()))));
+        assert_eq!(parse_command("/nick  bob"), Some(Ok(Command::Nick("bob".into()))));
+        assert!(parse_command("/nick bob\u{1b}[2J").unwrap().is_err());
+        let too_long = format!("A nickname can be at most {} characters long", MAX_NICK_LEN);
+        assert_eq!(parse_command(&format!("/nick {}", "b".repeat(MAX_NICK_LEN + 1))), Some(Err(too_long)));
+        assert_eq!(parse_command("/me waves hello"), Some(Ok(Command::Me("waves hello".into()))));
+        assert_eq!(
+            parse_command("/reply #3fa2c1  me too"),
+            Some(Ok(Command::Reply { id: "#3fa2c1".into(), text: "me too".into() }))
+        );
+        assert_eq!(parse_command("/reply 3fa2c1"), Some(Err("Usage: /reply <message id> <text>".to_string())));
+        assert_eq!(
+            parse_command("/react #3fa2c1  👍"),
+            Some(Ok(Command::React { id: "#3fa2c1".into(), emoji: "👍".into() }))
+        );
+        assert_eq!(parse_command("/react 3fa2c1"), Some(Err("Usage: /react <message id> <emoji>".to_string())));
+        assert!(parse_command("/react 3fa2c1 👍 👍").unwrap().is_err());
+        assert_eq!(parse_command("/dm"), Some(Ok(Command::Inbox)));
+        assert_eq!(parse_command("/dm bob"), Some(Ok(Command::Dm { peer: "bob".into(), text: None })));
+        assert_eq!(
+            parse_command("/dm bob  hi there"),
+            Some(Ok(Command::Dm { peer: "bob".into(), text: Some("hi there".into()) }))
+        );
+        assert_eq!(parse_command("hello /quit"), None);
+        assert_eq!(parse_command("quit"), None);
+        assert_eq!(parse_command("/jion rust"), Some(Ok(Command::Unknown("/jion".into()))));
+        assert_eq!(parse_command("//jion rust"), None);
+        assert_eq!(chat_text("//jion rust"), "/jion rust");
+        assert_eq!(chat_text("a // b"), "a // b");
+        assert_eq!(parse_command("/help join"), Some(Ok(Command::Help(Some("join".into())))));
+    }
+
+    #[test]
+    fn every_command_has_help() {
+        for command in COMMANDS {
+            assert!(parse_command(command.usage).is_some(), "{} does not parse", command.usage);
+            assert!(help(&command.name[1..]).is_some());
+            // What is shown for bad arguments matches the help.
+            if let Some(Err(usage)) = parse_command(&format!("{} x x", command.name)) {
+                assert_eq!(usage, format!("Usage: {}", command.usage));
+            }
+        }
+        assert!(help("/nope").is_none());
+    }
+}
@@ -0,0 +1,602 @@
+//! Startup configuration read from `peardchat.toml`.
+//!
+//! Each setting is taken from the first of these that gives it: a
+//! command-line flag, an environment variable, this file, or the default.
+//! The environment can set `nick` as `PEARDCHAT_NICK`, `bootstrap` as
+//! `PEARDCHAT_BOOTSTRAP`, with addresses separated by commas, and the room
+//! to join as `PEARDCHAT_TOPIC`. `PEARDCHAT_IDENTITY` holds the identity
+//! key, base64 encoded, in place of `~/.peardchat/identity.key`.
+//!
+//! ```toml
+//! # Addresses dialed on startup and used to join the DHT.
+//! bootstrap = ["/ip4/192.168.1.20/tcp/4001/p2p/12D3KooW..."]
+//! # Address and port to listen on, 0 lets the OS pick the port.
+//! bind = "0.0.0.0"
+//! listen_port = 4001
+//! # With an unspecified `bind`, which IP versions to listen on: `both`,
+//! # `ipv4` or `ipv6`.
+//! ip_families = "both"
+//! # Port to also accept WebSocket connections on, as browsers need.
+//! ws_listen_port = 4002
+//! nick = "alice"
+//! # Where files received with `/send` are written.
+//! downloads = "/home/alice/Downloads"
+//! # Whether to find peers on the local network with mDNS, and how often,
+//! # in seconds, to ask who is around.
+//! mdns = true
+//! mdns_query_interval_secs = 300
+//! # Whether to connect to every peer mDNS or Kademlia finds. Without it,
+//! # `/discovered` lists them and `/dial` connects to the ones you pick.
+//! auto_dial = true
+//! # Stream multiplexer to offer: `yamux`, `mplex`, or `both`, which
+//! # prefers Yamux and falls back to Mplex for older peers.
+//! muxer = "both"
+//! # How connections are encrypted: `noise-xx`, or `noise-ix`, which takes
+//! # one round trip less but sends our identity before knowing the peer's.
+//! # Neither needs keys shared beforehand; both sides must pick the same.
+//! security = "noise-xx"
+//! # How chat spreads: `gossipsub`, over a mesh, or `floodsub`, to every
+//! # peer on the topic. Peers only hear those using the same one.
+//! pubsub = "gossipsub"
+//! # Circuit relay v1 relays to listen through, for peers that cannot reach
+//! # us directly.
+//! relays = ["/ip4/203.0.113.7/tcp/4001/p2p/12D3KooW..."]
+//! # Relay connections for peers that are not connected to us yet.
+//! relay_server = false
+//! # What we tell peers we run, through identify.
+//! agent_version = "peardchat/0.1.0"
+//! # Longest message body, in bytes, we send or show.
+//! max_message_size = 4096
+//! # How long, in seconds, to remember messages so repeats are shown once.
+//! dedup_window_secs = 120
+//! # Whether to show our own messages again should they come back to us.
+//! echo = false
+//! # How long, in milliseconds, to hold incoming chat back so that messages
+//! # arriving out of order are shown in order. 0 shows them as they come.
+//! reorder_window_ms = 200
+//! # Oldest message format version we accept; messages from peers on an
+//! # older one are dropped. 0 accepts every peer.
+//! min_version = 0
+//! # How long, in seconds, to wait for a connection to be set up, either
+//! # way, before giving up on it.
+//! dial_timeout_secs = 10
+//! # How often, in seconds, to ping each connection, and how long to wait
+//! # for an answer. Connections that stop answering are closed.
+//! ping_interval_secs = 15
+//! ping_timeout_secs = 20
+//! # How long, in seconds, to keep a connection nothing is happening on,
+//! # and whether pings alone keep it open for as long as they are answered.
+//! idle_timeout_secs = 120
+//! ping_keep_alive = false
+//! # How long, in seconds, without typing before peers are told we are
+//! # away, 0 for never.
+//! away_after_secs = 600
+//! # Most chat messages one peer may send per window, in seconds; more are
+//! # dropped, 0 allows any number. Peers that keep going over are ignored
+//! # for a while, 0 never ignores them.
+//! rate_limit_messages = 10
+//! rate_limit_window_secs = 1
+//! soft_block_secs = 60
+//! # Most connections we keep open each way, and most we let be set up at
+//! # once, 0 for no limit. At the limit, the oldest connection to a peer we
+//! # do not chat with is closed to make room.
+//! max_incoming_connections = 64
+//! max_outgoing_connections = 64
+//! max_pending_connections = 16
+//! # Peers blocked with `/block`, one PeerId per line.
+//! blocklist = "/home/alice/.peardchat/blocklist"
+//! # If set, only the peers listed here, in the same format, may connect.
+//! allowlist = "/home/alice/.peardchat/allowlist"
+//! # Whether to remember where peers were reached, to dial them again on
+//! # startup, and where.
+//! remember_peers = true
+//! peerstore = "/home/alice/.peardchat/peerstore"
+//! # Where chat logs are kept, and how large each may grow before rotating.
+//! history_dir = "/home/alice/.peardchat/history"
+//! history_max_bytes = 1048576
+//! # Where `/profile save` keeps its sets of addresses.
+//! profiles_dir = "/home/alice/.peardchat/profiles"
+//! # Whether to replay the latest chat messages of each topic, up to this
+//! # many, to peers as they join it.
+//! history_provider = false
+//! history_provider_messages = 50
+//! # If set, every room is encrypted with a key derived from this
+//! # passphrase, and only peers with the same one can read it.
+//! room_key = "correct horse battery staple"
+//! # A message of the day for peers that ask, and the peer to ask for one,
+//! # by default the first bootstrap peer.
+//! motd = "Welcome to the dev room!"
+//! motd_peer = "12D3KooW..."
+//! # Whether to ratchet direct messages, so that each is sent with a key of
+//! # its own and a key getting out later does not open what came before.
+//! forward_secrecy = false
+//! # Address to serve Prometheus metrics on, at `/metrics`.
+//! metrics_addr = "127.0.0.1:9090"
+//! ```
+//!
+//! Every key is optional and the file itself may be missing, in which case the
+//! defaults below are used.
+
+use libp2p::{core::network::ConnectionLimits, multiaddr::Protocol, Multiaddr, PeerId};
+use serde::Deserialize;
+use std::{
+    error::Error,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Well-known nodes used to join the Kademlia DHT when the config lists none,
+/// so peers beyond the local network can be found.
+const DEFAULT_BOOTSTRAP: &[&str] = &[
+    "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+];
+
+/// Which IP versions to listen on when `bind` leaves the address open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamilies {
+    Both,
+    Ipv4,
+    Ipv6,
+}
+
+/// Which stream multiplexers connections may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Muxer {
+    Yamux,
+    /// Deprecated in libp2p, and slow on large transfers for want of flow
+    /// control, but all some older peers speak.
+    Mplex,
+    /// Yamux where the other side speaks it, Mplex otherwise.
+    Both,
+}
+
+impl FromStr for Muxer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "yamux" => Ok(Muxer::Yamux),
+            "mplex" => Ok(Muxer::Mplex),
+            "both" => Ok(Muxer::Both),
+            _ => Err(format!("Not a multiplexer, expected yamux, mplex or both: {}", s)),
+        }
+    }
+}
+
+/// How connections are authenticated and encrypted.
+///
+/// Only Noise handshakes where each side learns the other's key as it goes
+/// are offered. IK, which needs the peer's static key beforehand, would
+/// need it stored for every peer we dial; TLS is not in this libp2p yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Security {
+    /// Three messages, revealing our identity only to the peer we meant to
+    /// reach. What every other libp2p stack speaks as `/noise`.
+    NoiseXx,
+    /// Two messages, sending our identity in the first. Not standardized,
+    /// so only other rust-libp2p peers set to it can connect.
+    NoiseIx,
+}
+
+impl FromStr for Security {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "noise-xx" => Ok(Security::NoiseXx),
+            "noise-ix" => Ok(Security::NoiseIx),
+            "tls" => Err("TLS is not supported by this version of libp2p, use noise-xx or noise-ix".to_string()),
+            _ => Err(format!("Not a security protocol, expected noise-xx or noise-ix: {}", s)),
+        }
+    }
+}
+
+/// Which protocol spreads chat to the peers on a topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PubSubProtocol {
+    /// Sends messages over a mesh of a few peers per topic and gossips
+    /// about them to the rest.
+    Gossipsub,
+    /// Sends every message to every peer on the topic. Simpler, but heavier
+    /// on bandwidth, and it carries at most 2048 bytes at once, so longer
+    /// messages go in several fragments.
+    Floodsub,
+}
+
+impl FromStr for PubSubProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "gossipsub" => Ok(PubSubProtocol::Gossipsub),
+            "floodsub" => Ok(PubSubProtocol::Floodsub),
+            _ => Err(format!("Not a pubsub protocol, expected gossipsub or floodsub: {}", s)),
+        }
+    }
+}
+
+/// Name of the config file looked up in the working directory.
+pub const CONFIG_FILE: &str = "peardchat.toml";
+
+/// Directory holding everything peardchat keeps between runs, `~/.peardchat`.
+pub fn data_dir() -> PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_else(|| ".".into());
+    Path::new(&home).join(".peardchat")
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to dial on startup. Those ending in `/p2p/<peer id>` also
+    /// seed the Kademlia routing table.
+    pub bootstrap: Vec<Multiaddr>,
+    /// IP address to listen on; the unspecified address means every
+    /// interface.
+    pub bind: IpAddr,
+    /// TCP port to listen on, 0 lets the OS assign one.
+    pub listen_port: u16,
+    /// IP versions to listen on if `bind` is an unspecified address; a
+    /// specific address is listened on alone.
+    pub ip_families: IpFamilies,
+    /// TCP port to accept WebSocket connections on, at the same IP address,
+    /// if any.
+    pub ws_listen_port: Option<u16>,
+    /// Whether to discover peers on the local network with mDNS. Without it,
+    /// peers are only found by dialing them or through Kademlia.
+    pub mdns: bool,
+    /// Seconds between mDNS queries for peers we may have missed.
+    pub mdns_query_interval_secs: u64,
+    /// Whether to dial every peer mDNS finds or Kademlia adds to its
+    /// routing table, and keep passing chat on to it. Kademlia still
+    /// connects to some of them while looking up others.
+    pub auto_dial: bool,
+    /// Stream multiplexers to offer on new connections.
+    pub muxer: Muxer,
+    /// Handshake that authenticates and encrypts new connections.
+    pub security: Security,
+    /// Protocol spreading chat messages. Peers on the other one do not see
+    /// them.
+    pub pubsub: PubSubProtocol,
+    /// Circuit relay v1 relays, ending in `/p2p/<peer id>`, through which
+    /// we also accept connections, as `<relay>/p2p-circuit`. There are no
+    /// v2 reservations, so a relay passes on connections for as long as we
+    /// stay connected to it.
+    pub relays: Vec<Multiaddr>,
+    /// Whether to act as a relay for anyone who asks, dialing the
+    /// destination if need be. Without it we only relay to peers that are
+    /// already connected to us.
+    pub relay_server: bool,
+    /// The client name and version announced through identify, by default
+    /// [`AGENT_VERSION`](crate::AGENT_VERSION).
+    pub agent_version: String,
+    /// Nickname to use until changed with `/nick`.
+    pub nick: Option<String>,
+    /// Directory received files are written to.
+    pub downloads: PathBuf,
+    /// Longest message body in bytes. Longer input is not sent and longer
+    /// incoming messages are dropped.
+    pub max_message_size: usize,
+    /// How long to remember each message we have shown, in seconds; a
+    /// message reaching us again within that time is dropped.
+    pub dedup_window_secs: u64,
+    /// Whether to show our own chat messages again if the mesh hands them
+    /// back, rather than dropping them as repeats.
+    pub echo: bool,
+    /// How long to hold each incoming chat message, in milliseconds, so that
+    /// messages arriving within that time of each other are shown sorted by
+    /// their clocks. 0 shows every message as soon as it arrives.
+    pub reorder_window_ms: u64,
+    /// Lowest [`WIRE_VERSION`](crate::message::WIRE_VERSION) we take
+    /// messages in. Anything older is dropped.
+    pub min_version: u32,
+    /// Seconds a connection may take to be set up, from dialing or
+    /// accepting it to having negotiated encryption and multiplexing.
+    pub dial_timeout_secs: u64,
+    /// Seconds between pings on each connection.
+    pub ping_interval_secs: u64,
+    /// Seconds to wait for a ping to be answered before counting it as
+    /// failed.
+    pub ping_timeout_secs: u64,
+    /// Seconds a connection is kept open once no protocol has anything
+    /// more to do on it.
+    pub idle_timeout_secs: u64,
+    /// Whether answered pings keep connections open however quiet they
+    /// are, so peers in a quiet room stay connected.
+    pub ping_keep_alive: bool,
+    /// Seconds without typing anything after which we tell peers we are away,
+    /// until the next keystroke. 0 never does.
+    pub away_after_secs: u64,
+    /// Most chat messages we show from one peer per rate limit window, 0
+    /// for no limit.
+    pub rate_limit_messages: usize,
+    /// Length of the sliding rate limit window, in seconds.
+    pub rate_limit_window_secs: u64,
+    /// Seconds to drop everything from a peer that keeps going over the
+    /// rate limit, 0 to only drop what is over.
+    pub soft_block_secs: u64,
+    /// Most established connections other peers opened to us, 0 for no
+    /// limit.
+    pub max_incoming_connections: u32,
+    /// Most established connections we opened, 0 for no limit.
+    pub max_outgoing_connections: u32,
+    /// Most connections being set up at once in each direction, 0 for no
+    /// limit.
+    pub max_pending_connections: u32,
+    /// File listing the peers blocked with `/block`.
+    pub blocklist: PathBuf,
+    /// File listing the only peers we accept connections with, if any.
+    pub allowlist: Option<PathBuf>,
+    /// Whether to keep the addresses of the peers we see in `peerstore` and
+    /// dial them on startup.
+    pub remember_peers: bool,
+    /// JSON file of peers and their addresses.
+    pub peerstore: PathBuf,
+    /// Directory holding one chat log per topic.
+    pub history_dir: PathBuf,
+    /// Size in bytes after which a topic's log is rotated.
+    pub history_max_bytes: u64,
+    /// Directory holding one file per `/profile`.
+    pub profiles_dir: PathBuf,
+    /// Whether to keep the latest chat messages of each topic and hand them
+    /// to peers that ask as they join it.
+    pub history_provider: bool,
+    /// How many chat messages per topic a history provider keeps.
+    pub history_provider_messages: usize,
+    /// Passphrase the chat in every room is encrypted with, if any.
+    pub room_key: Option<String>,
+    /// Whether to start Double Ratchet conversations with the peers we send
+    /// direct messages to, rather than seal each to their identity key.
+    pub forward_secrecy: bool,
+    /// Message of the day handed to peers that ask for it, if any.
+    pub motd: Option<String>,
+    /// Peer to ask for its message of the day once connected to it. Without
+    /// one, the first bootstrap peer is asked.
+    #[serde(deserialize_with = "crate::serde_peer::deserialize_option")]
+    pub motd_peer: Option<PeerId>,
+    /// Where to serve [metrics](crate::metrics) over HTTP, if anywhere.
+    pub metrics_addr: Option<SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bootstrap: DEFAULT_BOOTSTRAP
+                .iter()
+                .map(|addr| addr.parse().expect("Default bootstrap addresses are valid"))
+                .collect(),
+            bind: Ipv4Addr::UNSPECIFIED.into(),
+            listen_port: 0,
+            ip_families: IpFamilies::Both,
+            ws_listen_port: None,
+            mdns: true,
+            mdns_query_interval_secs: 5 * 60,
+            auto_dial: true,
+            muxer: Muxer::Both,
+            security: Security::NoiseXx,
+            pubsub: PubSubProtocol::Gossipsub,
+            relays: Vec::new(),
+            relay_server: false,
+            agent_version: crate::AGENT_VERSION.to_string(),
+            nick: None,
+            downloads: data_dir().join("downloads"),
+            max_message_size: 4096,
+            dedup_window_secs: 120,
+            echo: false,
+            reorder_window_ms: 200,
+            min_version: 0,
+            dial_timeout_secs: 10,
+            ping_interval_secs: 15,
+            ping_timeout_secs: 20,
+            idle_timeout_secs: 120,
+            ping_keep_alive: false,
+            away_after_secs: 10 * 60,
+            rate_limit_messages: 10,
+            rate_limit_window_secs: 1,
+            soft_block_secs: 60,
+            max_incoming_connections: 64,
+            max_outgoing_connections: 64,
+            max_pending_connections: 16,
+            blocklist: data_dir().join("blocklist"),
+            allowlist: None,
+            remember_peers: true,
+            peerstore: data_dir().join("peerstore"),
+            history_dir: data_dir().join("history"),
+            history_max_bytes: 1024 * 1024,
+            profiles_dir: data_dir().join("profiles"),
+            history_provider: false,
+            history_provider_messages: 50,
+            room_key: None,
+            forward_secrecy: false,
+            motd: None,
+            motd_peer: None,
+            metrics_addr: None,
+        }
+    }
+}
+
+impl Config {
+    /// Address to listen on: the configured IP address and port, or `::`
+    /// in place of `0.0.0.0` when only listening on IPv6.
+    pub fn listen_addr(&self) -> Result<Multiaddr, libp2p::multiaddr::Error> {
+        let bind = match self.bind {
+            IpAddr::V4(ip) if ip.is_unspecified() && self.ip_families == IpFamilies::Ipv6 => {
+                Ipv6Addr::UNSPECIFIED.into()
+            }
+            bind => bind,
+        };
+        format!("{}/tcp/{}", Multiaddr::from(bind), self.listen_port).parse()
+    }
+
+    /// The IPv6 address to listen on as well, at the same port, when `bind`
+    /// leaves the IPv4 address open and both families are wanted. With port
+    /// 0 it gets a port of its own.
+    pub fn ipv6_listen_addr(&self) -> Option<Multiaddr> {
+        match self.bind {
+            IpAddr::V4(ip) if ip.is_unspecified() && self.ip_families == IpFamilies::Both => {
+                Some(Multiaddr::from(Ipv6Addr::UNSPECIFIED).with(Protocol::Tcp(self.listen_port)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Address to listen on for WebSocket connections, if configured.
+    pub fn ws_listen_addr(&self) -> Option<Multiaddr> {
+        let port = self.ws_listen_port?;
+        Some(Multiaddr::from(self.bind).with(Protocol::Tcp(port)).with(Protocol::Ws("/".into())))
+    }
+
+    pub fn mdns_query_interval(&self) -> Duration {
+        Duration::from_secs(self.mdns_query_interval_secs)
+    }
+
+    pub fn dedup_window(&self) -> Duration {
+        Duration::from_secs(self.dedup_window_secs)
+    }
+
+    pub fn reorder_window(&self) -> Duration {
+        Duration::from_millis(self.reorder_window_ms)
+    }
+
+    pub fn dial_timeout(&self) -> Duration {
+        Duration::from_secs(self.dial_timeout_secs)
+    }
+
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval_secs)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_timeout_secs)
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    /// How long without typing before we are away, if we ever are.
+    pub fn away_after(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.away_after_secs)).filter(|d| !d.is_zero())
+    }
+
+    pub fn rate_limit_window(&self) -> Duration {
+        Duration::from_secs(self.rate_limit_window_secs)
+    }
+
+    pub fn soft_block(&self) -> Duration {
+        Duration::from_secs(self.soft_block_secs)
+    }
+
+    pub fn max_incoming_connections(&self) -> Option<u32> {
+        limit(self.max_incoming_connections)
+    }
+
+    pub fn max_outgoing_connections(&self) -> Option<u32> {
+        limit(self.max_outgoing_connections)
+    }
+
+    /// The peer to ask for a message of the day: `motd_peer`, or else the
+    /// first bootstrap peer whose address says who it is.
+    pub fn motd_source(&self) -> Option<PeerId> {
+        self.motd_peer.or_else(|| self.bootstrap.iter().find_map(|a| crate::split_peer_id(a.clone())).map(|(p, _)| p))
+    }
+
+    /// The limits the swarm enforces, refusing connections beyond them.
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        let pending = limit(self.max_pending_connections);
+        ConnectionLimits::default()
+            .with_max_established_incoming(self.max_incoming_connections())
+            .with_max_established_outgoing(self.max_outgoing_connections())
+            .with_max_pending_incoming(pending)
+            .with_max_pending_outgoing(pending)
+    }
+
+    /// Reads the config at `path`, falling back to the defaults if there is
+    /// no such file.
+    pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|e| format!("Invalid {}: {}", path.display(), e).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Could not read {}: {}", path.display(), e).into()),
+        }
+    }
+
+    /// Overrides what the environment sets, see the [module docs](self).
+    pub fn apply_env(&mut self) -> Result<(), String> {
+        self.apply_vars(|name| std::env::var(name).ok())
+    }
+
+    /// Overrides the settings for which `var` gives a value.
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        if let Some(nick) = var("PEARDCHAT_NICK") {
+            self.nick = Some(nick).filter(|n| !n.is_empty());
+        }
+        // Set but empty, there are no bootstrap peers at all.
+        if let Some(addrs) = var("PEARDCHAT_BOOTSTRAP") {
+            self.bootstrap = addrs
+                .split(',')
+                .map(str::trim)
+                .filter(|a| !a.is_empty())
+                .map(|a| a.parse().map_err(|e| format!("Invalid PEARDCHAT_BOOTSTRAP address {}: {}", a, e)))
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+}
+
+/// A configured limit, where 0 means none.
+fn limit(n: u32) -> Option<u32> {
+    (n > 0).then_some(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_addr_parses() {
+        assert!(Config::default().listen_addr().is_ok());
+        let config = Config { listen_port: 4001, ..Config::default() };
+        assert_eq!(config.listen_addr().unwrap().to_string(), "/ip4/0.0.0.0/tcp/4001");
+        assert_eq!(config.ipv6_listen_addr().unwrap().to_string(), "/ip6/::/tcp/4001");
+        let v6 = Config { ip_families: IpFamilies::Ipv6, ..Config { listen_port: 4001, ..Config::default() } };
+        assert_eq!(v6.listen_addr().unwrap().to_string(), "/ip6/::/tcp/4001");
+        assert_eq!(v6.ipv6_listen_addr(), None);
+        let v4 = Config { ip_families: IpFamilies::Ipv4, ..v6 };
+        assert_eq!(v4.listen_addr().unwrap().to_string(), "/ip4/0.0.0.0/tcp/4001");
+        assert_eq!(v4.ipv6_listen_addr(), None);
+        let config = Config { bind: "::1".parse().unwrap(), ..config };
+        assert_eq!(config.listen_addr().unwrap().to_string(), "/ip6/::1/tcp/4001");
+        assert_eq!(config.ipv6_listen_addr(), None);
+        assert_eq!(config.ws_listen_addr(), None);
+        let config = Config { ws_listen_port: Some(4002), ..config };
+        assert_eq!(config.ws_listen_addr().unwrap().to_string(), "/ip6/::1/tcp/4002/ws");
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let mut config = Config { nick: Some("alice".into()), ..Config::default() };
+        config.apply_vars(|_| None).unwrap();
+        assert_eq!(config.nick.as_deref(), Some("alice"));
+        assert!(!config.bootstrap.is_empty());
+        let vars = |name: &str| match name {
+            "PEARDCHAT_NICK" => Some("bob".to_string()),
+            "PEARDCHAT_BOOTSTRAP" => Some("/ip4/10.0.0.2/tcp/4001, /dns4/example.com/tcp/4001".to_string()),
+            _ => None,
+        };
+        config.apply_vars(vars).unwrap();
+        assert_eq!(config.nick.as_deref(), Some("bob"));
+        assert_eq!(config.bootstrap.len(), 2);
+        config.apply_vars(|_| Some(String::new())).unwrap();
+        assert_eq!((config.nick, config.bootstrap), (None, Vec::new()));
+        let invalid = |name: &str| (name == "PEARDCHAT_BOOTSTRAP").then(|| "nope".to_string());
+        assert!(Config::default().apply_vars(invalid).is_err());
+    }
+}
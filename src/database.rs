@@ -0,0 +1,117 @@
+//! Every chat line sent or received, kept in a SQLite database for those who
+//! keep peardchat's history for good and want to search all of it. Unlike
+//! the per-topic [logs](crate::history), nothing is ever rotated away.
+
+use crate::message::{ChatMessage, MessageKind};
+use chrono::{DateTime, Local, TimeZone};
+use libp2p::PeerId;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+pub use rusqlite::Error;
+
+/// One stored chat line.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    /// When the line was stored, in milliseconds since the Unix epoch.
+    pub time: i64,
+    pub topic: String,
+    /// The author's PeerId, if known.
+    pub source: Option<String>,
+    pub nick: Option<String>,
+    pub body: String,
+    /// Whether the line was an action, as sent with `/me`.
+    pub action: bool,
+}
+
+impl Row {
+    pub fn time(&self) -> Option<DateTime<Local>> {
+        Local.timestamp_millis_opt(self.time).single()
+    }
+}
+
+pub struct Database {
+    conn: Connection,
+}
+
+impl Database {
+    /// Opens the database at `path`, creating it and its table if need be.
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                 time INTEGER NOT NULL,
+                 topic TEXT NOT NULL,
+                 source TEXT,
+                 nick TEXT,
+                 body TEXT NOT NULL,
+                 action INTEGER NOT NULL DEFAULT 0
+             );
+             CREATE INDEX IF NOT EXISTS messages_time ON messages (time);",
+        )?;
+        Ok(Database { conn })
+    }
+
+    /// Stores `message`, which `source` sent to `topic` just now.
+    pub fn insert(&self, topic: &str, source: Option<&PeerId>, message: &ChatMessage) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT INTO messages (time, topic, source, nick, body, action) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                Local::now().timestamp_millis(),
+                topic,
+                source.map(PeerId::to_string),
+                message.nick,
+                message.body,
+                message.kind == MessageKind::Action,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The latest `limit` lines in any topic whose body or nickname
+    /// contains `term`, ignoring ASCII case, oldest first.
+    pub fn search(&self, term: &str, limit: usize) -> Result<Vec<Row>, Error> {
+        let pattern = format!("%{}%", term.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        let mut statement = self.conn.prepare(
+            "SELECT time, topic, source, nick, body, action FROM messages
+             WHERE body LIKE ?1 ESCAPE '\\' OR nick LIKE ?1 ESCAPE '\\'
+             ORDER BY time DESC, rowid DESC LIMIT ?2",
+        )?;
+        let rows = statement.query_map(params![pattern, limit as i64], |row| {
+            Ok(Row {
+                time: row.get(0)?,
+                topic: row.get(1)?,
+                source: row.get(2)?,
+                nick: row.get(3)?,
+                body: row.get(4)?,
+                action: row.get(5)?,
+            })
+        })?;
+        let mut rows = rows.collect::<Result<Vec<_>, _>>()?;
+        rows.reverse();
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_messages_by_body_and_nick() {
+        let db = Database::open(Path::new(":memory:")).unwrap();
+        let peer = PeerId::random();
+        db.insert("chat", Some(&peer), &ChatMessage::new(Some("alice".into()), "hello 100% there")).unwrap();
+        db.insert("rust", None, &ChatMessage::action(Some("bob".into()), "waves")).unwrap();
+        db.insert("chat", None, &ChatMessage::new(None, "HELLO again")).unwrap();
+
+        let found = db.search("hello", 10).unwrap();
+        assert_eq!(found.iter().map(|r| r.body.as_str()).collect::<Vec<_>>(), ["hello 100% there", "HELLO again"]);
+        assert_eq!(found[0].source, Some(peer.to_string()));
+        assert_eq!(db.search("BOB", 10).unwrap()[0].topic, "rust");
+        assert!(db.search("bob", 10).unwrap()[0].action);
+        // `%` is matched as itself.
+        assert_eq!(db.search("0%", 10).unwrap().len(), 1);
+        assert_eq!(db.search("hello", 1).unwrap()[0].body, "HELLO again");
+    }
+}
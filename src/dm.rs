@@ -0,0 +1,284 @@
+//! End-to-end encrypted direct messages.
+//!
+//! Noise only protects each hop, so anything published on a topic is
+//! readable by every peer relaying it. A direct message is instead sealed to
+//! the recipient's key, which we can derive from their `PeerId` because it
+//! embeds their ed25519 public key. Sealed messages are published on
+//! [`DM_TOPIC`], which every node subscribes to; each node tries to open
+//! every message there and ignores the ones that are not for it. Sealed
+//! messages are published wrapped in the sender's signature, as chat
+//! messages are, and only opened if it checks out against the `PeerId` they
+//! came from, which floodsub would otherwise take on trust.
+//!
+//! There are two ways to seal one. With the static-key scheme, a payload is
+//! laid out as
+//!
+//! ```text
+//! version (1 byte, 0x01) | ephemeral x25519 public key (32 bytes) | ciphertext | MAC (16 bytes)
+//! ```
+//!
+//! The sender generates a fresh x25519 key for every message and does a
+//! Diffie-Hellman with the recipient's key (their ed25519 key converted to
+//! curve25519). HKDF-SHA256 turns the shared secret into a ChaCha20-Poly1305
+//! key, bound to both public keys and to the sender's `PeerId`. The plaintext
+//! is an encoded [`ChatMessage`]. As the key is never reused the nonce is
+//! always zero, and the header goes in as associated data. Because the
+//! sender's `PeerId` is mixed in, a message republished under another peer's
+//! signature fails authentication. Anyone who gets hold of the recipient's
+//! identity key later can open every message sent to them this way, though.
+//!
+//! With `forward_secrecy` on, we seal to a peer with a Double Ratchet instead,
+//! and keep up ratchets peers start with us whether it is on or not:
+//!
+//! ```text
+//! version (1 byte, 0x02) | ratchet header (41 bytes) | ciphertext | MAC (16 bytes)
+//! ```
+//!
+//! The ratchet starts from a Diffie-Hellman of both identity keys, bound to
+//! both `PeerId`s, and hands out a key for every message, which, as it is
+//! never reused, goes with a zero nonce. The header and both `PeerId`s go in
+//! as associated data. Ratchets live in memory, and are dropped when the
+//! last connection to the peer closes, as either of us may have restarted;
+//! the next message starts a new one. Should both peers start one at once,
+//! the one with the lower `PeerId` keeps its own.
+
+use crate::{
+    message::{public_key, ChatMessage},
+    ratchet::{self, Header, Ratchet},
+};
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use libp2p::{identity, noise, PeerId};
+use sha2::Sha256;
+use std::collections::HashMap;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// The topic sealed direct messages travel on.
+pub const DM_TOPIC: &str = "peardchat/dm";
+
+const VERSION: u8 = 1;
+const RATCHETED: u8 = 2;
+const HEADER_LEN: usize = 1 + 32;
+const MAC_LEN: usize = 16;
+
+/// Our half of direct message encryption: the curve25519 form of our
+/// ed25519 identity.
+pub(crate) struct DmKeys {
+    peer_id: PeerId,
+    secret: [u8; 32],
+    public: [u8; 32],
+    // Whether to start ratchets, rather than only keep up the ones peers
+    // start.
+    forward_secrecy: bool,
+    ratchets: HashMap<PeerId, Ratchet>,
+}
+
+impl DmKeys {
+    /// Returns `None` for identities that are not ed25519, which cannot take
+    /// part in direct messages. With `forward_secrecy`, what we send is
+    /// ratcheted.
+    pub fn new(id_keys: &identity::Keypair, forward_secrecy: bool) -> Option<Self> {
+        match id_keys {
+            identity::Keypair::Ed25519(keypair) => {
+                let keypair = noise::Keypair::<noise::X25519>::from(
+                    noise::SecretKey::<noise::X25519>::from_ed25519(&keypair.secret()),
+                );
+                Some(DmKeys {
+                    peer_id: PeerId::from(id_keys.public()),
+                    secret: to_array(keypair.secret().as_ref()),
+                    public: to_array(keypair.public().as_ref()),
+                    forward_secrecy,
+                    ratchets: HashMap::new(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Encrypts `message` so that only `to` can read it, returning it with
+    /// whether it is forward-secret.
+    pub fn seal(&mut self, to: &PeerId, message: &ChatMessage) -> Result<(Vec<u8>, bool), String> {
+        let recipient = public_key_of(to)
+            .ok_or_else(|| format!("{} does not have an ed25519 key", to))?;
+        if !self.forward_secrecy && !self.ratchets.contains_key(to) {
+            return self.seal_static(recipient, message).map(|payload| (payload, false));
+        }
+        if !self.ratchets.contains_key(to) {
+            let shared = self.shared_secret(recipient, &self.peer_id, to);
+            self.ratchets.insert(*to, Ratchet::initiate(shared, recipient));
+        }
+        let (header, key) = self.ratchets.get_mut(to).expect("ratchet added above").send();
+        let mut payload = vec![RATCHETED];
+        payload.extend_from_slice(&header.encode());
+        let ciphertext = message_cipher(&key)
+            .encrypt(Nonce::from_slice(&[0; 12]), Payload {
+                msg: &message.encode(),
+                aad: &associated(&payload, &self.peer_id, to),
+            })
+            .map_err(|_| "Encryption failed".to_string())?;
+        payload.extend_from_slice(&ciphertext);
+        Ok((payload, !header.initiating))
+    }
+
+    /// Forgets the ratchet with `peer`, if any; the next message to or from
+    /// them starts a new one.
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.ratchets.remove(peer);
+    }
+
+    fn seal_static(&self, recipient: [u8; 32], message: &ChatMessage) -> Result<Vec<u8>, String> {
+        let ephemeral_secret: [u8; 32] = rand::random();
+        let ephemeral = x25519(ephemeral_secret, X25519_BASEPOINT_BYTES);
+        let shared = x25519(ephemeral_secret, recipient);
+
+        let mut payload = Vec::with_capacity(HEADER_LEN);
+        payload.push(VERSION);
+        payload.extend_from_slice(&ephemeral);
+        let ciphertext = cipher(&shared, &ephemeral, &recipient, &self.peer_id)
+            .encrypt(Nonce::from_slice(&[0; 12]), Payload { msg: &message.encode(), aad: &payload })
+            .map_err(|_| "Encryption failed".to_string())?;
+        payload.extend_from_slice(&ciphertext);
+        Ok(payload)
+    }
+
+    /// Decrypts a payload `from` sent, returning it with whether it was
+    /// forward-secret, or `None` if it is malformed, addressed to someone
+    /// else or fails authentication.
+    pub fn open(&mut self, from: &PeerId, payload: &[u8]) -> Option<(ChatMessage, bool)> {
+        match payload.first() {
+            Some(&VERSION) => self.open_static(from, payload).map(|message| (message, false)),
+            Some(&RATCHETED) => self.open_ratcheted(from, payload),
+            _ => None,
+        }
+    }
+
+    fn open_ratcheted(&mut self, from: &PeerId, payload: &[u8]) -> Option<(ChatMessage, bool)> {
+        if payload.len() < 1 + ratchet::HEADER_LEN + MAC_LEN {
+            return None;
+        }
+        let (head, ciphertext) = payload.split_at(1 + ratchet::HEADER_LEN);
+        let header = Header::decode(&head[1..])?;
+        // The ratchet only moves on if the message opens.
+        let (key, moved) = match self.ratchets.get(from) {
+            Some(ours) if !header.initiating || ours.knows(&header) => {
+                let mut ours = ours.clone();
+                (ours.receive(&header)?, Some(ours))
+            }
+            _ if !header.initiating => return None,
+            ours => {
+                let sender = public_key_of(from)?;
+                let shared = self.shared_secret(sender, from, &self.peer_id);
+                let (key, theirs) = Ratchet::respond(shared, self.secret, &header)?;
+                let keep_ours = ours.is_some_and(|ours| ours.is_initiating()) && self.peer_id < *from;
+                (key, (!keep_ours).then_some(theirs))
+            }
+        };
+        let plaintext = message_cipher(&key)
+            .decrypt(Nonce::from_slice(&[0; 12]), Payload {
+                msg: ciphertext,
+                aad: &associated(head, from, &self.peer_id),
+            })
+            .ok()?;
+        let message = ChatMessage::decode(&plaintext)?;
+        if let Some(moved) = moved {
+            self.ratchets.insert(*from, moved);
+        }
+        Some((message, !header.initiating))
+    }
+
+    /// What a ratchet with the peer whose curve25519 key is `theirs` starts
+    /// from, as `initiator` started it with `responder`.
+    fn shared_secret(&self, theirs: [u8; 32], initiator: &PeerId, responder: &PeerId) -> [u8; 32] {
+        let mut info = b"peardchat dm ratchet v1".to_vec();
+        info.extend_from_slice(&initiator.to_bytes());
+        info.extend_from_slice(&responder.to_bytes());
+        let mut shared = [0; 32];
+        Hkdf::<Sha256>::new(None, &x25519(self.secret, theirs))
+            .expand(&info, &mut shared)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        shared
+    }
+
+    fn open_static(&self, from: &PeerId, payload: &[u8]) -> Option<ChatMessage> {
+        if payload.len() < HEADER_LEN + MAC_LEN || payload[0] != VERSION {
+            return None;
+        }
+        let (header, ciphertext) = payload.split_at(HEADER_LEN);
+        let ephemeral = to_array(&header[1..]);
+        let shared = x25519(self.secret, ephemeral);
+        let plaintext = cipher(&shared, &ephemeral, &self.public, from)
+            .decrypt(Nonce::from_slice(&[0; 12]), Payload { msg: ciphertext, aad: header })
+            .ok()?;
+        ChatMessage::decode(&plaintext)
+    }
+}
+
+/// The curve25519 public key of `peer`, if its `PeerId` embeds an ed25519 key.
+fn public_key_of(peer: &PeerId) -> Option<[u8; 32]> {
+    match public_key(peer)? {
+        identity::PublicKey::Ed25519(key) => {
+            Some(to_array(noise::PublicKey::<noise::X25519>::from_ed25519(&key).as_ref()))
+        }
+        _ => None,
+    }
+}
+
+fn cipher(shared: &[u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32], sender: &PeerId) -> ChaCha20Poly1305 {
+    let mut info = b"peardchat dm v1".to_vec();
+    info.extend_from_slice(ephemeral);
+    info.extend_from_slice(recipient);
+    info.extend_from_slice(&sender.to_bytes());
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(&info, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn message_cipher(key: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(Key::from_slice(key))
+}
+
+/// What a ratcheted message is bound to besides its `header`.
+fn associated(header: &[u8], sender: &PeerId, recipient: &PeerId) -> Vec<u8> {
+    [header, &sender.to_bytes(), &recipient.to_bytes()].concat()
+}
+
+fn to_array(bytes: &[u8]) -> [u8; 32] {
+    let mut array = [0; 32];
+    array.copy_from_slice(bytes);
+    array
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(forward_secrecy: bool) -> (DmKeys, PeerId) {
+        let id_keys = identity::Keypair::generate_ed25519();
+        (DmKeys::new(&id_keys, forward_secrecy).unwrap(), PeerId::from(id_keys.public()))
+    }
+
+    #[test]
+    fn only_the_recipient_opens_what_is_sealed_to_them() {
+        for forward_secrecy in [false, true] {
+            let ((mut alice, alice_id), (mut bob, bob_id), (mut eve, eve_id)) =
+                (keys(forward_secrecy), keys(false), keys(false));
+            let message = ChatMessage::new(Some("alice".into()), "psst");
+            let (payload, _) = alice.seal(&bob_id, &message).unwrap();
+
+            assert_eq!(eve.open(&alice_id, &payload), None);
+            // Bound to the sender, too.
+            assert_eq!(bob.open(&eve_id, &payload), None);
+            for i in [1, HEADER_LEN + 4, payload.len() - 1] {
+                let mut tampered = payload.clone();
+                tampered[i] ^= 1;
+                assert_eq!(bob.open(&alice_id, &tampered), None);
+            }
+            assert_eq!(bob.open(&alice_id, &payload), Some((message, false)));
+        }
+    }
+}
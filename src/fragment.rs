@@ -0,0 +1,246 @@
+//! Messages too long for pubsub to carry in one piece, split into numbered
+//! fragments with an id in common, and put back together on arrival.
+//!
+//! Each fragment is published on its own:
+//!
+//! ```text
+//! 0x04 | id (8 bytes) | index (2 bytes) | count (2 bytes) | part of the message
+//! ```
+//!
+//! with numbers big-endian. Fragments may arrive in any order, and a message
+//! still missing some after [`REASSEMBLY_TIMEOUT`] is dropped. So that
+//! nobody can make us hold on to much, each message is limited to
+//! [`MAX_MESSAGE_LEN`], each author to a few messages at once, and all of
+//! them together to [`MAX_PENDING_BYTES`].
+
+use crate::message::FRAGMENT;
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    time::{Duration, Instant},
+};
+
+/// How long to wait for the rest of a message after its first fragment.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Most fragments one message may be split into.
+pub const MAX_FRAGMENTS: usize = 256;
+
+/// Most messages being put back together at once; past that, the oldest
+/// is dropped.
+const MAX_PARTIAL: usize = 64;
+
+/// Most messages from one author being put back together at once; past
+/// that, the author's oldest is dropped.
+const MAX_PARTIAL_PER_SOURCE: usize = 4;
+
+/// Longest message we put back together. Nothing longer would decompress
+/// within the limit messages are held to, with room for the signature and
+/// room key wrapped around it.
+pub const MAX_MESSAGE_LEN: usize = 1024 * 1024 + 4096;
+
+/// Most bytes held for all messages still missing fragments; past that,
+/// the oldest are dropped.
+const MAX_PENDING_BYTES: usize = 8 * 1024 * 1024;
+
+const HEADER_LEN: usize = 1 + 8 + 2 + 2;
+
+/// Splits `data` into fragments of at most `max_len` bytes, headers
+/// included, each carrying `id`. Returns `None` if that would take more than
+/// [`MAX_FRAGMENTS`].
+pub fn split(id: u64, data: &[u8], max_len: usize) -> Option<Vec<Vec<u8>>> {
+    let chunks: Vec<_> = data.chunks(max_len.saturating_sub(HEADER_LEN).max(1)).collect();
+    if chunks.len() > MAX_FRAGMENTS {
+        return None;
+    }
+    let count = chunks.len() as u16;
+    let fragments = chunks.into_iter().enumerate().map(|(index, chunk)| {
+        let mut fragment = Vec::with_capacity(HEADER_LEN + chunk.len());
+        fragment.push(FRAGMENT);
+        fragment.extend_from_slice(&id.to_be_bytes());
+        fragment.extend_from_slice(&(index as u16).to_be_bytes());
+        fragment.extend_from_slice(&count.to_be_bytes());
+        fragment.extend_from_slice(chunk);
+        fragment
+    });
+    Some(fragments.collect())
+}
+
+/// What has arrived of one message.
+struct Partial {
+    started: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+    /// The bytes in `parts` so far.
+    len: usize,
+}
+
+/// Messages that have not all arrived yet, by author, topic and id.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<(Option<PeerId>, String, u64), Partial>,
+    /// The bytes held across every partial message.
+    len: usize,
+}
+
+impl Reassembler {
+    /// Takes in a `fragment` that `source` published on `topic`, returning
+    /// the whole message once this was the last of it still missing.
+    pub fn push(&mut self, source: Option<PeerId>, topic: &str, fragment: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let len = &mut self.len;
+        self.partial.retain(|(source, topic, id), partial| {
+            let waiting = now.saturating_duration_since(partial.started) < REASSEMBLY_TIMEOUT;
+            if !waiting {
+                tracing::debug!(%topic, source = ?source, id, "Dropped a message still missing fragments");
+                *len -= partial.len;
+            }
+            waiting
+        });
+        let (id, index, count, part) = match parse(fragment) {
+            Some(parsed) => parsed,
+            None => {
+                tracing::debug!(%topic, source = ?source, "Dropped a malformed fragment");
+                return None;
+            }
+        };
+        // Every fragment but the last is as long as this one.
+        if count.saturating_mul(part.len()) > MAX_MESSAGE_LEN {
+            tracing::debug!(%topic, source = ?source, id, count, "Dropped a fragment of an overlong message");
+            return None;
+        }
+        let key = (source, topic.to_string(), id);
+        if !self.partial.contains_key(&key) {
+            let from_source = self.partial.keys().filter(|(s, _, _)| *s == source).count();
+            if from_source >= MAX_PARTIAL_PER_SOURCE {
+                self.drop_oldest(|(s, _, _)| *s == source);
+            } else if self.partial.len() >= MAX_PARTIAL {
+                self.drop_oldest(|_| true);
+            }
+        }
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial { started: now, parts: vec![None; count], len: 0 });
+        // Fragments that disagree on the count are not of one message.
+        if partial.parts.len() != count || partial.parts[index].is_some() {
+            return None;
+        }
+        if partial.len + part.len() > MAX_MESSAGE_LEN {
+            tracing::debug!(%topic, source = ?source, id, "Dropped an overlong message");
+            self.remove(&key);
+            return None;
+        }
+        partial.parts[index] = Some(part.to_vec());
+        partial.len += part.len();
+        self.len += part.len();
+        while self.len > MAX_PENDING_BYTES {
+            if !self.drop_oldest(|k| *k != key) {
+                break;
+            }
+        }
+        if self.partial[&key].parts.iter().any(Option::is_none) {
+            return None;
+        }
+        let parts = self.remove(&key)?.parts;
+        Some(parts.into_iter().flatten().flatten().collect())
+    }
+
+    fn remove(&mut self, key: &(Option<PeerId>, String, u64)) -> Option<Partial> {
+        let partial = self.partial.remove(key)?;
+        self.len -= partial.len;
+        Some(partial)
+    }
+
+    /// Drops the oldest partial message whose key passes `filter`,
+    /// returning false if there was none.
+    fn drop_oldest(&mut self, filter: impl Fn(&(Option<PeerId>, String, u64)) -> bool) -> bool {
+        let oldest = self.partial.iter().filter(|(key, _)| filter(key)).min_by_key(|(_, p)| p.started);
+        match oldest.map(|(key, _)| key.clone()) {
+            Some(oldest) => {
+                tracing::debug!(topic = %oldest.1, source = ?oldest.0, id = oldest.2, "Dropped a message still missing fragments");
+                self.remove(&oldest).is_some()
+            }
+            None => false,
+        }
+    }
+}
+
+/// The id, index, count and part of the message in `fragment`, if it is one.
+fn parse(fragment: &[u8]) -> Option<(u64, usize, usize, &[u8])> {
+    let rest = fragment.strip_prefix(&[FRAGMENT])?;
+    if rest.len() < HEADER_LEN - 1 {
+        return None;
+    }
+    let id = u64::from_be_bytes(rest[..8].try_into().ok()?);
+    let index = u16::from_be_bytes(rest[8..10].try_into().ok()?) as usize;
+    let count = u16::from_be_bytes(rest[10..12].try_into().ok()?) as usize;
+    if count == 0 || count > MAX_FRAGMENTS || index >= count {
+        return None;
+    }
+    Some((id, index, count, &rest[12..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reassembles_out_of_order_and_drops_the_incomplete() {
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let fragments = split(7, &data, 100).unwrap();
+        assert_eq!(fragments.len(), 12);
+        assert!(fragments.iter().all(|f| f.len() <= 100));
+
+        let (start, peer) = (Instant::now(), Some(PeerId::random()));
+        let mut reassembler = Reassembler::default();
+        let (first, rest) = fragments.split_first().unwrap();
+        for fragment in rest.iter().rev() {
+            assert_eq!(reassembler.push(peer, "chat", fragment, start), None);
+        }
+        // A repeat changes nothing.
+        assert_eq!(reassembler.push(peer, "chat", &rest[0], start), None);
+        assert_eq!(reassembler.push(peer, "chat", first, start), Some(data.clone()));
+
+        for fragment in rest {
+            reassembler.push(peer, "chat", fragment, start);
+        }
+        let later = start + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembler.push(peer, "chat", first, later), None);
+        assert!(split(7, &data, HEADER_LEN + 3).is_none());
+    }
+
+    #[test]
+    fn holds_only_so_much_at_once() {
+        let start = Instant::now();
+        let mut reassembler = Reassembler::default();
+        let fragment = |id: u64, index: u16, count: u16, len: usize| {
+            let mut fragment = vec![FRAGMENT];
+            fragment.extend_from_slice(&id.to_be_bytes());
+            fragment.extend_from_slice(&index.to_be_bytes());
+            fragment.extend_from_slice(&count.to_be_bytes());
+            fragment.resize(HEADER_LEN + len, 0);
+            fragment
+        };
+
+        // Far longer than any message, once all of it would be in.
+        let flooder = Some(PeerId::random());
+        reassembler.push(flooder, "chat", &fragment(1, 0, 255, 60 * 1024), start);
+        assert!(reassembler.partial.is_empty());
+
+        // An author's fifth message in the works pushes out its first.
+        for id in 0..5 {
+            reassembler.push(flooder, "chat", &fragment(id, 0, 2, 10), start + Duration::from_millis(id));
+        }
+        let other = Some(PeerId::random());
+        reassembler.push(other, "chat", &fragment(0, 0, 2, 10), start);
+        assert_eq!(reassembler.push(flooder, "chat", &fragment(0, 1, 2, 10), start), None);
+        assert_eq!(reassembler.push(flooder, "chat", &fragment(4, 1, 2, 10), start).map(|m| m.len()), Some(20));
+        assert_eq!(reassembler.push(other, "chat", &fragment(0, 1, 2, 10), start).map(|m| m.len()), Some(20));
+
+        // Past the total, the oldest go, whoever they are from.
+        let authors: Vec<_> = (0..20).map(|_| Some(PeerId::random())).collect();
+        for (i, author) in authors.iter().enumerate() {
+            reassembler.push(*author, "chat", &fragment(0, 0, 2, 500_000), start + Duration::from_millis(i as u64));
+        }
+        assert!(reassembler.len <= MAX_PENDING_BYTES);
+        assert_eq!(reassembler.push(authors[0], "chat", &fragment(0, 1, 2, 500_000), start), None);
+        assert!(reassembler.push(authors[19], "chat", &fragment(0, 1, 2, 500_000), start).is_some());
+    }
+}
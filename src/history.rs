@@ -0,0 +1,240 @@
+//! Scrollback kept on disk, one log file per topic.
+//!
+//! Each line of `<dir>/<topic>.log` is a JSON [`Entry`]. Once a log grows
+//! past its size cap it is moved to `<topic>.log.1`, replacing the previous
+//! one, and a fresh log is started, so a topic never takes more than twice
+//! the cap.
+//!
+//! Apart from those, the [`Transcript`] holds what was said this session
+//! in memory, with the reactions to it, so it can be exported on demand.
+
+use crate::message::{ChatMessage, MessageKind};
+use chrono::{DateTime, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// How long a reaction to a line we do not have is held, in case the line
+/// is still on its way.
+const REACTION_WAIT: Duration = Duration::from_secs(10);
+
+/// Most reactions held at once; the oldest go first.
+const MAX_HELD: usize = 256;
+
+/// One logged chat line.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the line was logged, in milliseconds since the Unix epoch.
+    pub time: i64,
+    /// The message's `id`, if it had one.
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub sender: String,
+    pub body: String,
+    /// Whether the line was an action, as sent with `/me`.
+    #[serde(default)]
+    pub action: bool,
+}
+
+impl Entry {
+    /// `message`, which `sender` wrote just now.
+    pub fn new(sender: &str, message: &ChatMessage) -> Self {
+        Entry {
+            time: Local::now().timestamp_millis(),
+            id: message.id,
+            sender: sender.to_string(),
+            body: message.body.clone(),
+            action: message.kind == MessageKind::Action,
+        }
+    }
+
+    pub fn time(&self) -> Option<DateTime<Local>> {
+        Local.timestamp_millis_opt(self.time).single()
+    }
+}
+
+/// Per-topic chat logs in one directory.
+pub struct History {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl History {
+    /// Logs into `dir`, creating it on first write, and rotates each topic's
+    /// log once it reaches `max_bytes`.
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        History { dir, max_bytes }
+    }
+
+    /// Appends `message`, which `sender` wrote to `topic` just now.
+    pub fn append(&self, topic: &str, sender: &str, message: &ChatMessage) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(topic);
+        if fs::metadata(&path).map(|m| m.len() >= self.max_bytes).unwrap_or(false) {
+            fs::rename(&path, path.with_extension("log.1"))?;
+        }
+        let mut line = serde_json::to_vec(&Entry::new(sender, message)).expect("Entry always serializes");
+        line.push(b'\n');
+        fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(&line)
+    }
+
+    /// The last `n` lines logged for `topic`, oldest first. Lines that do
+    /// not parse are skipped.
+    pub fn tail(&self, topic: &str, n: usize) -> io::Result<Vec<Entry>> {
+        let path = self.path(topic);
+        let mut entries = Vec::new();
+        for path in [path.with_extension("log.1"), path] {
+            match fs::read_to_string(&path) {
+                Ok(contents) => entries.extend(contents.lines().filter_map(|l| serde_json::from_str(l).ok())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let skip = entries.len().saturating_sub(n);
+        Ok(entries.split_off(skip))
+    }
+
+    /// Topics may contain `/` and other characters unsafe in file names.
+    fn path(&self, topic: &str) -> PathBuf {
+        let name: String = topic
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.dir.join(format!("{}.log", name))
+    }
+}
+
+/// A chat line in a [`Transcript`], with the topic it was on.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Shown {
+    pub topic: String,
+    #[serde(flatten)]
+    pub entry: Entry,
+    /// Each emoji it was reacted with, in the order they were first used,
+    /// with who used it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<(String, Vec<String>)>,
+}
+
+impl Shown {
+    /// The reactions as they follow the line, such as `  [👍 x3 🎉 x1]`, or
+    /// nothing if there are none.
+    pub fn tally(&self) -> String {
+        if self.reactions.is_empty() {
+            return String::new();
+        }
+        let counts: Vec<_> = self.reactions.iter().map(|(emoji, whom)| format!("{} x{}", emoji, whom.len())).collect();
+        format!("  [{}]", counts.join(" "))
+    }
+
+    /// Records that `who` reacted with `emoji`, returning false if they had
+    /// already.
+    fn react(&mut self, emoji: &str, who: &str) -> bool {
+        match self.reactions.iter_mut().find(|(e, _)| e == emoji) {
+            Some((_, whom)) if whom.iter().any(|w| w == who) => false,
+            Some((_, whom)) => {
+                whom.push(who.to_string());
+                true
+            }
+            None => {
+                self.reactions.push((emoji.to_string(), vec![who.to_string()]));
+                true
+            }
+        }
+    }
+}
+
+/// A reaction held until the line it is for turns up.
+struct Held {
+    since: Instant,
+    topic: String,
+    to: u64,
+    emoji: String,
+    who: String,
+}
+
+/// The chat lines shown this session, across every topic, up to a limit.
+pub struct Transcript {
+    max_lines: usize,
+    lines: VecDeque<Shown>,
+    held: Vec<Held>,
+}
+
+impl Transcript {
+    /// Keeps the latest `max_lines` lines.
+    pub fn new(max_lines: usize) -> Self {
+        Transcript { max_lines, lines: VecDeque::new(), held: Vec::new() }
+    }
+
+    /// Adds `message`, which `sender` wrote to `topic` just now, with the
+    /// reactions to it that came first.
+    pub fn push(&mut self, topic: &str, sender: &str, message: &ChatMessage) {
+        if self.lines.len() == self.max_lines {
+            self.lines.pop_front();
+        }
+        let mut shown = Shown { topic: topic.to_string(), entry: Entry::new(sender, message), reactions: Vec::new() };
+        self.held.retain(|held| held.since.elapsed() < REACTION_WAIT);
+        if let Some(id) = message.id {
+            for held in self.held.iter().filter(|held| held.to == id && held.topic == topic) {
+                shown.react(&held.emoji, &held.who);
+            }
+            self.held.retain(|held| held.to != id || held.topic != topic);
+        }
+        self.lines.push_back(shown);
+    }
+
+    /// Records that `who` reacted with `emoji` to the line with the id `to`
+    /// on `topic`. Returns that line if this adds to its tally. A reaction
+    /// to a line we do not have is held for a moment, in case it turns up.
+    pub fn react(&mut self, topic: &str, to: u64, emoji: &str, who: &str) -> Option<&Shown> {
+        match self.lines.iter_mut().rev().find(|shown| shown.entry.id == Some(to) && shown.topic == topic) {
+            Some(shown) => shown.react(emoji, who).then_some(&*shown),
+            None => {
+                if self.held.len() == MAX_HELD {
+                    self.held.remove(0);
+                }
+                let (topic, emoji, who) = (topic.to_string(), emoji.to_string(), who.to_string());
+                self.held.push(Held { since: Instant::now(), topic, to, emoji, who });
+                None
+            }
+        }
+    }
+
+    /// The line with the id `id`, if we have it.
+    pub fn find(&self, id: u64) -> Option<&Shown> {
+        self.lines.iter().rev().find(|shown| shown.entry.id == Some(id))
+    }
+
+    /// The lines, oldest first.
+    pub fn lines(&self) -> impl DoubleEndedIterator<Item = &Shown> {
+        self.lines.iter()
+    }
+
+    /// Writes every line to `path`, replacing the file, and returns how many
+    /// there were. A path ending in `.json` gets a JSON array, anything else
+    /// one readable line per message.
+    pub fn export(&self, path: &Path) -> io::Result<usize> {
+        let contents = if path.extension().is_some_and(|e| e == "json") {
+            serde_json::to_string_pretty(&self.lines).expect("Lines always serialize")
+        } else {
+            let mut contents = String::new();
+            for shown @ Shown { topic, entry, .. } in &self.lines {
+                let time = entry.time().map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap_or_default();
+                let line = if entry.action {
+                    format!("* {} {}", entry.sender, entry.body)
+                } else {
+                    format!("{}: {}", entry.sender, entry.body)
+                };
+                contents.push_str(&format!("[{}] [{}] {}{}\n", time, topic, line, shown.tally()));
+            }
+            contents
+        };
+        fs::write(path, contents)?;
+        Ok(self.lines.len())
+    }
+}
@@ -0,0 +1,91 @@
+//! Direct messages kept apart from room chat, one conversation per peer.
+//!
+//! Every direct message we send or receive goes into the conversation with
+//! the other peer, which keeps its latest lines and counts those that came
+//! in while it was not open.
+
+use crate::{history::Entry, message::ChatMessage};
+use libp2p::PeerId;
+use std::collections::{HashMap, VecDeque};
+
+/// A line of a conversation, with the peer who wrote it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Said {
+    pub author: PeerId,
+    pub entry: Entry,
+}
+
+#[derive(Default)]
+struct Conversation {
+    lines: VecDeque<Said>,
+    unread: usize,
+}
+
+/// Our conversations, each holding up to `max_lines` lines.
+pub struct Inbox {
+    max_lines: usize,
+    conversations: HashMap<PeerId, Conversation>,
+}
+
+impl Inbox {
+    pub fn new(max_lines: usize) -> Self {
+        Inbox { max_lines, conversations: HashMap::new() }
+    }
+
+    /// Adds `message`, which `author`, going by `sender`, wrote just now in
+    /// the conversation with `peer`. Unless `read`, it counts as unread.
+    /// Returns how many lines of the conversation are now unread.
+    pub fn push(&mut self, peer: PeerId, author: PeerId, sender: &str, message: &ChatMessage, read: bool) -> usize {
+        let conversation = self.conversations.entry(peer).or_default();
+        if conversation.lines.len() == self.max_lines {
+            conversation.lines.pop_front();
+        }
+        conversation.lines.push_back(Said { author, entry: Entry::new(sender, message) });
+        if !read {
+            conversation.unread += 1;
+        }
+        conversation.unread
+    }
+
+    /// The last `n` lines of the conversation with `peer`, oldest first,
+    /// which are all read from now on.
+    pub fn open(&mut self, peer: &PeerId, n: usize) -> Vec<Said> {
+        match self.conversations.get_mut(peer) {
+            Some(conversation) => {
+                conversation.unread = 0;
+                let skip = conversation.lines.len().saturating_sub(n);
+                conversation.lines.iter().skip(skip).cloned().collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// The peers with unread lines and how many each has.
+    pub fn unread(&self) -> Vec<(PeerId, usize)> {
+        self.conversations.iter().filter(|(_, c)| c.unread > 0).map(|(p, c)| (*p, c.unread)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_unread_lines_until_the_conversation_is_opened() {
+        let [me, alice, bob] = [PeerId::random(), PeerId::random(), PeerId::random()];
+        let mut inbox = Inbox::new(2);
+        assert_eq!(inbox.push(alice, alice, "alice", &ChatMessage::new(None, "one"), false), 1);
+        assert_eq!(inbox.push(alice, me, "me", &ChatMessage::new(None, "two"), true), 1);
+        assert_eq!(inbox.push(alice, alice, "alice", &ChatMessage::new(None, "three"), false), 2);
+        inbox.push(bob, me, "me", &ChatMessage::new(None, "hi bob"), true);
+        assert_eq!(inbox.unread(), vec![(alice, 2)]);
+
+        // Only the last `max_lines` are kept.
+        let lines = inbox.open(&alice, 10);
+        let bodies: Vec<_> = lines.iter().map(|said| (said.author, said.entry.body.as_str())).collect();
+        assert_eq!(bodies, vec![(me, "two"), (alice, "three")]);
+        assert!(inbox.unread().is_empty());
+        assert_eq!(inbox.open(&bob, 1)[0].entry.sender, "me");
+        assert!(inbox.open(&PeerId::random(), 1).is_empty());
+    }
+}
@@ -0,0 +1,68 @@
+//! Invites: a PeerId and the addresses it can be reached on, packed into
+//! one base58 token to hand to a friend, who dials it with `/connect` or
+//! `--connect`.
+//!
+//! The token is the bincode encoding of the PeerId, the addresses and an
+//! expiry time, so one cannot be used long after it was made, by which
+//! time the addresses are likely stale anyway. Nothing in it is secret or
+//! signed: it only says where to dial, and the PeerId is still checked on
+//! connecting.
+
+use chrono::{DateTime, Local, TimeZone};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, time::Duration};
+
+/// An invite as it is encoded.
+#[derive(Serialize, Deserialize)]
+struct Token {
+    peer: Vec<u8>,
+    addrs: Vec<Vec<u8>>,
+    /// Seconds since the Unix epoch.
+    expires_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Invite {
+    pub peer: PeerId,
+    /// Addresses of `peer`, without its PeerId at the end.
+    pub addrs: Vec<Multiaddr>,
+    pub expires_at: DateTime<Local>,
+}
+
+impl Invite {
+    /// An invite to `peer` at `addrs`, valid for `valid_for` from now.
+    pub fn new(peer: PeerId, addrs: Vec<Multiaddr>, valid_for: Duration) -> Self {
+        let valid_for = chrono::Duration::from_std(valid_for).expect("Invites expire within a few days");
+        Invite { peer, addrs, expires_at: Local::now() + valid_for }
+    }
+
+    pub fn to_token(&self) -> String {
+        let token = Token {
+            peer: self.peer.to_bytes(),
+            addrs: self.addrs.iter().map(|a| a.to_vec()).collect(),
+            expires_at: self.expires_at.timestamp(),
+        };
+        bs58::encode(bincode::serialize(&token).expect("Tokens always serialize")).into_string()
+    }
+
+    /// Reads a token made by [`Invite::to_token`], failing with why if it
+    /// is not one or has expired. Addresses that do not parse are left out.
+    pub fn from_token(token: &str) -> Result<Self, String> {
+        let not_an_invite = || format!("Not an invite: {}", token);
+        let bytes = bs58::decode(token.trim()).into_vec().map_err(|_| not_an_invite())?;
+        let token: Token = bincode::deserialize(&bytes).map_err(|_| not_an_invite())?;
+        let peer = PeerId::from_bytes(&token.peer).map_err(|_| not_an_invite())?;
+        let expires_at = Local.timestamp_opt(token.expires_at, 0).single().ok_or_else(not_an_invite)?;
+        if expires_at < Local::now() {
+            return Err(format!("The invite expired at {}", expires_at.format("%Y-%m-%d %H:%M")));
+        }
+        let addrs = token.addrs.into_iter().filter_map(|a| Multiaddr::try_from(a).ok()).collect();
+        Ok(Invite { peer, addrs, expires_at })
+    }
+
+    /// The addresses to dial, each ending in the PeerId.
+    pub fn dial_addrs(&self) -> Vec<Multiaddr> {
+        self.addrs.iter().map(|a| a.clone().with(Protocol::P2p(self.peer.into()))).collect()
+    }
+}
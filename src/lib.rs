@@ -0,0 +1,51 @@
+//! A peer-to-peer chat built on libp2p, using gossipsub or floodsub to spread
+//! messages and mDNS and Kademlia to find peers.
+//!
+//! [`ChatNode`] runs a single peer. Drive it by awaiting
+//! [`ChatNode::poll_event`] in a loop and act on the [`ChatEvent`]s it yields:
+//!
+//! ```no_run
+//! use libp2p::identity::Keypair;
+//! use peardchat::{config::Config, ChatEvent, ChatMessage, ChatNode};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+//! let mut node = ChatNode::new(Keypair::generate_ed25519(), &Config::default()).await?;
+//! node.join("chat").expect("Subscribing to chat");
+//! node.send("chat", ChatMessage::new(Some("alice".into()), "hello")).await
+//!     .expect("Publishing to chat");
+//! loop {
+//!     if let ChatEvent::MessageReceived { message, .. } = node.poll_event().await {
+//!         println!("{}", message.body);
+//!     }
+//! }
+//! # }
+//! ```
+
+pub mod backlog;
+mod behaviour;
+pub mod blocklist;
+pub mod config;
+pub mod database;
+pub mod dm;
+mod fragment;
+pub mod history;
+pub mod inbox;
+pub mod invite;
+pub mod message;
+pub mod metrics;
+pub mod motd;
+mod node;
+pub mod peerstore;
+pub mod profile;
+mod pubsub;
+mod ratchet;
+pub mod roster;
+pub mod room_key;
+mod serde_peer;
+pub mod transfer;
+
+pub use message::{ChatMessage, MessageKind};
+pub use node::{
+    memory_transport, relay_config, split_peer_id, tcp_transport, ChatEvent, ChatNode, ChatTransport,
+    ConnectionPath, DiscoveredPeer, PeerInfo, Stats, TopicStats, Unconfirmed, AGENT_VERSION, PROTOCOL_VERSION,
+};
@@ -18,161 +18,2208 @@
 // FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-//! A basic chat application demonstrating libp2p with the mDNS and floodsub protocols
-//! using tokio for all asynchronous tasks and I/O. In order for all used libp2p
-//! crates to use tokio, it enables tokio-specific features for some crates.
+//! A basic chat application demonstrating libp2p with the mDNS and gossipsub or floodsub protocols
+//! using tokio for all asynchronous tasks and I/O. The networking lives in the
+//! `peardchat` library; this binary wires it up to stdin and stdout.
 //!
-//! The example is run per node as follows:
+//! Run one node per terminal, optionally passing the address of another node
+//! to dial:
 //!
 //! ```sh
-//! cargo run --example chat-tokio --features="tcp-tokio mdns"
+//! cargo run -- /ip4/127.0.0.1/tcp/4001
 //! ```
 //!
-//! Alternatively, to run with the minimal set of features and crates:
-//!
-//! ```sh
-//!cargo run --example chat-tokio \\
-//!    --no-default-features \\
-//!    --features="floodsub mplex noise tcp-tokio mdns"
-//! ```
+//! Pass `--tui` for a full-screen interface with the peer list alongside.
+
+mod command;
+mod reorder;
+mod template;
+mod tui;
 
-use libp2p::{
-    Multiaddr,
-    NetworkBehaviour,
-    PeerId,
-    Swarm,
-    Transport,
-    core::upgrade,
-    identity,
-    floodsub::{self, Floodsub, FloodsubEvent},
-    mdns::{Mdns, MdnsEvent},
-    mplex,
-    noise,
-    swarm::{NetworkBehaviourEventProcess, SwarmBuilder},
-    // `TokioTcpConfig` is available through the `tcp-tokio` feature.
-    tcp::TokioTcpConfig,
+use chrono::{DateTime, Local};
+use command::{chat_text, parse_command, Command, COMMANDS, DEFAULT_HISTORY_LINES};
+use libp2p::{gossipsub::error::PublishError, identity, multiaddr::Protocol, Multiaddr, PeerId};
+use peardchat::{
+    blocklist::load_allowlist,
+    config::{self, Config, IpFamilies, Muxer, PubSubProtocol, Security},
+    database::Database,
+    dm::DM_TOPIC,
+    history::{History, Shown, Transcript},
+    inbox::{Inbox, Said},
+    invite::Invite,
+    metrics,
+    profile::Profiles,
+    roster::{Presence, RosterEntry, AWAY_AFTER},
+    transfer::TransferEvent,
+    split_peer_id, ChatEvent, ChatMessage, ChatNode, DiscoveredPeer, MessageKind, Stats, TopicStats, Unconfirmed,
 };
-use std::error::Error;
-use tokio::io::{self, AsyncBufReadExt};
+use std::{
+    error::Error,
+    fs, io,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Mutex,
+    time::Duration,
+};
+use structopt::StructOpt;
+use reorder::ReorderBuffer;
+use template::{Fields, Template};
+use tokio::{sync::mpsc, time::Instant};
+use tracing_subscriber::EnvFilter;
+use crossterm::event::{Event, EventStream};
+use futures::StreamExt;
 
-/// The `tokio::main` attribute sets up a tokio runtime.
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    env_logger::init();
-
-    // Create a random PeerId
-    let id_keys = identity::Keypair::generate_ed25519();
-    let peer_id = PeerId::from(id_keys.public());
-    println!("Local peer id: {:?}", peer_id);
-
-    // Create a keypair for authenticated encryption of the transport.
-    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
-        .into_authentic(&id_keys)
-        .expect("Signing libp2p-noise static DH keypair failed.");
-
-    // Create a tokio-based TCP transport use noise for authenticated
-    // encryption and Mplex for multiplexing of substreams on a TCP stream.
-    let transport = TokioTcpConfig::new().nodelay(true)
-        .upgrade(upgrade::Version::V1)
-        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-        .multiplex(mplex::MplexConfig::new())
-        .boxed();
-
-    // Create a Floodsub topic
-    let floodsub_topic = floodsub::Topic::new("chat");
-
-    // We create a custom network behaviour that combines floodsub and mDNS.
-    // The derive generates a delegating `NetworkBehaviour` impl which in turn
-    // requires the implementations of `NetworkBehaviourEventProcess` for
-    // the events of each behaviour.
-    #[derive(NetworkBehaviour)]
-    struct MyBehaviour {
-        floodsub: Floodsub,
-        mdns: Mdns,
-    }
-
-    impl NetworkBehaviourEventProcess<FloodsubEvent> for MyBehaviour {
-        // Called when `floodsub` produces an event.
-        fn inject_event(&mut self, message: FloodsubEvent) {
-            if let FloodsubEvent::Message(message) = message {
-                println!("Received: '{:?}' from {:?}", String::from_utf8_lossy(&message.data), message.source);
-            }
-        }
-    }
-
-    impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
-        // Called when `mdns` produces an event.
-        fn inject_event(&mut self, event: MdnsEvent) {
-            match event {
-                MdnsEvent::Discovered(list) =>
-                    for (peer, _) in list {
-                        self.floodsub.add_node_to_partial_view(peer);
-                    }
-                MdnsEvent::Expired(list) =>
-                    for (peer, _) in list {
-                        if !self.mdns.has_node(&peer) {
-                            self.floodsub.remove_node_from_partial_view(&peer);
-                        }
-                    }
+#[derive(Debug, StructOpt)]
+#[structopt(name = "peardchat", about = "Peer-to-peer chat over libp2p")]
+struct Opts {
+    /// Addresses of other nodes to dial on startup.
+    addrs: Vec<String>,
+    /// Another address to dial on startup, as if given after the options.
+    /// May be repeated.
+    #[structopt(long, number_of_values = 1)]
+    dial: Vec<String>,
+    /// An invite made with `/invite` to connect with on startup. May be
+    /// repeated.
+    #[structopt(long, number_of_values = 1)]
+    connect: Vec<String>,
+    /// TCP port to listen on, overriding `listen_port` in the config.
+    #[structopt(long)]
+    port: Option<u16>,
+    /// TCP port to accept WebSocket connections on, overriding
+    /// `ws_listen_port` in the config.
+    #[structopt(long)]
+    ws_port: Option<u16>,
+    /// IP address to listen on, overriding `bind` in the config.
+    #[structopt(long)]
+    bind: Option<IpAddr>,
+    /// Only listen on IPv4, as `ip_families = "ipv4"` in the config.
+    #[structopt(long, conflicts_with = "ipv6-only")]
+    ipv4_only: bool,
+    /// Only listen on IPv6, as `ip_families = "ipv6"` in the config.
+    #[structopt(long)]
+    ipv6_only: bool,
+    /// Log debug output. `RUST_LOG` takes precedence when set.
+    #[structopt(short, long)]
+    verbose: bool,
+    /// Append logs to this file instead of writing them to stderr.
+    #[structopt(long, conflicts_with = "log-stderr")]
+    log_file: Option<PathBuf>,
+    /// Write logs to stderr even with `--tui`, which otherwise appends them
+    /// to `peardchat.log` in the data directory. Without `--tui` they go
+    /// there anyway, keeping stdout for chat.
+    #[structopt(long)]
+    log_stderr: bool,
+    /// Do not read stdin; just relay and print messages.
+    #[structopt(long)]
+    listen_only: bool,
+    /// Print without colors. Setting `NO_COLOR` does the same.
+    #[structopt(long)]
+    no_color: bool,
+    /// Do not look for peers on the local network with mDNS, as `mdns =
+    /// false` in the config.
+    #[structopt(long)]
+    no_mdns: bool,
+    /// Only connect to discovered peers when asked to with `/dial`, as
+    /// `auto_dial = false` in the config.
+    #[structopt(long)]
+    no_auto_dial: bool,
+    /// Only accept connections with the peers listed in this file, one
+    /// PeerId per line, as `allowlist` in the config.
+    #[structopt(long)]
+    allowlist: Option<PathBuf>,
+    /// Encrypt the chat in every room with a key derived from this
+    /// passphrase, so that only peers given the same one can read it, as
+    /// `room_key` in the config. Other users on this machine can see it
+    /// here, so prefer the config.
+    #[structopt(long)]
+    room_key: Option<String>,
+    /// Ratchet the direct messages we send, so that each has a key of its
+    /// own, as `forward_secrecy = true` in the config.
+    #[structopt(long)]
+    forward_secrecy: bool,
+    /// Message of the day to hand peers that ask, overriding `motd` in the
+    /// config.
+    #[structopt(long)]
+    motd: Option<String>,
+    /// Peer to ask for its message of the day on startup, overriding
+    /// `motd_peer` in the config. By default the first bootstrap peer.
+    #[structopt(long)]
+    motd_peer: Option<PeerId>,
+    /// Seconds to wait for a connection to be set up before giving up on it,
+    /// overriding `dial_timeout_secs` in the config.
+    #[structopt(long)]
+    dial_timeout: Option<u64>,
+    /// Stream multiplexer to offer, overriding `muxer` in the config.
+    /// `both` prefers Yamux and falls back to Mplex.
+    #[structopt(long, possible_values = &["yamux", "mplex", "both"])]
+    muxer: Option<Muxer>,
+    /// Handshake to secure connections with, overriding `security` in the
+    /// config: `noise-xx`, or `noise-ix`, which only other rust-libp2p
+    /// peers set to it speak. Both sides must use the same.
+    #[structopt(long)]
+    security: Option<Security>,
+    /// Protocol to spread chat with, overriding `pubsub` in the config.
+    /// Peers only see messages from those using the same one.
+    #[structopt(long, possible_values = &["gossipsub", "floodsub"])]
+    pubsub: Option<PubSubProtocol>,
+    /// Show our own messages again if they come back to us, as `echo` in
+    /// the config. Only useful for debugging.
+    #[structopt(long)]
+    echo: bool,
+    /// How long to hold incoming chat so that messages arriving out of order
+    /// are shown in order, such as `200ms`, overriding `reorder_window_ms`
+    /// in the config.
+    #[structopt(long, parse(try_from_str = parse_delay))]
+    reorder_window: Option<Duration>,
+    /// Show incoming chat as soon as it arrives, in whatever order, as
+    /// `reorder_window_ms = 0` in the config.
+    #[structopt(long, conflicts_with = "reorder-window")]
+    no_reorder: bool,
+    /// How long without typing before peers are told we are away, such as
+    /// `10m`, overriding `away_after_secs` in the config. `0s` never does.
+    #[structopt(long, parse(try_from_str = parse_delay))]
+    away_after: Option<Duration>,
+    /// Seconds to keep a connection nothing is happening on, overriding
+    /// `idle_timeout_secs` in the config.
+    #[structopt(long)]
+    idle_timeout: Option<u64>,
+    /// Keep connections open for as long as pings are answered, however
+    /// quiet, as `ping_keep_alive` in the config.
+    #[structopt(long)]
+    ping_keep_alive: bool,
+    /// Drop messages in a format older than this version, overriding
+    /// `min_version` in the config.
+    #[structopt(long)]
+    min_version: Option<u32>,
+    /// What to tell peers we run, such as "peardchat/0.2 myfork",
+    /// overriding `agent_version` in the config.
+    #[structopt(long)]
+    agent: Option<String>,
+    /// Relay connections for any peer that asks, over circuit relay v1, as
+    /// `relay_server` in the config.
+    #[structopt(long)]
+    relay_server: bool,
+    /// Run a full-screen terminal UI instead of reading and printing lines.
+    #[structopt(long, conflicts_with = "listen-only")]
+    tui: bool,
+    /// Room to join on startup. Nodes only see each other's messages in the
+    /// rooms they share.
+    #[structopt(long, env = "PEARDCHAT_TOPIC", default_value = "chat", global = true)]
+    topic: String,
+    /// Serve Prometheus metrics at `/metrics` on this address, such as
+    /// `127.0.0.1:9090`, as `metrics_addr` in the config.
+    #[structopt(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// Also keep every chat line sent and received in the SQLite database
+    /// at this path, created if need be, for `/search`.
+    #[structopt(long)]
+    db: Option<PathBuf>,
+    /// Read lines from this file as if typed, alongside stdin. Blank lines
+    /// and lines starting with `#` are skipped.
+    #[structopt(long)]
+    script: Option<PathBuf>,
+    /// How to print events: as lines for people, or as `json`, one object
+    /// per line on stdout, with everything else going to stderr. Lines for
+    /// people unless told otherwise.
+    // No default value, which would always conflict with `--tui`.
+    #[structopt(long, possible_values = &["human", "json"], conflicts_with = "tui")]
+    output: Option<String>,
+    /// Layout for chat lines, with the placeholders {time}, {nick},
+    /// {peerid}, {topic}, {body} and {id}, such as "[{time}] {nick}: {body}".
+    /// Write `{{` and `}}` for literal braces. An action's body starts
+    /// with `* `.
+    #[structopt(long)]
+    format: Option<Template>,
+    /// Check the config, environment, identity and the other options, print
+    /// what they come to and exit, with status 1 if anything is invalid.
+    /// Nothing is created and the network is left alone.
+    #[structopt(long)]
+    check_config: bool,
+    /// Pause between script lines, such as `500ms` or `2s`.
+    #[structopt(long, default_value = "0ms", parse(try_from_str = parse_delay))]
+    script_delay: Duration,
+    #[structopt(subcommand)]
+    mode: Option<Mode>,
+}
+
+/// Ways to run other than chatting.
+#[derive(Debug, StructOpt)]
+enum Mode {
+    /// Publish one message in the room given with `--topic`, wait for a
+    /// peer to confirm it arrived, and exit: with status 0 if one did, and
+    /// 1 if none did in time.
+    Send {
+        /// The message.
+        text: String,
+        /// How long to wait for a peer to join the room and confirm the
+        /// message, such as `30s`.
+        #[structopt(long, default_value = "30s", parse(try_from_str = parse_delay))]
+        timeout: Duration,
+    },
+}
+
+/// Parses a delay in whole milliseconds (`500ms`), seconds (`2s`) or
+/// minutes (`10m`).
+fn parse_delay(s: &str) -> Result<Duration, String> {
+    let not_a_delay = || format!("Not a delay, expected something like 500ms, 2s or 10m: {}", s);
+    let whole = |n: &str, unit: u64| n.parse::<u64>().ok().and_then(|n| n.checked_mul(unit));
+    let ms = match s.strip_suffix("ms") {
+        Some(ms) => whole(ms, 1),
+        None => match s.strip_suffix('s') {
+            Some(secs) => whole(secs, 1000),
+            None => s.strip_suffix('m').and_then(|mins| whole(mins, 60 * 1000)),
+        },
+    };
+    ms.map(Duration::from_millis).ok_or_else(not_a_delay)
+}
+
+/// `config` with what the flags in `opts` set overriding it.
+fn apply_opts(mut config: Config, opts: &Opts) -> Config {
+    config.relay_server |= opts.relay_server;
+    config.agent_version = opts.agent.clone().unwrap_or(config.agent_version);
+    config.listen_port = opts.port.unwrap_or(config.listen_port);
+    config.ws_listen_port = opts.ws_port.or(config.ws_listen_port);
+    config.bind = opts.bind.unwrap_or(config.bind);
+    if opts.ipv4_only {
+        config.ip_families = IpFamilies::Ipv4;
+    } else if opts.ipv6_only {
+        config.ip_families = IpFamilies::Ipv6;
+    }
+    config.allowlist = opts.allowlist.clone().or(config.allowlist);
+    config.mdns &= !opts.no_mdns;
+    config.auto_dial &= !opts.no_auto_dial;
+    config.echo |= opts.echo;
+    if let Some(window) = opts.reorder_window {
+        config.reorder_window_ms = window.as_millis() as u64;
+    } else if opts.no_reorder {
+        config.reorder_window_ms = 0;
+    }
+    config.min_version = opts.min_version.unwrap_or(config.min_version);
+    config.room_key = opts.room_key.clone().or(config.room_key);
+    config.forward_secrecy |= opts.forward_secrecy;
+    config.motd = opts.motd.clone().or(config.motd);
+    config.motd_peer = opts.motd_peer.or(config.motd_peer);
+    config.metrics_addr = opts.metrics_addr.or(config.metrics_addr);
+    config.muxer = opts.muxer.unwrap_or(config.muxer);
+    config.security = opts.security.unwrap_or(config.security);
+    config.pubsub = opts.pubsub.unwrap_or(config.pubsub);
+    config.dial_timeout_secs = opts.dial_timeout.unwrap_or(config.dial_timeout_secs);
+    config.idle_timeout_secs = opts.idle_timeout.unwrap_or(config.idle_timeout_secs);
+    config.ping_keep_alive |= opts.ping_keep_alive;
+    config.away_after_secs = opts.away_after.map_or(config.away_after_secs, |d| d.as_secs());
+    config
+}
+
+/// Why `topic` cannot be a room, if it cannot.
+fn check_topic(topic: &str) -> Result<(), String> {
+    if topic.trim().is_empty() {
+        Err("A room name cannot be blank".to_string())
+    } else if topic.chars().any(char::is_control) {
+        Err(format!("A room name cannot contain control characters: {:?}", topic))
+    } else if topic == DM_TOPIC {
+        Err(format!("{} is kept for direct messages", DM_TOPIC))
+    } else {
+        Ok(())
+    }
+}
+
+/// The identity startup would use, without creating one: `None` if there
+/// is none yet, and one would be.
+fn check_identity(path: &Path) -> io::Result<Option<PeerId>> {
+    if std::env::var_os(IDENTITY_VAR).is_none() && !path.exists() {
+        return Ok(None);
+    }
+    load_identity(path).map(|keys| Some(keys.public().into_peer_id()))
+}
+
+/// Reads and checks everything startup would, as `--check-config` asks,
+/// without creating an identity or touching the network, and prints what
+/// it finds. Returns whether all of it is valid.
+fn check_config(opts: &Opts) -> bool {
+    let mut problems = Vec::new();
+    let path = Path::new(config::CONFIG_FILE);
+    if path.exists() {
+        println!("Config: {}", path.display());
+    } else {
+        println!("Config: no {}, using the defaults", path.display());
+    }
+    let config = Config::load(path).and_then(|mut config| {
+        config.apply_env()?;
+        Ok(apply_opts(config, opts))
+    });
+    match &config {
+        Ok(config) => {
+            let listen = config.listen_addr().map(|addr| {
+                let mut addrs = vec![addr];
+                addrs.extend(config.ipv6_listen_addr());
+                addrs.extend(config.ws_listen_addr());
+                addrs
+            });
+            match listen {
+                Ok(addrs) => {
+                    let addrs: Vec<_> = addrs.iter().map(Multiaddr::to_string).collect();
+                    println!("Listen: {}", addrs.join(", "));
+                }
+                Err(e) => problems.push(format!("Cannot listen on {} port {}: {}", config.bind, config.listen_port, e)),
+            }
+            let seeds = config.bootstrap.iter().filter(|a| split_peer_id((*a).clone()).is_some()).count();
+            println!("Bootstrap: {} addresses, {} of them with a PeerId", config.bootstrap.len(), seeds);
+            for relay in config.relays.iter().filter(|a| split_peer_id((*a).clone()).is_none()) {
+                problems.push(format!("Relay {} does not end in /p2p/<peer id>", relay));
             }
+            if let Some(allowlist) = &config.allowlist {
+                match load_allowlist(allowlist) {
+                    Ok(peers) => println!("Allowlist: {} peers", peers.len()),
+                    Err(e) => problems.push(e.to_string()),
+                }
+            }
+            println!("Nick: {}", config.nick.as_deref().unwrap_or("none"));
         }
+        Err(e) => problems.push(e.to_string()),
+    }
+    match check_topic(&opts.topic) {
+        Ok(()) => println!("Room: {}", opts.topic),
+        Err(e) => problems.push(e),
+    }
+    for addr in opts.addrs.iter().chain(&opts.dial) {
+        if let Err(e) = addr.parse::<Multiaddr>() {
+            problems.push(format!("Not a multiaddr: {}: {}", addr, e));
+        }
+    }
+    for token in &opts.connect {
+        if let Err(e) = Invite::from_token(token) {
+            problems.push(e);
+        }
+    }
+    match check_identity(&default_identity_path()) {
+        Ok(Some(peer)) => println!("Identity: {}", peer),
+        Ok(None) => println!("Identity: none yet, one is created at {}", default_identity_path().display()),
+        Err(e) => problems.push(e.to_string()),
+    }
+    for problem in &problems {
+        eprintln!("Error: {}", problem);
     }
+    match problems.len() {
+        0 => println!("The configuration is valid"),
+        1 => println!("Found 1 problem"),
+        n => println!("Found {} problems", n),
+    }
+    problems.is_empty()
+}
+
+/// Sends logs to stderr, or appended to `file`, filtered by `RUST_LOG` if
+/// set and otherwise showing errors and peardchat's own warnings, or its
+/// debug output with `--verbose`. Colors are only used on a terminal.
+fn init_logging(verbose: bool, file: Option<&Path>) -> io::Result<()> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(_) => EnvFilter::from_default_env(),
+        Err(_) if verbose => EnvFilter::new("info,peardchat=debug"),
+        Err(_) => EnvFilter::new("error,peardchat=warn"),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match file {
+        Some(path) => {
+            if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+                fs::create_dir_all(dir)?;
+            }
+            let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+            subscriber.with_ansi(false).with_writer(Mutex::new(file)).init();
+        }
+        None => subscriber.with_ansi(atty::is(atty::Stream::Stderr)).with_writer(io::stderr).init(),
+    }
+    Ok(())
+}
+
+/// Where output lines go: stdout, or the TUI's message pane. With `json`
+/// set, stdout is kept for events and lines go to stderr, as errors always
+/// do outside the TUI. Chat lines are laid out by `format` when there is
+/// one.
+struct Output {
+    tui: Option<tui::Tui>,
+    json: bool,
+    format: Option<Template>,
+}
+
+impl Output {
+    fn line(&mut self, line: String) {
+        match &mut self.tui {
+            Some(tui) => tui.push(line),
+            None if self.json => eprintln!("{}", line),
+            None => println!("{}", line),
+        }
+    }
+
+    /// Like [`Output::line`], for something that went wrong.
+    fn error(&mut self, line: String) {
+        match &mut self.tui {
+            Some(tui) => tui.push(line),
+            None => eprintln!("{}", line),
+        }
+    }
+
+    /// Clears the TUI's message pane, or the terminal if `escapes` says it
+    /// can take ANSI escapes. Output to a pipe or in JSON is left alone.
+    fn clear(&mut self, escapes: bool) {
+        match &mut self.tui {
+            Some(tui) => tui.clear(),
+            None if escapes && !self.json => print!("\x1b[2J\x1b[H"),
+            None => {}
+        }
+    }
+
+    /// Prints `data` as a JSON event named `event`, like a serialized
+    /// [`ChatEvent`].
+    fn json_event<T: serde::Serialize>(&mut self, event: &str, data: T) {
+        #[derive(serde::Serialize)]
+        struct Event<'a, T> {
+            event: &'a str,
+            data: T,
+        }
+        println!("{}", serde_json::to_string(&Event { event, data }).expect("Events always serialize"));
+    }
+}
+
+/// `println!` into an [`Output`].
+macro_rules! out {
+    ($out:expr, $($arg:tt)*) => {
+        $out.line(format!($($arg)*))
+    };
+}
+
+/// `eprintln!` into an [`Output`].
+macro_rules! out_err {
+    ($out:expr, $($arg:tt)*) => {
+        $out.error(format!($($arg)*))
+    };
+}
+
+/// The next terminal event, or never if the TUI is off.
+async fn next_key(keys: &mut Option<EventStream>) -> Option<io::Result<Event>> {
+    match keys {
+        Some(keys) => keys.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Protobuf header of a libp2p `PrivateKey` message holding an ed25519 key:
+/// field 1 (`Type`) set to `Ed25519`, then field 2 (`Data`) with a 64 byte
+/// payload. This libp2p release only exposes protobuf encoding for public
+/// keys, so the header is written by hand; the result is readable by
+/// `Keypair::from_protobuf_encoding` in later releases.
+const ED25519_PROTOBUF_HEADER: [u8; 4] = [0x08, 0x01, 0x12, 0x40];
+
+/// Where the node identity lives unless told otherwise.
+fn default_identity_path() -> PathBuf {
+    config::data_dir().join("identity.key")
+}
+
+/// Variable holding the identity as base64, such as `base64 -w0
+/// ~/.peardchat/identity.key` prints, in place of the file.
+const IDENTITY_VAR: &str = "PEARDCHAT_IDENTITY";
+
+/// The ed25519 identity encoded in `bytes` as the identity file holds it.
+fn decode_identity(bytes: &[u8]) -> Option<identity::Keypair> {
+    let mut key = match bytes.strip_prefix(&ED25519_PROTOBUF_HEADER[..]) {
+        Some(key) if key.len() == 64 => key.to_vec(),
+        _ => return None,
+    };
+    identity::ed25519::Keypair::decode(&mut key).ok().map(identity::Keypair::Ed25519)
+}
+
+/// The identity in `PEARDCHAT_IDENTITY` if it is set, so that containers can
+/// get it as a secret, and otherwise the one at `path`, as
+/// [`load_or_create_identity`] finds it. A set but unreadable variable is an
+/// error rather than a reason to fall back on another identity.
+fn load_identity(path: &Path) -> io::Result<identity::Keypair> {
+    let encoded = match std::env::var(IDENTITY_VAR) {
+        Ok(encoded) => encoded,
+        Err(std::env::VarError::NotPresent) => return load_or_create_identity(path),
+        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", IDENTITY_VAR, e))),
+    };
+    base64::decode(encoded.trim()).ok().as_deref().and_then(decode_identity).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a base64 ed25519 identity", IDENTITY_VAR))
+    })
+}
+
+/// Loads the ed25519 identity stored at `path`, or generates a new one and
+/// writes it there with 0600 permissions so the `PeerId` survives restarts.
+fn load_or_create_identity(path: &Path) -> io::Result<identity::Keypair> {
+    match fs::read(path) {
+        Ok(bytes) => {
+            warn_if_too_open(path)?;
+            decode_identity(&bytes).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not an ed25519 identity", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = identity::ed25519::Keypair::generate();
+            let mut encoded = ED25519_PROTOBUF_HEADER.to_vec();
+            encoded.extend_from_slice(&keypair.encode());
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            write_private(path, &encoded)?;
+            eprintln!("Generated new identity at {}", path.display());
+            Ok(identity::Keypair::Ed25519(keypair))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::write(path, contents)
+}
+
+#[cfg(unix)]
+fn warn_if_too_open(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        eprintln!("Warning: {} is accessible by other users (mode {:o}), consider `chmod 600`",
+            path.display(), mode & 0o777);
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn warn_if_too_open(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// How long to wait for anyone to acknowledge a message we sent.
+const ACK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often we repeat that we are typing, while we are.
+const TYPING_REFRESH: Duration = Duration::from_secs(5);
+
+/// How long someone stays shown as typing without saying so again.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(12);
+
+/// How many lines `/search` shows.
+const SEARCH_RESULTS: usize = 20;
+
+/// How many chat lines of this session `/export` can write out.
+const TRANSCRIPT_LINES: usize = 10_000;
 
-    // Create a Swarm to manage peers and events.
-    let mut swarm = {
-        let mdns = Mdns::new(Default::default()).await?;
-        let mut behaviour = MyBehaviour {
-            floodsub: Floodsub::new(peer_id.clone()),
-            mdns,
+/// How long an `/invite` can be used for.
+const INVITE_VALIDITY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many hex digits of a message's id are shown, enough to tell apart
+/// the messages of a session.
+const ID_DIGITS: usize = 6;
+
+/// How much of a message a reply quotes, in characters.
+const QUOTE_CHARS: usize = 60;
+
+/// How many lines of each direct conversation are kept.
+const CONVERSATION_LINES: usize = 1_000;
+
+/// How often we tell the rooms we are still around.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A message of ours that is waiting for receipts.
+struct PendingAck {
+    deadline: Instant,
+    delivered: bool,
+}
+
+/// ANSI foreground colors peers are drawn in: cyan, green, yellow, blue,
+/// magenta and red.
+const PEER_COLORS: [u8; 6] = [36, 32, 33, 34, 35, 31];
+
+/// Paints names in a color of their peer's own, when stdout can show it.
+#[derive(Clone, Copy)]
+struct Colors {
+    enabled: bool,
+}
+
+impl Colors {
+    /// Colors are on for terminals unless `--no-color` or `NO_COLOR` say otherwise.
+    fn detect(no_color: bool) -> Self {
+        let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        Colors { enabled: !no_color && !no_color_env && atty::is(atty::Stream::Stdout) }
+    }
+
+    /// `name` in the color `peer` always gets. The color comes from an FNV-1a
+    /// hash of the PeerId, so it is the same on every run.
+    fn peer(self, peer: &PeerId, name: &str) -> String {
+        if !self.enabled {
+            return name.to_string();
+        }
+        let hash = peer.to_bytes().iter()
+            .fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ u64::from(*b)).wrapping_mul(0x100_0000_01b3));
+        let color = PEER_COLORS[(hash % PEER_COLORS.len() as u64) as usize];
+        format!("\x1b[{}m{}\x1b[0m", color, name)
+    }
+
+    /// `line` faint, for what arrives in rooms other than the current one.
+    fn dim(self, line: &str) -> String {
+        if !self.enabled {
+            return line.to_string();
+        }
+        format!("\x1b[2m{}\x1b[0m", line)
+    }
+}
+
+/// Formats a time the way every chat line is prefixed, e.g. `[14:32:05]`.
+fn timestamp(time: DateTime<Local>) -> String {
+    format!("[{}]", time_of_day(time))
+}
+
+/// A time as `{time}` in a `--format` shows it, e.g. `14:32:05`.
+fn time_of_day(time: DateTime<Local>) -> String {
+    time.format("%H:%M:%S").to_string()
+}
+
+/// Reaches out to the nodes given on the command line. One bad address
+/// does not keep us from trying the rest.
+fn dial_given(node: &mut ChatNode, opts: &Opts, out: &mut Output) {
+    for addr in opts.addrs.iter().chain(&opts.dial) {
+        match addr.parse::<Multiaddr>() {
+            Ok(addr) => dial(node, addr, out),
+            Err(e) => out!(out, "Not dialing {}: not a multiaddr: {}", addr, e),
+        }
+    }
+    for token in &opts.connect {
+        connect(node, token, out);
+    }
+}
+
+/// Publishes `message` on `topic` as soon as a peer there can get it, and
+/// waits for one to confirm it did. Returns whether one did within `wait`.
+async fn send_once(node: &mut ChatNode, topic: &str, message: ChatMessage, wait: Duration, out: &mut Output) -> bool {
+    match node.send_confirmed(topic, message, wait).await {
+        Ok((peer, nick)) => {
+            out!(out, "✓ delivered to {}", nick.unwrap_or_else(|| peer.to_string()));
+            true
+        }
+        Err(Unconfirmed::NoPeers) => {
+            out_err!(out, "Not sent: nobody joined [{}] within {}s", topic, wait.as_secs());
+            false
+        }
+        Err(Unconfirmed::NoAck) => {
+            out_err!(out, "✗ no acks after {}s", wait.as_secs());
+            false
+        }
+        Err(e) => {
+            out_err!(out, "{}", e);
+            false
+        }
+    }
+}
+
+/// Dials `addr`, saying whether that got started.
+fn dial(node: &mut ChatNode, addr: Multiaddr, out: &mut Output) {
+    match node.dial(addr.clone()) {
+        Ok(()) => out!(out, "Dialed {}", addr),
+        Err(e) => out_err!(out, "Could not dial {}: {}", addr, e),
+    }
+}
+
+/// What `/profile save` keeps: the `bootstrap` addresses, and every address
+/// each connected peer listens on, ending in its PeerId.
+fn profile_addrs(node: &mut ChatNode, bootstrap: &[Multiaddr]) -> Vec<Multiaddr> {
+    let mut addrs = bootstrap.to_vec();
+    for peer in node.peers() {
+        for addr in peer.routing_addrs {
+            let addr = match split_peer_id(addr.clone()) {
+                Some(_) => addr,
+                None => addr.with(Protocol::P2p(peer.peer_id.into())),
+            };
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+    }
+    addrs
+}
+
+/// Prints every command with a line about it, or the usage and details of
+/// the one called `name`.
+fn print_help(name: Option<&str>, out: &mut Output) {
+    match name.map(command::help) {
+        None => {
+            let width = COMMANDS.iter().map(|c| c.usage.len()).max().unwrap_or(0);
+            for command in COMMANDS {
+                out!(out, "{:width$}  {}", command.usage, command.summary, width = width);
+            }
+        }
+        Some(Some(command)) => out!(out, "Usage: {}\n{}. {}", command.usage, command.summary, command.details),
+        Some(None) => out!(out, "No such command, /help lists them all"),
+    }
+}
+
+/// Prints every connected peer with its addresses, whether mDNS still sees
+/// it and which of our topics it is known to be subscribed to.
+fn print_peers(node: &mut ChatNode, out: &mut Output) {
+    let peers = node.peers();
+    out!(out, "Connected peers: {}", peers.len());
+    for peer in peers {
+        let nick = peer.nick.map(|n| format!(" ({})", n)).unwrap_or_default();
+        let mdns = if peer.mdns_visible { "visible" } else { "not seen" };
+        let topics = if peer.topics.is_empty() { "none".to_string() } else { peer.topics.join(",") };
+        let agent = peer.agent_version.as_deref().unwrap_or("unknown client");
+        let ping = peer.rtt.map(|rtt| format!(", ping: {:.1?}", rtt)).unwrap_or_default();
+        // Older clients send no heartbeats, so they are never away.
+        let away = match peer.last_heartbeat {
+            Some(seen) if peer.away || seen.elapsed() > AWAY_AFTER => " away",
+            _ => "",
         };
+        out!(out, "  {}{} ({}){} mdns: {}, topics: {}{}", peer.peer_id, nick, agent, away, mdns, topics, ping);
+        for (addr, path) in peer.connected_addrs {
+            out!(out, "    connected via {} ({})", addr, path);
+        }
+        for addr in peer.discovered_addrs {
+            out!(out, "    discovered at {}", addr);
+        }
+    }
+}
+
+/// Tells every room in `rooms` that we, `nick`, are `away`, or back.
+async fn send_presence(node: &mut ChatNode, rooms: &[String], nick: &Option<String>, away: bool) {
+    for room in rooms {
+        // Fails while nobody else is in the room, which is fine.
+        if let Err(e) = node.send(room, ChatMessage::presence(nick.clone(), away)).await {
+            tracing::debug!("Could not tell [{}] we are away or back: {:?}", room, e);
+        }
+    }
+}
+
+/// Prints the peers discovery found that we are not connected to, numbered
+/// from 1 for `/dial`, and returns them in that order.
+fn print_discovered(node: &mut ChatNode, out: &mut Output) -> Vec<PeerId> {
+    let peers = node.discovered();
+    if peers.is_empty() {
+        out!(out, "No peers found that we are not connected to");
+        return Vec::new();
+    }
+    out!(out, "Discovered, not connected: {}", peers.len());
+    for (n, peer) in peers.iter().enumerate() {
+        let DiscoveredPeer { peer_id, nick, addrs, mdns_visible } = peer;
+        let nick = nick.as_ref().map(|n| format!(" ({})", n)).unwrap_or_default();
+        let source = if *mdns_visible { "mdns" } else { "kademlia" };
+        out!(out, "  {}. {}{} via {}", n + 1, peer_id, nick, source);
+        for addr in addrs {
+            out!(out, "    at {}", addr);
+        }
+    }
+    peers.into_iter().map(|p| p.peer_id).collect()
+}
+
+/// Dials `target`: a number from the last `/discovered`, given as
+/// `listed`, or a PeerId or nickname.
+fn dial_discovered(node: &mut ChatNode, target: &str, listed: &[PeerId], out: &mut Output) {
+    let peer = match target.parse::<usize>() {
+        Ok(n) => match n.checked_sub(1).and_then(|i| listed.get(i)) {
+            Some(peer) => *peer,
+            None => {
+                out_err!(out, "No peer {} in the last /discovered", n);
+                return;
+            }
+        },
+        Err(_) => match find_peer(node, target, out) {
+            Some(peer) => peer,
+            None => return,
+        },
+    };
+    if node.is_blocked(&peer) {
+        out_err!(out, "Cannot dial {} while it is blocked", peer);
+        return;
+    }
+    match node.dial_peer(&peer) {
+        Ok(()) => out!(out, "Dialing {}", peer),
+        Err(e) => out_err!(out, "Could not dial {}: {}", peer, e),
+    }
+}
+
+/// How a roster entry is shown: a dot for its presence, then its nickname,
+/// told apart from others going by it, or its PeerId.
+fn roster_line(node: &ChatNode, entry: &RosterEntry) -> String {
+    let dot = match entry.presence {
+        Presence::Online => "●",
+        Presence::Away => "◐",
+        Presence::Offline => "○",
+    };
+    match &entry.nick {
+        Some(nick) => format!("{} {}", dot, node.display_name(&entry.peer_id, nick)),
+        None => format!("{} {}", dot, entry.peer_id),
+    }
+}
+
+/// Prints everyone we have been connected to, online, away and offline.
+fn print_roster(node: &ChatNode, out: &mut Output) {
+    let roster = node.roster();
+    if roster.is_empty() {
+        out!(out, "Nobody yet, peers are listed once we connect to them");
+        return;
+    }
+    for (presence, name) in [(Presence::Online, "Online"), (Presence::Away, "Away"), (Presence::Offline, "Offline")] {
+        let group: Vec<_> = roster.iter().filter(|e| e.presence == presence).collect();
+        if group.is_empty() {
+            continue;
+        }
+        out!(out, "{} ({}):", name, group.len());
+        for entry in group {
+            out!(out, "  {}", roster_line(node, entry));
+        }
+    }
+}
+
+/// The peer with the PeerId or nickname `name`. If there is no such peer,
+/// or several go by the name, says so and returns `None`.
+fn find_peer(node: &ChatNode, name: &str, out: &mut Output) -> Option<PeerId> {
+    if let Ok(peer) = name.parse() {
+        return Some(peer);
+    }
+    match node.peers_named(name).as_slice() {
+        [peer] => Some(*peer),
+        [] => {
+            out!(out, "No peer is called {}", name);
+            None
+        }
+        peers => {
+            out!(out, "{} peers are called {}:", peers.len(), name);
+            for peer in peers {
+                out!(out, "  {}", peer);
+            }
+            None
+        }
+    }
+}
+
+/// Prints what we know about the peer with the PeerId or nickname `name`.
+fn print_whois(node: &mut ChatNode, name: &str, out: &mut Output) {
+    let peer = match find_peer(node, name, out) {
+        Some(peer) => peer,
+        None => return,
+    };
+    let info = node.peer_info(&peer);
+    out!(out, "{}", peer);
+    out!(out, "  nickname: {}", info.nick.as_deref().unwrap_or("none"));
+    out!(out, "  client: {}", info.agent_version.as_deref().unwrap_or("unknown"));
+    if node.is_blocked(&peer) {
+        out!(out, "  blocked");
+    }
+    if info.connected_addrs.is_empty() {
+        out!(out, "  not connected");
+    }
+    for (addr, path) in &info.connected_addrs {
+        out!(out, "  connected via {} ({})", addr, path);
+    }
+    match info.rtt {
+        Some(rtt) => out!(out, "  ping: {:.1?}", rtt),
+        None => out!(out, "  ping: not measured"),
+    }
+    match info.last_heartbeat.map(|seen| seen.elapsed()) {
+        Some(ago) if info.away || ago > AWAY_AFTER => out!(out, "  away, last heard from {}s ago", ago.as_secs()),
+        Some(ago) => out!(out, "  last heard from {}s ago", ago.as_secs()),
+        None => out!(out, "  no heartbeats heard"),
+    }
+    let mdns = if info.mdns_visible { "visible" } else { "not seen" };
+    out!(out, "  mdns: {}", mdns);
+    for addr in &info.discovered_addrs {
+        out!(out, "    discovered at {}", addr);
+    }
+    for addr in &info.routing_addrs {
+        out!(out, "  known at {}", addr);
+    }
+    match (info.pubsub_visible, info.topics.is_empty()) {
+        (false, _) => out!(out, "  pubsub: not a peer"),
+        (true, true) => out!(out, "  pubsub: a peer, on none of our topics"),
+        (true, false) => out!(out, "  pubsub: a peer, on {}", info.topics.join(",")),
+    }
+}
+
+/// `bytes` in the largest unit that keeps it at 1 or more, such as `3.2 MiB`.
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn print_stats(stats: &Stats, out: &mut Output) {
+    let secs = stats.uptime.as_secs();
+    out!(out, "Up for {}h {:02}m {:02}s, connected to {} peers", secs / 3600, secs / 60 % 60, secs % 60, stats.peers);
+    out!(out, "  received: {}, {} messages", human_bytes(stats.bytes_received), stats.messages_received);
+    out!(out, "  sent: {}, {} messages", human_bytes(stats.bytes_sent), stats.messages_sent);
+}
+
+/// Prints how busy each of our rooms has been, and which are in slow mode.
+fn print_topic_stats(node: &ChatNode, out: &mut Output) {
+    let stats = node.topic_stats();
+    if stats.is_empty() {
+        out!(out, "Not in any room");
+    }
+    for TopicStats { topic, per_minute, total, senders, last_activity } in stats {
+        let slowmode = node.slowmode(&topic).map(|(interval, _)| format!(", slow mode {}s", interval.as_secs()));
+        let slowmode = slowmode.unwrap_or_default();
+        match last_activity {
+            Some(last) => out!(
+                out,
+                "[{}] {}/min, {} messages from {} peers, latest at {}{}",
+                topic, per_minute, total, senders, time_of_day(last), slowmode,
+            ),
+            None => out!(out, "[{}] no messages yet{}", topic, slowmode),
+        }
+    }
+}
+
+/// Prints our addresses, ready to paste into someone's `dial`, with those
+/// that only work on our own network apart from the rest.
+fn print_addrs(node: &ChatNode, out: &mut Output) {
+    let (lan, public) = reachable_addrs(node);
+    out!(out, "On this network:");
+    for addr in &lan {
+        out!(out, "  {}", addr);
+    }
+    if public.is_empty() {
+        out!(out, "Not known to be reachable from elsewhere.");
+    } else {
+        out!(out, "From elsewhere:");
+        for addr in &public {
+            out!(out, "  {}", addr);
+        }
+    }
+}
+
+/// The addresses we can be reached on, split into those only reachable on
+/// this network and the others, such as those peers observed us at.
+fn reachable_addrs(node: &ChatNode) -> (Vec<Multiaddr>, Vec<Multiaddr>) {
+    let (lan, mut public): (Vec<_>, Vec<_>) = node.listen_addrs().into_iter().partition(is_lan);
+    for addr in node.external_addrs() {
+        if !public.contains(&addr) {
+            public.push(addr);
+        }
+    }
+    (lan, public)
+}
 
-        behaviour.floodsub.subscribe(floodsub_topic.clone());
+/// Dials every address in the invite `token`.
+fn connect(node: &mut ChatNode, token: &str, out: &mut Output) {
+    match Invite::from_token(token) {
+        Ok(invite) if invite.addrs.is_empty() => out!(out, "The invite from {} has no addresses", invite.peer),
+        Ok(invite) => for addr in invite.dial_addrs() {
+            dial(node, addr, out);
+        }
+        Err(e) => out!(out, "{}", e),
+    }
+}
 
-        SwarmBuilder::new(transport, behaviour, peer_id)
-            // We want the connection background tasks to be spawned
-            // onto the tokio runtime.
-            .executor(Box::new(|fut| { tokio::spawn(fut); }))
-            .build()
+/// Whether `addr` is on this machine only.
+fn is_loopback(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_loopback(),
+        Some(Protocol::Ip6(ip)) => ip.is_loopback(),
+        _ => false,
+    }
+}
+
+/// Whether `addr` is a loopback, private or link-local IP address.
+fn is_lan(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        // Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+        Some(Protocol::Ip6(ip)) => {
+            ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+        _ => false,
+    }
+}
+
+/// Indents every line of a message body after the first, setting them apart
+/// from the lines around them.
+fn indent(body: &str) -> String {
+    body.replace('\n', "\n    ")
+}
+
+/// `author: body`, or `* author body` for an action.
+fn said(author: &str, body: &str, action: bool) -> String {
+    if action {
+        format!("* {} {}", author, indent(body))
+    } else {
+        format!("{}: {}", author, indent(body))
+    }
+}
+
+/// The body of `message` as `{body}` in a `--format` shows it.
+fn shown_body(message: &ChatMessage) -> String {
+    match message.kind {
+        MessageKind::Action => format!("* {}", indent(&message.body)),
+        _ => indent(&message.body),
+    }
+}
+
+/// A message id as shown and as `/reply` takes it: its first few hex
+/// digits, e.g. `3fa2c1`.
+fn short_id(id: u64) -> String {
+    format!("{:016x}", id)[..ID_DIGITS].to_string()
+}
+
+/// The [`short_id`] of `message`, or nothing for a message without an id.
+fn shown_id(message: &ChatMessage) -> String {
+    message.id.map_or_else(String::new, short_id)
+}
+
+/// ` #3fa2c1` to follow a chat line, so it can be replied to.
+fn id_tag(message: &ChatMessage) -> String {
+    match shown_id(message) {
+        id if id.is_empty() => id,
+        id => format!(" #{}", id),
+    }
+}
+
+/// For a reply, a line quoting the start of the message it answers, or
+/// just its id if that is not in `transcript`.
+fn quote(message: &ChatMessage, transcript: &Transcript) -> Option<String> {
+    let to = message.reply_to?;
+    let original = transcript.lines().rev().find(|shown| shown.entry.id == Some(to));
+    Some(match original {
+        Some(shown) => format!("  ↳ re {}: {}", shown.entry.sender, snippet(&shown.entry.body)),
+        None => format!("  ↳ re #{}", short_id(to)),
+    })
+}
+
+/// The start of `body`, as much as a quote shows.
+fn snippet(body: &str) -> String {
+    let first = body.lines().next().unwrap_or_default();
+    let snippet: String = first.chars().take(QUOTE_CHARS).collect();
+    let more = if snippet.len() < body.len() { "…" } else { "" };
+    format!("{}{}", snippet, more)
+}
+
+/// What a reaction from `who` with `emoji` is shown as: the start of the
+/// line reacted to, followed by its tally.
+fn reacted(who: &str, emoji: &str, shown: &Shown) -> String {
+    let line = said(&shown.entry.sender, &snippet(&shown.entry.body), shown.entry.action);
+    format!("[{}] {} reacted {} to {}{}", shown.topic, who, emoji, line, shown.tally())
+}
+
+/// The tally of reactions to `message` that came before it did.
+fn early_tally(message: &ChatMessage, transcript: &Transcript) -> String {
+    message.id.and_then(|id| transcript.find(id)).map(Shown::tally).unwrap_or_default()
+}
+
+/// The message `/reply` means by `id`, which may be given with or without
+/// its `#` and cut short as shown: its full id, and the room it was in if
+/// it is in `transcript`. Fails with what to tell the user.
+fn find_message(transcript: &Transcript, id: &str) -> Result<(u64, Option<String>), String> {
+    let prefix = id.trim_start_matches('#').to_ascii_lowercase();
+    if prefix.is_empty() || prefix.len() > 16 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Not a message id: {}", id));
+    }
+    let mut found: Vec<_> = transcript
+        .lines()
+        .filter_map(|shown| Some((shown.entry.id?, &shown.topic)))
+        .filter(|(id, _)| format!("{:016x}", id).starts_with(&prefix))
+        .collect();
+    found.dedup_by_key(|(id, _)| *id);
+    match found.as_slice() {
+        [(id, topic)] => Ok((*id, Some(topic.to_string()))),
+        [] if prefix.len() == 16 => Ok((u64::from_str_radix(&prefix, 16).expect("Checked to be hex"), None)),
+        [] => Err(format!("No message #{} this session", prefix)),
+        _ => Err(format!("#{} could be several messages, give more of its id", prefix)),
+    }
+}
+
+/// Peers without a nickname are shown by their PeerId instead, and those
+/// whose nickname is taken by someone else by both.
+fn author(node: &ChatNode, message: &ChatMessage, source: Option<PeerId>) -> String {
+    match (&message.nick, source) {
+        (Some(nick), Some(source)) => node.display_name(&source, nick),
+        (Some(nick), None) => nick.clone(),
+        (None, Some(source)) => source.to_string(),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+/// How `node` names `peer`: by nickname if it has one, by PeerId if not.
+fn peer_name(node: &ChatNode, peer: &PeerId) -> String {
+    node.nick(peer).map_or_else(|| peer.to_string(), |nick| node.display_name(peer, nick))
+}
+
+/// Prints a line of a direct conversation.
+fn print_said(said: &Said, colors: Colors, out: &mut Output) {
+    let time = said.entry.time().map(timestamp).unwrap_or_default();
+    out!(out, "{} [dm] {}: {}", time, colors.peer(&said.author, &said.entry.sender), indent(&said.entry.body));
+}
+
+/// Sends `message` to `peer` privately, showing it, marked if it is
+/// forward-secret, and keeping it in our conversation with them.
+async fn send_direct(
+    node: &mut ChatNode,
+    inbox: &mut Inbox,
+    peer: PeerId,
+    message: ChatMessage,
+    colors: Colors,
+    out: &mut Output,
+) {
+    let me = *node.local_peer_id();
+    let sender = message.nick.clone().unwrap_or_else(|| "me".to_string());
+    let body = indent(&message.body);
+    inbox.push(peer, me, &sender, &message, true);
+    let sent = node.send_dm(&peer, message).await;
+    let scheme = if matches!(sent, Ok(true)) { ", forward-secret" } else { "" };
+    out!(out, "{} [dm to {}{}] {}: {}", timestamp(Local::now()),
+        colors.peer(&peer, &peer_name(node, &peer)), scheme, colors.peer(&me, &sender), body);
+    if let Err(e) = sent {
+        out_err!(out, "Cannot message {}: {}", peer, e);
+    }
+}
+
+/// How the rooms we are in are shown.
+struct View<'a> {
+    /// The room we talk in. Chat from the others is dimmed.
+    current: Option<&'a str>,
+    /// Rooms whose chat is not shown at all.
+    muted: &'a HashSet<String>,
+}
+
+/// Prints `event` as `view` says, naming peers as `node` tells them apart,
+/// and keeps chat in `transcript`, and `db` if there is one. `latest`
+/// holds the highest Lamport clock shown so far in each room, to point out
+/// messages that arrive behind later ones.
+#[allow(clippy::too_many_arguments)]
+fn print_event(
+    event: ChatEvent,
+    node: &ChatNode,
+    view: View,
+    colors: Colors,
+    history: &History,
+    db: Option<&Database>,
+    transcript: &mut Transcript,
+    latest: &mut HashMap<String, u64>,
+    out: &mut Output,
+) {
+    if let ChatEvent::MessageReceived { topic, source, message, .. } = &event {
+        let sender = author(node, message, *source);
+        if let Err(e) = history.append(topic, &sender, message) {
+            tracing::warn!("Could not write history for [{}]: {}", topic, e);
+        }
+        if let Some(Err(e)) = db.map(|db| db.insert(topic, source.as_ref(), message)) {
+            tracing::warn!("Could not store a message from [{}]: {}", topic, e);
+        }
+        transcript.push(topic, &sender, message);
+    }
+    if let ChatEvent::History { topic, source, message, .. } = &event {
+        transcript.push(topic, &author(node, message, *source), message);
+    }
+    // Only a reaction that changes a tally we have is worth showing.
+    let mut tallied = None;
+    if let ChatEvent::Reaction { topic, peer, nick, to, emoji } = &event {
+        let who = nick.as_ref().map_or_else(|| peer.to_string(), |nick| node.display_name(peer, nick));
+        tallied = transcript.react(topic, *to, emoji, &who).map(|shown| (who, shown.clone()));
+    }
+    if out.json {
+        println!("{}", serde_json::to_string(&event).expect("Events always serialize"));
+        return;
+    }
+    // Muted rooms are still logged, just not shown.
+    if let ChatEvent::MessageReceived { topic, .. } | ChatEvent::History { topic, .. } | ChatEvent::Reaction { topic, .. } =
+        &event
+    {
+        if view.muted.contains(topic) {
+            return;
+        }
+    }
+    match event {
+        ChatEvent::MessageReceived { topic, source, authenticated, message } => {
+            let received = timestamp(Local::now());
+            // Clocks differ between peers, so show the author's send time
+            // alongside ours whenever the two disagree.
+            let sent = match message.sent_at().map(timestamp) {
+                Some(sent) if sent != received => format!(" (sent {})", sent),
+                _ => String::new(),
+            };
+            let background = view.current != Some(topic.as_str());
+            let peerid = source.map_or_else(|| "unknown".to_string(), |s| s.to_string());
+            let author = author(node, &message, source);
+            // A peer's color would end the dimming partway through the line.
+            let author = match source {
+                Some(source) if !background => colors.peer(&source, &author),
+                _ => author,
+            };
+            // Who an unsigned message claims to be from proves nothing.
+            let flag = if authenticated { "" } else { " (unauthenticated)" };
+            let newest = latest.entry(topic.clone()).or_default();
+            let late = match message.clock {
+                Some(clock) if clock < *newest => " (out of order)",
+                _ => "",
+            };
+            *newest = (*newest).max(message.clock.unwrap_or(0));
+            let author = format!("{}{}", author, flag);
+            let line = match &out.format {
+                Some(format) => {
+                    let (time, body, id) = (time_of_day(Local::now()), shown_body(&message), shown_id(&message));
+                    let fields = Fields { time: &time, nick: &author, peerid: &peerid, topic: &topic, body: &body, id: &id };
+                    format!("{}{}{}", format.render(&fields), sent, late)
+                }
+                // A peer may be in several rooms at once, so say which one this arrived on.
+                None => {
+                    let line = said(&author, &message.body, message.kind == MessageKind::Action);
+                    let tally = early_tally(&message, transcript);
+                    format!("{} [{}] {}{}{}{}{}", received, topic, line, tally, id_tag(&message), sent, late)
+                }
+            };
+            for line in quote(&message, transcript).into_iter().chain(Some(line)) {
+                if background {
+                    out!(out, "{}", colors.dim(&line));
+                } else {
+                    out!(out, "{}", line);
+                }
+            }
+        }
+        ChatEvent::History { topic, source, authenticated, message } => {
+            // Replayed long after it was sent, so only the send time is of interest.
+            let sent = message.sent_at().unwrap_or_else(Local::now);
+            let peerid = source.map_or_else(|| "unknown".to_string(), |s| s.to_string());
+            let author = author(node, &message, source);
+            let author = match source {
+                Some(source) => colors.peer(&source, &author),
+                None => author,
+            };
+            let flag = if authenticated { "" } else { " (unauthenticated)" };
+            let author = format!("{}{}", author, flag);
+            if let Some(quote) = quote(&message, transcript) {
+                out!(out, "{}", quote);
+            }
+            match &out.format {
+                Some(format) => {
+                    let (time, body, id) = (time_of_day(sent), shown_body(&message), shown_id(&message));
+                    let fields = Fields { time: &time, nick: &author, peerid: &peerid, topic: &topic, body: &body, id: &id };
+                    out!(out, "[history] {}", format.render(&fields));
+                }
+                None => {
+                    let line = said(&author, &message.body, message.kind == MessageKind::Action);
+                    let tally = early_tally(&message, transcript);
+                    out!(out, "{} [history] [{}] {}{}{}", timestamp(sent), topic, line, tally, id_tag(&message));
+                }
+            }
+        }
+        ChatEvent::DirectMessage { source, message, forward_secret } => {
+            let author = author(node, &message, Some(source));
+            let scheme = if forward_secret { ", forward-secret" } else { "" };
+            out!(out, "{} [dm{}] {}: {}", timestamp(Local::now()), scheme, colors.peer(&source, &author), indent(&message.body));
+        }
+        ChatEvent::PeerJoined { peer, nick } => {
+            let name = nick.map_or_else(|| peer.to_string(), |nick| node.display_name(&peer, &nick));
+            out!(out, "*** {} joined", colors.peer(&peer, &name))
+        }
+        ChatEvent::PeerLeft { peer, nick } => {
+            let name = nick.map_or_else(|| peer.to_string(), |nick| node.display_name(&peer, &nick));
+            out!(out, "*** {} left", colors.peer(&peer, &name))
+        }
+        ChatEvent::SlowMode { topic, peer, nick, interval_secs } => {
+            let name = nick.map_or_else(|| peer.to_string(), |nick| node.display_name(&peer, &nick));
+            match interval_secs {
+                0 => out!(out, "*** {} turned slow mode off in [{}]", colors.peer(&peer, &name), topic),
+                secs => out!(out, "*** {} put [{}] in slow mode: one message every {}s each", colors.peer(&peer, &name), topic, secs),
+            }
+        }
+        ChatEvent::Motd { text, .. } => out!(out, "[motd] {}", text),
+        ChatEvent::Reaction { peer, emoji, .. } => {
+            if let Some((who, shown)) = tallied {
+                out!(out, "{} {}", timestamp(Local::now()), reacted(&colors.peer(&peer, &who), &emoji, &shown));
+            }
+        }
+        ChatEvent::SlowModeDropped { topic, peer, nick, interval_secs } => {
+            let name = nick.map_or_else(|| peer.to_string(), |nick| node.display_name(&peer, &nick));
+            out!(out, "*** Dropped a message from {} in [{}]: slow mode allows one every {}s", name, topic, interval_secs)
+        }
+        ChatEvent::NickChanged { peer, old, new } => {
+            let old = old.unwrap_or_else(|| peer.to_string());
+            out!(out, "*** {} is now known as {}", old, node.display_name(&peer, &new))
+        }
+        ChatEvent::PresenceChanged { peer, nick, away } => {
+            let name = nick.map_or_else(|| peer.to_string(), |nick| node.display_name(&peer, &nick));
+            out!(out, "*** {} is {}", colors.peer(&peer, &name), if away { "away" } else { "back" })
+        }
+        ChatEvent::NickCollision { nick, peer, others } => {
+            let others: Vec<_> = others.iter().map(PeerId::to_string).collect();
+            out!(
+                out,
+                "*** {} goes by {} just like {}, so is shown as {}",
+                peer, nick, others.join(", "), node.display_name(&peer, &nick),
+            )
+        }
+        ChatEvent::OutdatedPeer { peer, version, min_version, agent_version } => {
+            let agent = agent_version.map_or_else(String::new, |agent| format!(" ({})", agent));
+            out!(
+                out,
+                "*** {}{} sends version {} messages, older than the {} we accept, so they are dropped",
+                peer, agent, version, min_version,
+            )
+        }
+        ChatEvent::PeerIdentified { peer, agent_version, .. } => out!(out, "{} is running {}", peer, agent_version),
+        ChatEvent::ListeningOn(addr) => out!(out, "Listening on {:?}", addr),
+        ChatEvent::DialFailed { address, error } => out_err!(out, "Could not connect to {}: {}", address, error),
+        ChatEvent::IncomingFailed { address, error } => {
+            out!(out, "Connection from {} failed: {}", address, error)
+        }
+        ChatEvent::NetworkUnavailable { error } => {
+            out!(out, "No network available; running in offline mode ({}). Will keep trying.", error)
+        }
+        ChatEvent::NetworkRestored => out!(out, "Network is back"),
+        ChatEvent::ListenerFailed { addresses, error } if addresses.is_empty() => {
+            out!(out, "Listener error: {}", error)
+        }
+        ChatEvent::ListenerFailed { addresses, error } => {
+            let addresses: Vec<_> = addresses.iter().map(Multiaddr::to_string).collect();
+            out!(out, "Stopped listening on {}: {}", addresses.join(", "), error)
+        }
+        // Receipts only mean something next to what we sent, so the main loop handles them.
+        // Only the TUI has room to show who is typing, above its input line.
+        ChatEvent::PeerDiscovered(_) | ChatEvent::PeerExpired(_) | ChatEvent::Delivered { .. } | ChatEvent::Typing { .. } => {}
+        ChatEvent::Transfer(event) => match event {
+            TransferEvent::Offered { peer, offer, name, size } => out!(out,
+                "{} wants to send you {} ({} bytes), /accept {} or /reject {}",
+                peer, name, size, offer, offer
+            ),
+            TransferEvent::TooLarge { peer, name, size } => {
+                out!(out, "Refused {} from {}: {} bytes is over the limit", name, peer, size)
+            }
+            TransferEvent::Receiving { peer, name, size } => {
+                out!(out, "Receiving {} ({} bytes) from {}", name, size, peer)
+            }
+            TransferEvent::Progress { name, percent } => out!(out, "{}: {}%", name, percent),
+            TransferEvent::Saved { name, path, .. } => out!(out, "Saved {} to {}", name, path.display()),
+            TransferEvent::Sent { peer, name } => out!(out, "Sent {} to {}", name, peer),
+            TransferEvent::Declined { peer, name, reason } => out!(out, "{} refused {}: {}", peer, name, reason),
+            TransferEvent::Failed { peer, name, error } => out!(out, "Transfer of {} with {} failed: {}", name, peer, error),
+        },
+    }
+}
+
+/// The `tokio::main` attribute sets up a tokio runtime.
+#[tokio::main]
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let opts = Opts::from_args();
+    if opts.check_config {
+        return Ok(if check_config(&opts) { ExitCode::SUCCESS } else { ExitCode::FAILURE });
+    }
+    // The TUI owns the terminal, so its logs go to a file unless asked not to.
+    let log_file = match (&opts.log_file, opts.tui && !opts.log_stderr) {
+        (Some(path), _) => Some(path.clone()),
+        (None, true) => Some(config::data_dir().join("peardchat.log")),
+        (None, false) => None,
     };
+    init_logging(opts.verbose, log_file.as_deref())?;
+
+    let mut config = Config::load(Path::new(config::CONFIG_FILE))?;
+    config.apply_env()?;
+    let config = apply_opts(config, &opts);
+
+    // Load our PeerId from the environment or disk, creating one on first run
+    let id_keys = load_identity(&default_identity_path())?;
 
-    // Reach out to another node if specified
-    if let Some(to_dial) = std::env::args().nth(1) {
-        let addr: Multiaddr = to_dial.parse()?;
-        swarm.dial_addr(addr)?;
-        println!("Dialed {:?}", to_dial)
+    // The TUI draws plain text, so it goes without colors.
+    let colors = Colors::detect(opts.no_color || opts.tui);
+    let history = History::new(config.history_dir.clone(), config.history_max_bytes);
+    let db = match &opts.db {
+        Some(path) => Some(Database::open(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))?),
+        None => None,
+    };
+    let mut transcript = Transcript::new(TRANSCRIPT_LINES);
+    let mut inbox = Inbox::new(CONVERSATION_LINES);
+    let profiles = Profiles::new(config.profiles_dir.clone());
+    let mut node = ChatNode::new(id_keys, &config).await?;
+    let local_peer = *node.local_peer_id();
+    let mut out = Output { tui: None, json: opts.output.as_deref() == Some("json"), format: opts.format.clone() };
+    if out.json {
+        out.json_event("local_peer_id", local_peer.to_string());
+    } else {
+        out!(out, "Local peer id: {:?}", local_peer);
+    }
+    node.join(&opts.topic).map_err(|e| format!("Could not join [{}]: {:?}", opts.topic, e))?;
+    if let Some(Mode::Send { text, timeout }) = &opts.mode {
+        let delivered = if text.len() > config.max_message_size {
+            out_err!(out, "Not sent: message is {} bytes, the limit is {}", text.len(), config.max_message_size);
+            false
+        } else {
+            dial_given(&mut node, &opts, &mut out);
+            let message = ChatMessage::new(config.nick.clone(), text.as_str());
+            send_once(&mut node, &opts.topic, message, *timeout, &mut out).await
+        };
+        node.shutdown(ChatMessage::new(config.nick, "has left")).await;
+        return Ok(if delivered { ExitCode::SUCCESS } else { ExitCode::FAILURE });
     }
+    out.tui = if opts.tui { Some(tui::Tui::start()?) } else { None };
+    let mut keys = out.tui.as_ref().map(|_| EventStream::new());
+    dial_given(&mut node, &opts, &mut out);
 
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
+    // Read full lines from stdin. This happens on a plain thread rather than
+    // through `tokio::io::stdin()`, whose blocking read would otherwise keep the
+    // runtime from shutting down until the user hits enter. Once stdin is
+    // closed, or with `--listen-only`, the node carries on without it. The
+    // TUI reads keys itself and hands in lines through `typed`. A script's
+    // lines come in the same way, so the input runs out once both are done.
+    let (stdin_tx, mut stdin) = mpsc::unbounded_channel();
+    // Scrapes of our metrics wait on the loop below for the numbers.
+    let (scrapes, mut scraped) = mpsc::channel(8);
+    if let Some(addr) = config.metrics_addr {
+        let listener = metrics::bind(addr).await
+            .map_err(|e| format!("Cannot serve metrics on {}: {}", addr, e))?;
+        tokio::spawn(metrics::serve(listener, scrapes));
+    }
+    let mut stdin_open = !opts.listen_only || opts.script.is_some();
+    if let Some(path) = &opts.script {
+        let script = fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read script {}: {}", path.display(), e))?;
+        let (script_tx, delay) = (stdin_tx.clone(), opts.script_delay);
+        tokio::spawn(async move {
+            let lines = script.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#'));
+            for (i, line) in lines.enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(delay).await;
+                }
+                if script_tx.send(Ok(line.to_string())).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    let mut typed = None;
+    if opts.tui {
+        typed = Some(stdin_tx);
+    } else if !opts.listen_only {
+        std::thread::spawn(move || {
+            use std::io::BufRead;
+            for line in io::stdin().lock().lines() {
+                if stdin_tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
 
-    // Listen on all interfaces and whatever port the OS assigns
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    // Rooms we are subscribed to, most recently joined last. Publishes go to
+    // `current_topic`, which is `None` once we have left every room.
+    let mut rooms = vec![opts.topic.clone()];
+    let mut current_topic = Some(opts.topic.clone());
+    // The peer we are talking with privately, if any, since `/dm <peer>`.
+    // While there is one, what we type goes to them instead.
+    let mut talking_to: Option<PeerId> = None;
+    // The peers the last `/discovered` listed, which `/dial` numbers.
+    let mut discovered: Vec<PeerId> = Vec::new();
+    // Rooms we stay in without being shown their chat, since `/mute`.
+    let mut muted: HashSet<String> = HashSet::new();
+    // Incoming chat held back for a moment to be shown in order, unless
+    // turned off.
+    let window = config.reorder_window();
+    let mut reorder = Some(ReorderBuffer::new(window)).filter(|_| !window.is_zero());
+    let away_after = config.away_after();
+    let mut nick = config.nick;
+    // Ids of our recent messages, until `ACK_TIMEOUT` after each was sent.
+    let mut pending: HashMap<u64, PendingAck> = HashMap::new();
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    // The lines so far of a message continued with trailing backslashes.
+    let mut continued: Option<String> = None;
+    // The highest Lamport clock shown in each room, ours included.
+    let mut latest: HashMap<String, u64> = HashMap::new();
+    // Who is typing in which room, by the name to show and when they last
+    // said so.
+    let mut typing: HashMap<(String, PeerId), (String, Instant)> = HashMap::new();
+    // When we last told the current room we are typing, while we are.
+    let mut typing_sent: Option<Instant> = None;
+    // When we last typed anything, and whether our rooms have been told we
+    // are away since.
+    let mut last_typed = Instant::now();
+    let mut away = false;
 
     // Kick it off
-    let mut listening = false;
     loop {
+        if let Some(tui) = &mut out.tui {
+            let peers: Vec<_> = node.roster().iter().map(|entry| roster_line(&node, entry)).collect();
+            typing.retain(|_, (_, at)| at.elapsed() < TYPING_TIMEOUT);
+            let mut typists: Vec<_> = typing.iter()
+                .filter(|((topic, _), _)| current_topic.as_ref() == Some(topic))
+                .map(|(_, (name, _))| name.clone())
+                .collect();
+            typists.sort();
+            tui.draw(current_topic.as_deref(), &peers, &typists)?;
+        }
+        let next_typing_expiry = typing.values().map(|(_, at)| *at + TYPING_TIMEOUT).min();
+        let next_deadline = pending.values().map(|p| p.deadline).min();
+        let next_reorder = reorder.as_ref().and_then(ReorderBuffer::next_deadline);
+        // Nothing will be typed without input, so there is no being away.
+        let next_away = away_after.filter(|_| stdin_open && !away).map(|after| last_typed + after);
         let to_publish = {
             tokio::select! {
-                line = stdin.next_line() => {
-                    let line = line?.expect("stdin closed");
-                    Some((floodsub_topic.clone(), line))
+                line = stdin.recv(), if stdin_open => {
+                    let line = match line {
+                        Some(line) => line?,
+                        None => {
+                            out!(out, "Input closed, still relaying messages. Press Ctrl-C to quit");
+                            stdin_open = false;
+                            continue;
+                        }
+                    };
+                    last_typed = Instant::now();
+                    if std::mem::take(&mut away) {
+                        send_presence(&mut node, &rooms, &nick, false).await;
+                    }
+                    // A trailing backslash continues the message on the next
+                    // line, though a lone one is just a backslash.
+                    let line = match (line.strip_suffix('\\'), &mut continued) {
+                        (Some(""), None) => line,
+                        (Some(part), continued) => {
+                            let block = continued.get_or_insert_with(String::new);
+                            block.push_str(part);
+                            block.push('\n');
+                            continue;
+                        }
+                        (None, continued) => match continued.take() {
+                            Some(block) => block + &line,
+                            None => line,
+                        },
+                    };
+                    match parse_command(&line) {
+                        Some(Ok(Command::Quit)) => break,
+                        Some(Ok(Command::Help(name))) => {
+                            print_help(name.as_deref(), &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Peers)) => {
+                            print_peers(&mut node, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Roster)) => {
+                            print_roster(&node, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Discovered)) => {
+                            discovered = print_discovered(&mut node, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Dial(target))) => {
+                            dial_discovered(&mut node, &target, &discovered, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Invite)) => {
+                            let (lan, public) = reachable_addrs(&node);
+                            // Loopback addresses are of no use to anyone on another
+                            // machine, and the invite carries our PeerId only once.
+                            let addrs: Vec<_> = lan
+                                .into_iter()
+                                .filter(|a| !is_loopback(a))
+                                .chain(public)
+                                .filter_map(|a| split_peer_id(a).map(|(_, a)| a))
+                                .collect();
+                            if addrs.is_empty() {
+                                out!(out, "Not listening anywhere others can reach yet");
+                            } else {
+                                let invite = Invite::new(local_peer, addrs, INVITE_VALIDITY);
+                                out!(out, "Invite, good until {}:", invite.expires_at.format("%Y-%m-%d %H:%M"));
+                                out!(out, "  {}", invite.to_token());
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Connect(token))) => {
+                            connect(&mut node, &token, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Whois(name))) => {
+                            print_whois(&mut node, &name, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::MyAddr)) => {
+                            print_addrs(&node, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Stats)) => {
+                            print_stats(&node.stats(), &mut out);
+                            None
+                        }
+                        Some(Ok(Command::ListTopics)) => {
+                            if rooms.is_empty() {
+                                out!(out, "Not in any room");
+                            }
+                            // The room we are talking in is marked with a `*`.
+                            for room in &rooms {
+                                let mark = if current_topic.as_ref() == Some(room) { '*' } else { ' ' };
+                                let state = if muted.contains(room) { ", muted" } else { "" };
+                                out!(out, "{} [{}] {} peers{}", mark, room, node.topic_peer_count(room), state);
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Clear)) => {
+                            out.clear(colors.enabled);
+                            let room = current_topic.as_deref().map_or("no room".into(), |t| format!("[{}]", t));
+                            let name = nick.as_deref().unwrap_or("no nickname");
+                            out!(out, "*** {} in {}, {} peers connected", name, room, node.peers().len());
+                            None
+                        }
+                        Some(Ok(Command::Send { peer, path })) => {
+                            if let Err(e) = node.send_file(peer, &path) {
+                                out_err!(out, "Cannot send {}: {}", path.display(), e);
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Inbox)) => {
+                            let unread = inbox.unread();
+                            if unread.is_empty() {
+                                out!(out, "No unread direct messages");
+                            }
+                            for (peer, n) in unread {
+                                out!(out, "  {}: {} unread", colors.peer(&peer, &peer_name(&node, &peer)), n);
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Dm { text: Some(text), .. })) if text.len() > config.max_message_size => {
+                            out_err!(out, "Not sent: message is {} bytes, the limit is {}", text.len(), config.max_message_size);
+                            None
+                        }
+                        Some(Ok(Command::Dm { peer, text })) => {
+                            let peer = match find_peer(&node, &peer, &mut out) {
+                                Some(peer) => peer,
+                                None => continue,
+                            };
+                            match text {
+                                Some(text) => {
+                                    let message = ChatMessage::new(nick.clone(), text);
+                                    send_direct(&mut node, &mut inbox, peer, message, colors, &mut out).await;
+                                }
+                                None => {
+                                    talking_to = Some(peer);
+                                    let name = colors.peer(&peer, &peer_name(&node, &peer));
+                                    out!(out, "Talking privately with {}, /back to return", name);
+                                    for said in inbox.open(&peer, DEFAULT_HISTORY_LINES) {
+                                        print_said(&said, colors, &mut out);
+                                    }
+                                }
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Back)) => {
+                            match (talking_to.take(), &current_topic) {
+                                (None, _) => out!(out, "Not talking privately"),
+                                (Some(_), Some(topic)) => out!(out, "Back in [{}]", topic),
+                                (Some(_), None) => out!(out, "Not in any room, /join one to talk"),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Disconnect(name))) => {
+                            if let Some(peer) = find_peer(&node, &name, &mut out) {
+                                if node.disconnect(&peer) {
+                                    out!(out, "Disconnected from {}", peer_name(&node, &peer));
+                                } else {
+                                    out!(out, "Not connected to {}", peer_name(&node, &peer));
+                                }
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Block(peer))) => {
+                            match node.block(peer) {
+                                Ok(true) => out!(out, "Blocked {}", peer),
+                                Ok(false) => out!(out, "{} is already blocked", peer),
+                                Err(e) => out_err!(out, "Could not save the blocklist: {}", e),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Unblock(peer))) => {
+                            match node.unblock(&peer) {
+                                Ok(true) => out!(out, "Unblocked {}", peer),
+                                Ok(false) => out!(out, "{} is not blocked", peer),
+                                Err(e) => out_err!(out, "Could not save the blocklist: {}", e),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Accept(offer))) => {
+                            if !node.accept_file(offer) {
+                                out!(out, "No pending file offer #{}", offer);
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Reject(offer))) => {
+                            if node.reject_file(offer) {
+                                out!(out, "Declined offer #{}", offer);
+                            } else {
+                                out!(out, "No pending file offer #{}", offer);
+                            }
+                            None
+                        }
+                        Some(Ok(Command::History(n))) => {
+                            match &current_topic {
+                                None => out!(out, "Not in a room"),
+                                Some(topic) => match history.tail(topic, n) {
+                                    Ok(entries) => for entry in entries {
+                                        let time = entry.time().map(timestamp).unwrap_or_default();
+                                        out!(out, "{} [{}] {}", time, topic, said(&entry.sender, &entry.body, entry.action));
+                                    }
+                                    Err(e) => out_err!(out, "Could not read history for [{}]: {}", topic, e),
+                                },
+                            }
+                            None
+                        }
+                        Some(Ok(Command::ProfileList)) => {
+                            match profiles.list() {
+                                Ok(names) if names.is_empty() => out!(out, "No profiles saved"),
+                                Ok(names) => out!(out, "Profiles: {}", names.join(", ")),
+                                Err(e) => out_err!(out, "Could not list profiles: {}", e),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::ProfileSave(name))) => {
+                            let addrs = profile_addrs(&mut node, &config.bootstrap);
+                            match profiles.save(&name, &addrs) {
+                                Ok(()) => out!(out, "Saved {} addresses as {}", addrs.len(), name),
+                                Err(e) => out_err!(out, "Could not save profile {}: {}", name, e),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::ProfileLoad(name))) => {
+                            match profiles.load(&name) {
+                                Ok(addrs) if addrs.is_empty() => out!(out, "Profile {} has no addresses", name),
+                                Ok(addrs) => for addr in addrs {
+                                    dial(&mut node, addr, &mut out);
+                                }
+                                Err(e) => out_err!(out, "Could not load profile {}: {}", name, e),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Search(term))) => {
+                            match db.as_ref().map(|db| db.search(&term, SEARCH_RESULTS)) {
+                                None => out!(out, "Nothing to search: start with --db <path> to keep a database"),
+                                Some(Ok(rows)) if rows.is_empty() => out!(out, "Nothing found for {}", term),
+                                Some(Ok(rows)) => for row in rows {
+                                    let time = row.time().map(timestamp).unwrap_or_default();
+                                    let sender = row.nick.as_deref().or(row.source.as_deref()).unwrap_or("?");
+                                    out!(out, "{} [{}] {}", time, row.topic, said(sender, &row.body, row.action));
+                                }
+                                Some(Err(e)) => out_err!(out, "Could not search: {}", e),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Export(path))) => {
+                            match transcript.export(&path) {
+                                Ok(n) => out!(out, "Exported {} messages to {}", n, path.display()),
+                                Err(e) => out_err!(out, "Could not export to {}: {}", path.display(), e),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Me(action))) if action.len() > config.max_message_size => {
+                            out_err!(out, "Not sent: message is {} bytes, the limit is {}", action.len(), config.max_message_size);
+                            None
+                        }
+                        Some(Ok(Command::Me(action))) => match &current_topic {
+                            Some(topic) => Some((topic.clone(), ChatMessage::action(nick.clone(), action))),
+                            None => {
+                                out!(out, "Not in any room, /join one to talk");
+                                None
+                            }
+                        },
+                        Some(Ok(Command::Reply { text, .. })) if text.len() > config.max_message_size => {
+                            out_err!(out, "Not sent: message is {} bytes, the limit is {}", text.len(), config.max_message_size);
+                            None
+                        }
+                        Some(Ok(Command::Reply { id, text })) => {
+                            // A reply belongs in the room of what it answers.
+                            match (find_message(&transcript, &id), current_topic.clone()) {
+                                (Err(e), _) => {
+                                    out!(out, "{}", e);
+                                    None
+                                }
+                                (Ok((_, Some(room))), _) if !rooms.contains(&room) => {
+                                    out!(out, "Not in [{}] any more", room);
+                                    None
+                                }
+                                (Ok((to, Some(room))), _) | (Ok((to, None)), Some(room)) => {
+                                    Some((room, ChatMessage::reply(nick.clone(), text, to)))
+                                }
+                                (Ok((_, None)), None) => {
+                                    out!(out, "Not in any room, /join one to talk");
+                                    None
+                                }
+                            }
+                        }
+                        Some(Ok(Command::React { id, emoji })) => {
+                            // Like a reply, in the room of what it is about.
+                            match (find_message(&transcript, &id), current_topic.clone()) {
+                                (Err(e), _) => out!(out, "{}", e),
+                                (Ok((_, Some(room))), _) if !rooms.contains(&room) => {
+                                    out!(out, "Not in [{}] any more", room)
+                                }
+                                (Ok((to, Some(room))), _) | (Ok((to, None)), Some(room)) => {
+                                    // Checked and ordered like chat, as it is sent the same way.
+                                    if let Some(wait) = node.slowmode_wait(&room) {
+                                        out_err!(out, "Not sent: [{}] is in slow mode, wait {}s", room, wait.as_millis().div_ceil(1000));
+                                        continue;
+                                    }
+                                    let me = nick.clone().unwrap_or_else(|| local_peer.to_string());
+                                    if let Some(shown) = transcript.react(&room, to, &emoji, &me) {
+                                        let who = colors.peer(&local_peer, nick.as_deref().unwrap_or("me"));
+                                        out!(out, "{} {}", timestamp(Local::now()), reacted(&who, &emoji, shown));
+                                    }
+                                    let reaction = ChatMessage { clock: Some(node.tick()), ..ChatMessage::reaction(nick.clone(), emoji, to) };
+                                    match node.send(&room, reaction).await {
+                                        Ok(()) | Err(PublishError::InsufficientPeers) => {}
+                                        Err(e) => out_err!(out, "Publish error: {:?}", e),
+                                    }
+                                }
+                                (Ok((_, None)), None) => out!(out, "Not in any room, /join one to talk"),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Nick(name))) => {
+                            out!(out, "You are now known as {}", name);
+                            if nick.as_ref() != Some(&name) {
+                                node.announce_nick(nick.replace(name.clone()), &name);
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Join { room, exclusive })) => {
+                            match node.join(&room) {
+                                Ok(true) => rooms.push(room.clone()),
+                                Ok(false) => {}
+                                Err(e) => {
+                                    out_err!(out, "Could not join [{}]: {:?}", room, e);
+                                    continue;
+                                }
+                            }
+                            if exclusive {
+                                for other in rooms.iter().filter(|r| **r != room) {
+                                    node.leave(other);
+                                    out!(out, "Left [{}]", other);
+                                }
+                                rooms.retain(|r| *r == room);
+                                muted.retain(|r| *r == room);
+                            }
+                            out!(out, "Now talking in [{}]", room);
+                            current_topic = Some(room);
+                            None
+                        }
+                        Some(Ok(Command::Leave(room))) => {
+                            // `/leave` on its own leaves the current room.
+                            let room = match (room, &current_topic) {
+                                (Some(room), _) => room,
+                                (None, Some(current)) => current.clone(),
+                                (None, None) => {
+                                    out!(out, "Not in a room");
+                                    continue;
+                                }
+                            };
+                            if node.leave(&room) {
+                                rooms.retain(|r| *r != room);
+                                muted.remove(&room);
+                                out!(out, "Left [{}]", room);
+                            } else {
+                                out!(out, "Not in [{}]", room);
+                            }
+                            if current_topic.as_ref() == Some(&room) {
+                                current_topic = rooms.last().cloned();
+                                match &current_topic {
+                                    Some(t) => out!(out, "Now talking in [{}]", t),
+                                    None => out!(out, "Not in any room, /join one to talk"),
+                                }
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Mute(room))) => {
+                            match room.or_else(|| current_topic.clone()) {
+                                Some(room) if !rooms.contains(&room) => out!(out, "Not in [{}]", room),
+                                Some(room) if muted.insert(room.clone()) => {
+                                    out!(out, "Muted [{}], /unmute to see it again", room)
+                                }
+                                Some(room) => out!(out, "[{}] is already muted", room),
+                                None => out!(out, "Not in a room"),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::Unmute(room))) => {
+                            match room.or_else(|| current_topic.clone()) {
+                                Some(room) if muted.remove(&room) => out!(out, "Unmuted [{}]", room),
+                                Some(room) => out!(out, "[{}] is not muted", room),
+                                None => out!(out, "Not in a room"),
+                            }
+                            None
+                        }
+                        Some(Ok(Command::SlowMode(secs))) => {
+                            match (current_topic.as_deref(), secs) {
+                                (None, _) => out!(out, "Not in a room"),
+                                (Some(room), None) => match node.slowmode(room) {
+                                    Some((interval, by)) => {
+                                        let by = if by == local_peer { "you".to_string() } else { peer_name(&node, &by) };
+                                        out!(out, "[{}] is in slow mode, one message every {}s each, as {} set it", room, interval.as_secs(), by)
+                                    }
+                                    None => out!(out, "[{}] is not in slow mode", room),
+                                },
+                                (Some(room), Some(secs)) => {
+                                    let set = match node.set_slowmode(room, Duration::from_secs(secs), nick.clone()) {
+                                        // Peers learn it as they join.
+                                        Ok(set) => set,
+                                        Err(PublishError::InsufficientPeers) => true,
+                                        Err(e) => {
+                                            out_err!(out, "Publish error: {:?}", e);
+                                            true
+                                        }
+                                    };
+                                    match (set, secs) {
+                                        (false, _) => {
+                                            let host = node.slowmode_host(room).map(|h| peer_name(&node, &h)).unwrap_or_default();
+                                            out_err!(out, "Only {} can change slow mode in [{}]", host, room)
+                                        }
+                                        (true, 0) => out!(out, "Slow mode is off in [{}]", room),
+                                        (true, secs) => out!(out, "[{}] is in slow mode, one message every {}s each", room, secs),
+                                    }
+                                }
+                            }
+                            None
+                        }
+                        Some(Ok(Command::TopicStats { reset: true })) => {
+                            node.reset_topic_stats();
+                            out!(out, "Room stats start over now");
+                            None
+                        }
+                        Some(Ok(Command::TopicStats { reset: false })) => {
+                            print_topic_stats(&node, &mut out);
+                            None
+                        }
+                        Some(Ok(Command::Unknown(name))) => {
+                            out_err!(out, "unknown command: {}, try /help", name);
+                            None
+                        }
+                        Some(Err(usage)) => {
+                            out_err!(out, "{}", usage);
+                            None
+                        }
+                        None if line.len() > config.max_message_size => {
+                            out_err!(out, "Not sent: message is {} bytes, the limit is {}", line.len(), config.max_message_size);
+                            None
+                        }
+                        None => match (talking_to, &current_topic) {
+                            (Some(peer), _) => {
+                                let message = ChatMessage::new(nick.clone(), chat_text(&line));
+                                send_direct(&mut node, &mut inbox, peer, message, colors, &mut out).await;
+                                None
+                            }
+                            (None, Some(topic)) => Some((topic.clone(), ChatMessage::new(nick.clone(), chat_text(&line)))),
+                            (None, None) => {
+                                out!(out, "Not in any room, /join one to talk");
+                                None
+                            }
+                        },
+                    }
+                }
+                _ = tokio::signal::ctrl_c() => break,
+                key = next_key(&mut keys) => {
+                    match key {
+                        Some(Ok(Event::Key(key))) => {
+                            last_typed = Instant::now();
+                            if std::mem::take(&mut away) {
+                                send_presence(&mut node, &rooms, &nick, false).await;
+                            }
+                            let tui = out.tui.as_mut().expect("Keys are only read for the TUI");
+                            let line = tui.handle_key(key);
+                            let composing = tui.is_composing();
+                            // Tell the room when we start or give up on a message, and
+                            // keep reminding it while we go on. Sending is stopping too.
+                            let change = match (composing, typing_sent) {
+                                (true, Some(at)) if at.elapsed() < TYPING_REFRESH => None,
+                                (true, _) => Some(true),
+                                (false, Some(_)) if line.is_none() => Some(false),
+                                (false, _) => None,
+                            };
+                            if !composing {
+                                typing_sent = None;
+                            }
+                            // Nor is it the room's business while we talk privately.
+                            if let (Some(change), Some(topic), None) = (change, &current_topic, talking_to) {
+                                if change {
+                                    typing_sent = Some(Instant::now());
+                                }
+                                // Nobody may be in the room to tell.
+                                let _ = node.send(topic, ChatMessage::typing(nick.clone(), change)).await;
+                            }
+                            if let (Some(line), Some(typed)) = (line, &typed) {
+                                let _ = typed.send(Ok(line));
+                            }
+                        }
+                        // Anything else, such as a resize, just needs a redraw.
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => return Err(e.into()),
+                        None => keys = None,
+                    }
+                    None
+                }
+                _ = heartbeat.tick() => {
+                    for room in &rooms {
+                        // Being away goes on saying so, for peers that join later.
+                        let beat = if away {
+                            ChatMessage::presence(nick.clone(), true)
+                        } else {
+                            ChatMessage::heartbeat(nick.clone())
+                        };
+                        // Fails while nobody else is in the room, which is fine.
+                        if let Err(e) = node.send(room, beat).await {
+                            tracing::debug!("Could not send a heartbeat to [{}]: {:?}", room, e);
+                        }
+                    }
+                    None
+                }
+                _ = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                    let now = Instant::now();
+                    pending.retain(|_, p| {
+                        if p.deadline > now {
+                            return true;
+                        }
+                        if !p.delivered {
+                            out!(out, "✗ no acks after {}s", ACK_TIMEOUT.as_secs());
+                        }
+                        false
+                    });
+                    None
                 }
-                event = swarm.next() => {
-                    // All events are handled by the `NetworkBehaviourEventProcess`es.
-                    // I.e. the `swarm.next()` future drives the `Swarm` without ever
-                    // terminating.
-                    panic!("Unexpected event: {:?}", event);
+                _ = tokio::time::sleep_until(next_reorder.unwrap_or_else(Instant::now)), if next_reorder.is_some() => {
+                    let due = reorder.as_mut().map_or_else(Vec::new, |r| r.flush(Instant::now()));
+                    for event in due {
+                        let view = View { current: current_topic.as_deref(), muted: &muted };
+                        print_event(event, &node, view, colors, &history, db.as_ref(), &mut transcript, &mut latest, &mut out);
+                    }
+                    None
+                }
+                Some(scrape) = scraped.recv(), if config.metrics_addr.is_some() => {
+                    let rooms: Vec<_> = rooms.iter().map(|room| (room.clone(), node.topic_peer_count(room))).collect();
+                    let _ = scrape.send(metrics::render(&node.stats(), &rooms));
+                    None
+                }
+                _ = tokio::time::sleep_until(next_away.unwrap_or_else(Instant::now)), if next_away.is_some() => {
+                    away = true;
+                    send_presence(&mut node, &rooms, &nick, true).await;
+                    out!(out, "*** You are away, until you type again");
+                    None
+                }
+                _ = tokio::time::sleep_until(next_typing_expiry.unwrap_or_else(Instant::now)), if next_typing_expiry.is_some() => {
+                    // Just a redraw, which drops whoever went quiet.
+                    typing.retain(|_, (_, at)| at.elapsed() < TYPING_TIMEOUT);
+                    None
+                }
+                event = node.poll_event() => {
+                    match &event {
+                        ChatEvent::Typing { topic, peer, nick, typing: true } => {
+                            let name = nick.clone().unwrap_or_else(|| peer.to_string());
+                            typing.insert((topic.clone(), *peer), (name, Instant::now()));
+                        }
+                        ChatEvent::Typing { topic, peer, .. } => {
+                            typing.remove(&(topic.clone(), *peer));
+                        }
+                        // Done typing, then.
+                        ChatEvent::MessageReceived { topic, source: Some(peer), .. } => {
+                            typing.remove(&(topic.clone(), *peer));
+                        }
+                        _ => {}
+                    }
+                    if let ChatEvent::Delivered { id, peer, nick, .. } = &event {
+                        if let Some(p) = pending.get_mut(id) {
+                            p.delivered = true;
+                            if !out.json {
+                                let name = nick.clone().unwrap_or_else(|| peer.to_string());
+                                out!(out, "✓ delivered to {}", colors.peer(peer, &name));
+                            }
+                        }
+                    }
+                    // Direct messages go into their conversation, and are only
+                    // shown as they come while it is open.
+                    if let ChatEvent::DirectMessage { source, message, forward_secret } = &event {
+                        let open = talking_to == Some(*source);
+                        let name = author(&node, message, Some(*source));
+                        let unread = inbox.push(*source, *source, &name, message, open);
+                        if !open && !out.json {
+                            let name = colors.peer(source, &name);
+                            let scheme = if *forward_secret { ", forward-secret" } else { "" };
+                            out!(out, "*** Direct message from {} ({} unread{}), /dm {} to read", name, unread, scheme, source);
+                            continue;
+                        }
+                    }
+                    if let (Some(buffer), ChatEvent::MessageReceived { message, .. }) = (&mut reorder, &event) {
+                        let key = (message.clock.unwrap_or(0), message.sent_at.unwrap_or(0));
+                        buffer.push(key, event, Instant::now());
+                        continue;
+                    }
+                    let view = View { current: current_topic.as_deref(), muted: &muted };
+                    print_event(event, &node, view, colors, &history, db.as_ref(), &mut transcript, &mut latest, &mut out);
+                    None
                 }
             }
         };
-        if let Some((topic, line)) = to_publish {
-            swarm.behaviour_mut().floodsub.publish(topic, line.as_bytes());
-        }
-        if !listening {
-            for addr in Swarm::listeners(&swarm) {
-                println!("Listening on {:?}", addr);
-                listening = true;
+        if let Some((topic, message)) = to_publish {
+            if let Some(wait) = node.slowmode_wait(&topic) {
+                // Everyone would drop it anyway.
+                out_err!(out, "Not sent: [{}] is in slow mode, wait {}s", topic, wait.as_millis().div_ceil(1000));
+                continue;
+            }
+            let message = ChatMessage { clock: Some(node.tick()), ..message };
+            latest.insert(topic.clone(), message.clock.unwrap_or(0));
+            let me = colors.peer(&local_peer, nick.as_deref().unwrap_or("me"));
+            if let Some(quote) = quote(&message, &transcript) {
+                out!(out, "{}", quote);
+            }
+            match &out.format {
+                Some(format) => {
+                    let (time, peerid) = (time_of_day(Local::now()), local_peer.to_string());
+                    let (body, id) = (shown_body(&message), shown_id(&message));
+                    let fields = Fields { time: &time, nick: &me, peerid: &peerid, topic: &topic, body: &body, id: &id };
+                    out!(out, "{}", format.render(&fields));
+                }
+                None => {
+                    let line = said(&me, &message.body, message.kind == MessageKind::Action);
+                    out!(out, "{} [{}] {}{}", timestamp(Local::now()), topic, line, id_tag(&message));
+                }
+            }
+            let sender = nick.clone().unwrap_or_else(|| local_peer.to_string());
+            if let Err(e) = history.append(&topic, &sender, &message) {
+                tracing::warn!("Could not write history for [{}]: {}", topic, e);
+            }
+            let stored = ChatMessage { nick: nick.clone(), ..message.clone() };
+            if let Some(Err(e)) = db.as_ref().map(|db| db.insert(&topic, Some(&local_peer), &stored)) {
+                tracing::warn!("Could not store a message to [{}]: {}", topic, e);
+            }
+            transcript.push(&topic, &sender, &message);
+            let id = message.id;
+            match node.send(&topic, message).await {
+                Ok(()) => if let Some(id) = id {
+                    pending.insert(id, PendingAck { deadline: Instant::now() + ACK_TIMEOUT, delivered: false });
+                }
+                Err(e) => out_err!(out, "Publish error: {:?}", e),
             }
         }
     }
+
+    // Reached on Ctrl-C or `/quit`. Show and log what is still held back.
+    for event in reorder.as_mut().map_or_else(Vec::new, ReorderBuffer::drain) {
+        let view = View { current: current_topic.as_deref(), muted: &muted };
+        print_event(event, &node, view, colors, &history, db.as_ref(), &mut transcript, &mut latest, &mut out);
+    }
+    // Give the terminal back, and if the graceful path below hangs, a
+    // (further) Ctrl-C bails out for good.
+    out.tui = None;
+    tokio::spawn(async {
+        let _ = tokio::signal::ctrl_c().await;
+        std::process::exit(130);
+    });
+    // Say goodbye in every room and unsubscribe so peers drop us from their view.
+    out!(out, "Shutting down, press Ctrl-C again to force");
+    node.shutdown(ChatMessage::new(nick, "has left")).await;
+    Ok(ExitCode::SUCCESS)
 }
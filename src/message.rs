@@ -0,0 +1,331 @@
+//! The wire format of chat messages.
+
+use chrono::{DateTime, Local, TimeZone};
+use libp2p::{identity, multihash::Multihash, PeerId};
+use serde::{Deserialize, Serialize};
+
+/// What a [`ChatMessage`] is for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    /// A line of chat, which receivers acknowledge if it has an id.
+    #[default]
+    Chat,
+    /// A delivery receipt for the chat message whose id it carries. Acks
+    /// are never acknowledged themselves.
+    Ack,
+    /// Something the author does rather than says, sent with `/me` and shown
+    /// as `* alice waves`. Acknowledged like chat.
+    Action,
+    /// A periodic sign that the author is still around, carrying its
+    /// nickname. Not shown as chat.
+    Heartbeat,
+    /// The author has started writing a message, and repeats this every so
+    /// often while still writing. Not shown as chat.
+    Typing,
+    /// The author has stopped writing without sending anything.
+    StoppedTyping,
+    /// Puts the topic in slow mode: each peer may send one chat message per
+    /// `body` seconds, and faster ones are dropped. 0 turns it off. The
+    /// latest by `sent_at` holds; the author re-sends it unchanged to peers
+    /// that join later.
+    #[serde(rename = "slowmode")]
+    SlowMode,
+    /// The author now goes by `nick`, and went by `body` before, if that
+    /// is not empty. Not shown as chat.
+    Nick,
+    /// The author reacts with the emoji in `body` to the chat message whose
+    /// id is in `reply_to`. Shown as a tally under that message.
+    Reaction,
+    /// The author has gone idle, with `away` in `body`, or is back, with it
+    /// empty. Sent in place of heartbeats while away, and counted as one.
+    Presence,
+}
+
+/// Version of the message format we publish. Bump it whenever a change to
+/// [`ChatMessage`] or its encoding would have older peers misread what we
+/// send, so that networks can refuse the older ones with `min_version`.
+/// Peers from before there were versions leave it out, which reads as 0.
+pub const WIRE_VERSION: u32 = 6;
+
+/// Longest nickname, in characters.
+pub const MAX_NICK_LEN: usize = 32;
+
+/// Whether `nick` will do as a nickname: not blank, no longer than
+/// [`MAX_NICK_LEN`] and free of control characters, which could garble
+/// the terminals it is shown on.
+pub fn check_nick(nick: &str) -> Result<(), String> {
+    if nick.trim().is_empty() {
+        Err("A nickname cannot be blank".to_string())
+    } else if nick.chars().count() > MAX_NICK_LEN {
+        Err(format!("A nickname can be at most {} characters long", MAX_NICK_LEN))
+    } else if nick.chars().any(char::is_control) {
+        Err("A nickname cannot contain control characters".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Longest reaction, in characters; enough for any emoji, with its
+/// modifiers and joiners.
+pub const MAX_REACTION_LEN: usize = 16;
+
+/// Whether `emoji` will do as a reaction: not blank, no longer than
+/// [`MAX_REACTION_LEN`] and without spaces or control characters.
+pub fn check_reaction(emoji: &str) -> Result<(), String> {
+    if emoji.is_empty() {
+        Err("A reaction cannot be blank".to_string())
+    } else if emoji.chars().count() > MAX_REACTION_LEN {
+        Err(format!("A reaction can be at most {} characters long", MAX_REACTION_LEN))
+    } else if emoji.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        Err("A reaction cannot contain spaces or control characters".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Encoded messages at least this long are compressed, if that helps.
+const COMPRESS_THRESHOLD: usize = 256;
+
+/// The most a compressed message may expand to; anything larger is dropped
+/// rather than unpacked.
+const MAX_DECOMPRESSED: usize = 1024 * 1024;
+
+/// Leading byte of an encoded message: JSON follows as is, compressed with
+/// zstd, wrapped in the author's signature, encrypted with a
+/// [room key](crate::room_key), or a [fragment](crate::fragment) of
+/// something too long to publish whole.
+const PLAIN: u8 = 0;
+const ZSTD: u8 = 1;
+const SIGNED: u8 = 2;
+pub(crate) const SEALED: u8 = 3;
+pub(crate) const FRAGMENT: u8 = 4;
+
+/// What a signature covers ahead of the topic and the message, so it cannot
+/// be passed off as a signature over anything else.
+const SIGNING_CONTEXT: &[u8] = b"peardchat message v1";
+
+/// A chat line as it travels over gossipsub: a flag byte, then the message as
+/// JSON, zstd-compressed if the flag says so.
+///
+/// What we publish is signed as well:
+///
+/// ```text
+/// 0x02 | signature length (2 bytes, big-endian) | signature | encoded message
+/// ```
+///
+/// The signature is made with the author's identity key, over the topic and
+/// the encoded message, so receivers can check it against the key in the
+/// author's `PeerId`.
+///
+/// Older peers publish bare JSON, or even the raw UTF-8 text, so anything
+/// that does not start with a flag byte is taken as one of those, and what
+/// does not parse as a `ChatMessage` becomes a body without a nickname.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    /// The [`WIRE_VERSION`] of the author.
+    #[serde(default)]
+    pub version: u32,
+    #[serde(default)]
+    pub kind: MessageKind,
+    /// Identifies a chat message so receivers can acknowledge it, or for an
+    /// ack, the message being acknowledged.
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub nick: Option<String>,
+    pub body: String,
+    /// When the author sent the message, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub sent_at: Option<i64>,
+    /// The author's Lamport clock at sending, higher than that of every
+    /// message the author had seen by then. Set by the sender, see
+    /// [`ChatNode::tick`](crate::ChatNode::tick); older peers leave it out.
+    #[serde(default)]
+    pub clock: Option<u64>,
+    /// The `id` of the earlier message this one answers, if it is a reply.
+    #[serde(default)]
+    pub reply_to: Option<u64>,
+}
+
+impl ChatMessage {
+    /// A message sent now, with a fresh random id.
+    pub fn new(nick: Option<String>, body: impl Into<String>) -> ChatMessage {
+        ChatMessage {
+            version: WIRE_VERSION,
+            kind: MessageKind::Chat,
+            id: Some(rand::random()),
+            nick,
+            body: body.into(),
+            sent_at: Some(Local::now().timestamp_millis()),
+            clock: None,
+            reply_to: None,
+        }
+    }
+
+    /// A reply to the message with the id `to`, sent now.
+    pub fn reply(nick: Option<String>, body: impl Into<String>, to: u64) -> ChatMessage {
+        ChatMessage { reply_to: Some(to), ..ChatMessage::new(nick, body) }
+    }
+
+    /// An action sent now, with a fresh random id.
+    pub fn action(nick: Option<String>, body: impl Into<String>) -> ChatMessage {
+        ChatMessage { kind: MessageKind::Action, ..ChatMessage::new(nick, body) }
+    }
+
+    /// A receipt for the message with the given id.
+    pub fn ack(id: u64) -> ChatMessage {
+        ChatMessage {
+            version: WIRE_VERSION,
+            kind: MessageKind::Ack,
+            id: Some(id),
+            nick: None,
+            body: String::new(),
+            sent_at: Some(Local::now().timestamp_millis()),
+            clock: None,
+            reply_to: None,
+        }
+    }
+
+    /// A heartbeat sent now under `nick`.
+    pub fn heartbeat(nick: Option<String>) -> ChatMessage {
+        ChatMessage {
+            version: WIRE_VERSION,
+            kind: MessageKind::Heartbeat,
+            id: None,
+            nick,
+            body: String::new(),
+            sent_at: Some(Local::now().timestamp_millis()),
+            clock: None,
+            reply_to: None,
+        }
+    }
+
+    /// Says whether the author, `nick`, is `typing` a message.
+    pub fn typing(nick: Option<String>, typing: bool) -> ChatMessage {
+        ChatMessage {
+            kind: if typing { MessageKind::Typing } else { MessageKind::StoppedTyping },
+            ..ChatMessage::heartbeat(nick)
+        }
+    }
+
+    /// Puts the topic it is sent on in slow mode, one message per `secs`
+    /// seconds, or out of it for 0.
+    pub fn slowmode(nick: Option<String>, secs: u64) -> ChatMessage {
+        ChatMessage { kind: MessageKind::SlowMode, body: secs.to_string(), ..ChatMessage::heartbeat(nick) }
+    }
+
+    /// Tells peers that the author, who went by `old`, now goes by `new`.
+    pub fn nick_change(old: Option<String>, new: String) -> ChatMessage {
+        ChatMessage { kind: MessageKind::Nick, body: old.unwrap_or_default(), ..ChatMessage::heartbeat(Some(new)) }
+    }
+
+    /// Reacts with `emoji`, under `nick`, to the message with the id `to`.
+    pub fn reaction(nick: Option<String>, emoji: impl Into<String>, to: u64) -> ChatMessage {
+        ChatMessage {
+            kind: MessageKind::Reaction,
+            body: emoji.into(),
+            reply_to: Some(to),
+            ..ChatMessage::heartbeat(nick)
+        }
+    }
+
+    /// Says whether the author, `nick`, is `away`.
+    pub fn presence(nick: Option<String>, away: bool) -> ChatMessage {
+        let body = if away { "away" } else { "" };
+        ChatMessage { kind: MessageKind::Presence, body: body.to_string(), ..ChatMessage::heartbeat(nick) }
+    }
+
+    /// Returns `None` for compressed data that does not unpack.
+    pub fn decode(data: &[u8]) -> Option<ChatMessage> {
+        let json = match data.split_first() {
+            Some((&PLAIN, json)) => json,
+            Some((&ZSTD, compressed)) => &zstd::bulk::decompress(compressed, MAX_DECOMPRESSED).ok()?,
+            _ => data,
+        };
+        Some(serde_json::from_slice(json).unwrap_or_else(|_| ChatMessage {
+            version: 0,
+            kind: MessageKind::Chat,
+            id: None,
+            nick: None,
+            body: String::from_utf8_lossy(json).into_owned(),
+            sent_at: None,
+            clock: None,
+            reply_to: None,
+        }))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let json = serde_json::to_vec(self).expect("ChatMessage always serializes");
+        if json.len() >= COMPRESS_THRESHOLD {
+            if let Ok(compressed) = zstd::bulk::compress(&json, 0) {
+                if compressed.len() < json.len() {
+                    return [&[ZSTD][..], &compressed].concat();
+                }
+            }
+        }
+        [&[PLAIN][..], &json].concat()
+    }
+
+    /// Encodes the message for `topic`, signed with `keys`.
+    pub fn encode_signed(&self, topic: &str, keys: &identity::Keypair) -> Vec<u8> {
+        sign(topic, &self.encode(), keys)
+    }
+
+    /// Decodes a message that arrived on `topic`, along with whether
+    /// `source` signed it. Returns `None` if it does not decode at all.
+    pub fn decode_signed(data: &[u8], topic: &str, source: Option<&PeerId>) -> Option<(ChatMessage, bool)> {
+        if data.first() != Some(&SIGNED) {
+            return Some((ChatMessage::decode(data)?, false));
+        }
+        let (encoded, authentic) = open_signed(data, topic, source)?;
+        Some((ChatMessage::decode(encoded)?, authentic))
+    }
+
+    pub fn sent_at(&self) -> Option<DateTime<Local>> {
+        self.sent_at.and_then(|ms| Local.timestamp_millis_opt(ms).single())
+    }
+}
+
+/// The public key `peer` embeds in its `PeerId`, if it is short enough to
+/// be embedded rather than hashed.
+pub(crate) fn public_key(peer: &PeerId) -> Option<identity::PublicKey> {
+    let multihash: &Multihash = peer.as_ref();
+    identity::PublicKey::from_protobuf_encoding(multihash.digest()).ok()
+}
+
+/// `data` to publish on `topic`, wrapped in a signature made with `keys`,
+/// laid out as for a [`ChatMessage`].
+pub(crate) fn sign(topic: &str, data: &[u8], keys: &identity::Keypair) -> Vec<u8> {
+    let signature = keys
+        .sign(&signed_bytes(topic, data))
+        .expect("Our keys can sign");
+    let mut signed = Vec::with_capacity(3 + signature.len() + data.len());
+    signed.push(SIGNED);
+    signed.extend_from_slice(&(signature.len() as u16).to_be_bytes());
+    signed.extend_from_slice(&signature);
+    signed.extend_from_slice(data);
+    signed
+}
+
+/// What [`sign`] wrapped in `data`, along with whether `source` signed it
+/// for `topic`. Returns `None` if `data` is not signed at all.
+pub(crate) fn open_signed<'a>(data: &'a [u8], topic: &str, source: Option<&PeerId>) -> Option<(&'a [u8], bool)> {
+    if data.first() != Some(&SIGNED) {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([*data.get(1)?, *data.get(2)?]));
+    let signature = data.get(3..3 + len)?;
+    let signed = &data[3 + len..];
+    let authentic = source
+        .and_then(public_key)
+        .is_some_and(|key| key.verify(&signed_bytes(topic, signed), signature));
+    Some((signed, authentic))
+}
+
+fn signed_bytes(topic: &str, encoded: &[u8]) -> Vec<u8> {
+    let mut bytes = SIGNING_CONTEXT.to_vec();
+    bytes.extend_from_slice(&(topic.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(topic.as_bytes());
+    bytes.extend_from_slice(encoded);
+    bytes
+}
@@ -0,0 +1,172 @@
+//! How a running node is doing, served over HTTP in the Prometheus text
+//! format for monitoring to scrape: `GET /metrics` on the address given as
+//! `metrics_addr`.
+//!
+//! The libp2p release peardchat is built on predates libp2p's own metrics,
+//! so these are the node's [`Stats`] and the peers in each room.
+
+use crate::Stats;
+use std::{fmt::Write, io, net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+
+/// Longest request head we read before giving up on a request.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long a client has to send its request head before we hang up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A scrape waiting for the metrics as of now, which whoever runs the node
+/// renders with [`render`] and sends back.
+pub type Scrape = oneshot::Sender<String>;
+
+/// The metrics for `stats`, with each of our `rooms` and the peers known
+/// to be in it.
+pub fn render(stats: &Stats, rooms: &[(String, usize)]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    };
+    metric("peardchat_connected_peers", "gauge", "Peers we are connected to.", stats.peers as u64);
+    metric("peardchat_messages_received_total", "counter", "Chat messages shown to us.", stats.messages_received);
+    metric("peardchat_messages_sent_total", "counter", "Chat messages we published.", stats.messages_sent);
+    metric("peardchat_bytes_received_total", "counter", "Bytes received over every connection.", stats.bytes_received);
+    metric("peardchat_bytes_sent_total", "counter", "Bytes sent over every connection.", stats.bytes_sent);
+    metric("peardchat_uptime_seconds", "gauge", "Seconds since the node started.", stats.uptime.as_secs());
+    metric("peardchat_topics", "gauge", "Rooms we are subscribed to.", rooms.len() as u64);
+    out.push_str("# HELP peardchat_topic_peers Peers known to be subscribed to each room.\n");
+    out.push_str("# TYPE peardchat_topic_peers gauge\n");
+    for (room, peers) in rooms {
+        let _ = writeln!(out, "peardchat_topic_peers{{topic=\"{}\"}} {}", escape(room), peers);
+    }
+    out
+}
+
+/// `label` as a Prometheus label value, between the quotes.
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Listens on `addr` for scrapes.
+pub async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    TcpListener::bind(addr).await
+}
+
+/// Answers every request to `listener`, handing each scrape of `/metrics`
+/// to `scrapes`.
+pub async fn serve(listener: TcpListener, scrapes: mpsc::Sender<Scrape>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                tracing::debug!("Could not accept a metrics connection: {}", e);
+                continue;
+            }
+        };
+        let scrapes = scrapes.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, scrapes).await {
+                tracing::debug!("Could not answer a metrics request: {}", e);
+            }
+        });
+    }
+}
+
+/// Reads a request head from `stream`, or `None` if it closes first or the
+/// head is over [`MAX_REQUEST`].
+async fn read_head(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(head))
+}
+
+/// Reads one request from `stream` and answers it, closing the connection.
+async fn answer(mut stream: TcpStream, scrapes: mpsc::Sender<Scrape>) -> io::Result<()> {
+    // Slow clients would otherwise hold their connection open for good.
+    let head = match timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(Ok(Some(head))) => head,
+        Ok(Err(e)) => return Err(e),
+        Ok(Ok(None)) | Err(_) => return Ok(()),
+    };
+    let request_line = String::from_utf8_lossy(&head).lines().next().unwrap_or_default().to_string();
+    let mut parts = request_line.split(' ');
+    // A query string changes nothing.
+    let (method, path) = (parts.next(), parts.next().and_then(|target| target.split('?').next()));
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            let (reply, metrics) = oneshot::channel();
+            let metrics = match scrapes.send(reply).await {
+                Ok(()) => metrics.await.ok(),
+                Err(_) => None,
+            };
+            match metrics {
+                Some(metrics) => ("200 OK", metrics),
+                None => ("503 Service Unavailable", "The node is shutting down\n".to_string()),
+            }
+        }
+        (Some("GET"), _) => ("404 Not Found", "Metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body,
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn scrapes_get_the_rendered_stats() {
+        let stats = Stats {
+            bytes_received: 2048,
+            bytes_sent: 1024,
+            messages_received: 5,
+            messages_sent: 3,
+            peers: 2,
+            uptime: Duration::from_secs(90),
+        };
+        let listener = bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (scrapes, mut scraped) = mpsc::channel(1);
+        tokio::spawn(serve(listener, scrapes));
+        let rooms = [("chat".to_string(), 2), ("say \"hi\"".to_string(), 0)];
+        let expected = render(&stats, &rooms);
+        tokio::spawn(async move {
+            while let Some(reply) = scraped.recv().await {
+                let _ = reply.send(render(&stats, &rooms));
+            }
+        });
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&expected));
+        assert!(expected.contains("\npeardchat_connected_peers 2\n"));
+        assert!(expected.contains("\npeardchat_topic_peers{topic=\"say \\\"hi\\\"\"} 0\n"));
+        assert!(get("/metrics?format=text").await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404"));
+    }
+}
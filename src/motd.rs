@@ -0,0 +1,70 @@
+//! A message of the day, such as a welcome to the room, which a node set up
+//! with one hands to whoever asks. Nodes ask the peer they are told to, or
+//! their first bootstrap peer, once they first connect to it.
+
+use crate::backlog::{read_message, write_message};
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{core::upgrade::ProtocolName, request_response::RequestResponseCodec};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+/// Upper bound on an encoded message of the day.
+const MAX_MESSAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct MotdProtocol;
+
+impl ProtocolName for MotdProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/peardchat/motd/1.0.0"
+    }
+}
+
+/// Asks for the peer's message of the day.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MotdRequest;
+
+/// The peer's message of the day, `None` if it has none to give.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Motd(pub Option<String>);
+
+/// Encodes [`MotdRequest`]s and [`Motd`]s with bincode behind a length
+/// prefix.
+#[derive(Clone)]
+pub struct MotdCodec;
+
+#[async_trait]
+impl RequestResponseCodec for MotdCodec {
+    type Protocol = MotdProtocol;
+    type Request = MotdRequest;
+    type Response = Motd;
+
+    async fn read_request<T>(&mut self, _: &MotdProtocol, io: &mut T) -> io::Result<MotdRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, MAX_MESSAGE_SIZE).await
+    }
+
+    async fn read_response<T>(&mut self, _: &MotdProtocol, io: &mut T) -> io::Result<Motd>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io, MAX_MESSAGE_SIZE).await
+    }
+
+    async fn write_request<T>(&mut self, _: &MotdProtocol, io: &mut T, req: MotdRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &req).await
+    }
+
+    async fn write_response<T>(&mut self, _: &MotdProtocol, io: &mut T, res: Motd) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &res).await
+    }
+}
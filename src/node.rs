@@ -0,0 +1,1426 @@
+//! A running chat peer.
+
+use crate::{
+    behaviour::{MyBehaviour, ACTIVITY_WINDOW},
+    config::{Config, Muxer, PubSubProtocol, Security},
+    dm::DM_TOPIC,
+    message::{ChatMessage, MessageKind},
+    motd::MotdRequest,
+    peerstore::Peerstore,
+    roster::RosterEntry,
+    transfer::TransferEvent,
+};
+use chrono::{DateTime, Local};
+use libp2p::{
+    bandwidth::{BandwidthLogging, BandwidthSinks},
+    core::{
+        connection::{ConnectionError, PendingConnectionError},
+        muxing::StreamMuxerBox,
+        transport::{upgrade::Authenticated, Boxed, MemoryTransport, TransportError},
+        upgrade, ConnectedPoint,
+    },
+    gossipsub::{
+        self, error::{PublishError, SubscriptionError}, Gossipsub, GossipsubMessage, MessageAuthenticity, MessageId,
+        ValidationMode,
+    },
+    identify::{Identify, IdentifyConfig},
+    identity,
+    kad::{record::store::MemoryStore, Kademlia, KademliaConfig},
+    mdns::{Mdns, MdnsConfig},
+    mplex,
+    multiaddr::Protocol,
+    noise,
+    relay::{self, Relay, RelayConfig},
+    swarm::{DialError, NetworkBehaviour, SwarmBuilder, SwarmEvent},
+    // `TokioTcpConfig` is available through the `tcp-tokio` feature.
+    tcp::TokioTcpConfig,
+    websocket::WsConfig,
+    yamux, Multiaddr, PeerId, Swarm, Transport,
+};
+use serde::Serialize;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use futures::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+
+/// The protocol version we announce through identify.
+pub const PROTOCOL_VERSION: &str = "/peardchat/1.0.0";
+
+/// The client name and version we announce through identify, unless the
+/// config says otherwise.
+pub const AGENT_VERSION: &str = concat!("peardchat/", env!("CARGO_PKG_VERSION"));
+
+/// Something that happened on the network.
+///
+/// Serializes as `{"event": "message_received", "data": {...}}`, with
+/// PeerIds as strings.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum ChatEvent {
+    /// A chat message arrived on one of our topics.
+    MessageReceived {
+        topic: String,
+        /// The author, if the message says who it is from.
+        #[serde(serialize_with = "crate::serde_peer::serialize_option")]
+        source: Option<PeerId>,
+        /// Whether `source` signed the message. If not, anyone could have
+        /// sent it.
+        authenticated: bool,
+        message: ChatMessage,
+    },
+    /// A chat message sent on one of our topics before we joined it, which
+    /// a history provider replayed to us. Fields as in `MessageReceived`.
+    History {
+        topic: String,
+        #[serde(serialize_with = "crate::serde_peer::serialize_option")]
+        source: Option<PeerId>,
+        authenticated: bool,
+        message: ChatMessage,
+    },
+    /// A peer acknowledged a message on one of our topics. Every receiver
+    /// acks every message, so compare `id` against the ones we sent.
+    Delivered {
+        topic: String,
+        /// The `id` of the acknowledged [`ChatMessage`].
+        id: u64,
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        /// The peer's nickname, if it has told us.
+        nick: Option<String>,
+    },
+    /// A peer put one of our topics in slow mode, or out of it for an
+    /// `interval_secs` of 0.
+    SlowMode {
+        topic: String,
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        nick: Option<String>,
+        interval_secs: u64,
+    },
+    /// A chat message was dropped for coming from `peer` sooner after its
+    /// last one than slow mode allows. Every peer in the topic drops it.
+    SlowModeDropped {
+        topic: String,
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        nick: Option<String>,
+        interval_secs: u64,
+    },
+    /// The peer we asked for a message of the day sent it, to be shown on
+    /// startup.
+    Motd {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        text: String,
+    },
+    /// A peer started or stopped writing a message on one of our topics.
+    /// Peers repeat that they are typing every few seconds, so forget it if
+    /// they go quiet.
+    Typing {
+        topic: String,
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        nick: Option<String>,
+        typing: bool,
+    },
+    /// A peer told us it changed its nickname from `old`, if we or it knew
+    /// of one, to `new`.
+    NickChanged {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        old: Option<String>,
+        new: String,
+    },
+    /// A peer reacted with `emoji` to the chat message with the id `to` on
+    /// one of our topics, which we may not have seen.
+    Reaction {
+        topic: String,
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        /// The peer's nickname, if it has told us.
+        nick: Option<String>,
+        to: u64,
+        emoji: String,
+    },
+    /// A peer we are connected to went idle, or came back.
+    PresenceChanged {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        nick: Option<String>,
+        away: bool,
+    },
+    /// A peer went by a nickname that other peers go by too. Reported once
+    /// per peer and nickname; [`ChatNode::display_name`] tells them apart.
+    NickCollision {
+        nick: String,
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        /// The peers that had the nickname already.
+        #[serde(serialize_with = "crate::serde_peer::serialize_all")]
+        others: Vec<PeerId>,
+    },
+    /// Someone sent us an end-to-end encrypted direct message.
+    DirectMessage {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        source: PeerId,
+        message: ChatMessage,
+        /// Whether it was ratcheted, so that our identity key getting out
+        /// later would not open it.
+        forward_secret: bool,
+    },
+    /// A peer sent a message in a format older than `min_version`, so what
+    /// it sends is dropped. Reported once per peer.
+    OutdatedPeer {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        /// The [`WIRE_VERSION`](crate::message::WIRE_VERSION) the peer
+        /// sends, and the lowest we accept.
+        version: u32,
+        min_version: u32,
+        /// What the peer told identify it runs, if it has.
+        agent_version: Option<String>,
+    },
+    /// A peer told us, through identify, what it runs and where it listens.
+    PeerIdentified {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        agent_version: String,
+        protocol_version: String,
+        listen_addrs: Vec<Multiaddr>,
+    },
+    /// mDNS found a peer on the local network.
+    PeerDiscovered(#[serde(serialize_with = "crate::serde_peer::serialize")] PeerId),
+    /// mDNS no longer sees a peer.
+    PeerExpired(#[serde(serialize_with = "crate::serde_peer::serialize")] PeerId),
+    /// A peer came online: we connected to it or mDNS found it, whichever
+    /// happened first.
+    PeerJoined {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        /// The peer's nickname, if it has told us.
+        nick: Option<String>,
+    },
+    /// A peer went offline: our last connection to it closed, or mDNS lost
+    /// it before we ever connected.
+    PeerLeft {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        nick: Option<String>,
+    },
+    /// We are now reachable on this address.
+    ListeningOn(Multiaddr),
+    /// Dialing an address given to [`ChatNode::dial`] failed.
+    DialFailed {
+        address: Multiaddr,
+        error: String,
+    },
+    /// Someone tried to connect to us but the connection could not be set
+    /// up.
+    IncomingFailed {
+        /// Where the connection came from.
+        address: Multiaddr,
+        error: String,
+    },
+    /// One of our listeners ran into an error, or stopped because of one.
+    ListenerFailed {
+        /// The addresses the listener had been listening on, if it stopped.
+        addresses: Vec<Multiaddr>,
+        error: String,
+    },
+    /// Part of the network could not be set up, such as when no network
+    /// interface is up. Chat keeps working as far as it can, and the node
+    /// tries again every so often.
+    NetworkUnavailable { error: String },
+    /// Everything that failed before has now been set up.
+    NetworkRestored,
+    /// A file transfer made progress.
+    Transfer(TransferEvent),
+}
+
+/// What we know about a connected peer.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    /// Nickname from the last message the peer sent.
+    pub nick: Option<String>,
+    /// The client the peer identified itself as.
+    pub agent_version: Option<String>,
+    /// Remote addresses of our connections to the peer, and how each one
+    /// reaches it.
+    pub connected_addrs: Vec<(Multiaddr, ConnectionPath)>,
+    /// Addresses mDNS saw the peer listening on.
+    pub discovered_addrs: Vec<Multiaddr>,
+    /// Addresses the Kademlia routing table holds for the peer.
+    pub routing_addrs: Vec<Multiaddr>,
+    /// When the peer's latest heartbeat arrived.
+    pub last_heartbeat: Option<std::time::Instant>,
+    /// Whether the peer said it is away.
+    pub away: bool,
+    /// Round-trip time of the latest ping the peer answered.
+    pub rtt: Option<Duration>,
+    /// Whether mDNS currently sees the peer.
+    pub mdns_visible: bool,
+    /// Whether gossipsub or floodsub, whichever we use, counts the peer among
+    /// its peers, whatever their topics.
+    pub pubsub_visible: bool,
+    /// Which of our topics the peer is known to be subscribed to.
+    pub topics: Vec<String>,
+}
+
+/// A peer mDNS or Kademlia found that we are not connected to.
+#[derive(Debug, Clone)]
+pub struct DiscoveredPeer {
+    pub peer_id: PeerId,
+    /// Nickname from the last message the peer sent, if it ever did.
+    pub nick: Option<String>,
+    /// Addresses mDNS saw the peer listening on and the Kademlia routing
+    /// table holds for it, which dialing it tries.
+    pub addrs: Vec<Multiaddr>,
+    /// Whether mDNS currently sees the peer, rather than only Kademlia.
+    pub mdns_visible: bool,
+}
+
+/// How much a [`ChatNode`] has sent and received since it started.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Bytes over every connection, including everything besides chat.
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Chat messages and actions shown to us, and those we published.
+    pub messages_received: u64,
+    pub messages_sent: u64,
+    /// Peers we are connected to.
+    pub peers: usize,
+    pub uptime: Duration,
+}
+
+/// How busy one of our topics has been since starting, or since
+/// [`ChatNode::reset_topic_stats`], counting our own chat messages too.
+#[derive(Debug, Clone)]
+pub struct TopicStats {
+    pub topic: String,
+    /// Chat messages in the last minute.
+    pub per_minute: usize,
+    pub total: u64,
+    /// How many peers sent them, as far as we know who did.
+    pub senders: usize,
+    /// When the latest was seen.
+    pub last_activity: Option<DateTime<Local>>,
+}
+
+/// Whether a connection goes straight to the peer or through a relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionPath {
+    Direct,
+    Relayed,
+}
+
+impl ConnectionPath {
+    fn of(endpoint: &ConnectedPoint) -> Self {
+        // We dial through relays by `/p2p-circuit` addresses, and others
+        // reach us through the `/p2p-circuit` addresses we listen on.
+        let addr = match endpoint {
+            ConnectedPoint::Dialer { address } => address,
+            ConnectedPoint::Listener { local_addr, .. } => local_addr,
+        };
+        if addr.iter().any(|p| p == Protocol::P2pCircuit) {
+            ConnectionPath::Relayed
+        } else {
+            ConnectionPath::Direct
+        }
+    }
+}
+
+/// Why [`ChatNode::send_confirmed`] could not confirm a message arrived.
+#[derive(Debug)]
+pub enum Unconfirmed {
+    /// Nobody joined the topic in time, so it was not sent.
+    NoPeers,
+    /// Publishing failed.
+    Publish(PublishError),
+    /// Sent, but nobody acknowledged it in time.
+    NoAck,
+}
+
+impl fmt::Display for Unconfirmed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unconfirmed::NoPeers => f.write_str("nobody joined the topic in time"),
+            Unconfirmed::Publish(e) => write!(f, "Publish error: {:?}", e),
+            Unconfirmed::NoAck => f.write_str("nobody acknowledged it in time"),
+        }
+    }
+}
+
+impl Error for Unconfirmed {}
+
+impl fmt::Display for ConnectionPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionPath::Direct => "direct",
+            ConnectionPath::Relayed => "relayed",
+        })
+    }
+}
+
+/// Splits the trailing `/p2p/<peer id>` off an address.
+pub fn split_peer_id(mut addr: Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    match addr.pop() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok().map(|peer| (peer, addr)),
+        _ => None,
+    }
+}
+
+/// Why a connection could not be set up, in brief: the innermost cause,
+/// since the layers wrapped around it only repeat that it failed.
+fn connection_error(error: &PendingConnectionError<io::Error>) -> String {
+    if let PendingConnectionError::Transport(TransportError::MultiaddrNotSupported(_)) = error {
+        return "no transport for this kind of address".to_string();
+    }
+    innermost_cause(error)
+}
+
+/// Why listening on an address failed, in brief, like [`connection_error`].
+fn listen_error(error: TransportError<io::Error>) -> String {
+    if let TransportError::MultiaddrNotSupported(_) = error {
+        return "no transport for this kind of address".to_string();
+    }
+    innermost_cause(&error)
+}
+
+fn innermost_cause(error: &dyn Error) -> String {
+    let mut cause = error;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+async fn start_mdns(query_interval: Duration) -> io::Result<Mdns> {
+    Mdns::new(MdnsConfig { query_interval, ..Default::default() }).await
+}
+
+/// How long to wait before the first redial of a lost bootstrap peer.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest we ever wait between redials of a bootstrap peer.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long to wait before trying again to set up what failed for want of
+/// a network.
+const NETWORK_RETRY: Duration = Duration::from_secs(30);
+
+/// What could not be set up for want of a network, waiting to be retried.
+#[derive(Default)]
+struct Offline {
+    /// Addresses we could not listen on.
+    listen: Vec<Multiaddr>,
+    /// Query interval for mDNS, if it could not be started.
+    mdns: Option<Duration>,
+    next_attempt: Option<Instant>,
+}
+
+impl Offline {
+    fn is_empty(&self) -> bool {
+        self.listen.is_empty() && self.mdns.is_none()
+    }
+}
+
+/// Redial state for a bootstrap peer we are not connected to.
+struct Backoff {
+    /// When to dial next, or `None` while a dial is in flight.
+    next_attempt: Option<Instant>,
+    /// The wait before `next_attempt`, doubled after every failure.
+    delay: Duration,
+}
+
+/// A chat peer: a libp2p swarm plus the bookkeeping around it.
+///
+/// Nothing happens on the network unless [`ChatNode::poll_event`] is being
+/// awaited, so keep calling it in a loop.
+pub struct ChatNode {
+    swarm: Swarm<MyBehaviour>,
+    /// Remote addresses of every connection we currently have, per peer.
+    connections: HashMap<PeerId, HashMap<Multiaddr, ConnectionPath>>,
+    /// Configured bootstrap peers, which we redial whenever we lose them.
+    /// Only addresses ending in `/p2p/<peer id>` can be tracked.
+    bootstrap: HashSet<PeerId>,
+    redials: HashMap<PeerId, Backoff>,
+    /// Peers we closed every connection to with [`ChatNode::disconnect`],
+    /// until the last of them has closed.
+    hung_up: HashSet<PeerId>,
+    /// The peer to ask for a message of the day once we connect to it,
+    /// until we have.
+    motd_from: Option<PeerId>,
+    /// Where the peers we have seen were reached, if we remember them.
+    peerstore: Option<Peerstore>,
+    /// Relays we listen through, whose connections are never pruned.
+    relays: HashSet<PeerId>,
+    /// Most established connections we keep open, incoming and outgoing.
+    max_incoming: Option<u32>,
+    max_outgoing: Option<u32>,
+    /// Peers we have announced with [`ChatEvent::PeerJoined`].
+    present: HashSet<PeerId>,
+    /// Bytes that went over the transport, either way.
+    bandwidth: Arc<BandwidthSinks>,
+    started: Instant,
+    /// Chat messages published with [`ChatNode::send`].
+    messages_sent: u64,
+    /// What is yet to be set up once there is a network.
+    offline: Offline,
+    /// Events to hand out before polling the swarm again.
+    events: VecDeque<ChatEvent>,
+}
+
+/// What a [`ChatNode`] runs over: any transport that yields authenticated,
+/// multiplexed connections.
+pub type ChatTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+/// The default transport: TCP, or WebSocket over TCP for `/ws` addresses as
+/// browsers need, secured as `security` says and multiplexed as `muxer` says,
+/// which can also reach peers through `/p2p-circuit` relay addresses.
+/// Connections not set up within `timeout`, either way, are given up on.
+/// The returned [`Relay`] behaviour drives the relaying and must go to the
+/// same node, along with the [`BandwidthSinks`] counting the bytes that go
+/// over the transport.
+pub fn tcp_transport(
+    id_keys: &identity::Keypair,
+    relay_config: RelayConfig,
+    security: Security,
+    muxer: Muxer,
+    timeout: Duration,
+) -> (ChatTransport, Relay, Arc<BandwidthSinks>) {
+    // Create a tokio-based TCP transport, upgraded with the chosen
+    // handshake for authenticated encryption and the chosen muxer for
+    // substreams on each connection.
+    let tcp = TokioTcpConfig::new().nodelay(true);
+    upgrade_transport(id_keys, relay_config, security, muxer, timeout, WsConfig::new(tcp.clone()).or_transport(tcp))
+}
+
+/// Like [`tcp_transport`], but connecting only to nodes in the same process
+/// through `/memory/<port>` addresses, which is all tests need.
+pub fn memory_transport(id_keys: &identity::Keypair) -> (ChatTransport, Relay, Arc<BandwidthSinks>) {
+    let config = Config::default();
+    upgrade_transport(id_keys, RelayConfig::default(), config.security, config.muxer, config.dial_timeout(), MemoryTransport)
+}
+
+fn upgrade_transport<T>(
+    id_keys: &identity::Keypair,
+    relay_config: RelayConfig,
+    security: Security,
+    muxer: Muxer,
+    timeout: Duration,
+    base: T,
+) -> (ChatTransport, Relay, Arc<BandwidthSinks>)
+where
+    T: Transport + Clone + Send + Sync + 'static,
+    T::Output: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Listener: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Dial: Send + 'static,
+{
+    // Create a keypair for authenticated encryption of the transport.
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(id_keys)
+        .expect("Signing libp2p-noise static DH keypair failed.");
+
+    // Bytes are counted as they go over the wire, so relayed connections
+    // count once, as part of the connection to the relay.
+    let (base, bandwidth) = BandwidthLogging::new(base);
+    // Relayed connections get the same treatment on top of the relay's,
+    // which Mplex stalls on, so it is only a fallback.
+    let (relay_transport, relay) = relay::new_transport_and_behaviour(relay_config, base);
+    let upgraded = relay_transport.upgrade(upgrade::Version::V1);
+    // Each handshake is a type of its own, so the rest is built for each.
+    let transport = match security {
+        Security::NoiseXx => multiplex(upgraded.authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated()), muxer, timeout),
+        Security::NoiseIx => multiplex(upgraded.authenticate(noise::NoiseConfig::ix(noise_keys).into_authenticated()), muxer, timeout),
+    };
+    (transport, relay, bandwidth)
+}
+
+/// Multiplexes `authenticated` connections as `muxer` says, giving up on
+/// those not set up within `timeout`.
+fn multiplex<T, C>(authenticated: Authenticated<T>, muxer: Muxer, timeout: Duration) -> ChatTransport
+where
+    T: Transport<Output = (PeerId, C)> + Clone + Send + Sync + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Listener: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Dial: Send + 'static,
+    C: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match muxer {
+        Muxer::Yamux => authenticated.multiplex(yamux::YamuxConfig::default()).timeout(timeout).boxed(),
+        Muxer::Mplex => authenticated.multiplex(mplex::MplexConfig::new()).timeout(timeout).boxed(),
+        Muxer::Both => authenticated
+            .multiplex(upgrade::SelectUpgrade::new(yamux::YamuxConfig::default(), mplex::MplexConfig::new()))
+            .timeout(timeout)
+            .boxed(),
+    }
+}
+
+/// How a node relays for others over circuit relay v1, as `config` says.
+pub fn relay_config(config: &Config) -> RelayConfig {
+    if config.relay_server {
+        RelayConfig {
+            // Peers listening through us should not have to keep reconnecting.
+            connection_idle_timeout: config.idle_timeout().max(Duration::from_secs(10 * 60)),
+            actively_connect_to_dst_nodes: true,
+        }
+    } else {
+        RelayConfig { connection_idle_timeout: config.idle_timeout(), ..RelayConfig::default() }
+    }
+}
+
+impl ChatNode {
+    /// Starts a node with the given identity over [`tcp_transport`],
+    /// listening and dialing as `config` says.
+    pub async fn new(id_keys: identity::Keypair, config: &Config) -> Result<Self, Box<dyn Error>> {
+        let (transport, relay, bandwidth) = tcp_transport(&id_keys, relay_config(config), config.security, config.muxer, config.dial_timeout());
+        let listen_addr = config.listen_addr()?;
+        let mut node = Self::with_transport(id_keys, config, transport, relay, bandwidth, listen_addr).await?;
+        if let Some(addr) = config.ipv6_listen_addr() {
+            node.listen_or_retry(addr);
+        }
+        if let Some(addr) = config.ws_listen_addr() {
+            node.listen_or_retry(addr);
+        }
+        Ok(node)
+    }
+
+    /// Like [`ChatNode::new`], but over `transport`, with `relay` and
+    /// `bandwidth` being what came with it, and listening on `listen_addr` in
+    /// place of the configured address. Nothing else about the node depends
+    /// on how connections are made.
+    ///
+    /// Failing to listen or to start mDNS does not fail the node: it reports
+    /// [`ChatEvent::NetworkUnavailable`] and keeps trying.
+    pub async fn with_transport(
+        id_keys: identity::Keypair,
+        config: &Config,
+        transport: ChatTransport,
+        relay: Relay,
+        bandwidth: Arc<BandwidthSinks>,
+        listen_addr: Multiaddr,
+    ) -> Result<Self, Box<dyn Error>> {
+        let peer_id = PeerId::from(id_keys.public());
+        let identify_config = IdentifyConfig::new(PROTOCOL_VERSION.into(), id_keys.public())
+            .with_agent_version(config.agent_version.clone());
+
+        // Messages are identified by their author, sequence number and contents,
+        // so the same message arriving over several mesh links is only seen once.
+        let message_id_fn = |message: &GossipsubMessage| {
+            let mut s = DefaultHasher::new();
+            message.source.hash(&mut s);
+            message.sequence_number.hash(&mut s);
+            message.data.hash(&mut s);
+            MessageId::from(s.finish().to_string())
+        };
+
+        let gossipsub_config = gossipsub::GossipsubConfigBuilder::default()
+            .mesh_n_low(4)
+            .mesh_n(6)
+            .mesh_n_high(12)
+            .heartbeat_interval(Duration::from_secs(1))
+            .duplicate_cache_time(config.dedup_window())
+            .idle_timeout(config.idle_timeout())
+            // Gossipsub drops messages not signed by their `source`, which
+            // direct messages rely on. Chat messages carry a signature of
+            // their own as well, bound to the topic.
+            .validation_mode(ValidationMode::Strict)
+            .message_id_fn(message_id_fn)
+            .build()
+            .expect("Valid gossipsub config");
+
+        let mut offline = Offline::default();
+        let mut mdns_error = None;
+        let mdns = if config.mdns {
+            match start_mdns(config.mdns_query_interval()).await {
+                Ok(mdns) => Some(mdns),
+                Err(e) => {
+                    offline.mdns = Some(config.mdns_query_interval());
+                    mdns_error = Some(format!("Could not start mDNS: {}", e));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        // Create a Swarm to manage peers and events.
+        let mut swarm = {
+            let gossipsub = match config.pubsub {
+                PubSubProtocol::Gossipsub => {
+                    Some(Gossipsub::new(MessageAuthenticity::Signed(id_keys.clone()), gossipsub_config)?)
+                }
+                PubSubProtocol::Floodsub => None,
+            };
+            let behaviour = MyBehaviour::new(
+                gossipsub,
+                mdns.into(),
+                Kademlia::with_config(
+                    peer_id,
+                    MemoryStore::new(peer_id),
+                    KademliaConfig::default().set_connection_idle_timeout(config.idle_timeout()).clone(),
+                ),
+                Identify::new(identify_config),
+                relay,
+                id_keys,
+                config,
+            )
+            .map_err(|e| e.to_string())?;
+
+            SwarmBuilder::new(transport, behaviour, peer_id)
+                // We want the connection background tasks to be spawned
+                // onto the tokio runtime.
+                .executor(Box::new(|fut| { tokio::spawn(fut); }))
+                .connection_limits(config.connection_limits())
+                .build()
+        };
+
+        // Listening through a relay connects to it and waits for it to pass
+        // on connections meant for us.
+        for relay in &config.relays {
+            let circuit = relay.clone().with(Protocol::P2pCircuit);
+            if let Err(e) = swarm.listen_on(circuit.clone()) {
+                tracing::warn!("Could not listen on {}: {}", circuit, e);
+            }
+        }
+
+        let peerstore = config.remember_peers.then(|| Peerstore::load(config.peerstore.clone())).transpose()?;
+
+        // Direct messages to us could arrive on this topic at any time.
+        swarm.behaviour_mut().pubsub_mut().subscribe(DM_TOPIC)
+            .map_err(|e| format!("Could not subscribe to direct messages: {:?}", e))?;
+
+        let mut node = ChatNode {
+            swarm,
+            connections: HashMap::new(),
+            bootstrap: config.bootstrap.iter().filter_map(|a| split_peer_id(a.clone())).map(|(p, _)| p).collect(),
+            redials: HashMap::new(),
+            hung_up: HashSet::new(),
+            motd_from: config.motd_source(),
+            peerstore,
+            relays: config.relays.iter().filter_map(|a| split_peer_id(a.clone())).map(|(p, _)| p).collect(),
+            max_incoming: config.max_incoming_connections(),
+            max_outgoing: config.max_outgoing_connections(),
+            present: HashSet::new(),
+            bandwidth,
+            started: Instant::now(),
+            messages_sent: 0,
+            offline,
+            events: VecDeque::new(),
+        };
+        node.listen_or_retry(listen_addr);
+        if let Some(error) = mdns_error {
+            node.went_offline(error);
+        }
+        for addr in &config.bootstrap {
+            if let Err(e) = node.dial(addr.clone()) {
+                tracing::warn!("Could not dial {}: {}", addr, e);
+            }
+        }
+        node.dial_remembered();
+
+        // Join the DHT; results arrive as `KademliaEvent`s.
+        if let Err(e) = node.swarm.behaviour_mut().kademlia.bootstrap() {
+            tracing::info!("Kademlia bootstrap skipped: {:?}", e);
+        }
+
+        Ok(node)
+    }
+
+    /// Dials the peers in the peerstore, by way of the Kademlia routing
+    /// table, which they are added to. What fails only counts against them
+    /// in the peerstore.
+    fn dial_remembered(&mut self) {
+        let remembered: Vec<_> = match &self.peerstore {
+            Some(store) => store.peers().map(|(peer, addrs)| (*peer, addrs.to_vec())).collect(),
+            None => return,
+        };
+        for (peer, addrs) in remembered {
+            if peer == *self.local_peer_id() || self.bootstrap.contains(&peer) || self.is_blocked(&peer) {
+                continue;
+            }
+            for addr in addrs {
+                self.swarm.behaviour_mut().kademlia.add_address(&peer, addr);
+            }
+            tracing::debug!(%peer, "Dialing a remembered peer");
+            if let Err(e) = self.swarm.dial(&peer) {
+                tracing::debug!(%peer, "Could not dial a remembered peer: {:?}", e);
+            }
+        }
+    }
+
+    /// Applies `change` to the peerstore, if we keep one, logging whatever
+    /// keeps it from being saved.
+    fn update_peerstore<T>(&mut self, change: impl FnOnce(&mut Peerstore) -> io::Result<T>) -> Option<T> {
+        let result = change(self.peerstore.as_mut()?);
+        result.map_err(|e| tracing::warn!("Could not save the peerstore: {}", e)).ok()
+    }
+
+    /// Listens on `addr`, or if that fails, goes offline and tries again
+    /// later.
+    fn listen_or_retry(&mut self, addr: Multiaddr) {
+        if let Err(e) = self.swarm.listen_on(addr.clone()) {
+            let error = format!("Could not listen on {}: {}", addr, listen_error(e));
+            self.offline.listen.push(addr);
+            self.went_offline(error);
+        }
+    }
+
+    /// Reports that part of the network is down, the first time it is,
+    /// and schedules another attempt.
+    fn went_offline(&mut self, error: String) {
+        if self.offline.next_attempt.is_none() {
+            self.offline.next_attempt = Some(Instant::now() + NETWORK_RETRY);
+            self.events.push_back(ChatEvent::NetworkUnavailable { error });
+        }
+    }
+
+    /// Tries again to set up what failed before.
+    async fn retry_network(&mut self) {
+        for addr in std::mem::take(&mut self.offline.listen) {
+            if let Err(e) = self.swarm.listen_on(addr.clone()) {
+                tracing::debug!(%addr, "Still cannot listen: {}", listen_error(e));
+                self.offline.listen.push(addr);
+            }
+        }
+        if let Some(query_interval) = self.offline.mdns {
+            match start_mdns(query_interval).await {
+                Ok(mdns) => {
+                    self.swarm.behaviour_mut().mdns = Some(mdns).into();
+                    self.offline.mdns = None;
+                }
+                Err(e) => tracing::debug!("Still cannot start mDNS: {}", e),
+            }
+        }
+        if self.offline.is_empty() {
+            tracing::info!("Network set up after all");
+            self.offline.next_attempt = None;
+            self.events.push_back(ChatEvent::NetworkRestored);
+        } else {
+            self.offline.next_attempt = Some(Instant::now() + NETWORK_RETRY);
+        }
+    }
+
+    pub fn local_peer_id(&self) -> &PeerId {
+        self.swarm.local_peer_id()
+    }
+
+    /// The addresses we listen on, each ending in our `/p2p/` PeerId so that
+    /// others can dial them as they are.
+    pub fn listen_addrs(&self) -> Vec<Multiaddr> {
+        let peer = *self.local_peer_id();
+        self.swarm.listeners().map(|a| a.clone().with(Protocol::P2p(peer.into()))).collect()
+    }
+
+    /// Addresses other peers have seen us connect from, which may get
+    /// through a NAT that our listen addresses don't, ending in our PeerId.
+    /// They come from identify, and nothing checks that they can be dialed.
+    pub fn external_addrs(&self) -> Vec<Multiaddr> {
+        let peer = *self.local_peer_id();
+        self.swarm.external_addresses().map(|r| r.addr.clone().with(Protocol::P2p(peer.into()))).collect()
+    }
+
+    /// Dials `addr`. If it ends in a `/p2p/` PeerId, the peer is also added
+    /// to the Kademlia routing table.
+    pub fn dial(&mut self, addr: Multiaddr) -> Result<(), Box<dyn Error>> {
+        if let Some((peer, addr)) = split_peer_id(addr.clone()) {
+            self.swarm.behaviour_mut().kademlia.add_address(&peer, addr);
+        }
+        self.swarm.dial_addr(addr)?;
+        Ok(())
+    }
+
+    /// Dials `peer` at every address discovery found for it.
+    pub fn dial_peer(&mut self, peer: &PeerId) -> Result<(), DialError> {
+        self.swarm.dial(peer)
+    }
+
+    /// The peers mDNS or Kademlia found that we are not connected to, by
+    /// PeerId, leaving out those the blocklist or allowlist keeps out.
+    pub fn discovered(&mut self) -> Vec<DiscoveredPeer> {
+        let local = *self.local_peer_id();
+        let connections = &self.connections;
+        let behaviour = self.swarm.behaviour_mut();
+        let mut peers: Vec<PeerId> = behaviour.kademlia.kbuckets()
+            .flat_map(|bucket| bucket.iter().map(|entry| *entry.node.key.preimage()).collect::<Vec<_>>())
+            .collect();
+        peers.extend(behaviour.mdns.as_ref().into_iter().flat_map(|m| m.discovered_nodes().copied()));
+        peers.retain(|peer| *peer != local && !connections.contains_key(peer) && !behaviour.blocker.refuses(peer));
+        peers.sort();
+        peers.dedup();
+        peers.into_iter()
+            .map(|peer| {
+                let mut addrs = behaviour.mdns.addresses_of_peer(&peer);
+                for addr in behaviour.kademlia.addresses_of_peer(&peer) {
+                    if !addrs.contains(&addr) {
+                        addrs.push(addr);
+                    }
+                }
+                DiscoveredPeer {
+                    peer_id: peer,
+                    nick: behaviour.nicks.get(&peer).cloned(),
+                    addrs,
+                    mdns_visible: behaviour.mdns.as_ref().is_some_and(|m| m.has_node(&peer)),
+                }
+            })
+            .collect()
+    }
+
+    /// Subscribes to `topic`, returning false if we already were.
+    pub fn join(&mut self, topic: &str) -> Result<bool, SubscriptionError> {
+        self.swarm.behaviour_mut().join(topic)
+    }
+
+    /// Unsubscribes from `topic`, returning false if we were not subscribed.
+    pub fn leave(&mut self, topic: &str) -> bool {
+        self.swarm.behaviour_mut().leave(topic)
+    }
+
+    /// Traffic and message counts since the node started.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            bytes_received: self.bandwidth.total_inbound(),
+            bytes_sent: self.bandwidth.total_outbound(),
+            messages_received: self.swarm.behaviour().messages_received,
+            messages_sent: self.messages_sent,
+            peers: self.connections.len(),
+            uptime: self.started.elapsed(),
+        }
+    }
+
+    /// How busy each of our topics has been, by name.
+    pub fn topic_stats(&self) -> Vec<TopicStats> {
+        let behaviour = self.swarm.behaviour();
+        let mut topics: Vec<_> = behaviour.pubsub().topics().into_iter().filter(|t| t != DM_TOPIC).collect();
+        topics.sort();
+        topics.into_iter()
+            .map(|topic| match behaviour.activity.get(&topic) {
+                Some(activity) => TopicStats {
+                    per_minute: activity.recent.iter().filter(|seen| seen.elapsed() < ACTIVITY_WINDOW).count(),
+                    total: activity.total,
+                    senders: activity.senders.len(),
+                    last_activity: activity.last,
+                    topic,
+                },
+                None => TopicStats { topic, per_minute: 0, total: 0, senders: 0, last_activity: None },
+            })
+            .collect()
+    }
+
+    /// Starts the counts behind [`ChatNode::topic_stats`] over.
+    pub fn reset_topic_stats(&mut self) {
+        self.swarm.behaviour_mut().activity.reset();
+    }
+
+    /// `nick` as `peer` should be shown: as it is, unless another peer goes
+    /// by it too, in which case the PeerId tells them apart, as in
+    /// `alice(12D3..abcd)`.
+    pub fn display_name(&self, peer: &PeerId, nick: &str) -> String {
+        let taken = self.swarm.behaviour().nicks.iter().any(|(p, n)| p != peer && n == nick);
+        if !taken {
+            return nick.to_string();
+        }
+        let id = peer.to_string();
+        format!("{}({}..{})", nick, &id[..4], &id[id.len() - 4..])
+    }
+
+    /// How many peers we know to be subscribed to `topic`.
+    pub fn topic_peer_count(&self, topic: &str) -> usize {
+        self.swarm.behaviour().pubsub().peers().iter().filter(|(_, topics)| topics.iter().any(|t| t == topic)).count()
+    }
+
+    /// Advances our Lamport clock for a chat message about to be sent, and
+    /// returns the time to put in its `clock`.
+    pub fn tick(&mut self) -> u64 {
+        let clock = &mut self.swarm.behaviour_mut().clock;
+        *clock = clock.saturating_add(1);
+        *clock
+    }
+
+    /// Publishes `message` on `topic`.
+    pub async fn send(&mut self, topic: &str, message: ChatMessage) -> Result<(), PublishError> {
+        self.swarm.behaviour_mut().publish(topic, &message)?;
+        if let MessageKind::Chat | MessageKind::Action = message.kind {
+            self.messages_sent += 1;
+        }
+        tracing::info!(topic, id = ?message.id, "Chat message published");
+        Ok(())
+    }
+
+    /// Publishes `message` on `topic` as soon as a peer there can get it,
+    /// and waits for one to acknowledge it, returning who did. Gives up
+    /// after `wait` in all. Other events that come in meanwhile are dropped,
+    /// so this is for sending one message and leaving.
+    pub async fn send_confirmed(
+        &mut self,
+        topic: &str,
+        message: ChatMessage,
+        wait: Duration,
+    ) -> Result<(PeerId, Option<String>), Unconfirmed> {
+        let deadline = tokio::time::Instant::now() + wait;
+        let joined = async {
+            while self.topic_peer_count(topic) == 0 {
+                self.poll_event().await;
+            }
+        };
+        tokio::time::timeout_at(deadline, joined).await.map_err(|_| Unconfirmed::NoPeers)?;
+        let message = ChatMessage { clock: Some(self.tick()), ..message };
+        let id = message.id;
+        self.send(topic, message).await.map_err(Unconfirmed::Publish)?;
+        let acked = async {
+            loop {
+                if let ChatEvent::Delivered { id: acked, peer, nick, .. } = self.poll_event().await {
+                    if Some(acked) == id {
+                        return (peer, nick);
+                    }
+                }
+            }
+        };
+        tokio::time::timeout_at(deadline, acked).await.map_err(|_| Unconfirmed::NoAck)
+    }
+
+    /// Tells the peers in every topic we are in that we went by `old` and
+    /// now go by `new`. Topics nobody else is in are skipped.
+    pub fn announce_nick(&mut self, old: Option<String>, new: &str) {
+        let announcement = ChatMessage::nick_change(old, new.to_string());
+        let behaviour = self.swarm.behaviour_mut();
+        let topics: Vec<_> = behaviour.pubsub().topics().into_iter().filter(|t| t != DM_TOPIC).collect();
+        for topic in topics {
+            if let Err(e) = behaviour.publish(&topic, &announcement) {
+                tracing::debug!("Could not announce our nickname to [{}]: {:?}", topic, e);
+            }
+        }
+    }
+
+    /// Puts `topic` in slow mode for everyone, allowing each peer one chat
+    /// message per `interval`, or takes it out for a zero `interval`.
+    /// Returns false, changing nothing, if someone else set it first. The
+    /// setting holds even if nobody else is there yet to hear it, in which
+    /// case this fails with [`PublishError::InsufficientPeers`] and peers
+    /// learn it as they join.
+    pub fn set_slowmode(&mut self, topic: &str, interval: Duration, nick: Option<String>) -> Result<bool, PublishError> {
+        let announcement = ChatMessage::slowmode(nick, interval.as_secs());
+        let me = *self.local_peer_id();
+        let behaviour = self.swarm.behaviour_mut();
+        if behaviour.slowmode.host(topic).is_some_and(|host| host != me) {
+            return Ok(false);
+        }
+        behaviour.slowmode.set(topic, me, announcement.clone());
+        behaviour.publish(topic, &announcement).map(|()| true)
+    }
+
+    /// Who may change slow mode on `topic`, having set it first.
+    pub fn slowmode_host(&self, topic: &str) -> Option<PeerId> {
+        self.swarm.behaviour().slowmode.host(topic)
+    }
+
+    /// How long each peer has to wait between chat messages on `topic`,
+    /// and who said so, if it is in slow mode.
+    pub fn slowmode(&self, topic: &str) -> Option<(Duration, PeerId)> {
+        self.swarm.behaviour().slowmode.get(topic).map(|s| (s.interval, s.set_by))
+    }
+
+    /// How much longer we have to wait before sending chat on `topic`, if
+    /// slow mode would have peers drop it now. [`ChatNode::send`] does not
+    /// hold anything back itself.
+    pub fn slowmode_wait(&self, topic: &str) -> Option<Duration> {
+        self.swarm.behaviour().slowmode.wait(topic, *self.local_peer_id())
+    }
+
+    /// Encrypts `message` to `peer` and publishes it, so that only they can
+    /// read it. Returns whether it is forward-secret.
+    pub async fn send_dm(&mut self, peer: &PeerId, message: ChatMessage) -> Result<bool, Box<dyn Error>> {
+        let behaviour = self.swarm.behaviour_mut();
+        let keys = behaviour.dm_keys.as_mut()
+            .ok_or("Direct messages need an ed25519 identity")?;
+        let (payload, forward_secret) = keys.seal(peer, &message)?;
+        behaviour.publish_dm(&payload)
+            .map_err(|e| format!("Publish error: {:?}", e))?;
+        Ok(forward_secret)
+    }
+
+    /// Offers the file at `path` to `peer`.
+    pub fn send_file(&mut self, peer: PeerId, path: &Path) -> io::Result<()> {
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.transfers.send_file(&mut behaviour.request_response, peer, path)
+    }
+
+    /// Accepts a [`TransferEvent::Offered`] file, returning false if there is
+    /// no such offer.
+    pub fn accept_file(&mut self, offer: u64) -> bool {
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.transfers.accept(&mut behaviour.request_response, offer)
+    }
+
+    /// Declines a [`TransferEvent::Offered`] file, returning false if there is
+    /// no such offer.
+    pub fn reject_file(&mut self, offer: u64) -> bool {
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.transfers.reject(&mut behaviour.request_response, offer)
+    }
+
+    /// Blocks `peer`, now and in later runs: its messages are dropped and
+    /// connections to and from it refused. Returns whether it was not
+    /// blocked already.
+    pub fn block(&mut self, peer: PeerId) -> io::Result<bool> {
+        let behaviour = self.swarm.behaviour_mut();
+        if !behaviour.blocker.block(peer)? {
+            return Ok(false);
+        }
+        behaviour.pubsub_mut().remove_peer(&peer);
+        behaviour.roster.remove(&peer);
+        self.redials.remove(&peer);
+        self.update_peerstore(|store| store.remove(&peer));
+        Ok(true)
+    }
+
+    /// Closes every connection to `peer` and stops passing chat on to it,
+    /// without blocking it: it may connect again, but we do not redial it
+    /// once these are closed. Returns whether we had any connection to it.
+    pub fn disconnect(&mut self, peer: &PeerId) -> bool {
+        let behaviour = self.swarm.behaviour_mut();
+        if !behaviour.blocker.disconnect(peer) {
+            return false;
+        }
+        behaviour.pubsub_mut().remove_peer(peer);
+        self.redials.remove(peer);
+        self.hung_up.insert(*peer);
+        true
+    }
+
+    pub fn is_blocked(&self, peer: &PeerId) -> bool {
+        self.swarm.behaviour().blocker.list.contains(peer)
+    }
+
+    /// Lifts a block and reconnects if we know where `peer` is. Returns
+    /// whether it was blocked.
+    pub fn unblock(&mut self, peer: &PeerId) -> io::Result<bool> {
+        if !self.swarm.behaviour_mut().blocker.list.remove(peer)? {
+            return Ok(false);
+        }
+        // Discovery ignored the peer while it was blocked and may not report
+        // it again for a while.
+        if let Err(e) = self.swarm.dial(peer) {
+            tracing::debug!(%peer, "Not redialing unblocked peer: {:?}", e);
+        }
+        Ok(true)
+    }
+
+    /// Every peer we are connected to.
+    pub fn peers(&mut self) -> Vec<PeerInfo> {
+        let peers: Vec<_> = self.connections.keys().copied().collect();
+        peers.iter().map(|peer| self.peer_info(peer)).collect()
+    }
+
+    /// Everything we know about `peer`, whether or not we are connected.
+    pub fn peer_info(&mut self, peer: &PeerId) -> PeerInfo {
+        let behaviour = self.swarm.behaviour_mut();
+        let pubsub_topics = behaviour.pubsub().peers().into_iter().find(|(p, _)| p == peer).map(|(_, topics)| topics);
+        PeerInfo {
+            peer_id: *peer,
+            nick: behaviour.nicks.get(peer).cloned(),
+            agent_version: behaviour.agents.get(peer).cloned(),
+            connected_addrs: self.connections.get(peer)
+                .map(|addrs| addrs.iter().map(|(addr, path)| (addr.clone(), *path)).collect())
+                .unwrap_or_default(),
+            discovered_addrs: behaviour.mdns.addresses_of_peer(peer),
+            routing_addrs: behaviour.kademlia.addresses_of_peer(peer),
+            last_heartbeat: behaviour.heartbeats.get(peer).copied(),
+            away: behaviour.roster.is_away(peer),
+            rtt: behaviour.rtts.get(peer).copied(),
+            mdns_visible: behaviour.mdns.as_ref().is_some_and(|m| m.has_node(peer)),
+            pubsub_visible: pubsub_topics.is_some(),
+            topics: pubsub_topics.unwrap_or_default(),
+        }
+    }
+
+    /// Every peer we have been connected to since starting, and whether it is
+    /// online, away or gone, in the order to show them.
+    pub fn roster(&self) -> Vec<RosterEntry> {
+        self.swarm.behaviour().roster.entries(std::time::Instant::now())
+    }
+
+    /// The nickname on `peer`'s last message, if it had one.
+    pub fn nick(&self, peer: &PeerId) -> Option<&str> {
+        self.swarm.behaviour().nicks.get(peer).map(String::as_str)
+    }
+
+    /// The peers whose last message carried the nickname `nick`.
+    pub fn peers_named(&self, nick: &str) -> Vec<PeerId> {
+        let mut peers: Vec<_> = self.swarm.behaviour().nicks.iter()
+            .filter(|(_, n)| *n == nick)
+            .map(|(peer, _)| *peer)
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// Once the connections in one direction reach their limit, closes the
+    /// oldest one to a peer we neither chat with, rely on as a relay nor
+    /// bootstrap from, so that the next one is not refused. `newest` has
+    /// only just connected, too soon to tell, and is left alone.
+    fn prune_connections(&mut self, newest: PeerId, dialer: bool) {
+        let counters = self.swarm.network_info().connection_counters().clone();
+        let (established, limit) = if dialer {
+            (counters.num_established_outgoing(), self.max_outgoing)
+        } else {
+            (counters.num_established_incoming(), self.max_incoming)
+        };
+        if limit.is_none_or(|limit| established < limit) {
+            return;
+        }
+        let behaviour = self.swarm.behaviour_mut();
+        // Everyone running peardchat is at least on the direct message topic.
+        let chatting: HashSet<PeerId> =
+            behaviour.pubsub().peers().into_iter().filter(|(_, topics)| !topics.is_empty()).map(|(p, _)| p).collect();
+        let (relays, bootstrap) = (&self.relays, &self.bootstrap);
+        let keep = |p: &PeerId| *p == newest || chatting.contains(p) || relays.contains(p) || bootstrap.contains(p);
+        match behaviour.blocker.prune_oldest(dialer, keep) {
+            Some(peer) => tracing::info!(%peer, established, "Closing the oldest idle connection, at the limit"),
+            None => tracing::info!(established, "At the connection limit with no idle connection to close"),
+        }
+    }
+
+    /// Schedules the next redial of `peer` if it is a bootstrap peer,
+    /// backing off exponentially while it stays unreachable.
+    fn schedule_redial(&mut self, peer: PeerId) {
+        // Hung up on, just this once.
+        if self.hung_up.remove(&peer) {
+            return;
+        }
+        if !self.bootstrap.contains(&peer) || self.connections.contains_key(&peer) || self.is_blocked(&peer) {
+            return;
+        }
+        // One dial can fail several times over; the first failure decides.
+        if matches!(self.redials.get(&peer), Some(Backoff { next_attempt: Some(_), .. })) {
+            return;
+        }
+        let delay = match self.redials.get(&peer) {
+            Some(backoff) => (backoff.delay * 2).min(MAX_BACKOFF),
+            None => INITIAL_BACKOFF,
+        };
+        tracing::info!(%peer, ?delay, "Redialing bootstrap peer");
+        self.redials.insert(peer, Backoff { next_attempt: Some(Instant::now() + delay), delay });
+    }
+
+    /// Dials every bootstrap peer whose backoff has run out.
+    fn redial_due(&mut self) {
+        let now = Instant::now();
+        let mut failed = Vec::new();
+        for (peer, backoff) in &mut self.redials {
+            if backoff.next_attempt.is_some_and(|at| at <= now) {
+                backoff.next_attempt = None;
+                // Kademlia knows the peer's address from when we first dialed it.
+                if let Err(e) = self.swarm.dial(peer) {
+                    tracing::debug!(%peer, "Redial failed: {}", e);
+                    failed.push(*peer);
+                }
+            }
+        }
+        for peer in failed {
+            self.schedule_redial(peer);
+        }
+    }
+
+    /// Queues a [`ChatEvent::PeerJoined`] or [`ChatEvent::PeerLeft`] if this
+    /// changes whether `peer` is online.
+    fn set_presence(&mut self, peer: PeerId, online: bool) {
+        // Blocked peers, and strangers to the allowlist, connect only to be
+        // cut off again.
+        if online && self.swarm.behaviour().blocker.refuses(&peer) {
+            return;
+        }
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.roster.set_connected(peer, online);
+        let nick = behaviour.nicks.get(&peer).cloned();
+        if let (true, Some(nick)) = (online, &nick) {
+            behaviour.roster.set_nick(&peer, nick);
+        }
+        if online && self.present.insert(peer) {
+            self.events.push_back(ChatEvent::PeerJoined { peer, nick });
+        } else if !online && self.present.remove(&peer) {
+            self.events.push_back(ChatEvent::PeerLeft { peer, nick });
+        }
+    }
+
+    /// Drives the node until something worth reporting happens.
+    pub async fn poll_event(&mut self) -> ChatEvent {
+        loop {
+            if let Some(event) = self.events.pop_front() {
+                return event;
+            }
+            if let Some(event) = self.swarm.behaviour_mut().next_event() {
+                match &event {
+                    ChatEvent::PeerDiscovered(peer) => {
+                        let addrs = self.swarm.behaviour_mut().mdns.addresses_of_peer(peer);
+                        self.update_peerstore(|store| store.add(*peer, addrs));
+                        self.set_presence(*peer, true)
+                    }
+                    // mDNS records outlive the peer, so a closed connection
+                    // has usually announced the departure already.
+                    ChatEvent::PeerExpired(peer) if !self.connections.contains_key(peer) => {
+                        self.set_presence(*peer, false)
+                    }
+                    ChatEvent::PeerIdentified { peer, listen_addrs, .. } => {
+                        self.update_peerstore(|store| store.add(*peer, listen_addrs.clone()));
+                    }
+                    _ => {}
+                }
+                return event;
+            }
+            let next_redial = self.redials.values().filter_map(|b| b.next_attempt).min();
+            let next_retry = self.offline.next_attempt;
+            let event = tokio::select! {
+                event = self.swarm.next_event() => event,
+                _ = tokio::time::sleep_until(next_redial.unwrap_or_else(Instant::now)), if next_redial.is_some() => {
+                    self.redial_due();
+                    continue;
+                }
+                _ = tokio::time::sleep_until(next_retry.unwrap_or_else(Instant::now)), if next_retry.is_some() => {
+                    self.retry_network().await;
+                    continue;
+                }
+            };
+            match event {
+                SwarmEvent::Behaviour(event) => self.swarm.behaviour_mut().handle(event),
+                SwarmEvent::NewListenAddr(addr) => {
+                    tracing::debug!(%addr, "Listening");
+                    return ChatEvent::ListeningOn(addr);
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established } => {
+                    tracing::debug!(%peer_id, ?endpoint, num_established, "Connection established");
+                    let path = ConnectionPath::of(&endpoint);
+                    let addrs = self.connections.entry(peer_id).or_default();
+                    if path == ConnectionPath::Direct && addrs.values().any(|p| *p == ConnectionPath::Relayed) {
+                        tracing::info!(%peer_id, "Connected directly to a peer we reached through a relay");
+                    }
+                    addrs.insert(endpoint.get_remote_address().clone(), path);
+                    // Back on good terms, so the next loss starts over from a short delay.
+                    self.redials.remove(&peer_id);
+                    if let ConnectedPoint::Dialer { address } = &endpoint {
+                        let address = split_peer_id(address.clone()).map_or_else(|| address.clone(), |(_, a)| a);
+                        self.update_peerstore(|store| store.add(peer_id, vec![address]));
+                    }
+                    self.update_peerstore(|store| store.connected(&peer_id));
+                    self.set_presence(peer_id, true);
+                    if self.motd_from == Some(peer_id) {
+                        self.motd_from = None;
+                        self.swarm.behaviour_mut().motd.send_request(&peer_id, MotdRequest);
+                    }
+                    self.prune_connections(peer_id, endpoint.is_dialer());
+                }
+                SwarmEvent::ConnectionClosed { peer_id, endpoint, num_established, cause } => {
+                    tracing::debug!(%peer_id, ?endpoint, num_established, ?cause, "Connection closed");
+                    // Such as a ping timeout, for a connection that went dead.
+                    if let Some(ConnectionError::Handler(error)) = &cause {
+                        tracing::info!(%peer_id, %error, "Connection dropped");
+                    }
+                    if num_established == 0 {
+                        self.connections.remove(&peer_id);
+                        self.swarm.behaviour_mut().rtts.remove(&peer_id);
+                        // They may be restarting, ratchets and all.
+                        if let Some(keys) = self.swarm.behaviour_mut().dm_keys.as_mut() {
+                            keys.forget(&peer_id);
+                        }
+                        self.swarm.behaviour_mut().transfers.peer_gone(&peer_id);
+                        self.swarm.behaviour_mut().forget_backlogs(&peer_id);
+                        self.schedule_redial(peer_id);
+                        self.set_presence(peer_id, false);
+                    } else if let Some(addrs) = self.connections.get_mut(&peer_id) {
+                        addrs.remove(endpoint.get_remote_address());
+                    }
+                }
+                SwarmEvent::IncomingConnection { local_addr, send_back_addr } => {
+                    tracing::debug!(%local_addr, %send_back_addr, "Incoming connection");
+                }
+                SwarmEvent::IncomingConnectionError {
+                    local_addr,
+                    send_back_addr,
+                    error: PendingConnectionError::ConnectionLimit(limit),
+                } => {
+                    tracing::warn!(%local_addr, %send_back_addr, %limit, "Refused incoming connection over the limit");
+                }
+                SwarmEvent::IncomingConnectionError { local_addr, send_back_addr, error } => {
+                    tracing::debug!(%local_addr, %send_back_addr, %error, "Incoming connection failed");
+                    return ChatEvent::IncomingFailed { address: send_back_addr, error: connection_error(&error) };
+                }
+                SwarmEvent::BannedPeer { peer_id, endpoint } => {
+                    tracing::debug!(%peer_id, ?endpoint, "Refused connection from banned peer");
+                }
+                SwarmEvent::UnreachableAddr { peer_id, address, error, attempts_remaining } => {
+                    if let PendingConnectionError::ConnectionLimit(limit) = &error {
+                        tracing::warn!(%peer_id, %address, %limit, "Dropped connection over the limit");
+                    } else {
+                        tracing::debug!(%peer_id, %address, %error, attempts_remaining, "Dial failed");
+                    }
+                    if attempts_remaining == 0 {
+                        if self.update_peerstore(|store| store.failed(&peer_id)) == Some(true) {
+                            tracing::info!(%peer_id, "Forgot a peer that could not be reached");
+                        }
+                        self.schedule_redial(peer_id);
+                    }
+                }
+                SwarmEvent::UnknownPeerUnreachableAddr { address, error } => {
+                    tracing::debug!(%address, %error, "Dial failed");
+                    // Bootstrap addresses are dialed as plain addresses at startup.
+                    if let Some((peer, _)) = split_peer_id(address.clone()) {
+                        self.schedule_redial(peer);
+                    }
+                    // Only addresses we were asked to dial get here; peers
+                    // dialed by PeerId in the background fail quietly.
+                    return ChatEvent::DialFailed { address, error: connection_error(&error) };
+                }
+                SwarmEvent::ExpiredListenAddr(addr) => {
+                    tracing::debug!(%addr, "No longer listening");
+                }
+                SwarmEvent::ListenerClosed { addresses, reason } => {
+                    tracing::debug!(?addresses, ?reason, "Listener closed");
+                    if let Err(error) = reason {
+                        return ChatEvent::ListenerFailed { addresses, error: error.to_string() };
+                    }
+                }
+                SwarmEvent::ListenerError { error } => {
+                    tracing::debug!(%error, "Listener error");
+                    return ChatEvent::ListenerFailed { addresses: Vec::new(), error: error.to_string() };
+                }
+                SwarmEvent::Dialing(peer_id) => {
+                    tracing::debug!(%peer_id, "Dialing");
+                }
+            }
+        }
+    }
+
+    /// Publishes `farewell` on every chat topic, unsubscribes from them and gives
+    /// the swarm a moment to flush before it is dropped, closing all
+    /// connections.
+    pub async fn shutdown(mut self, farewell: ChatMessage) {
+        // Shown after what we sent last, rather than held back ahead of it.
+        let farewell = ChatMessage { clock: Some(self.tick()), ..farewell };
+        let behaviour = self.swarm.behaviour_mut();
+        let topics = behaviour.pubsub().topics();
+        for topic in topics.into_iter().filter(|t| t != DM_TOPIC) {
+            // Nobody may be listening any more, which is fine on the way out.
+            let _ = behaviour.publish(&topic, &farewell);
+            behaviour.pubsub_mut().unsubscribe(&topic);
+        }
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                self.swarm.next_event().await;
+            }
+        })
+        .await;
+    }
+}
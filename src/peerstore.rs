@@ -0,0 +1,121 @@
+//! Addresses of the peers we have seen, kept in a JSON file between runs so
+//! that they can be dialed again on startup, before discovery finds them.
+//!
+//! Addresses come from connecting to a peer, from what it tells identify
+//! it listens on and from mDNS. A peer we fail to reach [`MAX_FAILURES`]
+//! times in a row, across runs, is forgotten.
+
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, io, path::PathBuf};
+
+/// Failed dials in a row after which a peer is forgotten.
+pub const MAX_FAILURES: u32 = 3;
+
+/// Most addresses kept for any one peer; the oldest learned go first.
+const MAX_ADDRS: usize = 8;
+
+/// What is kept of a peer, as saved.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entry {
+    /// Newest first.
+    addrs: Vec<Multiaddr>,
+    /// Dials that failed since we last connected.
+    #[serde(default)]
+    failures: u32,
+}
+
+#[derive(Debug)]
+pub struct Peerstore {
+    path: PathBuf,
+    peers: HashMap<PeerId, Entry>,
+}
+
+impl Peerstore {
+    /// Reads the peerstore at `path`, which starts out empty if there is no
+    /// such file, or it is not a peerstore, since it only saves time.
+    /// Entries that are not PeerIds are skipped.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let peers = match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str::<HashMap<String, Entry>>(&contents) {
+                Ok(entries) => entries.into_iter().filter_map(|(peer, entry)| Some((peer.parse().ok()?, entry))).collect(),
+                Err(e) => {
+                    tracing::warn!("Starting over from an unreadable peerstore {}: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Peerstore { path, peers })
+    }
+
+    /// Every peer kept, with its addresses.
+    pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &[Multiaddr])> {
+        self.peers.iter().map(|(peer, entry)| (peer, entry.addrs.as_slice()))
+    }
+
+    /// Records that `peer` can be reached at `addrs`, and saves the store
+    /// if that is news.
+    pub fn add(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) -> io::Result<()> {
+        if addrs.is_empty() {
+            return Ok(());
+        }
+        let entry = self.peers.entry(peer).or_default();
+        let before = entry.addrs.clone();
+        for addr in addrs {
+            entry.addrs.retain(|a| *a != addr);
+            entry.addrs.insert(0, addr);
+        }
+        entry.addrs.truncate(MAX_ADDRS);
+        if entry.addrs == before {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// Records that we connected to `peer`, starting its count of failures
+    /// over.
+    pub fn connected(&mut self, peer: &PeerId) -> io::Result<()> {
+        match self.peers.get_mut(peer) {
+            Some(entry) if entry.failures > 0 => {
+                entry.failures = 0;
+                self.save()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Records that dialing `peer` failed, forgetting it once that has
+    /// happened [`MAX_FAILURES`] times in a row. Returns whether it was
+    /// forgotten.
+    pub fn failed(&mut self, peer: &PeerId) -> io::Result<bool> {
+        let entry = match self.peers.get_mut(peer) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+        entry.failures += 1;
+        let forgotten = entry.failures >= MAX_FAILURES;
+        if forgotten {
+            self.peers.remove(peer);
+        }
+        self.save()?;
+        Ok(forgotten)
+    }
+
+    /// Forgets `peer`, such as when it is blocked.
+    pub fn remove(&mut self, peer: &PeerId) -> io::Result<()> {
+        if self.peers.remove(peer).is_none() {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let entries: HashMap<String, &Entry> = self.peers.iter().map(|(peer, entry)| (peer.to_string(), entry)).collect();
+        fs::write(&self.path, serde_json::to_vec_pretty(&entries).expect("Peerstores always serialize"))
+    }
+}
@@ -0,0 +1,69 @@
+//! Named sets of addresses to dial, saved with `/profile save <name>` and
+//! dialed again with `/profile load <name>`.
+//!
+//! Each profile is a file in one directory, named after the profile and
+//! holding one multiaddr per line.
+
+use libp2p::Multiaddr;
+use std::{fs, io, path::PathBuf};
+
+pub struct Profiles {
+    dir: PathBuf,
+}
+
+impl Profiles {
+    /// Profiles kept in `dir`, which is created on first save.
+    pub fn new(dir: PathBuf) -> Self {
+        Profiles { dir }
+    }
+
+    /// Saves `addrs` as the profile `name`, replacing any it had.
+    pub fn save(&self, name: &str, addrs: &[Multiaddr]) -> io::Result<()> {
+        let path = self.path(name)?;
+        fs::create_dir_all(&self.dir)?;
+        let contents: String = addrs.iter().map(|a| format!("{}\n", a)).collect();
+        fs::write(path, contents)
+    }
+
+    /// The addresses saved as `name`. Lines that are not multiaddrs are
+    /// skipped.
+    pub fn load(&self, name: &str) -> io::Result<Vec<Multiaddr>> {
+        let contents = fs::read_to_string(self.path(name)?).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("No profile called {}", name)),
+            _ => e,
+        })?;
+        Ok(contents.lines().filter_map(|l| l.trim().parse().ok()).collect())
+    }
+
+    /// The names of every saved profile, sorted.
+    pub fn list(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            if let Some(name) = entry?.file_name().to_str().filter(|n| valid_name(n)) {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Profile names become file names, so only plain ones are allowed.
+    fn path(&self, name: &str) -> io::Result<PathBuf> {
+        if !valid_name(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Profile names may only have letters, digits, `-` and `_`",
+            ));
+        }
+        Ok(self.dir.join(name))
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
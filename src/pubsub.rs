@@ -0,0 +1,216 @@
+//! The two ways peardchat can spread chat: gossipsub, which builds a mesh and
+//! gossips about what it has seen, and floodsub, which sends everything to
+//! every peer on the topic. The rest of the chat talks to whichever one the
+//! config picks through [`PubSub`].
+//!
+//! [`Flood`] wraps floodsub to keep track of the topics peers are on, which
+//! floodsub keeps to itself, and so that every peer we connect to hears
+//! about our topics, as with gossipsub.
+
+use libp2p::{
+    core::connection::ConnectionId,
+    floodsub::{Floodsub, FloodsubEvent, Topic},
+    gossipsub::{
+        error::{PublishError, SubscriptionError},
+        Gossipsub, IdentTopic,
+    },
+    swarm::{IntoProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction, PollParameters, ProtocolsHandler},
+    Multiaddr, PeerId,
+};
+use std::{
+    collections::HashMap,
+    task::{Context, Poll},
+};
+
+/// Subscribing, publishing and who is on which topic, the same for either
+/// protocol. Errors are gossipsub's, which floodsub has no equivalent of.
+pub(crate) trait PubSub {
+    /// Subscribes to `topic`, returning false if we already were.
+    fn subscribe(&mut self, topic: &str) -> Result<bool, SubscriptionError>;
+
+    /// Unsubscribes from `topic`, returning false if we were not subscribed.
+    fn unsubscribe(&mut self, topic: &str) -> bool;
+
+    /// Publishes `data` on `topic`, failing with
+    /// [`PublishError::InsufficientPeers`] if no peer would get it.
+    fn publish(&mut self, topic: &str, data: Vec<u8>) -> Result<(), PublishError>;
+
+    /// The topics we are subscribed to.
+    fn topics(&self) -> Vec<String>;
+
+    /// Longest data [`PubSub::publish`] gets across in one piece, leaving
+    /// room for what the protocol wraps it in.
+    fn max_data_len(&self) -> usize;
+
+    /// Passes everything on to `peer`, and dials it if need be.
+    fn add_peer(&mut self, peer: &PeerId);
+
+    /// Undoes [`PubSub::add_peer`].
+    fn remove_peer(&mut self, peer: &PeerId);
+
+    /// Every connected peer that speaks the protocol, with the topics it is
+    /// subscribed to.
+    fn peers(&self) -> Vec<(PeerId, Vec<String>)>;
+}
+
+impl PubSub for Gossipsub {
+    fn subscribe(&mut self, topic: &str) -> Result<bool, SubscriptionError> {
+        Gossipsub::subscribe(self, &IdentTopic::new(topic))
+    }
+
+    fn unsubscribe(&mut self, topic: &str) -> bool {
+        matches!(Gossipsub::unsubscribe(self, &IdentTopic::new(topic)), Ok(true))
+    }
+
+    fn publish(&mut self, topic: &str, data: Vec<u8>) -> Result<(), PublishError> {
+        Gossipsub::publish(self, IdentTopic::new(topic), data).map(|_| ())
+    }
+
+    fn topics(&self) -> Vec<String> {
+        Gossipsub::topics(self).map(|t| t.to_string()).collect()
+    }
+
+    // Gossipsub refuses to send more than 64 KiB at once.
+    fn max_data_len(&self) -> usize {
+        60 * 1024
+    }
+
+    fn add_peer(&mut self, peer: &PeerId) {
+        self.add_explicit_peer(peer);
+    }
+
+    fn remove_peer(&mut self, peer: &PeerId) {
+        self.remove_explicit_peer(peer);
+    }
+
+    fn peers(&self) -> Vec<(PeerId, Vec<String>)> {
+        self.all_peers().map(|(peer, topics)| (*peer, topics.iter().map(|t| t.to_string()).collect())).collect()
+    }
+}
+
+/// Floodsub, and what it knows but keeps to itself.
+pub(crate) struct Flood {
+    inner: Floodsub,
+    topics: Vec<String>,
+    peers: HashMap<PeerId, Vec<String>>,
+}
+
+impl Flood {
+    pub fn new(local_peer_id: PeerId) -> Self {
+        Flood { inner: Floodsub::new(local_peer_id), topics: Vec::new(), peers: HashMap::new() }
+    }
+}
+
+impl PubSub for Flood {
+    fn subscribe(&mut self, topic: &str) -> Result<bool, SubscriptionError> {
+        if !self.inner.subscribe(Topic::new(topic)) {
+            return Ok(false);
+        }
+        self.topics.push(topic.to_string());
+        Ok(true)
+    }
+
+    fn unsubscribe(&mut self, topic: &str) -> bool {
+        self.topics.retain(|t| t != topic);
+        self.inner.unsubscribe(Topic::new(topic))
+    }
+
+    fn publish(&mut self, topic: &str, data: Vec<u8>) -> Result<(), PublishError> {
+        // Floodsub sends nothing to peers without the topic, and says nothing
+        // of it.
+        if !self.peers.values().any(|topics| topics.iter().any(|t| t == topic)) {
+            return Err(PublishError::InsufficientPeers);
+        }
+        self.inner.publish_any(Topic::new(topic), data);
+        Ok(())
+    }
+
+    fn topics(&self) -> Vec<String> {
+        self.topics.clone()
+    }
+
+    // Floodsub drops anything over 2048 bytes as received.
+    fn max_data_len(&self) -> usize {
+        1536
+    }
+
+    fn add_peer(&mut self, peer: &PeerId) {
+        self.inner.add_node_to_partial_view(*peer);
+    }
+
+    fn remove_peer(&mut self, peer: &PeerId) {
+        self.inner.remove_node_from_partial_view(peer);
+    }
+
+    fn peers(&self) -> Vec<(PeerId, Vec<String>)> {
+        self.peers.iter().map(|(peer, topics)| (*peer, topics.clone())).collect()
+    }
+}
+
+impl NetworkBehaviour for Flood {
+    type ProtocolsHandler = <Floodsub as NetworkBehaviour>::ProtocolsHandler;
+    type OutEvent = FloodsubEvent;
+
+    fn new_handler(&mut self) -> Self::ProtocolsHandler {
+        self.inner.new_handler()
+    }
+
+    fn addresses_of_peer(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
+        self.inner.addresses_of_peer(peer)
+    }
+
+    fn inject_connected(&mut self, peer: &PeerId) {
+        // Floodsub only tells the peers in its partial view about our topics.
+        self.inner.add_node_to_partial_view(*peer);
+        self.inner.inject_connected(peer);
+        self.peers.insert(*peer, Vec::new());
+    }
+
+    fn inject_disconnected(&mut self, peer: &PeerId) {
+        // Floodsub redials the peers in its partial view for as long as they
+        // are in it, whatever the reason they went.
+        self.inner.remove_node_from_partial_view(peer);
+        self.inner.inject_disconnected(peer);
+        self.peers.remove(peer);
+    }
+
+    fn inject_event(
+        &mut self,
+        peer: PeerId,
+        connection: ConnectionId,
+        event: <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::OutEvent,
+    ) {
+        self.inner.inject_event(peer, connection, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<
+            <<Self::ProtocolsHandler as IntoProtocolsHandler>::Handler as ProtocolsHandler>::InEvent,
+            FloodsubEvent,
+        >,
+    > {
+        let action = match self.inner.poll(cx, params) {
+            Poll::Ready(action) => action,
+            Poll::Pending => return Poll::Pending,
+        };
+        match &action {
+            NetworkBehaviourAction::GenerateEvent(FloodsubEvent::Subscribed { peer_id, topic }) => {
+                let topics = self.peers.entry(*peer_id).or_default();
+                if !topics.iter().any(|t| t == topic.id()) {
+                    topics.push(topic.id().to_string());
+                }
+            }
+            NetworkBehaviourAction::GenerateEvent(FloodsubEvent::Unsubscribed { peer_id, topic }) => {
+                if let Some(topics) = self.peers.get_mut(peer_id) {
+                    topics.retain(|t| t != topic.id());
+                }
+            }
+            _ => {}
+        }
+        Poll::Ready(action)
+    }
+}
@@ -0,0 +1,272 @@
+//! Forward secrecy for direct messages, with a Double Ratchet along the
+//! lines of Signal's.
+//!
+//! Both sides start from a secret their static keys agree on, and the
+//! initiator's first ratchet key meets the responder's static key. After
+//! that, each reply brings a fresh x25519 key that is mixed into the root
+//! key, and every message is sent with its own key, derived from a chain
+//! that only goes forward. Keys are forgotten once used, so a key taken
+//! from either side later does not open what was sent before, and a static
+//! key taken later only opens what the initiator sent before hearing back.
+//!
+//! A ratcheted message header, after the version byte [`crate::dm`] adds, is
+//! laid out as
+//!
+//! ```text
+//! flags (1 byte) | ratchet public key (32 bytes) | previous chain length (4 bytes) | message number (4 bytes)
+//! ```
+//!
+//! with numbers big-endian. The only flag, [`INITIATING`], marks messages
+//! still resting on the responder's static key.
+
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::{collections::VecDeque, convert::TryInto};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Set on messages the initiator sends before hearing back.
+pub const INITIATING: u8 = 1;
+
+pub const HEADER_LEN: usize = 1 + 32 + 4 + 4;
+
+/// Most message keys of one chain to work out ahead of the message being
+/// opened, for messages that arrive out of order or not at all.
+const MAX_SKIP: u32 = 100;
+
+/// Most message keys kept for messages yet to arrive; past that, the
+/// oldest are dropped.
+const MAX_SKIPPED: usize = 200;
+
+/// What a ratcheted message says about the key it was sent with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub initiating: bool,
+    pub key: [u8; 32],
+    pub previous: u32,
+    pub number: u32,
+}
+
+impl Header {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.push(if self.initiating { INITIATING } else { 0 });
+        header.extend_from_slice(&self.key);
+        header.extend_from_slice(&self.previous.to_be_bytes());
+        header.extend_from_slice(&self.number.to_be_bytes());
+        header
+    }
+
+    pub fn decode(header: &[u8]) -> Option<Self> {
+        if header.len() != HEADER_LEN || header[0] & !INITIATING != 0 {
+            return None;
+        }
+        Some(Header {
+            initiating: header[0] == INITIATING,
+            key: header[1..33].try_into().ok()?,
+            previous: u32::from_be_bytes(header[33..37].try_into().ok()?),
+            number: u32::from_be_bytes(header[37..41].try_into().ok()?),
+        })
+    }
+}
+
+/// One side of a conversation's ratchet.
+#[derive(Clone)]
+pub struct Ratchet {
+    root: [u8; 32],
+    secret: [u8; 32],
+    public: [u8; 32],
+    theirs: Option<[u8; 32]>,
+    sending: [u8; 32],
+    receiving: Option<[u8; 32]>,
+    sent: u32,
+    received: u32,
+    previous: u32,
+    // Whether we started the conversation and have not heard back yet.
+    initiating: bool,
+    skipped: VecDeque<([u8; 32], u32, [u8; 32])>,
+}
+
+impl Ratchet {
+    /// Starts a conversation from the `shared` secret, with the peer whose
+    /// static public key is `theirs`.
+    pub fn initiate(shared: [u8; 32], theirs: [u8; 32]) -> Self {
+        let secret: [u8; 32] = rand::random();
+        let (root, sending) = kdf_root(&shared, x25519(secret, theirs));
+        Ratchet {
+            root,
+            secret,
+            public: x25519(secret, X25519_BASEPOINT_BYTES),
+            theirs: Some(theirs),
+            sending,
+            receiving: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            initiating: true,
+            skipped: VecDeque::new(),
+        }
+    }
+
+    /// Takes up a conversation from the `shared` secret, as the peer whose
+    /// static secret key is `secret`, returning it with the key to open its
+    /// first message, the one with `header`.
+    pub fn respond(shared: [u8; 32], secret: [u8; 32], header: &Header) -> Option<([u8; 32], Self)> {
+        let mut ratchet = Ratchet {
+            root: shared,
+            secret,
+            public: x25519(secret, X25519_BASEPOINT_BYTES),
+            theirs: None,
+            sending: [0; 32],
+            receiving: None,
+            sent: 0,
+            received: 0,
+            previous: 0,
+            initiating: false,
+            skipped: VecDeque::new(),
+        };
+        let key = ratchet.receive(header)?;
+        Some((key, ratchet))
+    }
+
+    /// Whether we started the conversation and have not heard back, so what
+    /// we send still rests on their static key.
+    pub fn is_initiating(&self) -> bool {
+        self.initiating
+    }
+
+    /// Whether a message with `header` was sent on a chain we have
+    /// already heard from.
+    pub fn knows(&self, header: &Header) -> bool {
+        self.theirs == Some(header.key) || self.skipped.iter().any(|(key, _, _)| *key == header.key)
+    }
+
+    /// The header and key to send the next message with.
+    pub fn send(&mut self) -> (Header, [u8; 32]) {
+        let (sending, key) = kdf_chain(&self.sending);
+        self.sending = sending;
+        let header = Header { initiating: self.initiating, key: self.public, previous: self.previous, number: self.sent };
+        self.sent += 1;
+        (header, key)
+    }
+
+    /// The key to open the message with `header` with, moving the ratchet
+    /// on. Returns `None` for keys long gone or too far ahead; as a message
+    /// may still fail to open, callers move on a clone and keep it only if
+    /// it does.
+    pub fn receive(&mut self, header: &Header) -> Option<[u8; 32]> {
+        if let Some(i) = self.skipped.iter().position(|(key, n, _)| *key == header.key && *n == header.number) {
+            return self.skipped.remove(i).map(|(_, _, key)| key);
+        }
+        if self.theirs != Some(header.key) {
+            self.skip_to(header.previous)?;
+            self.previous = self.sent;
+            self.sent = 0;
+            self.received = 0;
+            self.theirs = Some(header.key);
+            let (root, receiving) = kdf_root(&self.root, x25519(self.secret, header.key));
+            self.secret = rand::random();
+            self.public = x25519(self.secret, X25519_BASEPOINT_BYTES);
+            let (root, sending) = kdf_root(&root, x25519(self.secret, header.key));
+            self.root = root;
+            self.receiving = Some(receiving);
+            self.sending = sending;
+        }
+        if header.number < self.received {
+            return None;
+        }
+        self.skip_to(header.number)?;
+        let (receiving, key) = kdf_chain(&self.receiving?);
+        self.receiving = Some(receiving);
+        self.received += 1;
+        // Once they answer, what we send no longer rests on their static key.
+        self.initiating = false;
+        Some(key)
+    }
+
+    /// Works out and keeps the keys of the messages on the receiving chain
+    /// before number `until`.
+    fn skip_to(&mut self, until: u32) -> Option<()> {
+        let (theirs, mut receiving) = match (self.theirs, self.receiving) {
+            (Some(theirs), Some(receiving)) => (theirs, receiving),
+            _ => return Some(()),
+        };
+        if until > self.received + MAX_SKIP {
+            return None;
+        }
+        while self.received < until {
+            let (next, key) = kdf_chain(&receiving);
+            receiving = next;
+            self.skipped.push_back((theirs, self.received, key));
+            self.received += 1;
+        }
+        self.receiving = Some(receiving);
+        while self.skipped.len() > MAX_SKIPPED {
+            self.skipped.pop_front();
+        }
+        Some(())
+    }
+}
+
+/// The next root key and a new chain key, from the root key and a fresh
+/// Diffie-Hellman output.
+fn kdf_root(root: &[u8; 32], dh: [u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut out = [0; 64];
+    Hkdf::<Sha256>::new(Some(root), &dh)
+        .expand(b"peardchat dm ratchet root", &mut out)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    split(out)
+}
+
+/// The next chain key and a message key, from a chain key.
+fn kdf_chain(chain: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut out = [0; 64];
+    Hkdf::<Sha256>::from_prk(chain)
+        .expect("32 bytes is a valid HKDF-SHA256 PRK length")
+        .expand(b"peardchat dm ratchet chain", &mut out)
+        .expect("64 bytes is a valid HKDF-SHA256 output length");
+    split(out)
+}
+
+fn split(out: [u8; 64]) -> ([u8; 32], [u8; 32]) {
+    let (first, second) = out.split_at(32);
+    (first.try_into().unwrap(), second.try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_message_gets_its_own_key_and_replies_move_the_ratchet() {
+        let bob_secret: [u8; 32] = rand::random();
+        let bob_public = x25519(bob_secret, X25519_BASEPOINT_BYTES);
+        let shared = [7; 32];
+        let mut alice = Ratchet::initiate(shared, bob_public);
+
+        let (first, first_key) = alice.send();
+        let (second, second_key) = alice.send();
+        assert!(first.initiating && first_key != second_key);
+        assert_eq!(Header::decode(&second.encode()), Some(second));
+        // The second arrives first.
+        let (key, mut bob) = Ratchet::respond(shared, bob_secret, &second).unwrap();
+        assert_eq!(key, second_key);
+        assert!(bob.knows(&first));
+        assert_eq!(bob.receive(&first), Some(first_key));
+        // Each key opens one message only.
+        assert_eq!(bob.clone().receive(&first), None);
+
+        let (reply, reply_key) = bob.send();
+        assert!(!reply.initiating && reply.key != bob_public);
+        assert!(!alice.knows(&reply));
+        assert_eq!(alice.receive(&reply), Some(reply_key));
+        assert!(!alice.is_initiating());
+        let (next, next_key) = alice.send();
+        assert!(!next.initiating && next.key != first.key);
+        assert_eq!(bob.receive(&next), Some(next_key));
+
+        // Too far ahead to work out.
+        let mut ahead = reply;
+        ahead.number = 2 * MAX_SKIP;
+        assert_eq!(alice.receive(&ahead), None);
+    }
+}
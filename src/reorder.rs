@@ -0,0 +1,79 @@
+//! Holds incoming chat back for a moment, so that messages written close
+//! together but delivered the other way round are shown in the order they
+//! were written.
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Where a message goes among the others: its Lamport clock, then when its
+/// author says it was sent.
+pub type Key = (u64, i64);
+
+/// Items held for up to `window` after they arrive, each let out in order
+/// once it has waited that long.
+pub struct ReorderBuffer<T> {
+    window: Duration,
+    held: Vec<(Key, Instant, T)>,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(window: Duration) -> Self {
+        ReorderBuffer { window, held: Vec::new() }
+    }
+
+    pub fn push(&mut self, key: Key, item: T, arrived: Instant) {
+        self.held.push((key, arrived, item));
+    }
+
+    /// When the item that has waited longest is due out.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.held.iter().map(|(_, arrived, _)| *arrived + self.window).min()
+    }
+
+    /// Lets out, in order, every item due by `now` and every item ordered
+    /// before one of those, which would otherwise be shown after it.
+    pub fn flush(&mut self, now: Instant) -> Vec<T> {
+        let window = self.window;
+        let last = self.held.iter().filter(|(_, arrived, _)| *arrived + window <= now).map(|(key, _, _)| *key).max();
+        match last {
+            Some(last) => self.take(|key| key <= last),
+            None => Vec::new(),
+        }
+    }
+
+    /// Lets out everything held, in order.
+    pub fn drain(&mut self) -> Vec<T> {
+        self.take(|_| true)
+    }
+
+    fn take(&mut self, out: impl Fn(Key) -> bool) -> Vec<T> {
+        let (mut taken, kept) = std::mem::take(&mut self.held).into_iter().partition(|(key, _, _)| out(*key));
+        self.held = kept;
+        // Stable, so items with the same key keep the order they came in.
+        taken.sort_by_key(|(key, _, _)| *key);
+        taken.into_iter().map(|(_, _, item)| item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swapped_messages_come_out_in_order() {
+        let start = Instant::now();
+        let window = Duration::from_millis(200);
+        let mut buffer = ReorderBuffer::new(window);
+        buffer.push((2, 0), "second", start);
+        buffer.push((1, 0), "first", start + Duration::from_millis(50));
+        buffer.push((5, 0), "later", start + Duration::from_millis(150));
+        assert!(buffer.flush(start).is_empty());
+        assert_eq!(buffer.next_deadline(), Some(start + window));
+        assert_eq!(buffer.flush(start + window), ["first", "second"]);
+        // Nothing newer has to come along for the rest to get out.
+        assert_eq!(buffer.flush(start + Duration::from_secs(1)), ["later"]);
+        assert_eq!(buffer.next_deadline(), None);
+        buffer.push((9, 0), "at shutdown", start);
+        assert_eq!(buffer.drain(), ["at shutdown"]);
+    }
+}
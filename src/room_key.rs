@@ -0,0 +1,71 @@
+//! Rooms only the holders of a shared passphrase can read.
+//!
+//! With a room key set, everything we publish on a chat topic is encrypted
+//! with a key derived from the passphrase and the topic, and whatever
+//! arrives that does not decrypt with it is dropped. Peers without the key
+//! only ever see ciphertext, which they drop likewise.
+//!
+//! A sealed payload is laid out as
+//!
+//! ```text
+//! 0x03 | nonce (12 bytes) | ciphertext | MAC (16 bytes)
+//! ```
+//!
+//! The passphrase goes through HKDF-SHA256 once at startup, and each topic's
+//! ChaCha20-Poly1305 key is expanded from the result with the topic as info,
+//! so the same passphrase gives every room a different key. The plaintext is
+//! the payload we would otherwise have published, signature and all. HKDF
+//! does nothing to slow down guessing, so the passphrase should be long.
+
+use crate::message::SEALED;
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+const SALT: &[u8] = b"peardchat room key v1";
+const NONCE_LEN: usize = 12;
+const MAC_LEN: usize = 16;
+
+/// The key shared by everyone allowed in our rooms.
+pub struct RoomKey {
+    hkdf: Hkdf<Sha256>,
+}
+
+impl RoomKey {
+    pub fn new(passphrase: &str) -> Self {
+        RoomKey { hkdf: Hkdf::new(Some(SALT), passphrase.as_bytes()) }
+    }
+
+    /// Encrypts `data` for publishing on `topic`.
+    pub fn seal(&self, topic: &str, data: &[u8]) -> Vec<u8> {
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = self
+            .cipher(topic)
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: topic.as_bytes() })
+            .expect("Encrypting into a Vec cannot fail");
+        [&[SEALED][..], &nonce, &ciphertext].concat()
+    }
+
+    /// Decrypts what arrived on `topic`, returning `None` unless it was
+    /// sealed with our key for that topic.
+    pub fn open(&self, topic: &str, payload: &[u8]) -> Option<Vec<u8>> {
+        if payload.len() < 1 + NONCE_LEN + MAC_LEN || payload[0] != SEALED {
+            return None;
+        }
+        let (nonce, ciphertext) = payload[1..].split_at(NONCE_LEN);
+        self.cipher(topic)
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: topic.as_bytes() })
+            .ok()
+    }
+
+    fn cipher(&self, topic: &str) -> ChaCha20Poly1305 {
+        let mut key = [0; 32];
+        self.hkdf
+            .expand(topic.as_bytes(), &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+    }
+}
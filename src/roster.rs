@@ -0,0 +1,169 @@
+//! Who is around: every peer we have been connected to this run, with its
+//! nickname and whether it is online, away or gone. Connections, heartbeats
+//! and nickname changes each keep it up to date.
+
+use libp2p::PeerId;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// How long after its last heartbeat a connected peer counts as away.
+pub const AWAY_AFTER: Duration = Duration::from_secs(90);
+
+/// Where a peer stands, in the order the roster lists them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Presence {
+    /// Connected, with a recent heartbeat, or sending none, as older clients
+    /// do.
+    Online,
+    /// Connected, but said it is away, or sent no heartbeat for
+    /// [`AWAY_AFTER`].
+    Away,
+    /// Connected before, but not now.
+    Offline,
+}
+
+/// One peer on the roster.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RosterEntry {
+    pub peer_id: PeerId,
+    pub nick: Option<String>,
+    pub presence: Presence,
+}
+
+#[derive(Debug, Default)]
+struct Known {
+    nick: Option<String>,
+    connected: bool,
+    away: bool,
+    last_heartbeat: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct Roster {
+    peers: HashMap<PeerId, Known>,
+}
+
+impl Roster {
+    /// Records that `peer` connected, putting it on the roster, or that its
+    /// last connection closed. Having been away is forgotten when it comes
+    /// back, until it says so again.
+    pub fn set_connected(&mut self, peer: PeerId, connected: bool) {
+        if connected {
+            let known = self.peers.entry(peer).or_default();
+            known.away &= known.connected;
+            known.connected = true;
+        } else if let Some(known) = self.peers.get_mut(&peer) {
+            known.connected = false;
+        }
+    }
+
+    /// Records a heartbeat from `peer`, if it is on the roster.
+    pub fn heartbeat(&mut self, peer: &PeerId, at: Instant) {
+        if let Some(known) = self.peers.get_mut(peer) {
+            known.last_heartbeat = Some(at);
+        }
+    }
+
+    /// Records that `peer`, if it is on the roster, said it is `away` or
+    /// back. Returns whether that is news.
+    pub fn set_away(&mut self, peer: &PeerId, away: bool) -> bool {
+        match self.peers.get_mut(peer) {
+            Some(known) if known.away != away => {
+                known.away = away;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether `peer` last said it is away.
+    pub fn is_away(&self, peer: &PeerId) -> bool {
+        self.peers.get(peer).is_some_and(|known| known.away)
+    }
+
+    /// Records that `peer`, if it is on the roster, goes by `nick`.
+    pub fn set_nick(&mut self, peer: &PeerId, nick: &str) {
+        if let Some(known) = self.peers.get_mut(peer) {
+            known.nick = Some(nick.to_string());
+        }
+    }
+
+    /// Takes `peer` off the roster, such as when it is blocked.
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Everyone on the roster as of `now`, online first, then away, then
+    /// offline, and by nickname within each. Peers without one come last,
+    /// by PeerId.
+    pub fn entries(&self, now: Instant) -> Vec<RosterEntry> {
+        let mut entries: Vec<_> = self.peers.iter()
+            .map(|(peer, known)| {
+                let quiet = known.last_heartbeat.is_some_and(|at| now.saturating_duration_since(at) > AWAY_AFTER);
+                let presence = match (known.connected, known.away || quiet) {
+                    (false, _) => Presence::Offline,
+                    (true, true) => Presence::Away,
+                    (true, false) => Presence::Online,
+                };
+                RosterEntry { peer_id: *peer, nick: known.nick.clone(), presence }
+            })
+            .collect();
+        entries.sort_by_cached_key(|e| {
+            (e.presence, e.nick.is_none(), e.nick.as_ref().map(|n| n.to_lowercase()), e.peer_id.to_string())
+        });
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_by_presence_and_sorts_by_nick() {
+        let start = Instant::now();
+        let [zed, alice, bob, nameless] = [PeerId::random(), PeerId::random(), PeerId::random(), PeerId::random()];
+        let mut roster = Roster::default();
+        for peer in [zed, alice, bob, nameless] {
+            roster.set_connected(peer, true);
+        }
+        roster.set_nick(&zed, "zed");
+        roster.set_nick(&alice, "Alice");
+        roster.set_nick(&bob, "bob");
+        // Not on the roster, so not added by hearing from it.
+        roster.set_nick(&PeerId::random(), "stranger");
+        roster.heartbeat(&alice, start);
+        roster.heartbeat(&bob, start);
+        roster.set_connected(zed, false);
+
+        let names = |roster: &Roster, now| {
+            roster.entries(now).into_iter().map(|e| (e.presence, e.nick)).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&roster, start), [
+            (Presence::Online, Some("Alice".to_string())),
+            (Presence::Online, Some("bob".to_string())),
+            (Presence::Online, None),
+            (Presence::Offline, Some("zed".to_string())),
+        ]);
+        let later = start + AWAY_AFTER + Duration::from_secs(1);
+        roster.heartbeat(&bob, later);
+        assert_eq!(names(&roster, later)[..2], [
+            (Presence::Online, Some("bob".to_string())),
+            (Presence::Online, None),
+        ]);
+        assert_eq!(names(&roster, later)[2], (Presence::Away, Some("Alice".to_string())));
+        assert!(roster.set_away(&bob, true));
+        assert!(!roster.set_away(&bob, true));
+        assert_eq!(names(&roster, later)[2], (Presence::Away, Some("bob".to_string())));
+        // Reconnecting forgets it.
+        roster.set_connected(bob, false);
+        roster.set_connected(bob, true);
+        assert!(!roster.is_away(&bob));
+        roster.remove(&zed);
+        // Its connection closing afterwards does not bring it back.
+        roster.set_connected(zed, false);
+        assert_eq!(roster.entries(later).len(), 3);
+    }
+}
@@ -0,0 +1,24 @@
+//! Serializes `PeerId`s, which have no serde support of their own, as the
+//! base58 strings they display as. Use with `#[serde(serialize_with)]`, or
+//! `#[serde(deserialize_with)]` to read them back.
+
+use libp2p::PeerId;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+pub fn serialize<S: Serializer>(peer: &PeerId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(peer)
+}
+
+pub fn serialize_option<S: Serializer>(peer: &Option<PeerId>, serializer: S) -> Result<S::Ok, S::Error> {
+    peer.map(|peer| peer.to_string()).serialize(serializer)
+}
+
+pub fn serialize_all<S: Serializer>(peers: &[PeerId], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(peers.iter().map(PeerId::to_string))
+}
+
+pub fn deserialize_option<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PeerId>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|peer| peer.parse().map_err(|_| D::Error::custom(format!("Not a PeerId: {}", peer))))
+        .transpose()
+}
@@ -0,0 +1,125 @@
+//! Layouts for chat lines given with `--format`, such as
+//! `"[{time}] {nick}: {body}"`.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Time,
+    Nick,
+    PeerId,
+    Topic,
+    Body,
+    Id,
+}
+
+/// The placeholders a template may use, by name.
+const FIELDS: [(&str, Field); 6] = [
+    ("time", Field::Time),
+    ("nick", Field::Nick),
+    ("peerid", Field::PeerId),
+    ("topic", Field::Topic),
+    ("body", Field::Body),
+    ("id", Field::Id),
+];
+
+/// What fills in a template's placeholders for one chat line.
+pub struct Fields<'a> {
+    pub time: &'a str,
+    pub nick: &'a str,
+    pub peerid: &'a str,
+    pub topic: &'a str,
+    pub body: &'a str,
+    pub id: &'a str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Text(String),
+    Field(Field),
+}
+
+/// A parsed `--format`. `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| format!("Unclosed `{{` in {:?}", s))?;
+                    let name = &rest[..end];
+                    let (_, field) = FIELDS.iter().find(|(n, _)| *n == name).ok_or_else(|| {
+                        let names: Vec<_> = FIELDS.iter().map(|(n, _)| format!("{{{}}}", n)).collect();
+                        format!("Unknown placeholder {{{}}} in {:?}, expected one of {}", name, s, names.join(", "))
+                    })?;
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Field(*field));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("Unmatched `}}` in {:?}, write `}}}}` for a brace", s)),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Template { pieces })
+    }
+}
+
+impl Template {
+    /// The line laid out with `fields`.
+    pub fn render(&self, fields: &Fields) -> String {
+        self.pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) => text.as_str(),
+                Piece::Field(Field::Time) => fields.time,
+                Piece::Field(Field::Nick) => fields.nick,
+                Piece::Field(Field::PeerId) => fields.peerid,
+                Piece::Field(Field::Topic) => fields.topic,
+                Piece::Field(Field::Body) => fields.body,
+                Piece::Field(Field::Id) => fields.id,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_fields() {
+        let template: Template = "[{time}] {{{topic}}} {nick}: {body}".parse().unwrap();
+        let fields = Fields { time: "14:32:05", nick: "alice", peerid: "12D3", topic: "rust", body: "hi", id: "3fa2c1" };
+        assert_eq!(template.render(&fields), "[14:32:05] {rust} alice: hi");
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert!("{nick".parse::<Template>().unwrap_err().starts_with("Unclosed"));
+        assert!("{name}: {body}".parse::<Template>().unwrap_err().starts_with("Unknown placeholder {name}"));
+        assert!("nick}".parse::<Template>().unwrap_err().starts_with("Unmatched"));
+    }
+}
@@ -0,0 +1,523 @@
+//! Direct file transfer between two peers over a request-response protocol.
+//!
+//! The sender first offers a file with its name and size. Small files are
+//! accepted straight away, larger ones wait for the user to `/accept` them.
+//! Once accepted the file is sent one chunk per request, and every chunk is
+//! acknowledged before the next one goes out.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    core::upgrade::{read_one, write_one, ProtocolName},
+    request_response::{
+        RequestId, RequestResponse, RequestResponseCodec, RequestResponseEvent,
+        RequestResponseMessage, ResponseChannel,
+    },
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Files larger than this are refused outright.
+pub const MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+/// Files larger than this need the receiver to confirm with `/accept`.
+pub const CONFIRM_ABOVE: u64 = 1024 * 1024;
+/// Most offers one peer may have waiting for confirmation; more are
+/// declined.
+pub const MAX_PENDING_PER_PEER: usize = 4;
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Upper bound on a single encoded request or response.
+const MAX_MESSAGE_SIZE: usize = CHUNK_SIZE + 1024;
+
+#[derive(Debug, Clone)]
+pub struct FileProtocol;
+
+impl ProtocolName for FileProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/peardchat/file/1.0.0"
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FileRequest {
+    /// Asks the receiver whether it wants the file.
+    Offer { id: u64, name: String, size: u64 },
+    /// The next piece of an accepted file.
+    Chunk { id: u64, data: Vec<u8> },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FileResponse {
+    Accepted,
+    Rejected(String),
+    /// A chunk was written to disk.
+    Received,
+}
+
+/// Encodes [`FileRequest`]s and [`FileResponse`]s with bincode behind a
+/// length prefix.
+#[derive(Clone)]
+pub struct FileCodec;
+
+async fn read_message<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: for<'de> Deserialize<'de>,
+{
+    let bytes = read_one(io, MAX_MESSAGE_SIZE)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    bincode::deserialize(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+async fn write_message<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    let bytes = bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_one(io, bytes).await
+}
+
+#[async_trait]
+impl RequestResponseCodec for FileCodec {
+    type Protocol = FileProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(&mut self, _: &FileProtocol, io: &mut T) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &FileProtocol, io: &mut T) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &FileProtocol, io: &mut T, req: FileRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &req).await
+    }
+
+    async fn write_response<T>(&mut self, _: &FileProtocol, io: &mut T, res: FileResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, &res).await
+    }
+}
+
+/// Progress of the transfers in flight, reported through
+/// [`ChatEvent::Transfer`](crate::ChatEvent::Transfer).
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TransferEvent {
+    /// `peer` wants to send a file large enough to need confirmation, answer
+    /// with [`ChatNode::accept_file`](crate::ChatNode::accept_file) or
+    /// [`ChatNode::reject_file`](crate::ChatNode::reject_file).
+    Offered {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        offer: u64,
+        name: String,
+        size: u64,
+    },
+    /// An offered file was over [`MAX_FILE_SIZE`] and refused.
+    TooLarge {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        name: String,
+        size: u64,
+    },
+    /// We started receiving a file.
+    Receiving {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        name: String,
+        size: u64,
+    },
+    /// Another tenth of an incoming file has arrived.
+    Progress { name: String, percent: u64 },
+    /// An incoming file was written to `path` in full.
+    Saved {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        name: String,
+        path: PathBuf,
+    },
+    /// An outgoing file was delivered in full.
+    Sent {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        name: String,
+    },
+    /// The receiver declined an outgoing file.
+    Declined {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        name: String,
+        reason: String,
+    },
+    /// A transfer in either direction was aborted.
+    Failed {
+        #[serde(serialize_with = "crate::serde_peer::serialize")]
+        peer: PeerId,
+        name: String,
+        error: String,
+    },
+}
+
+/// A file we are sending.
+struct Outgoing {
+    peer: PeerId,
+    name: String,
+    file: fs::File,
+    size: u64,
+    sent: u64,
+}
+
+/// A file we are receiving.
+struct Incoming {
+    name: String,
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    received: u64,
+}
+
+/// An offer waiting for the user to `/accept` or `/reject` it.
+struct PendingOffer {
+    peer: PeerId,
+    id: u64,
+    name: String,
+    size: u64,
+    channel: ResponseChannel<FileResponse>,
+}
+
+/// Tracks every transfer in flight, in both directions.
+pub struct Transfers {
+    downloads: PathBuf,
+    next_id: u64,
+    outgoing: HashMap<u64, Outgoing>,
+    /// Which outgoing transfer each request in flight belongs to.
+    requests: HashMap<RequestId, u64>,
+    incoming: HashMap<(PeerId, u64), Incoming>,
+    /// Offers awaiting confirmation, by the number shown to the user.
+    pending: HashMap<u64, PendingOffer>,
+    next_offer: u64,
+    events: VecDeque<TransferEvent>,
+}
+
+impl Transfers {
+    /// Received files are written to `downloads`.
+    pub fn new(downloads: PathBuf) -> Self {
+        Transfers {
+            downloads,
+            next_id: 0,
+            outgoing: HashMap::new(),
+            requests: HashMap::new(),
+            incoming: HashMap::new(),
+            pending: HashMap::new(),
+            next_offer: 1,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn next_event(&mut self) -> Option<TransferEvent> {
+        self.events.pop_front()
+    }
+
+    /// Offers the file at `path` to `peer`.
+    pub fn send_file(&mut self, rr: &mut RequestResponse<FileCodec>, peer: PeerId, path: &Path) -> io::Result<()> {
+        let file = fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        if size > MAX_FILE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is larger than the {} byte limit", path.display(), MAX_FILE_SIZE),
+            ));
+        }
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Not a file"))?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = rr.send_request(&peer, FileRequest::Offer { id, name: name.clone(), size });
+        self.requests.insert(request, id);
+        self.outgoing.insert(id, Outgoing { peer, name, file, size, sent: 0 });
+        Ok(())
+    }
+
+    /// Accepts the offer numbered `offer`, returning false if there is none.
+    pub fn accept(&mut self, rr: &mut RequestResponse<FileCodec>, offer: u64) -> bool {
+        match self.pending.remove(&offer) {
+            Some(offer) => {
+                self.start_receiving(rr, offer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Declines the offer numbered `offer`, returning false if there is none.
+    pub fn reject(&mut self, rr: &mut RequestResponse<FileCodec>, offer: u64) -> bool {
+        match self.pending.remove(&offer) {
+            Some(offer) => {
+                let _ = rr.send_response(offer.channel, FileResponse::Rejected("declined".to_string()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn inject_event(
+        &mut self,
+        rr: &mut RequestResponse<FileCodec>,
+        event: RequestResponseEvent<FileRequest, FileResponse>,
+    ) {
+        match event {
+            RequestResponseEvent::Message { peer, message: RequestResponseMessage::Request { request, channel, .. } } => {
+                match request {
+                    FileRequest::Offer { id, name, size } => self.on_offer(rr, PendingOffer { peer, id, name, size, channel }),
+                    FileRequest::Chunk { id, data } => {
+                        let response = self.on_chunk(peer, id, &data);
+                        let _ = rr.send_response(channel, response);
+                    }
+                }
+            }
+            RequestResponseEvent::Message { message: RequestResponseMessage::Response { request_id, response }, .. } => {
+                if let Some(id) = self.requests.remove(&request_id) {
+                    match response {
+                        FileResponse::Accepted | FileResponse::Received => self.send_next_chunk(rr, id),
+                        FileResponse::Rejected(reason) => {
+                            if let Some(transfer) = self.outgoing.remove(&id) {
+                                self.events.push_back(TransferEvent::Declined { peer: transfer.peer, name: transfer.name, reason });
+                            }
+                        }
+                    }
+                }
+            }
+            RequestResponseEvent::OutboundFailure { request_id, error, .. } => {
+                if let Some(transfer) = self.requests.remove(&request_id).and_then(|id| self.outgoing.remove(&id)) {
+                    let error = format!("{:?}", error);
+                    self.events.push_back(TransferEvent::Failed { peer: transfer.peer, name: transfer.name, error });
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                tracing::debug!("Inbound file request from {} failed: {:?}", peer, error);
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+
+    fn on_offer(&mut self, rr: &mut RequestResponse<FileCodec>, offer: PendingOffer) {
+        let key = (offer.peer, offer.id);
+        if self.incoming.contains_key(&key) || self.pending.values().any(|p| (p.peer, p.id) == key) {
+            let _ = rr.send_response(offer.channel, FileResponse::Rejected("transfer already offered".to_string()));
+        } else if offer.size > MAX_FILE_SIZE {
+            let PendingOffer { peer, name, size, channel, .. } = offer;
+            let _ = rr.send_response(channel, FileResponse::Rejected("file too large".to_string()));
+            self.events.push_back(TransferEvent::TooLarge { peer, name, size });
+        } else if offer.size > CONFIRM_ABOVE && self.pending.values().filter(|p| p.peer == offer.peer).count() >= MAX_PENDING_PER_PEER {
+            let _ = rr.send_response(offer.channel, FileResponse::Rejected("too many offers waiting".to_string()));
+        } else if offer.size > CONFIRM_ABOVE {
+            let number = self.next_offer;
+            self.next_offer += 1;
+            self.events.push_back(TransferEvent::Offered {
+                peer: offer.peer,
+                offer: number,
+                name: offer.name.clone(),
+                size: offer.size,
+            });
+            self.pending.insert(number, offer);
+        } else {
+            self.start_receiving(rr, offer);
+        }
+    }
+
+    fn start_receiving(&mut self, rr: &mut RequestResponse<FileCodec>, offer: PendingOffer) {
+        let response = match self.create_download(&offer.name) {
+            Ok((path, file)) => {
+                self.events.push_back(TransferEvent::Receiving {
+                    peer: offer.peer,
+                    name: offer.name.clone(),
+                    size: offer.size,
+                });
+                let incoming = Incoming { name: offer.name, path, file, size: offer.size, received: 0 };
+                self.incoming.insert((offer.peer, offer.id), incoming);
+                FileResponse::Accepted
+            }
+            Err(e) => {
+                let (peer, name, error) = (offer.peer, offer.name.clone(), e.to_string());
+                self.events.push_back(TransferEvent::Failed { peer, name, error });
+                FileResponse::Rejected("receiver could not save the file".to_string())
+            }
+        };
+        let _ = rr.send_response(offer.channel, response);
+        // Empty files are complete as soon as they are accepted.
+        if offer.size == 0 {
+            self.finish_receiving(offer.peer, offer.id);
+        }
+    }
+
+    /// Creates a new file in the downloads directory, never overwriting an
+    /// existing one. Only the final path component of `name` is used.
+    fn create_download(&self, name: &str) -> io::Result<(PathBuf, fs::File)> {
+        let name = Path::new(name)
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid file name"))?;
+        fs::create_dir_all(&self.downloads)?;
+        let mut path = self.downloads.join(name);
+        for n in 1.. {
+            match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    path = self.downloads.join(format!("{}.{}", name.to_string_lossy(), n));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+
+    fn on_chunk(&mut self, peer: PeerId, id: u64, data: &[u8]) -> FileResponse {
+        let transfer = match self.incoming.get_mut(&(peer, id)) {
+            Some(transfer) => transfer,
+            None => return FileResponse::Rejected("unknown transfer".to_string()),
+        };
+        if transfer.received + data.len() as u64 > transfer.size {
+            self.abandon(peer, id, "more data than offered");
+            return FileResponse::Rejected("more data than offered".to_string());
+        }
+        if let Err(e) = transfer.file.write_all(data) {
+            self.abandon(peer, id, &e.to_string());
+            return FileResponse::Rejected("receiver could not save the file".to_string());
+        }
+        let before = transfer.received * 10 / transfer.size;
+        transfer.received += data.len() as u64;
+        let after = transfer.received * 10 / transfer.size;
+        if after > before && transfer.received < transfer.size {
+            self.events.push_back(TransferEvent::Progress { name: transfer.name.clone(), percent: after * 10 });
+        }
+        if transfer.received == transfer.size {
+            self.finish_receiving(peer, id);
+        }
+        FileResponse::Received
+    }
+
+    /// Gives up on receiving a file, removing what was written of it.
+    fn abandon(&mut self, peer: PeerId, id: u64, error: &str) {
+        if let Some(transfer) = self.incoming.remove(&(peer, id)) {
+            remove_partial(&transfer);
+            let (name, error) = (transfer.name, error.to_string());
+            self.events.push_back(TransferEvent::Failed { peer, name, error });
+        }
+    }
+
+    /// Gives up on every file coming from `peer`, and every offer of one,
+    /// once we are no longer connected to it.
+    pub fn peer_gone(&mut self, peer: &PeerId) {
+        let ids: Vec<u64> = self.incoming.keys().filter(|(p, _)| p == peer).map(|(_, id)| *id).collect();
+        for id in ids {
+            self.abandon(*peer, id, "connection closed");
+        }
+        let offers: Vec<u64> = self.pending.iter().filter(|(_, o)| o.peer == *peer).map(|(n, _)| *n).collect();
+        for offer in offers {
+            if let Some(PendingOffer { peer, name, .. }) = self.pending.remove(&offer) {
+                self.events.push_back(TransferEvent::Failed { peer, name, error: "connection closed".to_string() });
+            }
+        }
+    }
+
+    fn finish_receiving(&mut self, peer: PeerId, id: u64) {
+        if let Some(transfer) = self.incoming.remove(&(peer, id)) {
+            self.events.push_back(TransferEvent::Saved { peer, name: transfer.name, path: transfer.path });
+        }
+    }
+
+    fn send_next_chunk(&mut self, rr: &mut RequestResponse<FileCodec>, id: u64) {
+        let transfer = match self.outgoing.get_mut(&id) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        if transfer.sent == transfer.size {
+            if let Some(transfer) = self.outgoing.remove(&id) {
+                self.events.push_back(TransferEvent::Sent { peer: transfer.peer, name: transfer.name });
+            }
+            return;
+        }
+        let mut data = vec![0; CHUNK_SIZE.min((transfer.size - transfer.sent) as usize)];
+        if let Err(e) = transfer.file.read_exact(&mut data) {
+            let (peer, name, error) = (transfer.peer, transfer.name.clone(), e.to_string());
+            self.events.push_back(TransferEvent::Failed { peer, name, error });
+            self.outgoing.remove(&id);
+            return;
+        }
+        transfer.sent += data.len() as u64;
+        let request = rr.send_request(&transfer.peer, FileRequest::Chunk { id, data });
+        self.requests.insert(request, id);
+    }
+}
+
+/// Files still being received when we shut down are not kept half-written.
+impl Drop for Transfers {
+    fn drop(&mut self) {
+        for transfer in self.incoming.values() {
+            remove_partial(transfer);
+        }
+    }
+}
+
+fn remove_partial(transfer: &Incoming) {
+    if let Err(e) = fs::remove_file(&transfer.path) {
+        tracing::warn!("Could not remove partly received {}: {}", transfer.path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abandoned_downloads_leave_no_file_behind() {
+        let dir = std::env::temp_dir().join(format!("peardchat-test-abandon-{}", rand::random::<u64>()));
+        let mut transfers = Transfers::new(dir.clone());
+        let peer = PeerId::random();
+        let receive = |transfers: &mut Transfers, id| {
+            let (path, file) = transfers.create_download("notes.txt").unwrap();
+            let incoming = Incoming { name: "notes.txt".to_string(), path: path.clone(), file, size: 4, received: 0 };
+            transfers.incoming.insert((peer, id), incoming);
+            path
+        };
+
+        let path = receive(&mut transfers, 0);
+        assert!(matches!(transfers.on_chunk(peer, 0, b"no"), FileResponse::Received));
+        assert!(matches!(transfers.on_chunk(peer, 0, b"more"), FileResponse::Rejected(_)));
+        assert!(!path.exists());
+        assert!(matches!(transfers.events.back(), Some(TransferEvent::Failed { .. })));
+
+        // Still partly written when the sender goes away, or when we exit.
+        let path = receive(&mut transfers, 1);
+        transfers.on_chunk(peer, 1, b"no");
+        transfers.peer_gone(&peer);
+        assert!(!path.exists());
+        let path = receive(&mut transfers, 2);
+        drop(transfers);
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,147 @@
+//! The full-screen terminal UI behind `--tui`: chat scrolling in the main
+//! pane, the roster of peers in a sidebar and a fixed input line at the bottom.
+//!
+//! The event loop in `main` stays in charge; it feeds output lines in with
+//! [`Tui::push`], key presses with [`Tui::handle_key`] and redraws with
+//! [`Tui::draw`] whenever something changed.
+
+use crossterm::{
+    event::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Direction, Layout},
+    widgets::{Block, Borders, List, ListItem, Paragraph},
+    Terminal,
+};
+use std::io::{self, Stdout};
+
+/// How many output lines are kept for scrolling back.
+const SCROLLBACK: usize = 10_000;
+
+pub struct Tui {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    lines: Vec<String>,
+    input: String,
+    /// How many lines up from the newest the message pane is scrolled.
+    scroll: usize,
+}
+
+impl Tui {
+    /// Switches the terminal to raw mode on the alternate screen. Dropping
+    /// the `Tui` puts it back.
+    pub fn start() -> io::Result<Self> {
+        enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        let terminal = Terminal::new(CrosstermBackend::new(stdout))?;
+        Ok(Tui { terminal, lines: Vec::new(), input: String::new(), scroll: 0 })
+    }
+
+    /// Adds a line to the message pane.
+    pub fn push(&mut self, line: String) {
+        // Output may hold several lines, which the pane shows one per row.
+        self.lines.extend(line.lines().map(str::to_string));
+        if self.lines.len() > SCROLLBACK {
+            self.lines.drain(..self.lines.len() - SCROLLBACK);
+        }
+    }
+
+    /// Empties the message pane.
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.scroll = 0;
+    }
+
+    /// Edits the input line. Returns a line of input once Enter is pressed,
+    /// and `/quit` on Ctrl-C or Ctrl-D, since raw mode swallows the signal.
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<String> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') | KeyCode::Char('d') if ctrl => return Some("/quit".to_string()),
+            KeyCode::Char(c) if !ctrl => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Enter if !self.input.is_empty() => {
+                self.scroll = 0;
+                return Some(std::mem::take(&mut self.input));
+            }
+            KeyCode::Up => self.scroll = (self.scroll + 1).min(self.lines.len()),
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::PageUp => self.scroll = (self.scroll + 10).min(self.lines.len()),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ => {}
+        }
+        None
+    }
+
+    /// Whether something has been typed that is not sent yet.
+    pub fn is_composing(&self) -> bool {
+        !self.input.is_empty()
+    }
+
+    /// Redraws the screen, titling the message pane with the current room,
+    /// listing the roster lines `peers` in the sidebar and naming those `typing` above the
+    /// input line.
+    pub fn draw(&mut self, room: Option<&str>, peers: &[String], typing: &[String]) -> io::Result<()> {
+        let Tui { terminal, lines, input, scroll } = self;
+        terminal.draw(|frame| {
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(3), Constraint::Length(3)])
+                .split(frame.size());
+            let columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Min(20), Constraint::Length(30)])
+                .split(rows[0]);
+
+            // Show the newest lines that fit, `scroll` lines back.
+            let height = columns[0].height.saturating_sub(2) as usize;
+            let end = lines.len() - (*scroll).min(lines.len());
+            let visible: Vec<_> = lines[end.saturating_sub(height)..end]
+                .iter()
+                .map(|l| ListItem::new(l.as_str()))
+                .collect();
+            let title = match (room, *scroll) {
+                (Some(room), 0) => format!(" [{}] ", room),
+                (Some(room), n) => format!(" [{}] ({} lines back) ", room, n),
+                (None, _) => " not in a room ".to_string(),
+            };
+            frame.render_widget(
+                List::new(visible).block(Block::default().borders(Borders::ALL).title(title)),
+                columns[0],
+            );
+
+            let peers: Vec<_> = peers.iter().map(|p| ListItem::new(p.as_str())).collect();
+            let title = format!(" peers ({}) ", peers.len());
+            frame.render_widget(List::new(peers).block(Block::default().borders(Borders::ALL).title(title)), columns[1]);
+
+            let title = match typing {
+                [] => String::new(),
+                [name] => format!(" {} is typing… ", name),
+                names => format!(" {} are typing… ", names.join(", ")),
+            };
+            frame.render_widget(
+                Paragraph::new(format!("> {}", input)).block(Block::default().borders(Borders::ALL).title(title)),
+                rows[1],
+            );
+            frame.set_cursor(rows[1].x + 3 + input.chars().count() as u16, rows[1].y + 1);
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
@@ -0,0 +1,944 @@
+//! Message delivery between nodes in one process. Most connect over
+//! `MemoryTransport`, so no real network is needed.
+
+use futures::future::select_all;
+use libp2p::{identity, multiaddr::Protocol, Multiaddr};
+use peardchat::{
+    config::{Config, PubSubProtocol},
+    dm::DM_TOPIC,
+    invite::Invite,
+    memory_transport,
+    message::WIRE_VERSION,
+    roster::{Presence, RosterEntry},
+    transfer::{TransferEvent, MAX_PENDING_PER_PEER},
+    ChatEvent, ChatMessage, ChatNode, Unconfirmed,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use std::time::{Duration, Instant};
+use tokio::time::timeout;
+
+const TOPIC: &str = "chat";
+
+/// How long any one step may take before the test fails.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Starts a node listening on a fresh `/memory` address, joined to `TOPIC`.
+async fn start_node() -> (ChatNode, Multiaddr) {
+    start_node_with(Config::default()).await
+}
+
+/// Like [`start_node`], but with `config` apart from its bootstrap peers,
+/// and remembering no peers unless `config` has a peerstore of its own.
+async fn start_node_with(config: Config) -> (ChatNode, Multiaddr) {
+    let id_keys = identity::Keypair::generate_ed25519();
+    let (transport, relay, bandwidth) = memory_transport(&id_keys);
+    let addr = Multiaddr::from(Protocol::Memory(rand::random::<u64>() | 1));
+    let remember_peers = config.peerstore != Config::default().peerstore;
+    let config = Config { bootstrap: Vec::new(), remember_peers, ..config };
+    let mut node = ChatNode::with_transport(id_keys, &config, transport, relay, bandwidth, addr.clone())
+        .await
+        .expect("Starting a node");
+    node.join(TOPIC).expect("Joining the topic");
+    (node, addr)
+}
+
+/// Polls every node, handing each event to `f` along with the index of the
+/// node that emitted it, until `f` returns something. Panics after `TIMEOUT`.
+async fn run_until<T>(nodes: &mut [ChatNode], mut f: impl FnMut(&mut [ChatNode], usize, ChatEvent) -> Option<T>) -> T {
+    let run = async {
+        loop {
+            let (event, i, _) = select_all(nodes.iter_mut().map(|n| Box::pin(n.poll_event()))).await;
+            if let Some(result) = f(nodes, i, event) {
+                return result;
+            }
+        }
+    };
+    timeout(TIMEOUT, run).await.expect("Timed out")
+}
+
+/// Runs the nodes until `nodes[i]` knows that `count` peers are on `TOPIC`,
+/// so that what it publishes reaches them.
+async fn subscribed(nodes: &mut [ChatNode], i: usize, count: usize) {
+    run_until(nodes, |nodes, _, _| {
+        let subscribed = nodes[i].peers().iter().filter(|p| p.topics.iter().any(|t| t == TOPIC)).count();
+        (subscribed >= count).then_some(())
+    })
+    .await
+}
+
+/// Runs the nodes until `nodes[i]` receives a chat message on `TOPIC`.
+async fn received(nodes: &mut [ChatNode], i: usize) -> ChatMessage {
+    run_until(nodes, |_, from, event| match event {
+        ChatEvent::MessageReceived { topic, message, .. } if from == i && topic == TOPIC => Some(message),
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn message_round_trip() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    let sent = ChatMessage::new(None, "hello over memory");
+    nodes[0].send(TOPIC, sent.clone()).await.unwrap();
+    let got = received(&mut nodes, 1).await;
+    assert_eq!(got, sent);
+    assert_eq!(got.encode(), sent.encode());
+}
+
+#[tokio::test]
+async fn nickname_travels_with_message() {
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    nodes[0].send(TOPIC, ChatMessage::new(Some("alice".into()), "hi")).await.unwrap();
+    let got = received(&mut nodes, 1).await;
+    assert_eq!(got.nick.as_deref(), Some("alice"));
+    assert_eq!(got.body, "hi");
+    let a_seen_by_b = nodes[1].peers().into_iter().find(|p| p.peer_id == a_id).unwrap();
+    assert_eq!(a_seen_by_b.nick.as_deref(), Some("alice"));
+}
+
+#[tokio::test]
+async fn late_joiner_misses_earlier_messages() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr.clone()).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    nodes[0].send(TOPIC, ChatMessage::new(None, "before")).await.unwrap();
+    assert_eq!(received(&mut nodes, 1).await.body, "before");
+
+    let (mut c, _) = start_node().await;
+    c.dial(a_addr).unwrap();
+    nodes.push(c);
+    subscribed(&mut nodes, 0, 2).await;
+    nodes[0].send(TOPIC, ChatMessage::new(None, "after")).await.unwrap();
+    assert_eq!(received(&mut nodes, 2).await.body, "after");
+}
+
+#[tokio::test]
+async fn floodsub_passes_messages_on() {
+    let config = || Config { pubsub: PubSubProtocol::Floodsub, ..Config::default() };
+    let (a, a_addr) = start_node_with(config()).await;
+    let (mut b, _) = start_node_with(config()).await;
+    let (mut c, _) = start_node_with(config()).await;
+    b.dial(a_addr.clone()).unwrap();
+    c.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b, c];
+    subscribed(&mut nodes, 0, 2).await;
+    subscribed(&mut nodes, 1, 1).await;
+
+    // `a` hands it on from `b` to `c`, which are not connected.
+    nodes[1].send(TOPIC, ChatMessage::new(Some("bob".into()), "flooded")).await.unwrap();
+    let got = received(&mut nodes, 2).await;
+    assert_eq!((got.nick.as_deref(), got.body.as_str()), (Some("bob"), "flooded"));
+}
+
+#[tokio::test]
+async fn direct_message_crosses_floodsub_under_its_signature() {
+    let config = || Config { pubsub: PubSubProtocol::Floodsub, ..Config::default() };
+    let (a, a_addr) = start_node_with(config()).await;
+    let (mut b, _) = start_node_with(config()).await;
+    let (mut c, _) = start_node_with(config()).await;
+    b.dial(a_addr.clone()).unwrap();
+    c.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b, c];
+    run_until(&mut nodes, |nodes, _, _| {
+        let on_dm = nodes[0].peers().iter().filter(|p| p.topics.iter().any(|t| t == DM_TOPIC)).count();
+        (on_dm == 2).then_some(())
+    })
+    .await;
+
+    let (b_id, c_id) = (*nodes[1].local_peer_id(), *nodes[2].local_peer_id());
+    nodes[1].send_dm(&c_id, ChatMessage::new(None, "psst")).await.unwrap();
+    let (source, body) = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::DirectMessage { source, message, .. } if from == 2 => Some((source, message.body)),
+        _ => None,
+    })
+    .await;
+    assert_eq!((source, body.as_str()), (b_id, "psst"));
+}
+
+#[tokio::test]
+async fn long_message_crosses_floodsub_in_fragments() {
+    let config = || Config { pubsub: PubSubProtocol::Floodsub, ..Config::default() };
+    let (a, a_addr) = start_node_with(config()).await;
+    let (mut b, _) = start_node_with(config()).await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 1, 1).await;
+
+    // Too random to compress below what floodsub carries at once.
+    let body: String = rand::thread_rng().sample_iter(&Alphanumeric).take(4000).map(char::from).collect();
+    nodes[1].send(TOPIC, ChatMessage::new(None, body.clone())).await.unwrap();
+    let got = received(&mut nodes, 0).await;
+    assert_eq!(got.body, body);
+}
+
+#[tokio::test]
+async fn republished_message_is_shown_once() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    let ok = ChatMessage::new(None, "ok");
+    nodes[0].send(TOPIC, ok.clone()).await.unwrap();
+    nodes[0].send(TOPIC, ok.clone()).await.unwrap();
+    // Typed again, so with a new id.
+    let again = ChatMessage::new(None, "ok");
+    nodes[0].send(TOPIC, again.clone()).await.unwrap();
+    assert_eq!(received(&mut nodes, 1).await, ok);
+    assert_eq!(received(&mut nodes, 1).await, again);
+}
+
+#[tokio::test]
+async fn long_message_arrives_compressed() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    let sent = ChatMessage::new(None, "all work and no play ".repeat(100));
+    assert!(sent.encode().len() < sent.body.len());
+    nodes[0].send(TOPIC, sent.clone()).await.unwrap();
+    assert_eq!(received(&mut nodes, 1).await, sent);
+}
+
+#[tokio::test]
+async fn received_message_is_authenticated() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    nodes[0].send(TOPIC, ChatMessage::new(None, "signed")).await.unwrap();
+    let authenticated = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::MessageReceived { authenticated, .. } if from == 1 => Some(authenticated),
+        _ => None,
+    })
+    .await;
+    assert!(authenticated);
+}
+
+#[test]
+fn signature_binds_author_and_topic() {
+    let author = identity::Keypair::generate_ed25519();
+    let author_id = author.public().into_peer_id();
+    let other_id = identity::Keypair::generate_ed25519().public().into_peer_id();
+    let sent = ChatMessage::new(None, "hi");
+    let data = sent.encode_signed(TOPIC, &author);
+
+    assert_eq!(ChatMessage::decode_signed(&data, TOPIC, Some(&author_id)), Some((sent.clone(), true)));
+    assert_eq!(ChatMessage::decode_signed(&data, TOPIC, Some(&other_id)), Some((sent.clone(), false)));
+    assert_eq!(ChatMessage::decode_signed(&data, "elsewhere", Some(&author_id)), Some((sent.clone(), false)));
+    assert_eq!(ChatMessage::decode_signed(&sent.encode(), TOPIC, Some(&author_id)), Some((sent, false)));
+}
+
+#[tokio::test]
+async fn invite_token_dials_the_inviter() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    let invite = Invite::new(*a.local_peer_id(), vec![a_addr], Duration::from_secs(60));
+    let received = Invite::from_token(&invite.to_token()).unwrap();
+    assert_eq!((received.peer, &received.addrs), (invite.peer, &invite.addrs));
+    for addr in received.dial_addrs() {
+        b.dial(addr).unwrap();
+    }
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    let expired = Invite { expires_at: chrono::Local::now() - chrono::Duration::minutes(1), ..invite };
+    assert!(Invite::from_token(&expired.to_token()).unwrap_err().starts_with("The invite expired"));
+    assert!(Invite::from_token("not-base58!").unwrap_err().starts_with("Not an invite"));
+}
+
+#[tokio::test]
+async fn flood_is_cut_off_at_the_rate_limit() {
+    let (a, a_addr) = start_node().await;
+    let config = Config { rate_limit_messages: 3, rate_limit_window_secs: 1, ..Config::default() };
+    let (mut b, _) = start_node_with(config).await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    for i in 0..6 {
+        nodes[0].send(TOPIC, ChatMessage::new(None, i.to_string())).await.unwrap();
+    }
+    // Collect what gets through until the window has passed.
+    let mut shown = Vec::new();
+    let _ = timeout(Duration::from_millis(1500), run_until(&mut nodes, |_, from, event| {
+        if let ChatEvent::MessageReceived { message, .. } = event {
+            if from == 1 {
+                shown.push(message.body);
+            }
+        }
+        None::<()>
+    }))
+    .await;
+    assert_eq!(shown, ["0", "1", "2"]);
+
+    nodes[0].send(TOPIC, ChatMessage::new(None, "later")).await.unwrap();
+    assert_eq!(received(&mut nodes, 1).await.body, "later");
+}
+
+#[tokio::test]
+async fn clock_moves_past_received_messages() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    assert_eq!(nodes[1].tick(), 1);
+    let sent = ChatMessage { clock: Some(5), ..ChatMessage::new(None, "from the future") };
+    nodes[0].send(TOPIC, sent).await.unwrap();
+    assert_eq!(received(&mut nodes, 1).await.clock, Some(5));
+    assert_eq!(nodes[1].tick(), 6);
+}
+
+#[tokio::test]
+async fn history_provider_replays_to_late_joiner() {
+    let config = Config { history_provider: true, history_provider_messages: 2, ..Config::default() };
+    let (a, a_addr) = start_node_with(config).await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr.clone()).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 1, 1).await;
+    for body in ["one", "two"] {
+        nodes[1].send(TOPIC, ChatMessage::new(None, body)).await.unwrap();
+        assert_eq!(received(&mut nodes, 0).await.body, body);
+    }
+    nodes[0].send(TOPIC, ChatMessage::new(None, "three")).await.unwrap();
+
+    let b_id = *nodes[1].local_peer_id();
+    let (mut c, _) = start_node().await;
+    c.dial(a_addr).unwrap();
+    nodes.push(c);
+    let mut replayed = Vec::new();
+    run_until(&mut nodes, |_, from, event| {
+        if let ChatEvent::History { source, authenticated, message, .. } = event {
+            if from == 2 {
+                assert!(authenticated);
+                replayed.push((source, message.body));
+            }
+        }
+        (replayed.len() == 2).then_some(())
+    })
+    .await;
+    assert_eq!(replayed[0], (Some(b_id), "two".to_string()));
+    assert_eq!(replayed[1].1, "three");
+
+    // Asked for again on joining another room, from peers already there.
+    nodes[0].join("other").unwrap();
+    nodes[1].join("other").unwrap();
+    run_until(&mut nodes, |nodes, _, _| (nodes[0].topic_peer_count("other") == 1).then_some(())).await;
+    nodes[1].send("other", ChatMessage::new(None, "elsewhere")).await.unwrap();
+    run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::MessageReceived { topic, .. } if from == 0 && topic == "other" => Some(()),
+        _ => None,
+    })
+    .await;
+    nodes[2].join("other").unwrap();
+    let replayed = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::History { topic, message, .. } if from == 2 && topic == "other" => Some(message.body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(replayed, "elsewhere");
+}
+
+#[tokio::test]
+async fn nick_change_is_announced() {
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    nodes[0].send(TOPIC, ChatMessage::new(Some("alice".into()), "hi")).await.unwrap();
+    received(&mut nodes, 1).await;
+
+    nodes[0].announce_nick(Some("alice".into()), "bob");
+    let (peer, old, new) = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::NickChanged { peer, old, new } if from == 1 => Some((peer, old, new)),
+        _ => None,
+    })
+    .await;
+    assert_eq!((peer, old.as_deref(), new.as_str()), (a_id, Some("alice"), "bob"));
+    assert_eq!(nodes[1].nick(&a_id), Some("bob"));
+}
+
+#[tokio::test]
+async fn reactions_refer_to_the_message() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    let b_id = *b.local_peer_id();
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    subscribed(&mut nodes, 1, 1).await;
+    let sent = ChatMessage::new(Some("alice".into()), "nice idea");
+    nodes[0].send(TOPIC, sent.clone()).await.unwrap();
+    let to = received(&mut nodes, 1).await.id.unwrap();
+
+    nodes[1].send(TOPIC, ChatMessage::reaction(Some("bob".into()), "👍", to)).await.unwrap();
+    let reaction = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::Reaction { peer, nick, to, emoji, .. } if from == 0 => Some((peer, nick, to, emoji)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(reaction, (b_id, Some("bob".to_string()), sent.id.unwrap(), "👍".to_string()));
+}
+
+#[tokio::test]
+async fn roster_follows_heartbeats_and_departures() {
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    nodes[0].send(TOPIC, ChatMessage::heartbeat(Some("alice".into()))).await.unwrap();
+    run_until(&mut nodes, |nodes, _, _| nodes[1].roster().iter().any(|e| e.nick.is_some()).then_some(())).await;
+    let entry = RosterEntry { peer_id: a_id, nick: Some("alice".into()), presence: Presence::Online };
+    assert_eq!(nodes[1].roster(), std::slice::from_ref(&entry));
+
+    nodes.remove(0);
+    run_until(&mut nodes, |_, _, event| matches!(event, ChatEvent::PeerLeft { .. }).then_some(())).await;
+    assert_eq!(nodes[0].roster(), [RosterEntry { presence: Presence::Offline, ..entry }]);
+}
+
+#[tokio::test]
+async fn going_away_and_back_is_announced_once() {
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    let changed = |_: &mut [ChatNode], from, event| match event {
+        ChatEvent::PresenceChanged { peer, away, .. } if from == 1 && peer == a_id => Some(away),
+        _ => None,
+    };
+
+    nodes[0].send(TOPIC, ChatMessage::presence(None, true)).await.unwrap();
+    assert!(run_until(&mut nodes, changed).await);
+    assert_eq!(nodes[1].roster()[0].presence, Presence::Away);
+    // Repeating it changes nothing, so the next change is coming back.
+    nodes[0].send(TOPIC, ChatMessage::presence(None, true)).await.unwrap();
+    nodes[0].send(TOPIC, ChatMessage::presence(None, false)).await.unwrap();
+    assert!(!run_until(&mut nodes, changed).await);
+    assert_eq!(nodes[1].roster()[0].presence, Presence::Online);
+}
+
+#[tokio::test]
+async fn message_of_the_day_is_fetched_on_connecting() {
+    let config = Config { motd: Some("Welcome to the dev room!".into()), ..Config::default() };
+    let (a, a_addr) = start_node_with(config).await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node_with(Config { motd_peer: Some(a_id), ..Config::default() }).await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    let (peer, text) = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::Motd { peer, text } if from == 1 => Some((peer, text)),
+        _ => None,
+    })
+    .await;
+    assert_eq!((peer, text.as_str()), (a_id, "Welcome to the dev room!"));
+}
+
+#[tokio::test]
+async fn messages_flow_again_after_reconnecting() {
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    // Blocking writes the blocklist, which must not be the real one.
+    let blocklist = std::env::temp_dir().join(format!("peardchat-test-{}", rand::random::<u64>()));
+    let (mut b, _) = start_node_with(Config { blocklist: blocklist.clone(), ..Config::default() }).await;
+    b.dial(a_addr.clone()).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    // Blocking closes the connection, and unblocking lets it be opened again.
+    nodes[1].block(a_id).unwrap();
+    run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::PeerLeft { peer, .. } if from == 1 && peer == a_id => Some(()),
+        _ => None,
+    })
+    .await;
+    nodes[1].unblock(&a_id).unwrap();
+    nodes[1].dial(a_addr).unwrap();
+    subscribed(&mut nodes, 0, 1).await;
+    subscribed(&mut nodes, 1, 1).await;
+
+    nodes[0].send(TOPIC, ChatMessage::new(None, "welcome back")).await.unwrap();
+    assert_eq!(received(&mut nodes, 1).await.body, "welcome back");
+    nodes[1].send(TOPIC, ChatMessage::new(None, "glad to be")).await.unwrap();
+    assert_eq!(received(&mut nodes, 0).await.body, "glad to be");
+    let _ = std::fs::remove_file(blocklist);
+}
+
+#[tokio::test]
+async fn remembered_peers_are_dialed_on_startup() {
+    let peerstore = std::env::temp_dir().join(format!("peardchat-test-peerstore-{}", rand::random::<u64>()));
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node_with(Config { peerstore: peerstore.clone(), ..Config::default() }).await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    let joined = |id| move |_: &mut [ChatNode], _, event| match event {
+        ChatEvent::PeerJoined { peer, .. } if peer == id => Some(()),
+        _ => None,
+    };
+    run_until(&mut nodes, joined(a_id)).await;
+
+    // A node with the same peerstore reaches `a` without being told to.
+    let (c, _) = start_node_with(Config { peerstore: peerstore.clone(), ..Config::default() }).await;
+    nodes[1] = c;
+    run_until(&mut nodes, joined(a_id)).await;
+    let _ = std::fs::remove_file(peerstore);
+}
+
+#[tokio::test]
+async fn disconnecting_closes_the_connection_without_blocking() {
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr.clone()).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    assert!(nodes[1].disconnect(&a_id));
+    run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::PeerLeft { peer, .. } if from == 1 && peer == a_id => Some(()),
+        _ => None,
+    })
+    .await;
+    assert!(!nodes[1].disconnect(&a_id));
+    assert!(!nodes[1].is_blocked(&a_id));
+    nodes[1].dial(a_addr).unwrap();
+    subscribed(&mut nodes, 0, 1).await;
+}
+
+#[tokio::test]
+async fn discovered_peers_are_listed_until_dialed() {
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node_with(Config { auto_dial: false, ..Config::default() }).await;
+    b.dial(a_addr.with(Protocol::P2p(a_id.into()))).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    assert!(nodes[1].discovered().is_empty());
+
+    // Kademlia still knows where `a` is once the connection is gone.
+    nodes[1].disconnect(&a_id);
+    run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::PeerLeft { peer, .. } if from == 1 && peer == a_id => Some(()),
+        _ => None,
+    })
+    .await;
+    let discovered: Vec<_> = nodes[1].discovered().into_iter().map(|p| (p.peer_id, p.addrs.len())).collect();
+    assert_eq!(discovered, [(a_id, 1)]);
+    nodes[1].dial_peer(&a_id).unwrap();
+    subscribed(&mut nodes, 1, 1).await;
+    assert!(nodes[1].discovered().is_empty());
+}
+
+#[tokio::test]
+async fn topic_stats_count_messages_and_senders() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    subscribed(&mut nodes, 1, 1).await;
+
+    nodes[0].send(TOPIC, ChatMessage::new(None, "one")).await.unwrap();
+    received(&mut nodes, 1).await;
+    nodes[0].send(TOPIC, ChatMessage::new(None, "two")).await.unwrap();
+    received(&mut nodes, 1).await;
+    nodes[1].send(TOPIC, ChatMessage::new(None, "three")).await.unwrap();
+    let stats = nodes[1].topic_stats();
+    assert_eq!(stats.len(), 1);
+    assert_eq!((stats[0].topic.as_str(), stats[0].per_minute, stats[0].total, stats[0].senders), (TOPIC, 3, 3, 2));
+    assert!(stats[0].last_activity.is_some());
+
+    nodes[1].reset_topic_stats();
+    assert_eq!(nodes[1].topic_stats()[0].total, 0);
+}
+
+#[tokio::test]
+async fn direct_messages_become_forward_secret_once_answered() {
+    let (a, a_addr) = start_node_with(Config { forward_secrecy: true, ..Config::default() }).await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    run_until(&mut nodes, |nodes, _, _| {
+        let on_dm = |node: &mut ChatNode| node.peers().iter().any(|p| p.topics.iter().any(|t| t == DM_TOPIC));
+        (on_dm(&mut nodes[0]) && on_dm(&mut nodes[1])).then_some(())
+    })
+    .await;
+    let (a_id, b_id) = (*nodes[0].local_peer_id(), *nodes[1].local_peer_id());
+    let direct = |to| move |_: &mut [ChatNode], from, event| match event {
+        ChatEvent::DirectMessage { message, forward_secret, .. } if from == to => Some((message.body, forward_secret)),
+        _ => None,
+    };
+
+    // What a sends before hearing back still rests on b's identity key.
+    assert!(!nodes[0].send_dm(&b_id, ChatMessage::new(None, "one")).await.unwrap());
+    assert_eq!(run_until(&mut nodes, direct(1)).await, ("one".to_string(), false));
+    assert!(nodes[1].send_dm(&a_id, ChatMessage::new(None, "two")).await.unwrap());
+    assert_eq!(run_until(&mut nodes, direct(0)).await, ("two".to_string(), true));
+    assert!(nodes[0].send_dm(&b_id, ChatMessage::new(None, "three")).await.unwrap());
+    assert_eq!(run_until(&mut nodes, direct(1)).await, ("three".to_string(), true));
+}
+
+#[tokio::test]
+async fn room_key_keeps_out_peers_without_it() {
+    let keyed = || Config { room_key: Some("open sesame".into()), ..Config::default() };
+    let (a, a_addr) = start_node_with(keyed()).await;
+    let (mut b, _) = start_node_with(keyed()).await;
+    let (mut c, _) = start_node_with(Config { room_key: Some("wrong".into()), ..Config::default() }).await;
+    let (mut d, _) = start_node().await;
+    for node in [&mut b, &mut c, &mut d] {
+        node.dial(a_addr.clone()).unwrap();
+    }
+    let mut nodes = vec![a, b, c, d];
+    subscribed(&mut nodes, 0, 3).await;
+
+    nodes[0].send(TOPIC, ChatMessage::new(None, "for members")).await.unwrap();
+    nodes[3].send(TOPIC, ChatMessage::new(None, "let me in")).await.unwrap();
+    let mut shown = Vec::new();
+    let _ = timeout(Duration::from_millis(1000), run_until(&mut nodes, |_, from, event| {
+        if let ChatEvent::MessageReceived { message, .. } = event {
+            shown.push((from, message.body));
+        }
+        None::<()>
+    }))
+    .await;
+    assert_eq!(shown, [(1, "for members".to_string())]);
+}
+
+#[tokio::test]
+async fn messages_older_than_min_version_are_dropped() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node_with(Config { min_version: WIRE_VERSION + 1, ..Config::default() }).await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    for body in ["old", "still old"] {
+        nodes[0].send(TOPIC, ChatMessage::new(None, body)).await.unwrap();
+    }
+    let newer = ChatMessage { version: WIRE_VERSION + 1, ..ChatMessage::new(None, "new") };
+    nodes[0].send(TOPIC, newer).await.unwrap();
+    let mut warnings = Vec::new();
+    let got = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::OutdatedPeer { version, .. } if from == 1 => {
+            warnings.push(version);
+            None
+        }
+        ChatEvent::MessageReceived { message, .. } if from == 1 => Some(message.body),
+        _ => None,
+    })
+    .await;
+    assert_eq!(got, "new");
+    assert_eq!(warnings, [WIRE_VERSION]);
+}
+
+#[tokio::test]
+async fn slow_mode_reaches_late_joiners_and_drops_fast_messages() {
+    let (mut a, a_addr) = start_node().await;
+    // Nobody is there to hear it yet.
+    assert!(a.set_slowmode(TOPIC, Duration::from_secs(60), None).is_err());
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    let interval = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::SlowMode { interval_secs, .. } if from == 1 => Some(interval_secs),
+        _ => None,
+    })
+    .await;
+    assert_eq!(interval, 60);
+    assert!(nodes[1].slowmode(TOPIC).is_some());
+    // Only `a` can lift it.
+    assert!(matches!(nodes[1].set_slowmode(TOPIC, Duration::ZERO, None), Ok(false)));
+    assert!(nodes[1].slowmode(TOPIC).is_some());
+
+    let first = ChatMessage::new(None, "first");
+    let too_soon = ChatMessage { sent_at: first.sent_at.map(|t| t + 1_000), ..ChatMessage::new(None, "too soon") };
+    let later = ChatMessage { sent_at: first.sent_at.map(|t| t + 61_000), ..ChatMessage::new(None, "later") };
+    for message in [first, too_soon, later] {
+        nodes[1].send(TOPIC, message).await.unwrap();
+    }
+    assert!(nodes[1].slowmode_wait(TOPIC).is_some());
+    let mut seen = Vec::new();
+    run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::MessageReceived { message, .. } if from == 0 => {
+            seen.push(message.body.clone());
+            (message.body == "later").then_some(())
+        }
+        ChatEvent::SlowModeDropped { .. } if from == 0 => {
+            seen.push("dropped".to_string());
+            None
+        }
+        _ => None,
+    })
+    .await;
+    assert_eq!(seen, ["first", "dropped", "later"]);
+}
+
+#[tokio::test]
+async fn peers_see_the_configured_agent_version() {
+    let config = Config { agent_version: "peardchat/0.2 myfork".into(), ..Config::default() };
+    let (a, a_addr) = start_node_with(config).await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    let agent = run_until(&mut nodes, |_, from, event| match event {
+        ChatEvent::PeerIdentified { agent_version, .. } if from == 1 => Some(agent_version),
+        _ => None,
+    })
+    .await;
+    assert_eq!(agent, "peardchat/0.2 myfork");
+}
+
+#[tokio::test]
+async fn nodes_connect_over_ipv6() {
+    // Unlike the rest, over real TCP, as the memory transport has no IP.
+    let config = Config {
+        bootstrap: Vec::new(),
+        bind: "::1".parse().unwrap(),
+        mdns: false,
+        remember_peers: false,
+        ..Config::default()
+    };
+    let mut a = ChatNode::new(identity::Keypair::generate_ed25519(), &config).await.unwrap();
+    a.join(TOPIC).unwrap();
+    let listening = timeout(TIMEOUT, async {
+        loop {
+            if let ChatEvent::ListeningOn(addr) = a.poll_event().await {
+                return addr;
+            }
+        }
+    })
+    .await
+    .expect("Timed out");
+    assert!(matches!(listening.iter().next(), Some(Protocol::Ip6(ip)) if ip.is_loopback()));
+
+    let mut b = ChatNode::new(identity::Keypair::generate_ed25519(), &config).await.unwrap();
+    b.join(TOPIC).unwrap();
+    b.dial(listening.to_string().parse().unwrap()).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+    nodes[1].send(TOPIC, ChatMessage::new(None, "over v6")).await.unwrap();
+    assert_eq!(received(&mut nodes, 0).await.body, "over v6");
+}
+
+#[tokio::test]
+async fn one_shot_send_waits_for_a_delivery() {
+    let (mut a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let b_id = *b.local_peer_id();
+
+    let listening = async {
+        loop {
+            b.poll_event().await;
+        }
+    };
+    let sent = tokio::select! {
+        sent = a.send_confirmed(TOPIC, ChatMessage::new(None, "deploy finished"), TIMEOUT) => sent,
+        _ = listening => unreachable!(),
+    };
+    assert_eq!(sent.unwrap().0, b_id);
+}
+
+#[tokio::test]
+async fn one_shot_send_gives_up_without_peers() {
+    let (mut a, _) = start_node().await;
+    let sent = a.send_confirmed(TOPIC, ChatMessage::new(None, "anyone?"), Duration::from_millis(300)).await;
+    assert!(matches!(sent, Err(Unconfirmed::NoPeers)));
+}
+
+#[tokio::test]
+async fn files_arrive_whole_in_the_downloads_directory() {
+    let dir = std::env::temp_dir().join(format!("peardchat-test-transfer-{}", rand::random::<u64>()));
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node_with(Config { downloads: dir.join("downloads"), ..Config::default() }).await;
+    let b_id = *b.local_peer_id();
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    // A few chunks' worth, small enough to be taken without confirmation.
+    let contents: Vec<u8> = (0..200_000).map(|_| rand::random()).collect();
+    let path = dir.join("notes.bin");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(&path, &contents).unwrap();
+    nodes[0].send_file(b_id, &path).unwrap();
+    let (mut saved, mut sent) = (None, false);
+    run_until(&mut nodes, |_, _, event| {
+        match event {
+            ChatEvent::Transfer(TransferEvent::Saved { path, .. }) => saved = Some(path),
+            ChatEvent::Transfer(TransferEvent::Sent { peer, name }) => sent = peer == b_id && name == "notes.bin",
+            ChatEvent::Transfer(TransferEvent::Failed { error, .. }) => panic!("Transfer failed: {}", error),
+            _ => {}
+        }
+        (saved.is_some() && sent).then_some(())
+    })
+    .await;
+    let saved = saved.unwrap();
+    assert_eq!(saved, dir.join("downloads").join("notes.bin"));
+    assert_eq!(std::fs::read(&saved).unwrap(), contents);
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[tokio::test]
+async fn every_receiver_acknowledges_a_message() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    let (mut c, _) = start_node().await;
+    let (b_id, c_id) = (*b.local_peer_id(), *c.local_peer_id());
+    b.dial(a_addr.clone()).unwrap();
+    c.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b, c];
+    subscribed(&mut nodes, 0, 2).await;
+
+    let sent = ChatMessage::new(None, "did everyone get this?");
+    nodes[0].send(TOPIC, sent.clone()).await.unwrap();
+    let mut acked = Vec::new();
+    run_until(&mut nodes, |_, from, event| {
+        // The others hear each other's acks too.
+        match event {
+            ChatEvent::Delivered { id, peer, .. } if from == 0 && Some(id) == sent.id => acked.push(peer),
+            _ => {}
+        }
+        (acked.len() == 2).then_some(())
+    })
+    .await;
+    acked.sort_by_key(|peer| *peer != b_id);
+    assert_eq!(acked, vec![b_id, c_id]);
+}
+
+#[tokio::test]
+async fn lost_bootstrap_peers_are_redialed_until_they_return() {
+    let a_keys = identity::Keypair::generate_ed25519();
+    let a_id = a_keys.public().into_peer_id();
+    let a_addr = Multiaddr::from(Protocol::Memory(rand::random::<u64>() | 1));
+    // Without mDNS, as `a` would find `b` through it when it comes back.
+    let start_a = || async {
+        let (transport, relay, bandwidth) = memory_transport(&a_keys);
+        let config = Config { bootstrap: Vec::new(), remember_peers: false, mdns: false, ..Config::default() };
+        let mut a = ChatNode::with_transport(a_keys.clone(), &config, transport, relay, bandwidth, a_addr.clone())
+            .await
+            .unwrap();
+        a.join(TOPIC).unwrap();
+        a
+    };
+    let a = start_a().await;
+    let b_keys = identity::Keypair::generate_ed25519();
+    let (transport, relay, bandwidth) = memory_transport(&b_keys);
+    let bootstrap = vec![a_addr.clone().with(Protocol::P2p(a_id.into()))];
+    let config = Config { bootstrap, remember_peers: false, mdns: false, ..Config::default() };
+    let b_addr = Multiaddr::from(Protocol::Memory(rand::random::<u64>() | 1));
+    let b = ChatNode::with_transport(b_keys, &config, transport, relay, bandwidth, b_addr).await.unwrap();
+    let mut nodes = vec![b, a];
+    let presence = |online| move |_: &mut [ChatNode], from, event| match event {
+        ChatEvent::PeerJoined { peer, .. } if online && from == 0 && peer == a_id => Some(()),
+        ChatEvent::PeerLeft { peer, .. } if !online && from == 0 && peer == a_id => Some(()),
+        _ => None,
+    };
+    run_until(&mut nodes, presence(true)).await;
+
+    nodes.truncate(1);
+    run_until(&mut nodes, presence(false)).await;
+    let left = Instant::now();
+    // Long enough for the first redial, after a second, to fail.
+    let away = async {
+        loop {
+            nodes[0].poll_event().await;
+        }
+    };
+    let _ = timeout(Duration::from_millis(1500), away).await;
+    nodes.push(start_a().await);
+    run_until(&mut nodes, presence(true)).await;
+    // The next one waits twice as long.
+    assert!(left.elapsed() > Duration::from_millis(2500));
+}
+
+#[tokio::test]
+async fn clocks_far_ahead_are_not_taken_up() {
+    let (a, a_addr) = start_node().await;
+    let (mut b, _) = start_node().await;
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    let ahead = ChatMessage { clock: Some(u64::MAX), ..ChatMessage::new(None, "from the end of time") };
+    nodes[0].send(TOPIC, ahead).await.unwrap();
+    let got = received(&mut nodes, 1).await;
+    assert_eq!((got.body.as_str(), got.clock), ("from the end of time", None));
+    // Neither overflows nor jumps ahead.
+    assert_eq!(nodes[1].tick(), 1);
+    let near = ChatMessage { clock: Some(1_000), ..ChatMessage::new(None, "a little ahead") };
+    nodes[0].send(TOPIC, near).await.unwrap();
+    assert_eq!(received(&mut nodes, 1).await.clock, Some(1_000));
+    assert_eq!(nodes[1].tick(), 1_001);
+}
+
+#[tokio::test]
+async fn offers_waiting_are_capped_and_dropped_when_the_sender_leaves() {
+    let dir = std::env::temp_dir().join(format!("peardchat-test-offers-{}", rand::random::<u64>()));
+    let (a, a_addr) = start_node().await;
+    let a_id = *a.local_peer_id();
+    let (mut b, _) = start_node_with(Config { downloads: dir.join("downloads"), ..Config::default() }).await;
+    let b_id = *b.local_peer_id();
+    b.dial(a_addr).unwrap();
+    let mut nodes = vec![a, b];
+    subscribed(&mut nodes, 0, 1).await;
+
+    // Large enough to need confirmation.
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("big.bin");
+    std::fs::write(&path, vec![0; 2 * 1024 * 1024]).unwrap();
+    for _ in 0..=MAX_PENDING_PER_PEER {
+        nodes[0].send_file(b_id, &path).unwrap();
+    }
+    let (mut offered, mut declined) = (0, None);
+    run_until(&mut nodes, |_, _, event| {
+        match event {
+            ChatEvent::Transfer(TransferEvent::Offered { .. }) => offered += 1,
+            ChatEvent::Transfer(TransferEvent::Declined { reason, .. }) => declined = Some(reason),
+            _ => {}
+        }
+        (offered == MAX_PENDING_PER_PEER && declined.is_some()).then_some(())
+    })
+    .await;
+    assert_eq!(declined.as_deref(), Some("too many offers waiting"));
+
+    assert!(nodes[1].disconnect(&a_id));
+    let mut failed = 0;
+    run_until(&mut nodes, |_, from, event| {
+        if let (1, ChatEvent::Transfer(TransferEvent::Failed { peer, .. })) = (from, event) {
+            assert_eq!(peer, a_id);
+            failed += 1;
+        }
+        (failed == MAX_PENDING_PER_PEER).then_some(())
+    })
+    .await;
+    assert!(!nodes[1].accept_file(1));
+    let _ = std::fs::remove_dir_all(dir);
+}